@@ -0,0 +1,228 @@
+use crate::{Message, Notifier, NotifyError};
+use async_trait::async_trait;
+use lettre::message::header::ContentType;
+use lettre::message::{Attachment, Mailbox, MultiPart, SinglePart};
+use lettre::transport::smtp::authentication::Credentials;
+use lettre::transport::smtp::client::{Tls, TlsParameters};
+use lettre::{AsyncSmtpTransport, AsyncTransport, Tokio1Executor};
+use serde::Deserialize;
+
+/// How to secure the connection to an SMTP server.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SmtpTls {
+    /// Plain text, only for relays on the same host or network
+    None,
+    /// Upgrade a plain connection, usually on port 587
+    #[default]
+    Starttls,
+    /// TLS from the start, usually on port 465
+    Tls,
+}
+
+/// The `[smtp]` section of a project's config file.
+///
+/// ```toml
+/// [smtp]
+/// host = "smtp.example.com"
+/// port = 587
+/// username = "piper@example.com"
+/// password = "env:SMTP_PASSWORD"
+/// from = "Piper <piper@example.com>"
+/// ```
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SmtpConfig {
+    pub host: String,
+    /// 25 without TLS, 587 with STARTTLS and 465 with TLS if not given
+    pub port: Option<u16>,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub from: String,
+    #[serde(default)]
+    pub tls: SmtpTls,
+}
+
+impl SmtpConfig {
+    pub fn port(&self) -> u16 {
+        self.port.unwrap_or(match self.tls {
+            SmtpTls::None => 25,
+            SmtpTls::Starttls => 587,
+            SmtpTls::Tls => 465,
+        })
+    }
+
+    fn transport(&self) -> Result<AsyncSmtpTransport<Tokio1Executor>, NotifyError> {
+        let tls = match self.tls {
+            SmtpTls::None => Tls::None,
+            mode => {
+                let parameters = TlsParameters::new(self.host.clone()).map_err(email_error)?;
+                if mode == SmtpTls::Tls {
+                    Tls::Wrapper(parameters)
+                } else {
+                    Tls::Required(parameters)
+                }
+            }
+        };
+        let mut builder = AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(&self.host)
+            .port(self.port())
+            .tls(tls);
+        if let Some(username) = &self.username {
+            let password = self.password.clone().unwrap_or_default();
+            builder = builder.credentials(Credentials::new(username.clone(), password));
+        }
+        Ok(builder.build())
+    }
+}
+
+/// Sends notifications by email, with their attachments.
+#[derive(Debug, Clone)]
+pub struct Email {
+    config: SmtpConfig,
+    /// Addresses separated by commas
+    to: String,
+}
+
+impl Email {
+    pub fn new(config: SmtpConfig, to: &str) -> Self {
+        Email {
+            config,
+            to: to.to_string(),
+        }
+    }
+
+    async fn build(&self, message: &Message) -> Result<lettre::Message, NotifyError> {
+        let mailbox = |address: &str| {
+            address
+                .trim()
+                .parse::<Mailbox>()
+                .map_err(|e| NotifyError::Invalid(format!("invalid address '{}': {}", address, e)))
+        };
+        let mut builder = lettre::Message::builder()
+            .from(mailbox(&self.config.from)?)
+            .subject(message.subject.as_deref().unwrap_or("Piper notification"));
+        for address in self.to.split(',').filter(|a| !a.trim().is_empty()) {
+            builder = builder.to(mailbox(address)?);
+        }
+
+        let body = SinglePart::plain(message.body.clone());
+        if message.attachments.is_empty() {
+            return builder.singlepart(body).map_err(email_error);
+        }
+        let mut parts = MultiPart::mixed().singlepart(body);
+        for path in &message.attachments {
+            let content = tokio::fs::read(path).await.map_err(|e| {
+                NotifyError::Invalid(format!("failed to attach {}: {}", path.display(), e))
+            })?;
+            let name = path.file_name().map_or_else(
+                || "attachment".to_string(),
+                |n| n.to_string_lossy().to_string(),
+            );
+            let content_type = ContentType::parse("application/octet-stream").unwrap();
+            parts = parts.singlepart(Attachment::new(name).body(content, content_type));
+        }
+        builder.multipart(parts).map_err(email_error)
+    }
+}
+
+#[async_trait]
+impl Notifier for Email {
+    async fn send(&self, message: &Message) -> Result<(), NotifyError> {
+        let email = self.build(message).await?;
+        self.config
+            .transport()?
+            .send(email)
+            .await
+            .map_err(email_error)?;
+        Ok(())
+    }
+
+    fn destination(&self) -> String {
+        self.to.clone()
+    }
+}
+
+fn email_error(error: impl std::fmt::Display) -> NotifyError {
+    NotifyError::Email(error.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+    use tokio::net::TcpListener;
+
+    // An SMTP server taking one email without TLS or authentication,
+    // returning its port and the recipients and content it was sent
+    async fn stand_in() -> (u16, tokio::task::JoinHandle<(Vec<String>, String)>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let handle = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let (reader, mut writer) = stream.into_split();
+            let mut lines = BufReader::new(reader).lines();
+            let (mut recipients, mut data) = (Vec::new(), String::new());
+            writer.write_all(b"220 stand-in ready\r\n").await.unwrap();
+            while let Some(line) = lines.next_line().await.unwrap() {
+                let command = line.to_ascii_uppercase();
+                let reply: &[u8] = if command.starts_with("RCPT TO:") {
+                    recipients.push(line[8..].trim_matches(['<', '>', ' ']).to_string());
+                    b"250 ok\r\n"
+                } else if command == "DATA" {
+                    writer.write_all(b"354 go ahead\r\n").await.unwrap();
+                    while let Some(line) = lines.next_line().await.unwrap() {
+                        if line == "." {
+                            break;
+                        }
+                        data.push_str(&line);
+                        data.push('\n');
+                    }
+                    b"250 queued\r\n"
+                } else if command == "QUIT" {
+                    writer.write_all(b"221 bye\r\n").await.unwrap();
+                    break;
+                } else {
+                    b"250 ok\r\n"
+                };
+                writer.write_all(reply).await.unwrap();
+            }
+            (recipients, data)
+        });
+        (port, handle)
+    }
+
+    #[tokio::test]
+    async fn sends_emails_with_attachments() {
+        let (port, received) = stand_in().await;
+        let config = SmtpConfig {
+            host: "127.0.0.1".to_string(),
+            port: Some(port),
+            from: "Piper <piper@example.com>".to_string(),
+            tls: SmtpTls::None,
+            ..Default::default()
+        };
+        let attachment = std::env::temp_dir().join(format!("piper-report-{}.txt", port));
+        std::fs::write(&attachment, "3 hosts up").unwrap();
+
+        let fields = serde_json::json!({
+            "to": "alice@example.com, bob@example.com",
+            "subject": "Recon finished",
+            "body": "See the report",
+            "attachment": attachment.to_string_lossy(),
+        });
+        let message = Message::from_fields(fields.as_object().unwrap().clone());
+        let email = Email::new(config, "alice@example.com, bob@example.com");
+        email.send(&message).await.unwrap();
+        std::fs::remove_file(&attachment).unwrap();
+
+        let (recipients, data) = received.await.unwrap();
+        assert_eq!(recipients, ["alice@example.com", "bob@example.com"]);
+        assert!(data.contains("Subject: Recon finished"), "{}", data);
+        assert!(data.contains("See the report"), "{}", data);
+        assert!(
+            data.contains(&format!("filename=\"piper-report-{}.txt\"", port)),
+            "{}",
+            data
+        );
+    }
+}
@@ -0,0 +1,65 @@
+use crate::{Message, Notifier, NotifyError};
+use async_trait::async_trait;
+use serde_json::json;
+use std::path::PathBuf;
+use tokio::io::AsyncWriteExt;
+
+/// Writes notifications to stdout as text, or appends them to a file as
+/// JSON lines.
+#[derive(Debug, Clone)]
+pub struct FileSink {
+    path: Option<PathBuf>,
+}
+
+impl FileSink {
+    pub fn stdout() -> Self {
+        FileSink { path: None }
+    }
+
+    pub fn file(path: PathBuf) -> Self {
+        FileSink { path: Some(path) }
+    }
+
+    /// The file notifications are appended to, None for stdout.
+    pub fn path(&self) -> Option<&PathBuf> {
+        self.path.as_ref()
+    }
+}
+
+#[async_trait]
+impl Notifier for FileSink {
+    async fn send(&self, message: &Message) -> Result<(), NotifyError> {
+        let Some(path) = &self.path else {
+            println!("[notify] {}", message.text());
+            for attachment in &message.attachments {
+                println!("[notify] attached {}", attachment.display());
+            }
+            return Ok(());
+        };
+
+        if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            tokio::fs::create_dir_all(dir).await?;
+        }
+        let line = json!({
+            "subject": message.subject,
+            "body": message.body,
+            "attachments": message.attachments,
+            "fields": message.fields,
+        });
+        let mut file = tokio::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .await?;
+        file.write_all(format!("{}\n", line).as_bytes()).await?;
+        file.flush().await?;
+        Ok(())
+    }
+
+    fn destination(&self) -> String {
+        match &self.path {
+            Some(path) => path.display().to_string(),
+            None => "stdout".to_string(),
+        }
+    }
+}
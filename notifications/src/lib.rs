@@ -1,8 +1,295 @@
+//! Notifications sent by pipelines: generic webhooks, Slack, Discord and
+//! Teams incoming webhooks, email over SMTP, and files or stdout.
+
+mod email;
+mod file;
+mod webhook;
+
+pub use email::{Email, SmtpConfig, SmtpTls};
+pub use file::FileSink;
+pub use webhook::{ChatService, ChatWebhook, Webhook};
+
+use async_trait::async_trait;
+use serde_json::{Map, Value};
+use std::path::PathBuf;
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum NotifyError {
+    #[error("Failed to send the notification: {0}")]
+    Http(#[from] reqwest::Error),
+
+    #[error("{service} answered with {status}: {body}")]
+    Rejected {
+        service: String,
+        status: u16,
+        body: String,
+    },
+
+    #[error("Failed to send the email: {0}")]
+    Email(String),
+
+    #[error("Failed to write the notification: {0}")]
+    Io(#[from] std::io::Error),
+
+    #[error("Invalid notification: {0}")]
+    Invalid(String),
+}
+
+/// What a notification says, whichever way it is sent.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Message {
+    pub subject: Option<String>,
+    pub body: String,
+    /// Files sent along by email, and listed by the file sink
+    pub attachments: Vec<PathBuf>,
+    /// Every field the notification was given, which generic webhooks send
+    /// without the `ROUTING_FIELDS`
+    pub fields: Map<String, Value>,
+}
+
+impl Message {
+    /// A message from a notify task's fields: `subject` or `title`, `body`,
+    /// `message` or `text`, and `attachment` or `attachments`. Without a
+    /// body the fields themselves are the body.
+    pub fn from_fields(fields: Map<String, Value>) -> Message {
+        let text = |names: &[&str]| {
+            names
+                .iter()
+                .find_map(|name| fields.get(*name))
+                .map(|value| match value {
+                    Value::String(s) => s.clone(),
+                    value => value.to_string(),
+                })
+        };
+        let attachments = match fields
+            .get("attachments")
+            .or_else(|| fields.get("attachment"))
+        {
+            Some(Value::String(path)) if !path.is_empty() => vec![PathBuf::from(path)],
+            Some(Value::Array(paths)) => paths
+                .iter()
+                .filter_map(Value::as_str)
+                .map(PathBuf::from)
+                .collect(),
+            _ => Vec::new(),
+        };
+        let body = text(&["body", "message", "text"])
+            .unwrap_or_else(|| serde_json::to_string_pretty(&fields).unwrap_or_default());
+        Message {
+            subject: text(&["subject", "title"]),
+            body,
+            attachments,
+            fields,
+        }
+    }
+
+    /// The subject and body as one text, for services without a subject.
+    pub fn text(&self) -> String {
+        match &self.subject {
+            Some(subject) => format!("{}\n{}", subject, self.body),
+            None => self.body.clone(),
+        }
+    }
+}
+
+/// Somewhere notifications are sent.
+#[async_trait]
+pub trait Notifier: Send + Sync {
+    async fn send(&self, message: &Message) -> Result<(), NotifyError>;
+
+    /// Where it sends to, such as a URL, an address or a path.
+    fn destination(&self) -> String;
+}
+
+/// Fields that say where a notification goes rather than what it says,
+/// left out of what generic webhooks are sent.
+pub const ROUTING_FIELDS: &[&str] = &["uri", "webhook", "service"];
+
+/// Where a notification goes.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Route {
+    Webhook(String),
+    Chat(ChatService, String),
+    /// Addresses separated by commas
+    Email(String),
+    File(PathBuf),
+    Stdout,
+}
+
+impl Route {
+    /// The route for a notify task's fields. `service` names one of webhook,
+    /// slack, discord, teams, email, file or stdout. Otherwise `to` sends an
+    /// email, a `uri` or `webhook` of `stdout` or `file:PATH` writes the
+    /// message out, and any other URL is a chat service if its host is
+    /// Slack's, Discord's or Teams', or a generic webhook.
+    pub fn from_fields(fields: &Map<String, Value>) -> Result<Route, NotifyError> {
+        let field = |name: &str| fields.get(name).and_then(Value::as_str);
+        let uri = field("uri").or_else(|| field("webhook"));
+        let service = match (field("service"), uri, field("to")) {
+            (Some(service), _, _) => service.to_ascii_lowercase(),
+            (None, Some("stdout") | Some("-"), _) => "stdout".to_string(),
+            (None, Some(uri), _) if uri.starts_with("file:") => "file".to_string(),
+            (None, Some(uri), _) => ChatService::from_url(uri)
+                .map_or("webhook", ChatService::name)
+                .to_string(),
+            (None, None, Some(_)) => "email".to_string(),
+            (None, None, None) => {
+                return Err(NotifyError::Invalid(
+                    "give a uri, a webhook or an address to send to".to_string(),
+                ))
+            }
+        };
+        let uri = || {
+            uri.map(str::to_string).ok_or_else(|| {
+                NotifyError::Invalid(format!("{} notifications need a uri", service))
+            })
+        };
+
+        Ok(match service.as_str() {
+            "webhook" => Route::Webhook(uri()?),
+            "stdout" => Route::Stdout,
+            "file" => {
+                let uri = uri()?;
+                Route::File(PathBuf::from(uri.strip_prefix("file:").unwrap_or(&uri)))
+            }
+            "email" => match field("to") {
+                Some(to) => Route::Email(to.to_string()),
+                None => {
+                    return Err(NotifyError::Invalid(
+                        "email notifications need `to`".to_string(),
+                    ))
+                }
+            },
+            name => match ChatService::from_name(name) {
+                Some(chat) => Route::Chat(chat, uri()?),
+                None => return Err(NotifyError::Invalid(format!("unknown service '{}'", name))),
+            },
+        })
+    }
+
+    /// The notifier that sends along this route. Email needs `smtp`.
+    pub fn notifier(self, smtp: Option<&SmtpConfig>) -> Result<Box<dyn Notifier>, NotifyError> {
+        Ok(match self {
+            Route::Webhook(uri) => Box::new(Webhook::new(uri)),
+            Route::Chat(chat, uri) => Box::new(ChatWebhook::new(chat, uri)),
+            Route::Email(to) => {
+                let smtp = smtp.ok_or_else(|| {
+                    NotifyError::Invalid("no SMTP server is configured for email".to_string())
+                })?;
+                Box::new(Email::new(smtp.clone(), &to))
+            }
+            Route::File(path) => Box::new(FileSink::file(path)),
+            Route::Stdout => Box::new(FileSink::stdout()),
+        })
+    }
+}
+
+/// The notifier for a notify task's fields, as `Route::from_fields` picks
+/// it.
+pub fn notifier_for(
+    fields: &Map<String, Value>,
+    smtp: Option<&SmtpConfig>,
+) -> Result<Box<dyn Notifier>, NotifyError> {
+    Route::from_fields(fields)?.notifier(smtp)
+}
+
 #[cfg(test)]
 mod tests {
-    #[test]
-    fn it_works() {
-        let result = 2 + 2;
-        assert_eq!(result, 4);
+    use super::*;
+    use serde_json::json;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    // An HTTP server answering one request with `status`, returning its
+    // address and the body it was sent
+    async fn stand_in(status: u16) -> (String, tokio::task::JoinHandle<Value>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = format!("http://{}", listener.local_addr().unwrap());
+        let handle = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut request = Vec::new();
+            let mut buffer = [0u8; 4096];
+            let body = loop {
+                let read = stream.read(&mut buffer).await.unwrap();
+                request.extend_from_slice(&buffer[..read]);
+                let text = String::from_utf8_lossy(&request).to_string();
+                if let Some((head, body)) = text.split_once("\r\n\r\n") {
+                    let length = head
+                        .lines()
+                        .find_map(|line| {
+                            line.to_ascii_lowercase()
+                                .strip_prefix("content-length:")
+                                .map(|n| n.trim().parse::<usize>().unwrap())
+                        })
+                        .unwrap_or(0);
+                    if body.len() >= length {
+                        break body.to_string();
+                    }
+                }
+            };
+            let response = format!(
+                "HTTP/1.1 {} Stand-in\r\ncontent-length: 4\r\n\r\nnope",
+                status
+            );
+            stream.write_all(response.as_bytes()).await.unwrap();
+            serde_json::from_str(&body).unwrap()
+        });
+        (address, handle)
+    }
+
+    fn fields(value: Value) -> Map<String, Value> {
+        match value {
+            Value::Object(fields) => fields,
+            _ => unreachable!(),
+        }
+    }
+
+    #[tokio::test]
+    async fn sends_to_webhooks_and_chat_services() {
+        let (address, received) = stand_in(200).await;
+        let task = fields(json!({"uri": address, "message": "scan done", "hosts": 3}));
+        let notifier = notifier_for(&task, None).unwrap();
+        notifier
+            .send(&Message::from_fields(task.clone()))
+            .await
+            .unwrap();
+        assert_eq!(
+            received.await.unwrap(),
+            json!({"message": "scan done", "hosts": 3})
+        );
+
+        let (address, received) = stand_in(200).await;
+        let task =
+            fields(json!({"service": "slack", "uri": address, "subject": "Recon", "body": "done"}));
+        let notifier = notifier_for(&task, None).unwrap();
+        notifier.send(&Message::from_fields(task)).await.unwrap();
+        assert_eq!(received.await.unwrap(), json!({"text": "*Recon*\ndone"}));
+
+        let (address, received) = stand_in(500).await;
+        let task = fields(json!({"service": "discord", "uri": address, "text": "done"}));
+        let error = notifier_for(&task, None)
+            .unwrap()
+            .send(&Message::from_fields(task))
+            .await
+            .unwrap_err();
+        assert!(
+            matches!(error, NotifyError::Rejected { status: 500, .. }),
+            "{}",
+            error
+        );
+        assert_eq!(received.await.unwrap(), json!({"content": "done"}));
+
+        let teams = fields(json!({"uri": "https://acme.webhook.office.com/webhookb2/x"}));
+        assert_eq!(
+            Route::from_fields(&fields(json!({"uri": "file:out/notes.jsonl"}))).unwrap(),
+            Route::File(PathBuf::from("out/notes.jsonl"))
+        );
+        assert_eq!(
+            notifier_for(&teams, None).unwrap().destination(),
+            "teams https://acme.webhook.office.com/webhookb2/x"
+        );
+        assert!(notifier_for(&fields(json!({"to": "a@example.com"})), None).is_err());
+        assert!(notifier_for(&fields(json!({"body": "x"})), None).is_err());
     }
 }
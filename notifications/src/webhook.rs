@@ -0,0 +1,174 @@
+use crate::{Message, Notifier, NotifyError, ROUTING_FIELDS};
+use async_trait::async_trait;
+use serde_json::{json, Value};
+
+// Discord refuses messages longer than this
+const DISCORD_LIMIT: usize = 2000;
+
+/// POSTs a notification's fields as JSON.
+#[derive(Debug, Clone)]
+pub struct Webhook {
+    uri: String,
+    client: reqwest::Client,
+}
+
+impl Webhook {
+    pub fn new(uri: String) -> Self {
+        Webhook {
+            uri,
+            client: client(),
+        }
+    }
+}
+
+#[async_trait]
+impl Notifier for Webhook {
+    async fn send(&self, message: &Message) -> Result<(), NotifyError> {
+        let mut payload = message.fields.clone();
+        payload.retain(|name, _| !ROUTING_FIELDS.contains(&name.as_str()));
+        post(&self.client, &self.uri, "webhook", &Value::Object(payload)).await
+    }
+
+    fn destination(&self) -> String {
+        self.uri.clone()
+    }
+}
+
+/// Chat services that take messages on incoming webhooks.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChatService {
+    Slack,
+    Discord,
+    Teams,
+}
+
+impl ChatService {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "slack" => Some(ChatService::Slack),
+            "discord" => Some(ChatService::Discord),
+            "teams" => Some(ChatService::Teams),
+            _ => None,
+        }
+    }
+
+    /// The service a webhook URL belongs to, by its host.
+    pub fn from_url(uri: &str) -> Option<Self> {
+        let host = uri
+            .split_once("://")
+            .map_or(uri, |(_, rest)| rest)
+            .split(['/', ':', '?'])
+            .next()?
+            .to_ascii_lowercase();
+        let under = |domain: &str| host == domain || host.ends_with(&format!(".{}", domain));
+        if under("hooks.slack.com") {
+            Some(ChatService::Slack)
+        } else if (under("discord.com") || under("discordapp.com")) && uri.contains("/webhooks/") {
+            Some(ChatService::Discord)
+        } else if under("webhook.office.com") {
+            Some(ChatService::Teams)
+        } else {
+            None
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            ChatService::Slack => "slack",
+            ChatService::Discord => "discord",
+            ChatService::Teams => "teams",
+        }
+    }
+
+    /// The JSON the service expects for `message`.
+    pub fn payload(self, message: &Message) -> Value {
+        match self {
+            ChatService::Slack => {
+                let text = match &message.subject {
+                    Some(subject) => format!("*{}*\n{}", subject, message.body),
+                    None => message.body.clone(),
+                };
+                json!({ "text": text })
+            }
+            ChatService::Discord => {
+                let text = match &message.subject {
+                    Some(subject) => format!("**{}**\n{}", subject, message.body),
+                    None => message.body.clone(),
+                };
+                json!({ "content": truncate(&text, DISCORD_LIMIT) })
+            }
+            ChatService::Teams => match &message.subject {
+                Some(subject) => json!({ "title": subject, "text": message.body }),
+                None => json!({ "text": message.body }),
+            },
+        }
+    }
+}
+
+/// Sends to a Slack, Discord or Teams incoming webhook.
+#[derive(Debug, Clone)]
+pub struct ChatWebhook {
+    service: ChatService,
+    uri: String,
+    client: reqwest::Client,
+}
+
+impl ChatWebhook {
+    pub fn new(service: ChatService, uri: String) -> Self {
+        ChatWebhook {
+            service,
+            uri,
+            client: client(),
+        }
+    }
+}
+
+#[async_trait]
+impl Notifier for ChatWebhook {
+    async fn send(&self, message: &Message) -> Result<(), NotifyError> {
+        let payload = self.service.payload(message);
+        post(&self.client, &self.uri, self.service.name(), &payload).await
+    }
+
+    fn destination(&self) -> String {
+        format!("{} {}", self.service.name(), self.uri)
+    }
+}
+
+// A client that doesn't follow redirects, so notifications only go to the
+// host their URL was checked for
+fn client() -> reqwest::Client {
+    reqwest::Client::builder()
+        .redirect(reqwest::redirect::Policy::none())
+        .build()
+        .unwrap_or_default()
+}
+
+async fn post(
+    client: &reqwest::Client,
+    uri: &str,
+    service: &str,
+    payload: &Value,
+) -> Result<(), NotifyError> {
+    let response = client.post(uri).json(payload).send().await?;
+    let status = response.status();
+    if status.is_success() {
+        return Ok(());
+    }
+    let body = response.text().await.unwrap_or_default();
+    Err(NotifyError::Rejected {
+        service: service.to_string(),
+        status: status.as_u16(),
+        body: truncate(body.trim(), 200),
+    })
+}
+
+// At most `limit` characters, ending with an ellipsis if it was cut
+fn truncate(text: &str, limit: usize) -> String {
+    if text.chars().count() <= limit {
+        return text.to_string();
+    }
+    let mut cut: String = text.chars().take(limit.saturating_sub(1)).collect();
+    cut.push('…');
+    cut
+}
@@ -0,0 +1,202 @@
+use crate::ProjectConfig;
+use anyhow::{anyhow, bail, Context, Result};
+use std::fs;
+use std::path::Path;
+use toml_edit::visit_mut::{self, VisitMut};
+use toml_edit::{ArrayOfTables, DocumentMut, Item, Table};
+
+/// Print the agents defined in config.toml.
+pub fn list(config: &ProjectConfig) -> Result<()> {
+    if config.agents.is_empty() {
+        println!("No agents defined, add one with `piper agents add <name> <address>`");
+        return Ok(());
+    }
+
+    println!(
+        "{:<16} {:<28} {:<7} {:<4} COMMENT",
+        "NAME", "ADDRESS", "AUTH", "TLS"
+    );
+    for agent in &config.agents {
+        let auth = match (&agent.auth_key, &config.auth_key) {
+            (Some(_), _) => "own",
+            (None, Some(_)) => "global",
+            (None, None) => "none",
+        };
+        let tls = agent.tls.is_some() || config.tls.is_some();
+        println!(
+            "{:<16} {:<28} {:<7} {:<4} {}",
+            agent.name.as_deref().unwrap_or("-"),
+            agent.ip,
+            auth,
+            if tls { "yes" } else { "no" },
+            agent.comment.as_deref().unwrap_or("")
+        );
+    }
+
+    Ok(())
+}
+
+/// Add an agent to the config file, keeping the rest of the file as it is.
+pub fn add(
+    path: &Path,
+    name: &str,
+    address: &str,
+    auth_key: Option<&str>,
+    comment: Option<&str>,
+) -> Result<()> {
+    let mut doc = read_config(path)?;
+    let agents = agents_mut(&mut doc)?;
+    if agents.iter().any(|agent| agent_name(agent) == Some(name)) {
+        bail!("An agent named {} is already defined", name);
+    }
+
+    let mut agent = Table::new();
+    agent["name"] = toml_edit::value(name);
+    agent["ip"] = toml_edit::value(address);
+    if let Some(comment) = comment {
+        agent["comment"] = toml_edit::value(comment);
+    }
+    if let Some(auth_key) = auth_key {
+        agent["auth_key"] = toml_edit::value(auth_key);
+    }
+    agents.push(agent);
+
+    write_config(path, &doc)?;
+    println!("[+] Added agent {} at {}", name, address);
+    Ok(())
+}
+
+/// Remove an agent from the config file, keeping the rest of the file as it is.
+pub fn remove(path: &Path, name: &str) -> Result<()> {
+    let mut doc = read_config(path)?;
+    let agents = agents_mut(&mut doc)?;
+    let index = agents
+        .iter()
+        .position(|agent| agent_name(agent) == Some(name))
+        .ok_or_else(|| anyhow!("No agent named {} in {}", name, path.display()))?;
+    let removed = agents.get(index).cloned();
+    agents.remove(index);
+    if let Some(removed) = removed {
+        keep_trailing_comments(&mut doc, &removed);
+    }
+
+    write_config(path, &doc)?;
+    println!("[+] Removed agent {}", name);
+    Ok(())
+}
+
+// Comments above a table's header are stored with it, but those before the
+// last blank line trail the table above it. Move them to whatever follows the
+// removed table so they aren't lost with it.
+fn keep_trailing_comments(doc: &mut DocumentMut, removed: &Table) {
+    let prefix = removed.decor().prefix().and_then(|p| p.as_str()).unwrap_or("");
+    let kept = match prefix.rfind("\n\n") {
+        Some(end) => &prefix[..end + 2],
+        None => return,
+    };
+    let Some(after) = removed.position() else {
+        return;
+    };
+
+    let mut next = NextTable { after, found: None };
+    next.visit_document_mut(doc);
+    match next.found {
+        Some(table) => {
+            let mut visitor = PrependComments {
+                position: table,
+                text: kept,
+            };
+            visitor.visit_document_mut(doc);
+        }
+        None => {
+            let trailing = doc.trailing().as_str().unwrap_or("").to_string();
+            doc.set_trailing(format!("{}{}", kept, trailing));
+        }
+    }
+}
+
+// Finds the position of the first table after `after`
+struct NextTable {
+    after: usize,
+    found: Option<usize>,
+}
+
+impl VisitMut for NextTable {
+    fn visit_table_mut(&mut self, table: &mut Table) {
+        if let Some(position) = table.position() {
+            if position > self.after && self.found.is_none_or(|found| position < found) {
+                self.found = Some(position);
+            }
+        }
+        visit_mut::visit_table_mut(self, table);
+    }
+}
+
+struct PrependComments<'a> {
+    position: usize,
+    text: &'a str,
+}
+
+impl VisitMut for PrependComments<'_> {
+    fn visit_table_mut(&mut self, table: &mut Table) {
+        if table.position() == Some(self.position) {
+            let prefix = table.decor().prefix().and_then(|p| p.as_str()).unwrap_or("");
+            let prefix = format!("{}{}", self.text, prefix);
+            table.decor_mut().set_prefix(prefix);
+        }
+        visit_mut::visit_table_mut(self, table);
+    }
+}
+
+fn read_config(path: &Path) -> Result<DocumentMut> {
+    let content =
+        fs::read_to_string(path).with_context(|| format!("Failed to read {}", path.display()))?;
+    content
+        .parse()
+        .with_context(|| format!("Failed to parse {}", path.display()))
+}
+
+fn write_config(path: &Path, doc: &DocumentMut) -> Result<()> {
+    fs::write(path, doc.to_string()).with_context(|| format!("Failed to write {}", path.display()))
+}
+
+fn agents_mut(doc: &mut DocumentMut) -> Result<&mut ArrayOfTables> {
+    doc.entry("agents")
+        .or_insert(Item::ArrayOfTables(ArrayOfTables::new()))
+        .as_array_of_tables_mut()
+        .ok_or_else(|| anyhow!("agents in config.toml must be a list of [[agents]] tables"))
+}
+
+fn agent_name(agent: &Table) -> Option<&str> {
+    agent.get("name").and_then(|name| name.as_str())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn edits_agents_in_place() {
+        let path = std::env::temp_dir().join(format!("piper-agents-{}.toml", std::process::id()));
+        fs::write(
+            &path,
+            "# project settings\nowner = \"me\"\n\n[[agents]]\nname = \"alpha\"\nip = \"10.0.0.1:50051\"\n# auth_key = \"alpha key\"\n\n# recon box\n[[agents]]\nname = \"beta\"\nip = \"10.0.0.2:50051\"\n\n[database]\nlocal = true # keep history\n",
+        )
+        .unwrap();
+
+        add(&path, "gamma", "10.0.0.3:50051", None, Some("scans")).unwrap();
+        assert!(add(&path, "gamma", "10.0.0.4:50051", None, None).is_err());
+        remove(&path, "beta").unwrap();
+
+        let content = fs::read_to_string(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert!(content.starts_with("# project settings\n"));
+        assert!(content.contains("local = true # keep history"));
+        assert!(content.contains("# auth_key = \"alpha key\""));
+        assert!(!content.contains("beta"));
+        assert!(!content.contains("recon box"));
+        let doc: DocumentMut = content.parse().unwrap();
+        assert_eq!(doc["agents"][1]["name"].as_str(), Some("gamma"));
+        assert_eq!(doc["database"]["local"].as_bool(), Some(true));
+    }
+}
@@ -0,0 +1,74 @@
+use anyhow::{anyhow, bail, Context, Result};
+use piper_runner::history::History;
+use piper_runner::run_log::Artifact;
+use sha2::{Digest, Sha256};
+use std::fs;
+use std::io::Write;
+use std::path::Path;
+
+/// Print the artifacts a run collected.
+pub fn list(history: &History, run_id: i64) -> Result<()> {
+    let artifacts = history.artifacts(run_id)?;
+    if artifacts.is_empty() {
+        println!("Run {} has no artifacts", run_id);
+        return Ok(());
+    }
+
+    println!("{:<40} {:>9} {:<14} PATH", "NAME", "SIZE", "SHA256");
+    for artifact in artifacts {
+        println!(
+            "{:<40} {:>9} {:<14} {}",
+            format!("{}/{}", artifact.task, artifact.name),
+            artifact.size,
+            &artifact.sha256[..12],
+            artifact.path
+        );
+    }
+
+    Ok(())
+}
+
+/// Print an artifact, or save it to `output`. `path` is either the path the
+/// task wrote or `<task>/<name>`.
+pub fn get(history: &History, run_id: i64, path: &str, output: Option<&Path>) -> Result<()> {
+    let artifacts = history.artifacts(run_id)?;
+    // A loop may write the same path on every iteration, so take the latest
+    let artifact = artifacts
+        .iter()
+        .rev()
+        .find(|a| a.path == path || format!("{}/{}", a.task, a.name) == path)
+        .ok_or_else(|| {
+            anyhow!(
+                "Run {} has no artifact {}, see `piper artifacts ls {}`",
+                run_id,
+                path,
+                run_id
+            )
+        })?;
+
+    let content = read_verified(artifact)?;
+    match output {
+        Some(output) => {
+            fs::write(output, &content)
+                .with_context(|| format!("Failed to write {}", output.display()))?;
+            println!("Saved {} to {}", artifact.path, output.display());
+        }
+        None => std::io::stdout().write_all(&content)?,
+    }
+
+    Ok(())
+}
+
+// Read a stored artifact, checking it still matches the hash recorded when
+// it was collected
+fn read_verified(artifact: &Artifact) -> Result<Vec<u8>> {
+    let content = fs::read(&artifact.file)
+        .with_context(|| format!("Failed to read {}", artifact.file.display()))?;
+    if format!("{:x}", Sha256::digest(&content)) != artifact.sha256 {
+        bail!(
+            "{} was modified after it was collected, its hash no longer matches",
+            artifact.file.display()
+        );
+    }
+    Ok(content)
+}
@@ -0,0 +1,36 @@
+use anyhow::Result;
+use piper_runner::cache::TaskCache;
+
+/// Print every cached task result, newest first.
+pub fn list(cache: &TaskCache) -> Result<()> {
+    let entries = cache.entries()?;
+    if entries.is_empty() {
+        println!("No cached task results");
+        return Ok(());
+    }
+
+    println!(
+        "{:<12} {:<24} {:<6} {:<20} {:<20} {:>8}",
+        "KEY", "TASK", "TYPE", "CACHED (UTC)", "EXPIRES (UTC)", "SIZE"
+    );
+    for entry in entries {
+        println!(
+            "{:<12} {:<24} {:<6} {:<20} {:<20} {:>8}",
+            &entry.key[..12],
+            entry.task,
+            entry.task_type,
+            entry.created_at,
+            entry.expires_at.as_deref().unwrap_or("never"),
+            entry.output.len()
+        );
+    }
+
+    Ok(())
+}
+
+pub fn clear(cache: &TaskCache, expired_only: bool) -> Result<()> {
+    let removed = cache.clear(expired_only)?;
+    let kind = if expired_only { "expired " } else { "" };
+    println!("Removed {} {}cached task results", removed, kind);
+    Ok(())
+}
@@ -4,9 +4,21 @@ use clap::{Parser, Subcommand};
 use config::Config;
 use piper_agent::{agent::Agent, *};
 use piper_runner::*;
-use piper_dsl::Pipeline;
+use piper_dsl::{GraphFormat, Pipeline};
+use piper_runner::task_executor::TaskExecutor;
 use serde::Deserialize;
-use std::{collections::HashMap, fs, path::Path};
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+mod agents;
+mod artifacts;
+mod cache;
+mod runs;
+mod secrets;
 
 #[derive(Parser, Debug)]
 #[clap(author, version, about, long_about = None)]
@@ -26,37 +38,203 @@ enum SubCommand {
         /// Emit an agent config file instead
         #[clap(short, long)]
         agent: bool,
+        /// Mint a local CA with agent and client certificates for mutual TLS
+        #[clap(long, requires = "agent")]
+        generate_certs: bool,
+        /// Hostnames and IP addresses the agent certificate is valid for
+        #[clap(
+            long,
+            requires = "generate-certs",
+            use_value_delimiter = true,
+            default_value = "localhost,127.0.0.1"
+        )]
+        cert_names: Vec<String>,
         /// Whether or not to interactively initialize TODO
         #[clap(short, long)]
         interactive: bool,
     },
     /// Run a pipeline
     Run {
-        /// Path to a pipeline file
-        #[clap(short, long, parse(from_os_str))]
-        path: std::path::PathBuf,
+        /// Path to a pipeline file, defaults to the resumed run's pipeline
+        #[clap(short, long, parse(from_os_str), required_unless_present = "resume")]
+        path: Option<PathBuf>,
         /// Whether the pipeline should be ran against a remote agent
         #[clap(short, long)]
         remote: bool,
-        /// IP and port or name of a remote agent --remote must be specified for this to be used
+        /// IP and port or name of a remote agent --remote must be specified for this to be used.
+        /// `auto` picks the least busy agent in config.toml that has every tool the pipeline needs
         #[clap(short, long, default_value = "http://127.0.0.1:50051")]
         agent: String,
         /// Force regeneration of meta-pipeline (only applies to meta-pipelines)
-        #[clap(short, long)]
+        #[clap(long)]
         regenerate: bool,
+        /// Resume a failed run, skipping the tasks it completed
+        #[clap(long, value_name = "RUN_ID")]
+        resume: Option<i64>,
+        /// Resume even if the pipeline changed since the resumed run
+        #[clap(long, requires = "resume")]
+        force: bool,
+        /// Run every task, ignoring and not storing cached results
+        #[clap(long)]
+        no_cache: bool,
+        /// Go ahead with targets outside the project's scope, for the reason
+        /// given, which is kept in the run history
+        #[clap(long, value_name = "REASON")]
+        ignore_scope: Option<String>,
     },
     /// Start in agent mode
     StartAgent {
         // Start in agent mode
         #[clap(long, default_value = "127.0.0.1:50051")]
         agent_listen_addr: String,
+        /// Key clients must send, overriding auth_key in config.toml. Accepts
+        /// env:NAME and file:PATH
         #[clap(long)]
         auth_key: Option<String>,
+        /// Largest bundle of files accepted with a pipeline, in MiB
+        #[clap(long, default_value = "64")]
+        max_bundle_mb: u64,
+    },
+    /// Render a pipeline's flow as a graph
+    Graph {
+        /// Path to a pipeline file
+        #[clap(parse(from_os_str))]
+        path: std::path::PathBuf,
+        /// Output format: dot, mermaid or ascii
+        #[clap(short, long, default_value = "ascii")]
+        format: GraphFormat,
+    },
+    /// Query the local run history
+    Runs {
+        #[clap(subcommand)]
+        cmd: RunsCommand,
+    },
+    /// Fetch the files tasks declared as artifacts
+    Artifacts {
+        #[clap(subcommand)]
+        cmd: ArtifactsCommand,
+    },
+    /// Manage cached task results
+    Cache {
+        #[clap(subcommand)]
+        cmd: CacheCommand,
+    },
+    /// Manage the project's encrypted secrets
+    Secrets {
+        #[clap(subcommand)]
+        cmd: SecretsCommand,
     },
     /// Manage remote agents
-    Agents {},
-    /// Check the status of a remote pipeline
-    Status {},
+    Agents {
+        #[clap(subcommand)]
+        cmd: AgentsCommand,
+    },
+    /// Check the status of a remote pipeline, or list the agent's runs
+    Status {
+        /// Id of the run on the agent
+        run_id: Option<u64>,
+        /// IP and port or name of a remote agent
+        #[clap(short, long, default_value = "http://127.0.0.1:50051")]
+        agent: String,
+        /// Follow the run until it finishes, showing everything it did so far
+        #[clap(short, long, requires = "run-id")]
+        follow: bool,
+    },
+    /// Cancel a remote pipeline
+    Cancel {
+        /// Id of the run on the agent
+        run_id: u64,
+        /// IP and port or name of a remote agent
+        #[clap(short, long, default_value = "http://127.0.0.1:50051")]
+        agent: String,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum RunsCommand {
+    /// List recent runs
+    List {
+        /// Number of runs to show
+        #[clap(short, long, default_value = "20")]
+        limit: usize,
+    },
+    /// Show a run's tasks and their outputs
+    Show {
+        /// Id of the run
+        id: i64,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum ArtifactsCommand {
+    /// List a run's artifacts
+    Ls {
+        /// Id of the run
+        run_id: i64,
+    },
+    /// Print an artifact, or save it with --output
+    Get {
+        /// Id of the run
+        run_id: i64,
+        /// Path the task wrote, or <task>/<name> as listed by `artifacts ls`
+        path: String,
+        /// File to save the artifact to
+        #[clap(short, long, parse(from_os_str))]
+        output: Option<PathBuf>,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum AgentsCommand {
+    /// List the agents defined in config.toml
+    List,
+    /// Add an agent to config.toml
+    Add {
+        /// Name to refer to the agent by
+        name: String,
+        /// IP and port of the agent
+        address: String,
+        /// Key to send to this agent instead of the global auth_key
+        #[clap(long)]
+        auth_key: Option<String>,
+        /// What the agent is for
+        #[clap(long)]
+        comment: Option<String>,
+    },
+    /// Remove an agent from config.toml
+    Remove {
+        /// Name of the agent
+        name: String,
+    },
+    /// Check an agent is up and what it has installed
+    Ping {
+        /// Name or IP and port of the agent
+        agent: String,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum CacheCommand {
+    /// List cached task results
+    Ls,
+    /// Remove cached task results
+    Clear {
+        /// Only remove results past their cache_ttl
+        #[clap(long)]
+        expired: bool,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum SecretsCommand {
+    /// Store a secret, read from stdin unless given
+    Set { name: String, value: Option<String> },
+    /// Print a secret
+    Get { name: String },
+    /// List the names of stored secrets
+    Ls,
+    /// Remove a secret
+    Rm { name: String },
 }
 
 // Representation of an agent
@@ -83,18 +261,189 @@ impl std::fmt::Display for ConfigError {
 }
 
 #[derive(Default, Debug, Deserialize)]
+#[serde(default)]
 struct ProjectConfig {
     owner: Option<String>,
     description: Option<String>,
     auth_key: Option<String>,
+    // The agent's certificates in an agent config, otherwise the client's
+    // certificates for every agent without its own
+    tls: Option<tls::TlsConfig>,
     agents: Vec<AgentConfig>,
+    database: DatabaseConfig,
+    cache: CacheConfig,
+    // What an agent takes on at once, in an agent config
+    limits: limits::LimitsConfig,
+    // The policy file tasks sent to an agent are held to, in an agent config
+    policy: Option<PathBuf>,
+    // Where an agent records the tasks its policy stops
+    audit_log: Option<PathBuf>,
+    // Hosts the project's runs may target
+    scope: Option<scope::ScopeConfig>,
+    secrets: SecretsConfig,
+    // The server notify tasks send email through
+    smtp: Option<notifications::SmtpConfig>,
 }
 
+impl ProjectConfig {
+    // The agent with this name in config.toml, otherwise `agent` is taken
+    // to be an address. An agent's own auth_key overrides the global one.
+    fn agent_endpoint(&self, agent: &str) -> Result<client::AgentEndpoint, String> {
+        let config = self.agents.iter().find(|a| a.name.as_deref() == Some(agent));
+        if config.is_none() && !agent.contains(':') {
+            return Err(format!("No agent named {} in config.toml", agent));
+        }
+        let tls = config.and_then(|a| a.tls.clone()).or_else(|| self.tls.clone());
+        let address = config.map_or(agent, |a| a.ip.as_str());
+        let address = match &tls {
+            _ if address.contains("://") => address.to_string(),
+            Some(_) => format!("https://{}", address),
+            None => format!("http://{}", address),
+        };
+
+        let auth_key = config
+            .and_then(|a| a.auth_key.as_deref())
+            .or(self.auth_key.as_deref())
+            .map(auth::resolve_key)
+            .transpose()?;
+
+        Ok(client::AgentEndpoint {
+            address,
+            auth_key,
+            tls,
+        })
+    }
+
+    // Executors for the named agents, which tasks can be sent to
+    fn task_executors(&self) -> BTreeMap<String, Arc<dyn TaskExecutor>> {
+        self.agents
+            .iter()
+            .filter_map(|agent| agent.name.as_deref())
+            .map(|name| {
+                let executor = client::RemoteExecutor::new(name, self.agent_endpoint(name));
+                (name.to_string(), Arc::new(executor) as Arc<dyn TaskExecutor>)
+            })
+            .collect()
+    }
+
+    // The SMTP server, with its password resolved like an auth key
+    fn smtp(&self) -> Result<Option<notifications::SmtpConfig>, String> {
+        let Some(smtp) = &self.smtp else {
+            return Ok(None);
+        };
+        let password = smtp
+            .password
+            .as_deref()
+            .map(|password| {
+                auth::resolve_key(password).map_err(|e| format!("[smtp] password: {}", e))
+            })
+            .transpose()?;
+        Ok(Some(notifications::SmtpConfig {
+            password,
+            ..smtp.clone()
+        }))
+    }
+}
+
+// Where the artifacts of runs on an agent are saved
+fn remote_artifact_root(agent: &str) -> PathBuf {
+    let agent: String = agent
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '-' { c } else { '_' })
+        .collect();
+    PathBuf::from(".piper/remote").join(agent)
+}
+
+fn exit_on_failure(status: &str) {
+    if status == run_log::RunStatus::Failed.as_str() || status == "cancelled" {
+        std::process::exit(1);
+    }
+}
+
+#[derive(Default, Debug, Deserialize)]
+#[serde(default)]
+struct DatabaseConfig {
+    local: bool,
+    // Location of the local database, .piper/piper.db by default
+    path: Option<PathBuf>,
+}
+
+impl DatabaseConfig {
+    fn local_path(&self) -> Option<PathBuf> {
+        self.local.then(|| {
+            self.path
+                .clone()
+                .unwrap_or_else(|| PathBuf::from(".piper/piper.db"))
+        })
+    }
+}
+
+#[derive(Default, Debug, Deserialize)]
+#[serde(default)]
+struct CacheConfig {
+    // Location of the task cache, .piper/cache.db by default
+    path: Option<PathBuf>,
+}
+
+impl CacheConfig {
+    fn path(&self) -> PathBuf {
+        self.path
+            .clone()
+            .unwrap_or_else(|| PathBuf::from(".piper/cache.db"))
+    }
+}
+
+#[derive(Default, Debug, Deserialize)]
+#[serde(default)]
+struct SecretsConfig {
+    // Location of the encrypted secrets file, .piper/secrets.enc by default
+    path: Option<PathBuf>,
+    // Passphrase of the secrets file, env:PIPER_SECRETS_KEY by default.
+    // Accepts env:NAME and file:PATH
+    key: Option<String>,
+}
+
+impl SecretsConfig {
+    // The secrets file, which can't be read if its passphrase isn't set
+    fn file(&self) -> piper_runner::secrets::SecretsFile {
+        let path = self
+            .path
+            .clone()
+            .unwrap_or_else(|| PathBuf::from(piper_runner::secrets::DEFAULT_SECRETS_FILE));
+        let key = self
+            .key
+            .clone()
+            .unwrap_or_else(|| format!("env:{}", piper_runner::secrets::DEFAULT_KEY_VAR));
+        piper_runner::secrets::SecretsFile::new(path, auth::resolve_key(&key).ok())
+    }
+}
+
+// Added to the agent config written by `init --agent --generate-certs`
+const AGENT_TLS_CONFIG: &str = r#"
+
+[tls]
+cert = "certs/agent.pem"
+key = "certs/agent.key"
+# only clients with a certificate signed by this CA can connect
+client_ca = "certs/ca.pem"
+"#;
+
+const PROJECT_TLS_CONFIG: &str = r#"
+[tls]
+ca = "certs/ca.pem"
+cert = "certs/client.pem"
+key = "certs/client.key"
+# the name in the agent's certificate, needed when agents are reached by IP
+domain = "piper-agent"
+"#;
+
 #[derive(Default, Debug, Deserialize)]
 struct AgentConfig {
     name: Option<String>,
     ip: String,               // required
     auth_key: Option<String>, // required
+    comment: Option<String>,
+    tls: Option<tls::TlsConfig>,
 }
 
 #[tokio::main]
@@ -107,28 +456,38 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     // Include an example agent config file
     let example_agent_config = include_str!("../agent_config.toml");
 
-    // Create a config struct and check if the file is present
-    // if it is then
-    // Slurp up the config file
-    let config = Config::builder()
-        .add_source(config::File::with_name("config"))
+    // Slurp up the config file if there is one. One that doesn't load is an
+    // error rather than no config, which would quietly turn off the scope,
+    // policy, TLS and auth keys it sets
+    let project_config: ProjectConfig = Config::builder()
+        .add_source(config::File::with_name("config").required(false))
         .build()
-        .unwrap();
-
-    // println!("{:?}", config);
+        .and_then(Config::try_deserialize)
+        .map_err(|e| format!("Failed to load config.toml: {}", e))?;
 
     let args = Args::parse();
     match args.cmd {
         SubCommand::Init {
             path,
             agent,
+            generate_certs,
+            cert_names,
             interactive,
         } => {
             // Handle emitting various config files
             if agent {
                 println!("Emitting agent config example");
                 // emit agent config
-                fs::write(path.join("config.toml"), example_agent_config);
+                let mut agent_config = example_agent_config.to_string();
+                if generate_certs {
+                    for file in tls::generate_certs(&path.join("certs"), &cert_names)? {
+                        println!("[+] Wrote {}", file.display());
+                    }
+                    agent_config.push_str(AGENT_TLS_CONFIG);
+                    println!("Copy certs/ca.pem, certs/client.pem and certs/client.key to the project and add to its config.toml:");
+                    print!("{}", PROJECT_TLS_CONFIG);
+                }
+                fs::write(path.join("config.toml"), agent_config)?;
             } else {
                 println!("Emitting project config example");
                 fs::write(path.join("config.toml"), example_project_config);
@@ -139,29 +498,172 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
             remote,
             agent,
             regenerate,
+            resume,
+            force,
+            no_cache,
+            ignore_scope,
         } => {
+            if ignore_scope.as_deref().is_some_and(|reason| reason.trim().is_empty()) {
+                return Err("--ignore-scope needs a reason".into());
+            }
             // if remote flag is present run against
             // a given remote agent ip
             if remote {
+                if resume.is_some() {
+                    return Err("--resume is only supported for local runs".into());
+                }
+                let path = path.unwrap();
+                let agent = if agent == "auto" {
+                    let mut candidates = Vec::new();
+                    for name in project_config.agents.iter().filter_map(|a| a.name.as_ref()) {
+                        candidates.push((name.clone(), project_config.agent_endpoint(name)?));
+                    }
+                    client::pick_agent(&candidates, &path).await?
+                } else {
+                    agent
+                };
                 // use the agent value to look up the agent IP in the config struct
                 // if it's not found then attempt to use the value supplied as the addr
-                client::client_run(agent, path).await?;
+                let endpoint = project_config.agent_endpoint(&agent)?;
+                let status = client::client_run(
+                    &endpoint,
+                    path,
+                    &remote_artifact_root(&agent),
+                    project_config.scope.as_ref(),
+                    ignore_scope,
+                )
+                .await?;
+                exit_on_failure(&status);
             } else {
                 // otherwise run the pipeline locally using the runner
-                runner::run_from_file_with_options(path, regenerate);
+                let history = project_config.database.local_path();
+                let path = match (path, resume) {
+                    (Some(path), _) => path,
+                    (None, Some(run_id)) => runs::source_path(&project_config.database, run_id)?,
+                    (None, None) => unreachable!("clap requires --path without --resume"),
+                };
+                let options = runner::RunOptions {
+                    regenerate,
+                    history,
+                    resume,
+                    force,
+                    cache: (!no_cache).then(|| project_config.cache.path()),
+                    agents: project_config.task_executors(),
+                    scope: project_config.scope.as_ref().map(|scope| scope.scope()).transpose()?,
+                    ignore_scope,
+                    secrets: Some(project_config.secrets.file()),
+                    smtp: project_config.smtp()?,
+                    ..Default::default()
+                };
+                let log = tokio::task::spawn_blocking(move || {
+                    runner::run_from_file_with_options(path, &options)
+                })
+                .await??;
+                if log.status == run_log::RunStatus::Failed {
+                    std::process::exit(1);
+                }
             }
         }
         SubCommand::StartAgent {
             auth_key,
             agent_listen_addr,
+            max_bundle_mb,
+        } => {
+            // Start the gRPC agent, with the key from the flag or the config file
+            let smtp = project_config.smtp()?;
+            let auth_key = auth_key
+                .or(project_config.auth_key)
+                .map(|key| auth::resolve_key(&key))
+                .transpose()?;
+            agent::start_agent(agent::AgentOptions {
+                listen_addr: agent_listen_addr,
+                auth_key,
+                tls: project_config.tls,
+                max_bundle_size: max_bundle_mb * 1024 * 1024,
+                limits: project_config.limits.limits()?,
+                policy: project_config.policy.as_deref().map(policy::Policy::load).transpose()?,
+                audit_log: project_config
+                    .audit_log
+                    .unwrap_or_else(|| PathBuf::from(agent::DEFAULT_AUDIT_LOG)),
+                secrets: Some(project_config.secrets.file()),
+                smtp,
+            })
+            .await?;
+        }
+        SubCommand::Graph { path, format } => {
+            let pipeline = Pipeline::load(&path)?;
+            print!("{}", pipeline.render_graph(format)?);
+        }
+        SubCommand::Runs { cmd } => {
+            let history = runs::open_history(&project_config.database)?;
+            match cmd {
+                RunsCommand::List { limit } => runs::list(&history, limit)?,
+                RunsCommand::Show { id } => runs::show(&history, id)?,
+            }
+        }
+        SubCommand::Artifacts { cmd } => {
+            let history = runs::open_history(&project_config.database)?;
+            match cmd {
+                ArtifactsCommand::Ls { run_id } => artifacts::list(&history, run_id)?,
+                ArtifactsCommand::Get {
+                    run_id,
+                    path,
+                    output,
+                } => artifacts::get(&history, run_id, &path, output.as_deref())?,
+            }
+        }
+        SubCommand::Cache { cmd } => {
+            let cache = piper_runner::cache::TaskCache::open(&project_config.cache.path())?;
+            match cmd {
+                CacheCommand::Ls => cache::list(&cache)?,
+                CacheCommand::Clear { expired } => cache::clear(&cache, expired)?,
+            }
+        }
+        SubCommand::Secrets { cmd } => {
+            let file = project_config.secrets.file();
+            match cmd {
+                SecretsCommand::Set { name, value } => secrets::set(&file, &name, value)?,
+                SecretsCommand::Get { name } => secrets::get(&file, &name)?,
+                SecretsCommand::Ls => secrets::list(&file)?,
+                SecretsCommand::Rm { name } => secrets::remove(&file, &name)?,
+            }
+        }
+        SubCommand::Agents { cmd } => {
+            let path = Path::new("config.toml");
+            match cmd {
+                AgentsCommand::List => agents::list(&project_config)?,
+                AgentsCommand::Add {
+                    name,
+                    address,
+                    auth_key,
+                    comment,
+                } => agents::add(path, &name, &address, auth_key.as_deref(), comment.as_deref())?,
+                AgentsCommand::Remove { name } => agents::remove(path, &name)?,
+                AgentsCommand::Ping { agent } => {
+                    client::ping(&project_config.agent_endpoint(&agent)?).await?
+                }
+            }
+        }
+        SubCommand::Status {
+            run_id,
+            agent,
+            follow,
         } => {
-            // Load the config file
-            // Start the gRPC agent
-            // TODO: Pass constructed config struct to agent start function
-            agent::start_agent(agent_listen_addr).await?;
+            let endpoint = project_config.agent_endpoint(&agent)?;
+            match run_id {
+                Some(run_id) if follow => {
+                    let status =
+                        client::follow_run(&endpoint, run_id, &remote_artifact_root(&agent))
+                            .await?;
+                    exit_on_failure(&status);
+                }
+                Some(run_id) => client::run_status(&endpoint, run_id).await?,
+                None => client::list_runs(&endpoint).await?,
+            }
+        }
+        SubCommand::Cancel { run_id, agent } => {
+            client::cancel_run(&project_config.agent_endpoint(&agent)?, run_id).await?;
         }
-        SubCommand::Agents {} => todo!(),
-        SubCommand::Status {} => todo!(),
     }
 
     Ok(())
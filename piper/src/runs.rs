@@ -0,0 +1,117 @@
+use crate::DatabaseConfig;
+use anyhow::{anyhow, Result};
+use piper_runner::history::History;
+use piper_runner::run_log;
+use std::path::PathBuf;
+use std::time::Duration;
+
+pub fn open_history(database: &DatabaseConfig) -> Result<History> {
+    let path = database.local_path().ok_or_else(|| {
+        anyhow!("Run history needs a local database, set `local = true` under [database] in config.toml")
+    })?;
+    History::open(&path)
+}
+
+/// The pipeline file a recorded run was started from.
+pub fn source_path(database: &DatabaseConfig, id: i64) -> Result<PathBuf> {
+    let run = open_history(database)?
+        .run(id)?
+        .ok_or_else(|| anyhow!("No run with id {}", id))?;
+    run.source_path
+        .map(PathBuf::from)
+        .ok_or_else(|| anyhow!("Run {} has no pipeline file, pass it with --path", id))
+}
+
+/// Print the most recent runs.
+pub fn list(history: &History, limit: usize) -> Result<()> {
+    let runs = history.runs(limit)?;
+    if runs.is_empty() {
+        println!("No runs recorded yet");
+        return Ok(());
+    }
+
+    println!(
+        "{:<6} {:<24} {:<17} {:<20} {:>9}",
+        "ID", "PIPELINE", "STATUS", "STARTED (UTC)", "DURATION"
+    );
+    for run in runs {
+        println!(
+            "{:<6} {:<24} {:<17} {:<20} {:>9}",
+            run.id,
+            run.pipeline,
+            run.status,
+            run.started_at,
+            run.duration_ms.map(format_duration).unwrap_or_default()
+        );
+    }
+
+    Ok(())
+}
+
+/// Print a run with every task attempt and its output.
+pub fn show(history: &History, id: i64) -> Result<()> {
+    let run = history
+        .run(id)?
+        .ok_or_else(|| anyhow!("No run with id {}", id))?;
+
+    println!("Run {}: {}", run.id, run.pipeline);
+    println!("  Status:      {}", run.status);
+    if let Some(path) = &run.source_path {
+        println!("  Source:      {}", path);
+    }
+    println!("  Source hash: {}", run.source_hash);
+    if let Some(resumed_from) = run.resumed_from {
+        println!("  Resumed:     from run {}", resumed_from);
+    }
+    if let Some(reason) = &run.ignored_scope {
+        println!("  Scope:       ignored, {}", reason);
+    }
+    println!("  Started:     {} UTC", run.started_at);
+    if let Some(finished_at) = &run.finished_at {
+        println!("  Finished:    {} UTC", finished_at);
+    }
+    if let Some(duration) = run.duration_ms {
+        println!("  Duration:    {}", format_duration(duration));
+    }
+    for (name, value) in &run.parameters {
+        println!("  Parameter:   {} = {}", name, value);
+    }
+    if let Some(error) = &run.error {
+        println!("  Error:       {}", error);
+    }
+
+    let tasks = history.task_runs(id)?;
+    println!();
+    println!(
+        "{:<24} {:>7} {:<10} {:>9} {:>5}",
+        "TASK", "ATTEMPT", "STATUS", "DURATION", "EXIT"
+    );
+    for task in &tasks {
+        println!(
+            "{:<24} {:>7} {:<10} {:>9} {:>5}",
+            task.task,
+            task.attempt,
+            task.status,
+            format_duration(task.duration_ms),
+            task.exit_code.map(|c| c.to_string()).unwrap_or_default()
+        );
+    }
+
+    for task in &tasks {
+        if let Some(error) = &task.error {
+            println!("\n--- {} (attempt {}) failed ---\n{}", task.task, task.attempt, error);
+        }
+        match &task.output {
+            Some(output) if !output.is_empty() => {
+                println!("\n--- {} (attempt {}) output ---\n{}", task.task, task.attempt, output);
+            }
+            _ => {}
+        }
+    }
+
+    Ok(())
+}
+
+fn format_duration(ms: i64) -> String {
+    run_log::format_duration(Duration::from_millis(ms.max(0) as u64))
+}
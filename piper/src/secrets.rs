@@ -0,0 +1,53 @@
+use anyhow::{anyhow, bail, Result};
+use piper_runner::secrets::SecretsFile;
+use std::io::Read;
+
+/// Store a secret, reading it from stdin when no value is given so it stays
+/// out of the shell's history.
+pub fn set(file: &SecretsFile, name: &str, value: Option<String>) -> Result<()> {
+    if name.is_empty() || name.starts_with("env:") || name.starts_with("file:") {
+        bail!("Invalid secret name '{}'", name);
+    }
+    let value = match value {
+        Some(value) => value,
+        None => {
+            let mut value = String::new();
+            std::io::stdin().read_to_string(&mut value)?;
+            value.trim_end_matches(['\r', '\n']).to_string()
+        }
+    };
+    if value.is_empty() {
+        bail!("The secret {} is empty", name);
+    }
+    file.set(name, &value)?;
+    println!("[+] Stored secret {} in {}", name, file.path.display());
+    Ok(())
+}
+
+pub fn get(file: &SecretsFile, name: &str) -> Result<()> {
+    let value = file
+        .get(name)?
+        .ok_or_else(|| anyhow!("No secret {} in {}", name, file.path.display()))?;
+    println!("{}", value);
+    Ok(())
+}
+
+/// Print the names of the stored secrets, never their values.
+pub fn list(file: &SecretsFile) -> Result<()> {
+    let secrets = file.load()?;
+    if secrets.is_empty() {
+        println!("No secrets in {}", file.path.display());
+    }
+    for name in secrets.keys() {
+        println!("{}", name);
+    }
+    Ok(())
+}
+
+pub fn remove(file: &SecretsFile, name: &str) -> Result<()> {
+    if !file.remove(name)? {
+        bail!("No secret {} in {}", name, file.path.display());
+    }
+    println!("Removed secret {}", name);
+    Ok(())
+}
@@ -1,61 +1,434 @@
+use hyper::Body;
 use tonic::{transport::Server, Request, Response, Status};
 // Import the generated rust code into module
-mod agent_proto {
+pub(crate) mod agent_proto {
     tonic::include_proto!("piper");
 }
 // Proto generated server traits
 use agent_proto::piper_agent_server::{PiperAgent, PiperAgentServer};
 // Proto message structs
-use agent_proto::{PipelineInput, PipelineOutput};
+use agent_proto::{
+    CapabilitiesRequest, CapabilityReport, HealthReport, HealthRequest, ListRunsRequest, PipelineInput,
+    RunEvent, RunId, RunList, RunSummary, TaskEvent, TaskInput,
+};
 
-use piper_runner::*;
-use piper_tasks::*;
-use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
-use std::fs;
+use crate::audit::AuditLog;
+use crate::auth;
+use crate::limits::Limits;
+use crate::tls::TlsConfig;
+use crate::queue::RunQueue;
+use crate::tasks::{self, TaskSettings};
+use piper_dsl::TaskType;
+use piper_runner::bundle::Bundle;
+use piper_runner::policy::Policy;
+use piper_runner::scope::{Scope, ScopeConfig};
+use notifications::SmtpConfig;
+use piper_runner::secrets::SecretsFile;
+use piper_runner::task_executor::TaskRequest;
+use piper_tasks::cmd;
 use std::net::SocketAddr;
-use std::str::FromStr;
-use tokio::runtime::Runtime;
-
-use pyo3::{
-    prelude::*,
-    types::{IntoPyDict, PyBytes, PyDict, PyModule},
-};
+use std::fs;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio_stream::wrappers::UnboundedReceiverStream;
+use tokio_stream::StreamExt;
+use tower::util::MapRequestLayer;
 
 pub struct AgentOptions {
-    listen_addr: String,
+    pub listen_addr: String,
+    /// Key clients must send, already resolved. One is generated if None.
+    pub auth_key: Option<String>,
+    /// Certificates for mutual TLS, plaintext if None
+    pub tls: Option<TlsConfig>,
+    /// Largest bundle of files accepted with a pipeline, in bytes
+    pub max_bundle_size: u64,
+    /// What the agent takes on at once and what its runs can use
+    pub limits: Limits,
+    /// What tasks sent to the agent may do, anything if None
+    pub policy: Option<Policy>,
+    /// Where tasks the policy stops are recorded
+    pub audit_log: PathBuf,
+    /// Where runs look up secrets not in the agent's environment
+    pub secrets: Option<SecretsFile>,
+    /// The server runs send email notifications through
+    pub smtp: Option<SmtpConfig>,
+}
+
+pub struct Agent {
+    queue: RunQueue,
+    started: Instant,
+    max_bundle_size: u64,
+    limits: Limits,
+    policy: Option<Arc<Policy>>,
+    audit: AuditLog,
+}
+
+impl Default for Agent {
+    fn default() -> Self {
+        Agent::new(
+            Limits::default(),
+            None,
+            AuditLog::new(PathBuf::from(DEFAULT_AUDIT_LOG)),
+            None,
+            None,
+        )
+    }
 }
 
-#[derive(Default)]
-pub struct Agent {}
+/// Where an agent records the tasks its policy stops by default.
+pub const DEFAULT_AUDIT_LOG: &str = ".piper/audit.log";
+
+// Tools pipelines commonly call, reported by the Health RPC when installed
+const KNOWN_TOOLS: &[&str] = &[
+    "amass", "curl", "dig", "ffuf", "gobuster", "httpx", "masscan", "nikto", "nmap", "nuclei",
+    "sqlmap", "subfinder", "whois",
+];
+
+// The host's one minute load average
+fn load_average() -> Option<f64> {
+    let loadavg = fs::read_to_string("/proc/loadavg").ok()?;
+    loadavg.split_whitespace().next()?.parse().ok()
+}
+
+type EventStream =
+    std::pin::Pin<Box<dyn tokio_stream::Stream<Item = Result<RunEvent, Status>> + Send>>;
+type TaskEventStream =
+    std::pin::Pin<Box<dyn tokio_stream::Stream<Item = Result<TaskEvent, Status>> + Send>>;
+
+/// Largest bundle of files an agent accepts with a pipeline by default.
+pub const DEFAULT_MAX_BUNDLE_SIZE: u64 = 64 * 1024 * 1024;
+
+// Room a request has besides its bundle, for the pipeline's source and the
+// bundle's paths
+const REQUEST_OVERHEAD: u64 = 1024 * 1024;
+
+impl Agent {
+    pub fn new(
+        limits: Limits,
+        policy: Option<Policy>,
+        audit: AuditLog,
+        secrets: Option<SecretsFile>,
+        smtp: Option<SmtpConfig>,
+    ) -> Self {
+        let policy = policy.map(Arc::new);
+        Agent {
+            queue: RunQueue::new(limits.clone(), policy.clone(), audit.clone(), secrets, smtp),
+            started: Instant::now(),
+            max_bundle_size: DEFAULT_MAX_BUNDLE_SIZE,
+            limits,
+            policy,
+            audit,
+        }
+    }
+
+    // Why a run or task is refused when the agent has no room for it
+    fn at_capacity(&self) -> Status {
+        let (running, queued) = self.queue.counts();
+        Status::resource_exhausted(format!(
+            "The agent is at capacity with {} running and {} queued, try again later",
+            running, queued
+        ))
+    }
+
+    // Why a bundle is refused, if it is
+    fn reject_bundle(&self, bundle: Option<&Bundle>) -> Option<Status> {
+        let size = bundle?.size();
+        (size > self.max_bundle_size).then(|| {
+            Status::resource_exhausted(format!(
+                "The files sent are {} bytes, over the agent's limit of {} bytes",
+                size, self.max_bundle_size
+            ))
+        })
+    }
+
+    fn follow(&self, id: u64) -> Option<Response<EventStream>> {
+        let events = self.queue.follow(id)?;
+        let stream = UnboundedReceiverStream::new(events).map(Ok);
+        Some(Response::new(Box::pin(stream)))
+    }
+}
+
+fn client_address<T>(request: &Request<T>) -> Option<String> {
+    request.remote_addr().map(|address| address.to_string())
+}
+
+// The scope a run was sent with, checked on the agent as targets are only
+// known once parameters are bound
+fn run_scope(scope: Option<agent_proto::Scope>) -> Result<Option<Scope>, String> {
+    scope
+        .map(|scope| {
+            ScopeConfig {
+                include: scope.include,
+                exclude: scope.exclude,
+                params: scope.params,
+            }
+            .scope()
+        })
+        .transpose()
+}
+
+fn unbundle(bundle: agent_proto::Bundle) -> Bundle {
+    Bundle {
+        entry: bundle.entry,
+        files: bundle
+            .files
+            .into_iter()
+            .map(|file| (file.path, file.sha256))
+            .collect(),
+        blobs: bundle
+            .blobs
+            .into_iter()
+            .map(|blob| (blob.sha256, blob.content))
+            .collect(),
+    }
+}
+
+// The task to run, or why it can't be run on its own
+fn task_request(input: TaskInput) -> Result<TaskRequest, String> {
+    let task_type = match TaskType::from_str(&input.task_type) {
+        Ok(task_type @ (TaskType::Cmd | TaskType::Llm)) => task_type,
+        _ => return Err(format!("{} tasks can't run on their own", input.task_type)),
+    };
+    Ok(TaskRequest {
+        task: input.task,
+        task_type,
+        args: input.args,
+        timeout: input.timeout_ms.map(Duration::from_millis),
+        artifacts: input.artifacts,
+        inputs: input.inputs.map(unbundle),
+    })
+}
+
+fn not_found(id: u64) -> Status {
+    Status::not_found(format!("No run with id {}", id))
+}
 
 #[tonic::async_trait]
 impl PiperAgent for Agent {
+    type RunPipelineStream = EventStream;
+    type StreamRunEventsStream = EventStream;
+    type RunTaskStream = TaskEventStream;
+
     async fn run_pipeline(
         &self,
         request: Request<PipelineInput>,
-    ) -> Result<Response<PipelineOutput>, Status> {
-        // Need to run without a file path
-        let req_pipeline = request.into_inner();
-        let pipeline = req_pipeline.pipeline;
-
-        runner::run(pipeline);
-        Ok(Response::new(PipelineOutput {
-            output: "".to_string(),
+    ) -> Result<Response<Self::RunPipelineStream>, Status> {
+        let client = client_address(&request);
+        let input = request.into_inner();
+        let bundle = input.bundle.map(unbundle);
+        if let Some(status) = self.reject_bundle(bundle.as_ref()) {
+            return Err(status);
+        }
+        let scope = run_scope(input.scope).map_err(Status::invalid_argument)?;
+        let id = self
+            .queue
+            .submit(input.pipeline, bundle, client, scope, input.ignore_scope)
+            .ok_or_else(|| self.at_capacity())?;
+        self.follow(id).ok_or_else(|| not_found(id))
+    }
+
+    async fn submit_pipeline(
+        &self,
+        request: Request<PipelineInput>,
+    ) -> Result<Response<RunId>, Status> {
+        let client = client_address(&request);
+        let input = request.into_inner();
+        let bundle = input.bundle.map(unbundle);
+        if let Some(status) = self.reject_bundle(bundle.as_ref()) {
+            return Err(status);
+        }
+        let scope = run_scope(input.scope).map_err(Status::invalid_argument)?;
+        let id = self
+            .queue
+            .submit(input.pipeline, bundle, client, scope, input.ignore_scope)
+            .ok_or_else(|| self.at_capacity())?;
+        Ok(Response::new(RunId { id }))
+    }
+
+    async fn get_run_status(&self, request: Request<RunId>) -> Result<Response<RunSummary>, Status> {
+        let id = request.into_inner().id;
+        let summary = self.queue.summary(id).ok_or_else(|| not_found(id))?;
+        Ok(Response::new(summary))
+    }
+
+    async fn list_runs(
+        &self,
+        _request: Request<ListRunsRequest>,
+    ) -> Result<Response<RunList>, Status> {
+        Ok(Response::new(RunList {
+            runs: self.queue.summaries(),
         }))
     }
+
+    async fn cancel_run(&self, request: Request<RunId>) -> Result<Response<RunSummary>, Status> {
+        let id = request.into_inner().id;
+        let summary = self.queue.cancel(id).ok_or_else(|| not_found(id))?;
+        Ok(Response::new(summary))
+    }
+
+    async fn stream_run_events(
+        &self,
+        request: Request<RunId>,
+    ) -> Result<Response<Self::StreamRunEventsStream>, Status> {
+        let id = request.into_inner().id;
+        self.follow(id).ok_or_else(|| not_found(id))
+    }
+
+    async fn run_task(
+        &self,
+        request: Request<TaskInput>,
+    ) -> Result<Response<Self::RunTaskStream>, Status> {
+        let client = client_address(&request);
+        let mut request = task_request(request.into_inner()).map_err(Status::invalid_argument)?;
+        if let Some(status) = self.reject_bundle(request.inputs.as_ref()) {
+            return Err(status);
+        }
+        if let Some(limit) = self.limits.max_run_time {
+            request.timeout = Some(request.timeout.map_or(limit, |timeout| timeout.min(limit)));
+        }
+        let slot = self.queue.task_slot().await.ok_or_else(|| self.at_capacity())?;
+        let settings = TaskSettings {
+            limits: self.limits.commands,
+            policy: self.policy.clone(),
+            audit: self.audit.clone(),
+            client,
+        };
+        let events = tasks::run_task(request, slot, settings);
+        Ok(Response::new(Box::pin(UnboundedReceiverStream::new(events).map(Ok))))
+    }
+
+    async fn health(
+        &self,
+        _request: Request<HealthRequest>,
+    ) -> Result<Response<HealthReport>, Status> {
+        let (running_runs, queued_runs) = self.queue.counts();
+        let tools = KNOWN_TOOLS
+            .iter()
+            .filter(|tool| cmd::find_executable(tool).is_some())
+            .map(|tool| tool.to_string())
+            .collect();
+
+        Ok(Response::new(HealthReport {
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            uptime_secs: self.started.elapsed().as_secs(),
+            load_average: load_average(),
+            running_runs,
+            queued_runs,
+            tools,
+        }))
+    }
+
+    async fn capabilities(
+        &self,
+        request: Request<CapabilitiesRequest>,
+    ) -> Result<Response<CapabilityReport>, Status> {
+        let (found, missing) = request
+            .into_inner()
+            .tools
+            .into_iter()
+            .partition(|tool| cmd::find_executable(tool).is_some());
+        Ok(Response::new(CapabilityReport { found, missing }))
+    }
 }
 
-pub async fn start_agent(listen_addr: String) -> Result<(), Box<dyn std::error::Error>> {
-    let addr: SocketAddr = listen_addr.parse().unwrap();
-    let agent = Agent::default();
+pub async fn start_agent(options: AgentOptions) -> Result<(), Box<dyn std::error::Error>> {
+    let addr: SocketAddr = options.listen_addr.parse()?;
+    if options.policy.is_some() {
+        println!(
+            "[+] Holding tasks to the agent's policy, violations are recorded in {}",
+            options.audit_log.display()
+        );
+    }
+    let audit = AuditLog::new(options.audit_log);
+    let agent = Agent {
+        max_bundle_size: options.max_bundle_size,
+        ..Agent::new(
+            options.limits,
+            options.policy,
+            audit,
+            options.secrets,
+            options.smtp,
+        )
+    };
+
+    let auth_key = options.auth_key.unwrap_or_else(|| {
+        let key = auth::generate_key();
+        println!("[!] No auth key is configured, generated one for this session: {}", key);
+        println!("    Set it as auth_key in the agent and project configs to keep it");
+        key
+    });
+
+    let mut server = Server::builder();
+    match &options.tls {
+        Some(tls) => server = server.tls_config(tls.server_config()?)?,
+        None if !addr.ip().is_loopback() => {
+            println!("[!] Listening on {} without TLS, configure [tls] to encrypt connections", addr)
+        }
+        None => {}
+    }
 
     println!("Piper remote agent listening on {}", addr);
 
-    Server::builder()
-        .add_service(PiperAgentServer::new(agent))
+    let limit = options.max_bundle_size.saturating_add(REQUEST_OVERHEAD);
+    server
+        .layer(MapRequestLayer::new(move |request| limit_body(request, limit)))
+        .add_service(PiperAgentServer::with_interceptor(
+            agent,
+            auth::CheckKey::new(&auth_key),
+        ))
         .serve(addr)
         .await?;
 
     Ok(())
 }
+
+// Fail a request once its body goes over `limit` bytes. Bundles are only
+// checked once tonic has read the whole message into memory, which it does
+// whatever the size
+fn limit_body(request: hyper::Request<Body>, limit: u64) -> hyper::Request<Body> {
+    let (parts, body) = request.into_parts();
+    let mut received = 0;
+    let body = body.map(move |chunk| -> Result<_, Box<dyn std::error::Error + Send + Sync>> {
+        let chunk = chunk?;
+        received += chunk.len() as u64;
+        if received > limit {
+            return Err(format!("The request is over the agent's limit of {} bytes", limit).into());
+        }
+        Ok(chunk)
+    });
+    hyper::Request::from_parts(parts, Body::wrap_stream(body))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::client::agent_proto::piper_agent_client::PiperAgentClient;
+    use tokio::net::TcpListener;
+    use tokio_stream::wrappers::TcpListenerStream;
+
+    #[tokio::test]
+    async fn refuses_requests_over_the_size_limit() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = format!("http://{}", listener.local_addr().unwrap());
+        let server = Server::builder()
+            .layer(MapRequestLayer::new(|request| limit_body(request, 100_000)))
+            .add_service(PiperAgentServer::new(Agent::default()))
+            .serve_with_incoming(TcpListenerStream::new(listener));
+        let server = tokio::spawn(server);
+
+        let mut client = PiperAgentClient::connect(address).await.unwrap();
+        let input = |pipeline: String| crate::client::agent_proto::PipelineInput {
+            pipeline,
+            ..Default::default()
+        };
+        let error = client
+            .submit_pipeline(input(format!("pipeline p {{}}{}", " ".repeat(200_000))))
+            .await
+            .unwrap_err();
+        assert!(error.message().contains("limit of 100000 bytes"), "{}", error);
+        client.submit_pipeline(input("pipeline p {}".to_string())).await.unwrap();
+        server.abort();
+    }
+}
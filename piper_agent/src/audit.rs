@@ -0,0 +1,98 @@
+use piper_runner::policy::Violation;
+use serde::Serialize;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Where an agent records the tasks its policy stopped, one JSON object per
+/// line.
+#[derive(Debug, Clone)]
+pub struct AuditLog {
+    path: PathBuf,
+    // Keeps entries written at once from interleaving
+    lock: Arc<Mutex<()>>,
+}
+
+/// A task the agent's policy stopped.
+#[derive(Debug, Serialize)]
+pub struct AuditEntry {
+    /// Seconds since the Unix epoch
+    pub time: u64,
+    /// Address of the client that sent the run or task
+    pub client: Option<String>,
+    /// The run the task was part of, None for a single task
+    pub run: Option<u64>,
+    pub task: String,
+    pub rule: String,
+    pub violation: String,
+}
+
+impl AuditEntry {
+    pub fn new(
+        client: Option<String>,
+        run: Option<u64>,
+        task: &str,
+        violation: &Violation,
+    ) -> Self {
+        AuditEntry {
+            time: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or_default(),
+            client,
+            run,
+            task: task.to_string(),
+            rule: violation.rule.to_string(),
+            violation: violation.message.clone(),
+        }
+    }
+}
+
+impl AuditLog {
+    pub fn new(path: PathBuf) -> Self {
+        AuditLog {
+            path,
+            lock: Arc::default(),
+        }
+    }
+
+    /// Append an entry. Failing to write it doesn't stop the agent, the
+    /// task is stopped either way.
+    pub fn record(&self, entry: &AuditEntry) {
+        let run = entry
+            .run
+            .map(|run| format!(" of run {}", run))
+            .unwrap_or_default();
+        println!(
+            "[!] Stopped task {}{}: {}",
+            entry.task, run, entry.violation
+        );
+
+        let _lock = self.lock.lock().unwrap();
+        if let Err(e) = self.append(entry) {
+            println!(
+                "[!] Failed to write to the audit log {}: {}",
+                self.path.display(),
+                e
+            );
+        }
+    }
+
+    fn append(&self, entry: &AuditEntry) -> std::io::Result<()> {
+        if let Some(parent) = self
+            .path
+            .parent()
+            .filter(|parent| !parent.as_os_str().is_empty())
+        {
+            fs::create_dir_all(parent)?;
+        }
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        let line = serde_json::to_string(entry).map_err(std::io::Error::other)?;
+        writeln!(file, "{}", line)
+    }
+}
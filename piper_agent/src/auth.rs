@@ -0,0 +1,124 @@
+use rand::distributions::{Alphanumeric, DistString};
+use sha2::{Digest, Sha256};
+use subtle::ConstantTimeEq;
+use tonic::metadata::MetadataValue;
+use tonic::service::Interceptor;
+use tonic::{Request, Status};
+
+// Request metadata holding the pre-shared key
+const AUTH_HEADER: &str = "authorization";
+
+/// Resolve a key from config: `env:NAME` reads the environment variable NAME,
+/// `file:PATH` reads the file at PATH and anything else is the key itself.
+pub fn resolve_key(value: &str) -> Result<String, String> {
+    let key = if let Some(name) = value.strip_prefix("env:") {
+        std::env::var(name).map_err(|_| format!("The environment variable {} is not set", name))?
+    } else if let Some(path) = value.strip_prefix("file:") {
+        std::fs::read_to_string(path).map_err(|e| format!("Failed to read {}: {}", path, e))?
+    } else {
+        value.to_string()
+    };
+
+    let key = key.trim();
+    if key.is_empty() {
+        return Err("The auth key is empty".to_string());
+    }
+    Ok(key.to_string())
+}
+
+/// A random key for an agent started without one.
+pub fn generate_key() -> String {
+    Alphanumeric.sample_string(&mut rand::thread_rng(), 32)
+}
+
+/// Rejects requests that don't carry the agent's key.
+#[derive(Clone)]
+pub struct CheckKey {
+    // Comparing digests keeps the key's length out of the timing too
+    digest: [u8; 32],
+}
+
+impl CheckKey {
+    pub fn new(key: &str) -> Self {
+        CheckKey {
+            digest: Sha256::digest(key.as_bytes()).into(),
+        }
+    }
+}
+
+impl Interceptor for CheckKey {
+    fn call(&mut self, request: Request<()>) -> Result<Request<()>, Status> {
+        let sent = request
+            .metadata()
+            .get(AUTH_HEADER)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .ok_or_else(|| Status::unauthenticated("Missing auth key"))?;
+
+        let digest: [u8; 32] = Sha256::digest(sent.as_bytes()).into();
+        if bool::from(digest.ct_eq(&self.digest)) {
+            Ok(request)
+        } else {
+            Err(Status::unauthenticated("Invalid auth key"))
+        }
+    }
+}
+
+/// Adds a key to every request sent to an agent.
+#[derive(Clone)]
+pub struct SendKey {
+    header: Option<MetadataValue<tonic::metadata::Ascii>>,
+}
+
+impl SendKey {
+    pub fn new(key: Option<&str>) -> Result<Self, String> {
+        let header = key
+            .map(|key| format!("Bearer {}", key).parse())
+            .transpose()
+            .map_err(|_| "The auth key can only contain printable ASCII characters".to_string())?;
+        Ok(SendKey { header })
+    }
+}
+
+impl Interceptor for SendKey {
+    fn call(&mut self, mut request: Request<()>) -> Result<Request<()>, Status> {
+        if let Some(header) = &self.header {
+            request.metadata_mut().insert(AUTH_HEADER, header.clone());
+        }
+        Ok(request)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn checks_the_sent_key() {
+        let mut check = CheckKey::new("right key");
+
+        let mut send = SendKey::new(Some("right key")).unwrap();
+        assert!(check.call(send.call(Request::new(())).unwrap()).is_ok());
+
+        let mut send = SendKey::new(Some("wrong key")).unwrap();
+        let status = check.call(send.call(Request::new(())).unwrap()).unwrap_err();
+        assert_eq!(status.code(), tonic::Code::Unauthenticated);
+
+        let mut send = SendKey::new(None).unwrap();
+        let status = check.call(send.call(Request::new(())).unwrap()).unwrap_err();
+        assert_eq!(status.code(), tonic::Code::Unauthenticated);
+    }
+
+    #[test]
+    fn resolves_key_indirections() {
+        std::env::set_var("PIPER_TEST_AUTH_KEY", "from env");
+        assert_eq!(resolve_key("env:PIPER_TEST_AUTH_KEY").unwrap(), "from env");
+        assert!(resolve_key("env:PIPER_TEST_UNSET_AUTH_KEY").is_err());
+        assert_eq!(resolve_key("plain").unwrap(), "plain");
+
+        let path = std::env::temp_dir().join(format!("piper-auth-key-{}", std::process::id()));
+        std::fs::write(&path, "from file\n").unwrap();
+        assert_eq!(resolve_key(&format!("file:{}", path.display())).unwrap(), "from file");
+        std::fs::remove_file(path).unwrap();
+    }
+}
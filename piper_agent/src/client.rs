@@ -5,26 +5,544 @@ pub mod agent_proto {
 // Proto generated client
 use agent_proto::piper_agent_client::PiperAgentClient;
 
-use agent_proto::PipelineInput;
+use agent_proto::{
+    run_event::Event, task_event, Artifact, Blob, BundleFile, CapabilitiesRequest, HealthRequest, ListRunsRequest,
+    LogStream, PipelineInput, RunId, RunSummary, Scope, TaskInput,
+};
+use crate::auth::SendKey;
+use crate::tls::TlsConfig;
+use piper_dsl::Pipeline;
+use piper_runner::bundle::Bundle;
+use piper_runner::scope::ScopeConfig;
+use piper_runner::task_executor::{TaskExecutor, TaskOutput, TaskRequest};
+use piper_tasks::cmd;
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::net::IpAddr;
+use std::path::{Component, Path};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tonic::codegen::InterceptedService;
+use tonic::transport::Channel;
 
+type Client = PiperAgentClient<InterceptedService<Channel, SendKey>>;
+type Error = Box<dyn std::error::Error>;
+
+/// Where an agent is and how to authenticate with it.
+#[derive(Debug, Clone)]
+pub struct AgentEndpoint {
+    /// URL of the agent, such as http://127.0.0.1:50051
+    pub address: String,
+    /// Key sent with every request, already resolved
+    pub auth_key: Option<String>,
+    /// Certificates for mutual TLS, plaintext if None
+    pub tls: Option<TlsConfig>,
+}
+
+async fn connect(agent: &AgentEndpoint) -> Result<Client, Error> {
+    let mut endpoint = Channel::from_shared(agent.address.clone())?;
+    if let Some(tls) = &agent.tls {
+        let host = endpoint.uri().host().unwrap_or_default();
+        if tls.domain.is_none() && host.trim_matches(['[', ']']).parse::<IpAddr>().is_ok() {
+            return Err(format!(
+                "The certificate of agent {} can't be checked against an IP address, set domain in its [tls] section",
+                agent.address
+            )
+            .into());
+        }
+        endpoint = endpoint.tls_config(tls.client_config()?)?;
+    }
+    let channel = endpoint.connect().await?;
+    Ok(PiperAgentClient::with_interceptor(
+        channel,
+        SendKey::new(agent.auth_key.as_deref())?,
+    ))
+}
+
+/// Run a pipeline on a remote agent, showing its progress as it runs and
+/// saving the artifacts it sends back under `artifact_root/<run_id>/artifacts/`.
+/// The files it reads are sent along, see `Bundle::collect`, after checking
+/// the agent has the tools its commands run. Returns the status the run
+/// finished with.
 pub async fn client_run(
-    agent_addr: String,
+    agent: &AgentEndpoint,
     path: std::path::PathBuf,
-) -> Result<(), Box<dyn std::error::Error>> {
+    artifact_root: &Path,
+    scope: Option<&ScopeConfig>,
+    ignore_scope: Option<String>,
+) -> Result<String, Error> {
     println!("Running remote pipeline!");
     // Connect to server
     // User server add if given, otherwise use default
-    let mut client = PiperAgentClient::connect(agent_addr).await?;
+    let mut client = connect(agent).await?;
+
+    let tools = required_tools(&path)?;
+    let missing = missing_tools(&mut client, &tools).await?;
+    if !missing.is_empty() {
+        return Err(format!(
+            "Agent {} is missing tools: {}",
+            agent.address,
+            describe_missing(&tools, &missing)
+        )
+        .into());
+    }
 
     // load the pipeline file contents
-    let pipeline_string = std::fs::read_to_string(path).unwrap();
+    let pipeline_string = std::fs::read_to_string(&path)?;
+    let bundle = Bundle::collect(&path, Path::new("."))?;
+    println!(
+        "[+] Sending {} files ({} bytes)",
+        bundle.files.len(),
+        bundle.size()
+    );
     let request = tonic::Request::new(PipelineInput {
         pipeline: pipeline_string,
+        bundle: Some(to_proto(bundle)),
+        scope: scope.map(|scope| Scope {
+            include: scope.include.clone(),
+            exclude: scope.exclude.clone(),
+            params: scope.params.clone(),
+        }),
+        ignore_scope,
     });
 
-    let response = client.run_pipeline(request).await?;
+    let id = client.submit_pipeline(request).await.map_err(rpc_error)?.into_inner().id;
+    println!("[+] Submitted run {}", id);
+    follow(&mut client, id, artifact_root).await
+}
+
+/// Pick the agent to run the pipeline at `path` on: of the agents that have
+/// every tool it needs, the one with the fewest running and queued runs.
+/// Agents that can't be reached are skipped. Returns the agent's name.
+pub async fn pick_agent(
+    agents: &[(String, AgentEndpoint)],
+    path: &Path,
+) -> Result<String, Error> {
+    let tools = required_tools(path)?;
+    let mut best: Option<(u32, &str)> = None;
+    let mut rejected = Vec::new();
+    for (name, agent) in agents {
+        let mut client = match connect(agent).await {
+            Ok(client) => client,
+            Err(e) => {
+                rejected.push(format!("{} is unreachable ({})", name, e));
+                continue;
+            }
+        };
+        let missing = missing_tools(&mut client, &tools).await?;
+        if !missing.is_empty() {
+            rejected.push(format!("{} is missing {}", name, missing.join(", ")));
+            continue;
+        }
+        let health = client
+            .health(HealthRequest {})
+            .await
+            .map_err(rpc_error)?
+            .into_inner();
+        let runs = health.running_runs + health.queued_runs;
+        if best.is_none_or(|(fewest, _)| runs < fewest) {
+            best = Some((runs, name));
+        }
+    }
 
-    println!("RESPONSE={:?}", response);
+    match best {
+        Some((_, name)) => {
+            println!("[+] Picked agent {}", name);
+            Ok(name.to_string())
+        }
+        None if agents.is_empty() => Err("--agent auto needs agents defined in config.toml".into()),
+        None => Err(format!("No agent can run the pipeline: {}", rejected.join("; ")).into()),
+    }
+}
+
+// The executables the pipeline's cmd tasks run, with the tasks that run them
+fn required_tools(path: &Path) -> Result<BTreeMap<String, BTreeSet<String>>, Error> {
+    let pipeline = Pipeline::load(path)
+        .map_err(|e| format!("Failed to load pipeline {}: {}", path.display(), e))?;
+    Ok(pipeline.required_tools()?)
+}
+
+// Which of the tools the agent doesn't have on its PATH
+async fn missing_tools(
+    client: &mut Client,
+    tools: &BTreeMap<String, BTreeSet<String>>,
+) -> Result<Vec<String>, Error> {
+    if tools.is_empty() {
+        return Ok(Vec::new());
+    }
+    let request = CapabilitiesRequest {
+        tools: tools.keys().cloned().collect(),
+    };
+    Ok(client
+        .capabilities(request)
+        .await
+        .map_err(rpc_error)?
+        .into_inner()
+        .missing)
+}
+
+fn describe_missing(tools: &BTreeMap<String, BTreeSet<String>>, missing: &[String]) -> String {
+    missing
+        .iter()
+        .map(|tool| {
+            let tasks: Vec<&str> = tools.get(tool).into_iter().flatten().map(String::as_str).collect();
+            format!("{} (needed by {})", tool, tasks.join(", "))
+        })
+        .collect::<Vec<_>>()
+        .join(", ")
+}
 
+/// Runs single tasks of a local pipeline on an agent. Made within a Tokio
+/// runtime, which it blocks on from the runner's threads.
+#[derive(Debug)]
+pub struct RemoteExecutor {
+    name: String,
+    // Resolved up front, but only an error once a task is sent
+    agent: Result<AgentEndpoint, String>,
+    runtime: tokio::runtime::Handle,
+}
+
+impl RemoteExecutor {
+    pub fn new(name: &str, agent: Result<AgentEndpoint, String>) -> Self {
+        RemoteExecutor {
+            name: name.to_string(),
+            agent,
+            runtime: tokio::runtime::Handle::current(),
+        }
+    }
+
+    async fn connect(&self) -> anyhow::Result<Client> {
+        let agent = self.agent.as_ref().map_err(|e| anyhow::anyhow!("{}", e))?;
+        connect(agent)
+            .await
+            .map_err(|e| anyhow::anyhow!("Failed to reach agent {}: {}", self.name, e))
+    }
+
+    async fn run_task(
+        &self,
+        request: &TaskRequest,
+        on_line: cmd::LineHandler,
+        cancel: Option<Arc<AtomicBool>>,
+    ) -> anyhow::Result<TaskOutput> {
+        let mut client = self.connect().await?;
+        let input = TaskInput {
+            task: request.task.clone(),
+            task_type: request.task_type.to_string(),
+            args: request.args.clone(),
+            timeout_ms: request.timeout.map(|timeout| timeout.as_millis() as u64),
+            artifacts: request.artifacts.clone(),
+            inputs: request.inputs.clone().map(to_proto),
+        };
+        let mut events = client
+            .run_task(input)
+            .await
+            .map_err(|status| anyhow::anyhow!("{}", status.message()))?
+            .into_inner();
+
+        // Kept for the error of a failed command, as a local one has them
+        let (mut stdout, mut stderr) = (String::new(), String::new());
+        loop {
+            // Dropping the stream kills the task on the agent
+            let event = tokio::select! {
+                event = events.message() => event.map_err(|status| anyhow::anyhow!("{}", status.message()))?,
+                _ = tokio::time::sleep(Duration::from_millis(200)) => {
+                    if cancel.as_ref().is_some_and(|cancel| cancel.load(Ordering::SeqCst)) {
+                        return Err(cmd::CmdError::Cancelled.into());
+                    }
+                    continue;
+                }
+            };
+            let event = event
+                .and_then(|event| event.event)
+                .ok_or_else(|| anyhow::anyhow!("Agent {} stopped before the task finished", self.name))?;
+
+            let result = match event {
+                task_event::Event::LogLine(line) => {
+                    let (stream, lines) = match line.stream() {
+                        LogStream::Stdout => (cmd::Stream::Stdout, &mut stdout),
+                        LogStream::Stderr => (cmd::Stream::Stderr, &mut stderr),
+                    };
+                    on_line(stream, &line.line);
+                    lines.push_str(&line.line);
+                    lines.push('\n');
+                    continue;
+                }
+                task_event::Event::Result(result) => result,
+            };
+
+            return match (result.error, result.exit_code) {
+                (Some(_), _) if result.timed_out => {
+                    let timeout = result.timeout_ms.map(Duration::from_millis).or(request.timeout);
+                    Err(cmd::CmdError::Timeout(timeout.unwrap_or_default()).into())
+                }
+                (Some(_), Some(code)) => Err(cmd::CmdError::ExitCode { code, stdout, stderr }.into()),
+                (Some(error), None) => Err(anyhow::anyhow!(error)),
+                (None, _) => Ok(TaskOutput {
+                    output: result.output.unwrap_or_default(),
+                    files: result
+                        .files
+                        .into_iter()
+                        .map(|file| (file.path, file.content))
+                        .collect(),
+                }),
+            };
+        }
+    }
+
+    async fn missing(&self, tools: &[String]) -> anyhow::Result<Vec<String>> {
+        let mut client = self.connect().await?;
+        let request = CapabilitiesRequest {
+            tools: tools.to_vec(),
+        };
+        Ok(client
+            .capabilities(request)
+            .await
+            .map_err(|status| anyhow::anyhow!("{}", status.message()))?
+            .into_inner()
+            .missing)
+    }
+}
+
+impl TaskExecutor for RemoteExecutor {
+    fn run(
+        &self,
+        request: &TaskRequest,
+        on_line: cmd::LineHandler,
+        cancel: Option<Arc<AtomicBool>>,
+    ) -> anyhow::Result<TaskOutput> {
+        self.runtime.block_on(self.run_task(request, on_line, cancel))
+    }
+
+    fn missing_tools(&self, tools: &[String]) -> anyhow::Result<Vec<String>> {
+        self.runtime.block_on(self.missing(tools))
+    }
+}
+
+fn to_proto(bundle: Bundle) -> agent_proto::Bundle {
+    agent_proto::Bundle {
+        entry: bundle.entry,
+        files: bundle
+            .files
+            .into_iter()
+            .map(|(path, sha256)| BundleFile { path, sha256 })
+            .collect(),
+        blobs: bundle
+            .blobs
+            .into_iter()
+            .map(|(sha256, content)| Blob { sha256, content })
+            .collect(),
+    }
+}
+
+/// Reattach to a remote run, replaying what it did so far and following it
+/// until it finishes. Returns the status the run finished with.
+pub async fn follow_run(
+    agent: &AgentEndpoint,
+    id: u64,
+    artifact_root: &Path,
+) -> Result<String, Error> {
+    let mut client = connect(agent).await?;
+    follow(&mut client, id, artifact_root).await
+}
+
+async fn follow(client: &mut Client, id: u64, artifact_root: &Path) -> Result<String, Error> {
+    let mut events = client
+        .stream_run_events(RunId { id })
+        .await
+        .map_err(rpc_error)?
+        .into_inner();
+    loop {
+        let event = match events.message().await {
+            Ok(Some(event)) => event,
+            Ok(None) => return Err("The agent closed the connection before the run finished".into()),
+            Err(e) => {
+                println!("[!] Lost the connection to the agent, run {} carries on", id);
+                println!("    Reattach with: piper status {} --follow", id);
+                return Err(e.into());
+            }
+        };
+
+        match event.event {
+            Some(Event::TaskStarted(started)) if started.attempt > 1 => println!(
+                "[+] Running Task: {} ({}), attempt {}",
+                started.task, started.task_type, started.attempt
+            ),
+            Some(Event::TaskStarted(started)) => {
+                println!("[+] Running Task: {} ({})", started.task, started.task_type)
+            }
+            Some(Event::LogLine(line)) => match line.stream() {
+                LogStream::Stdout => println!("{}", line.line),
+                LogStream::Stderr => eprintln!("{}", line.line),
+            },
+            Some(Event::TaskFinished(finished)) => {
+                let duration = Duration::from_millis(finished.duration_ms);
+                match &finished.error {
+                    Some(error) => println!(
+                        "[!] Task {} failed after {:?}: {}",
+                        finished.task, duration, error
+                    ),
+                    None => println!("[+] Task {} finished in {:?}", finished.task, duration),
+                }
+            }
+            Some(Event::RunFinished(finished)) => {
+                let artifact_dir = artifact_root.join(id.to_string()).join("artifacts");
+                for artifact in &finished.artifacts {
+                    let file = save_artifact(&artifact_dir, artifact)?;
+                    println!("[+] Saved artifact {} to {}", artifact.path, file.display());
+                }
+                match &finished.error {
+                    Some(error) => println!("[!] Remote run {} {}: {}", id, finished.status, error),
+                    None => println!("[+] Remote run {} finished: {}", id, finished.status),
+                }
+                return Ok(finished.status);
+            }
+            None => {}
+        }
+    }
+}
+
+/// Print where a remote run is at.
+pub async fn run_status(agent: &AgentEndpoint, id: u64) -> Result<(), Error> {
+    let mut client = connect(agent).await?;
+    let run = client.get_run_status(RunId { id }).await.map_err(rpc_error)?.into_inner();
+
+    println!("Run {}: {}", run.id, run.pipeline);
+    println!("  Status:    {}", run.status);
+    if let Some(task) = &run.current_task {
+        println!("  Task:      {}", task);
+    }
+    println!("  Submitted: {} ago", format_age(run.submitted_at));
+    if let Some(duration) = run_duration(&run) {
+        println!("  Duration:  {:?}", duration);
+    }
+    if let Some(error) = &run.error {
+        println!("  Error:     {}", error);
+    }
+
+    Ok(())
+}
+
+/// Print every run the agent knows about.
+pub async fn list_runs(agent: &AgentEndpoint) -> Result<(), Error> {
+    let mut client = connect(agent).await?;
+    let runs = client
+        .list_runs(ListRunsRequest {})
+        .await
+        .map_err(rpc_error)?
+        .into_inner()
+        .runs;
+    if runs.is_empty() {
+        println!("The agent has no runs");
+        return Ok(());
+    }
+
+    println!(
+        "{:<6} {:<24} {:<17} {:>10} {:>9}",
+        "ID", "PIPELINE", "STATUS", "SUBMITTED", "DURATION"
+    );
+    for run in runs.iter().rev() {
+        println!(
+            "{:<6} {:<24} {:<17} {:>10} {:>9}",
+            run.id,
+            run.pipeline,
+            run.status,
+            format!("{} ago", format_age(run.submitted_at)),
+            run_duration(run)
+                .map(|d| format!("{}s", d.as_secs()))
+                .unwrap_or_default()
+        );
+    }
+
+    Ok(())
+}
+
+/// Cancel a remote run.
+pub async fn cancel_run(agent: &AgentEndpoint, id: u64) -> Result<(), Error> {
+    let mut client = connect(agent).await?;
+    let run = client.cancel_run(RunId { id }).await.map_err(rpc_error)?.into_inner();
+    match run.status.as_str() {
+        "queued" | "running" => println!("[+] Cancelling run {}", id),
+        "cancelled" => println!("[+] Cancelled run {}", id),
+        status => println!("[!] Run {} already finished: {}", id, status),
+    }
+    Ok(())
+}
+
+/// Check an agent is up, printing what it reports about itself.
+pub async fn ping(agent: &AgentEndpoint) -> Result<(), Error> {
+    let sent = std::time::Instant::now();
+    let mut client = connect(agent).await?;
+    let health = client
+        .health(HealthRequest {})
+        .await
+        .map_err(rpc_error)?
+        .into_inner();
+
+    println!("[+] {} answered in {:?}", agent.address, sent.elapsed());
+    println!("  Version:  {}", health.version);
+    println!("  Uptime:   {:?}", Duration::from_secs(health.uptime_secs));
+    if let Some(load) = health.load_average {
+        println!("  Load:     {:.2}", load);
+    }
+    println!(
+        "  Runs:     {} running, {} queued",
+        health.running_runs, health.queued_runs
+    );
+    if health.tools.is_empty() {
+        println!("  Tools:    none of the well-known tools are installed");
+    } else {
+        println!("  Tools:    {}", health.tools.join(", "));
+    }
     Ok(())
 }
+
+// The agent's own message rather than the whole status
+fn rpc_error(status: tonic::Status) -> Error {
+    status.message().into()
+}
+
+// How long a run has been running, or ran for
+fn run_duration(run: &RunSummary) -> Option<Duration> {
+    let started_at = run.started_at?;
+    let until = run.finished_at.unwrap_or_else(unix_now);
+    Some(Duration::from_secs(until.saturating_sub(started_at)))
+}
+
+fn format_age(timestamp: u64) -> String {
+    let seconds = unix_now().saturating_sub(timestamp);
+    match seconds {
+        0..=59 => format!("{}s", seconds),
+        60..=3599 => format!("{}m", seconds / 60),
+        3600..=86399 => format!("{}h", seconds / 3600),
+        _ => format!("{}d", seconds / 86400),
+    }
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+fn save_artifact(artifact_dir: &Path, artifact: &Artifact) -> Result<std::path::PathBuf, Error> {
+    let sha256 = format!("{:x}", Sha256::digest(&artifact.content));
+    if sha256 != artifact.sha256 {
+        return Err(format!("Artifact {} was corrupted in transfer", artifact.path).into());
+    }
+
+    // The names come from the agent, so don't let them climb out of the directory
+    let relative = Path::new(&artifact.task).join(&artifact.name);
+    if !relative.components().all(|c| matches!(c, Component::Normal(_))) {
+        return Err(format!("Artifact {} has an invalid name", artifact.path).into());
+    }
+
+    let file = artifact_dir.join(relative);
+    if let Some(dir) = file.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    fs::write(&file, &artifact.content)?;
+    Ok(file)
+}
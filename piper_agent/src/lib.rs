@@ -1,2 +1,8 @@
 pub mod agent;
+pub mod audit;
+pub mod auth;
 pub mod client;
+pub mod limits;
+mod queue;
+mod tasks;
+pub mod tls;
@@ -0,0 +1,73 @@
+use piper_dsl::parse_duration;
+use piper_runner::task_executor::CommandLimits;
+use serde::Deserialize;
+use std::time::Duration;
+
+/// Runs and tasks waiting for a slot an agent accepts by default before
+/// refusing more.
+pub const DEFAULT_MAX_QUEUED_RUNS: usize = 16;
+
+/// The `[limits]` section of an agent's config file. Durations are written
+/// like task timeouts, such as "90s" or "2h".
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct LimitsConfig {
+    /// Runs and single tasks run at once, one per CPU if unset
+    pub max_concurrent_runs: Option<usize>,
+    /// Runs and single tasks left waiting for a slot before more are refused
+    pub max_queued_runs: Option<usize>,
+    /// How long a run or single task can take before it is stopped
+    pub max_run_time: Option<String>,
+    /// Memory each command can map, in MiB
+    pub memory_mb: Option<u64>,
+    /// CPU time each command can use before it is killed
+    pub cpu_time: Option<String>,
+}
+
+/// What an agent takes on at once and what the runs it takes can use.
+#[derive(Debug, Clone)]
+pub struct Limits {
+    pub max_concurrent: usize,
+    pub max_queued: usize,
+    pub max_run_time: Option<Duration>,
+    /// Put on every command the agent's runs and tasks start
+    pub commands: CommandLimits,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            max_concurrent: std::thread::available_parallelism().map_or(1, |n| n.get()),
+            max_queued: DEFAULT_MAX_QUEUED_RUNS,
+            max_run_time: None,
+            commands: CommandLimits::default(),
+        }
+    }
+}
+
+impl LimitsConfig {
+    pub fn limits(&self) -> Result<Limits, String> {
+        let defaults = Limits::default();
+        let duration = |value: &Option<String>, field: &str| {
+            value
+                .as_deref()
+                .map(|value| {
+                    parse_duration(value).map_err(|e| format!("{} in [limits]: {}", field, e))
+                })
+                .transpose()
+        };
+        if self.max_concurrent_runs == Some(0) {
+            return Err("max_concurrent_runs in [limits] must be at least 1".to_string());
+        }
+
+        Ok(Limits {
+            max_concurrent: self.max_concurrent_runs.unwrap_or(defaults.max_concurrent),
+            max_queued: self.max_queued_runs.unwrap_or(defaults.max_queued),
+            max_run_time: duration(&self.max_run_time, "max_run_time")?,
+            commands: CommandLimits {
+                memory: self.memory_mb.map(|mb| mb * 1024 * 1024),
+                cpu_time: duration(&self.cpu_time, "cpu_time")?,
+            },
+        })
+    }
+}
@@ -0,0 +1,660 @@
+use crate::agent::agent_proto::{
+    run_event::Event, Artifact, LogLine, LogStream, RunEvent, RunFinished, RunSummary,
+    TaskFinished, TaskStarted,
+};
+use crate::audit::{AuditEntry, AuditLog};
+use crate::limits::Limits;
+use piper_runner::bundle::Bundle;
+use piper_runner::policy::Policy;
+use piper_runner::scope::Scope;
+use notifications::SmtpConfig;
+use piper_runner::secrets::SecretsFile;
+use piper_runner::{events, run_log, runner};
+use piper_tasks::cmd;
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::{mpsc, watch, OwnedSemaphorePermit, Semaphore};
+
+/// Where a submitted run is in its life.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RunState {
+    Queued,
+    Running,
+    Finished(run_log::RunStatus),
+    Cancelled,
+}
+
+impl RunState {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            RunState::Queued => "queued",
+            RunState::Running => "running",
+            RunState::Finished(status) => status.as_str(),
+            RunState::Cancelled => "cancelled",
+        }
+    }
+
+    fn is_done(&self) -> bool {
+        matches!(self, RunState::Finished(_) | RunState::Cancelled)
+    }
+}
+
+struct QueuedRun {
+    pipeline: String,
+    // Address of the client that submitted it
+    client: Option<String>,
+    state: RunState,
+    error: Option<String>,
+    submitted_at: SystemTime,
+    started_at: Option<SystemTime>,
+    finished_at: Option<SystemTime>,
+    current_task: Option<String>,
+    // Kept so a client can follow the run from the start at any point
+    events: Vec<RunEvent>,
+    cancel: Arc<AtomicBool>,
+    // The number of events, which followers wait on to change
+    progress: watch::Sender<usize>,
+}
+
+// How long finished runs are kept, and how many at most
+const FINISHED_RUN_TTL: Duration = Duration::from_secs(60 * 60);
+const MAX_FINISHED_RUNS: usize = 100;
+
+/// Pipelines submitted to the agent, run in the background a few at a time.
+/// Finished runs are kept in memory for an hour, and only the last 100, so
+/// clients can follow them after the fact. Single tasks sent by pipelines
+/// running elsewhere take the same slots as runs.
+#[derive(Clone)]
+pub struct RunQueue {
+    runs: Arc<Mutex<BTreeMap<u64, QueuedRun>>>,
+    finished_ttl: Duration,
+    max_finished: usize,
+    next_id: Arc<AtomicU64>,
+    slots: Arc<Semaphore>,
+    limits: Limits,
+    policy: Option<Arc<Policy>>,
+    audit: AuditLog,
+    // Where runs look up secrets not in the agent's environment
+    secrets: Option<SecretsFile>,
+    // The server runs send email notifications through
+    smtp: Option<SmtpConfig>,
+    waiting_tasks: Arc<AtomicUsize>,
+    running_tasks: Arc<AtomicUsize>,
+}
+
+/// A slot a single task runs in, given back when dropped.
+pub struct TaskSlot {
+    _permit: OwnedSemaphorePermit,
+    _running: Counted,
+}
+
+// Counts itself in a counter for as long as it lives
+struct Counted(Arc<AtomicUsize>);
+
+impl Counted {
+    fn new(counter: &Arc<AtomicUsize>) -> Self {
+        counter.fetch_add(1, Ordering::SeqCst);
+        Counted(counter.clone())
+    }
+}
+
+impl Drop for Counted {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl RunQueue {
+    /// A queue that runs up to `limits.max_concurrent` pipelines and tasks at
+    /// once and leaves up to `limits.max_queued` waiting. Runs are held to
+    /// `policy`, with the tasks it stops recorded in `audit`.
+    pub fn new(
+        limits: Limits,
+        policy: Option<Arc<Policy>>,
+        audit: AuditLog,
+        secrets: Option<SecretsFile>,
+        smtp: Option<SmtpConfig>,
+    ) -> Self {
+        RunQueue {
+            runs: Arc::default(),
+            finished_ttl: FINISHED_RUN_TTL,
+            max_finished: MAX_FINISHED_RUNS,
+            next_id: Arc::new(AtomicU64::new(1)),
+            slots: Arc::new(Semaphore::new(limits.max_concurrent.max(1))),
+            limits,
+            policy,
+            audit,
+            secrets,
+            smtp,
+            waiting_tasks: Arc::default(),
+            running_tasks: Arc::default(),
+        }
+    }
+
+    /// Queue a pipeline and return its run id, or None if the agent is
+    /// already running and queueing all it can. A pipeline sent with its
+    /// files runs in a workspace of its own, removed once it finishes.
+    pub fn submit(
+        &self,
+        source: String,
+        bundle: Option<Bundle>,
+        client: Option<String>,
+        scope: Option<Scope>,
+        ignore_scope: Option<String>,
+    ) -> Option<u64> {
+        let pipeline = piper_dsl::Pipeline::parse(&source)
+            .map(|pipeline| pipeline.name)
+            .unwrap_or_default();
+
+        let mut runs = self.runs.lock().unwrap();
+        self.evict_finished(&mut runs);
+        if !self.has_room(&runs) {
+            return None;
+        }
+        let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+        runs.insert(
+            id,
+            QueuedRun {
+                pipeline,
+                client,
+                state: RunState::Queued,
+                error: None,
+                submitted_at: SystemTime::now(),
+                started_at: None,
+                finished_at: None,
+                current_task: None,
+                events: Vec::new(),
+                cancel: Arc::default(),
+                progress: watch::channel(0).0,
+            },
+        );
+        drop(runs);
+        let options = runner::RunOptions {
+            scope,
+            ignore_scope,
+            ..Default::default()
+        };
+        tokio::spawn(self.clone().execute(id, source, bundle, options));
+        Some(id)
+    }
+
+    /// Wait for a slot to run a single task in, or return None if the agent
+    /// is already running and queueing all it can.
+    pub async fn task_slot(&self) -> Option<TaskSlot> {
+        let waiting = {
+            let runs = self.runs.lock().unwrap();
+            if !self.has_room(&runs) {
+                return None;
+            }
+            Counted::new(&self.waiting_tasks)
+        };
+        let permit = self
+            .slots
+            .clone()
+            .acquire_owned()
+            .await
+            .expect("the run queue is never closed");
+        drop(waiting);
+        Some(TaskSlot {
+            _permit: permit,
+            _running: Counted::new(&self.running_tasks),
+        })
+    }
+
+    // Whether another run or task would get a slot or fit in the queue
+    fn has_room(&self, runs: &BTreeMap<u64, QueuedRun>) -> bool {
+        let waiting = runs.values().filter(|run| run.state == RunState::Queued).count()
+            + self.waiting_tasks.load(Ordering::SeqCst);
+        self.slots.available_permits() > waiting || waiting < self.limits.max_queued
+    }
+
+    // Forget finished runs that are past their time, then the oldest ones
+    // over the limit
+    fn evict_finished(&self, runs: &mut BTreeMap<u64, QueuedRun>) {
+        let now = SystemTime::now();
+        runs.retain(|_, run| {
+            let age = run.finished_at.and_then(|at| now.duration_since(at).ok());
+            age.is_none_or(|age| age < self.finished_ttl)
+        });
+        let mut finished: Vec<(SystemTime, u64)> = runs
+            .iter()
+            .filter_map(|(id, run)| run.finished_at.map(|at| (at, *id)))
+            .collect();
+        finished.sort();
+        let excess = finished.len().saturating_sub(self.max_finished);
+        for (_, id) in &finished[..excess] {
+            runs.remove(id);
+        }
+    }
+
+    pub fn summary(&self, id: u64) -> Option<RunSummary> {
+        self.runs.lock().unwrap().get(&id).map(|run| summary(id, run))
+    }
+
+    pub fn summaries(&self) -> Vec<RunSummary> {
+        let runs = self.runs.lock().unwrap();
+        runs.iter().map(|(id, run)| summary(*id, run)).collect()
+    }
+
+    /// How many runs and single tasks are running and how many are waiting
+    /// for a slot.
+    pub fn counts(&self) -> (u32, u32) {
+        let runs = self.runs.lock().unwrap();
+        let count = |state| runs.values().filter(|run| run.state == state).count();
+        let running = count(RunState::Running) + self.running_tasks.load(Ordering::SeqCst);
+        let queued = count(RunState::Queued) + self.waiting_tasks.load(Ordering::SeqCst);
+        (running as u32, queued as u32)
+    }
+
+    /// Stop a run. A queued run never starts, a running one has its running
+    /// command killed and starts no more tasks.
+    pub fn cancel(&self, id: u64) -> Option<RunSummary> {
+        let mut runs = self.runs.lock().unwrap();
+        let run = runs.get_mut(&id)?;
+        run.cancel.store(true, Ordering::SeqCst);
+        if run.state == RunState::Queued {
+            let error = Some("Cancelled before it started".to_string());
+            finish(run, RunState::Cancelled, error, Vec::new());
+        }
+        let summary = summary(id, run);
+        self.evict_finished(&mut runs);
+        Some(summary)
+    }
+
+    /// Every event of a run so far followed by new ones as they happen,
+    /// ending after the run's RunFinished event. The run's artifacts are
+    /// only sent to the first client to follow it to the end, and are then
+    /// let go of.
+    pub fn follow(&self, id: u64) -> Option<mpsc::UnboundedReceiver<RunEvent>> {
+        // Subscribe before reading any events so none are missed
+        let mut progress = self.runs.lock().unwrap().get(&id)?.progress.subscribe();
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let queue = self.clone();
+
+        tokio::spawn(async move {
+            let mut sent = 0;
+            loop {
+                let (events, done) = {
+                    let runs = queue.runs.lock().unwrap();
+                    let Some(run) = runs.get(&id) else {
+                        // Finished and forgotten
+                        return;
+                    };
+                    (run.events[sent..].to_vec(), run.state.is_done())
+                };
+                for event in events {
+                    let finished = matches!(event.event, Some(Event::RunFinished(_)));
+                    if sender.send(event).is_err() {
+                        // The client went away, the run carries on
+                        return;
+                    }
+                    sent += 1;
+                    if finished {
+                        queue.drop_artifacts(id);
+                    }
+                }
+                if done || progress.changed().await.is_err() {
+                    return;
+                }
+            }
+        });
+
+        Some(receiver)
+    }
+
+    // Runs the pipeline with `options` as sent by the client
+    async fn execute(
+        self,
+        id: u64,
+        source: String,
+        bundle: Option<Bundle>,
+        options: runner::RunOptions,
+    ) {
+        let _slot = self.slots.acquire().await.expect("the run queue is never closed");
+
+        let cancel = {
+            let mut runs = self.runs.lock().unwrap();
+            // Cancelled while queued, and maybe forgotten since
+            let Some(run) = runs.get_mut(&id).filter(|run| !run.state.is_done()) else {
+                return;
+            };
+            run.state = RunState::Running;
+            run.started_at = Some(SystemTime::now());
+            run.cancel.clone()
+        };
+
+        // Artifacts are kept until they are sent back, as the client can't
+        // reach the agent's files
+        let artifact_dir = std::env::temp_dir()
+            .join(format!("piper-artifacts-{}-{}", std::process::id(), id));
+        let workspace = std::env::temp_dir()
+            .join(format!("piper-workspace-{}-{}", std::process::id(), id));
+        let (events, mut received) = mpsc::unbounded_channel();
+        let mut options = runner::RunOptions {
+            artifact_dir: Some(artifact_dir.clone()),
+            events: Some(events),
+            cancel: Some(cancel.clone()),
+            command_limits: self.limits.commands,
+            policy: self.policy.clone(),
+            secrets: self.secrets.clone(),
+            smtp: self.smtp.clone(),
+            ..options
+        };
+
+        // Stops the run like a cancel once it goes over the agent's limit
+        let timed_out = Arc::new(AtomicBool::new(false));
+        let watchdog = self.limits.max_run_time.map(|limit| {
+            let cancel = cancel.clone();
+            let timed_out = timed_out.clone();
+            tokio::spawn(async move {
+                tokio::time::sleep(limit).await;
+                timed_out.store(true, Ordering::SeqCst);
+                cancel.store(true, Ordering::SeqCst);
+            })
+        });
+
+        // The runner blocks on its own runtime, so keep it off the async workers
+        let run_workspace = workspace.clone();
+        let run = tokio::task::spawn_blocking(move || match bundle {
+            Some(bundle) => {
+                let path = unpack(&bundle, &run_workspace)?;
+                options.working_dir = Some(run_workspace);
+                runner::run_from_file_with_options(path, &options)
+            }
+            None => runner::run_with_options(source, &options),
+        });
+
+        // Ends once the runner has finished and dropped its sender
+        while let Some(event) = received.recv().await {
+            if let events::RunEvent::PolicyViolation { task, violation } = &event {
+                let client = self.runs.lock().unwrap()[&id].client.clone();
+                self.audit.record(&AuditEntry::new(client, Some(id), task, violation));
+            }
+            if let Some(event) = task_event(event) {
+                self.push_event(id, event);
+            }
+        }
+
+        let failed = |error: String| {
+            (RunState::Finished(run_log::RunStatus::Failed), Some(error), Vec::new())
+        };
+        let result = run.await;
+        if let Some(watchdog) = watchdog {
+            watchdog.abort();
+        }
+        let (state, error, artifacts) = match result {
+            Ok(Ok(log)) => match read_artifacts(&log) {
+                Ok(artifacts) => (RunState::Finished(log.status), log.error, artifacts),
+                Err(e) => failed(e.to_string()),
+            },
+            Ok(Err(e)) => failed(format!("{:#}", e)),
+            Err(e) => failed(e.to_string()),
+        };
+        let stopped = matches!(
+            state,
+            RunState::Finished(status) if status != run_log::RunStatus::Succeeded
+        );
+        let (state, error) = if stopped && timed_out.load(Ordering::SeqCst) {
+            let limit = self.limits.max_run_time.unwrap_or_default();
+            let error = format!("The run went over the agent's limit of {:?}", limit);
+            (RunState::Finished(run_log::RunStatus::Failed), Some(error))
+        } else if stopped && cancel.load(Ordering::SeqCst) {
+            (RunState::Cancelled, error)
+        } else {
+            (state, error)
+        };
+        let _ = fs::remove_dir_all(&artifact_dir);
+        let _ = fs::remove_dir_all(&workspace);
+
+        let mut runs = self.runs.lock().unwrap();
+        let run = runs.get_mut(&id).expect("running runs aren't evicted");
+        finish(run, state, error, artifacts);
+        self.evict_finished(&mut runs);
+    }
+
+    // Let go of the contents of a run's artifacts once they were sent
+    fn drop_artifacts(&self, id: u64) {
+        let mut runs = self.runs.lock().unwrap();
+        let finished = runs
+            .get_mut(&id)
+            .and_then(|run| run.events.last_mut())
+            .and_then(|event| event.event.as_mut());
+        if let Some(Event::RunFinished(finished)) = finished {
+            finished.artifacts.clear();
+        }
+    }
+
+    fn push_event(&self, id: u64, event: RunEvent) {
+        let mut runs = self.runs.lock().unwrap();
+        let run = runs.get_mut(&id).expect("running runs aren't evicted");
+        if let Some(Event::TaskStarted(started)) = &event.event {
+            run.current_task = Some(started.task.clone());
+        }
+        run.events.push(event);
+        run.progress.send_replace(run.events.len());
+    }
+}
+
+// End a run, adding its RunFinished event
+fn finish(run: &mut QueuedRun, state: RunState, error: Option<String>, artifacts: Vec<Artifact>) {
+    run.state = state;
+    run.error = error.clone();
+    run.finished_at = Some(SystemTime::now());
+    run.current_task = None;
+    run.events.push(RunEvent {
+        event: Some(Event::RunFinished(RunFinished {
+            status: state.as_str().to_string(),
+            error,
+            artifacts,
+        })),
+    });
+    run.progress.send_replace(run.events.len());
+}
+
+fn summary(id: u64, run: &QueuedRun) -> RunSummary {
+    RunSummary {
+        id,
+        pipeline: run.pipeline.clone(),
+        status: run.state.as_str().to_string(),
+        error: run.error.clone(),
+        submitted_at: unix_secs(run.submitted_at),
+        started_at: run.started_at.map(unix_secs),
+        finished_at: run.finished_at.map(unix_secs),
+        current_task: run.current_task.clone(),
+    }
+}
+
+fn unix_secs(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+fn task_event(event: events::RunEvent) -> Option<RunEvent> {
+    let event = match event {
+        events::RunEvent::TaskStarted {
+            task,
+            task_type,
+            attempt,
+        } => Event::TaskStarted(TaskStarted {
+            task,
+            task_type,
+            attempt,
+        }),
+        events::RunEvent::LogLine { task, stream, line } => {
+            let stream = match stream {
+                cmd::Stream::Stdout => LogStream::Stdout,
+                cmd::Stream::Stderr => LogStream::Stderr,
+            };
+            Event::LogLine(LogLine {
+                task,
+                stream: stream as i32,
+                line,
+            })
+        }
+        events::RunEvent::TaskFinished {
+            task,
+            attempt,
+            duration,
+            exit_code,
+            output,
+            error,
+        } => Event::TaskFinished(TaskFinished {
+            task,
+            attempt,
+            duration_ms: duration.as_millis() as u64,
+            exit_code,
+            output,
+            error,
+        }),
+        // Audited rather than sent, the task's error says what it did
+        events::RunEvent::PolicyViolation { .. } => return None,
+    };
+    Some(RunEvent { event: Some(event) })
+}
+
+// A fresh workspace holding the bundle's files
+fn unpack(bundle: &Bundle, workspace: &Path) -> anyhow::Result<PathBuf> {
+    create_workspace(workspace)?;
+    bundle.unpack(workspace)
+}
+
+// A fresh directory only the agent's user can read
+pub(crate) fn create_workspace(workspace: &Path) -> anyhow::Result<()> {
+    let mut builder = fs::DirBuilder::new();
+    #[cfg(unix)]
+    std::os::unix::fs::DirBuilderExt::mode(&mut builder, 0o700);
+    builder
+        .create(workspace)
+        .map_err(|e| anyhow::anyhow!("Failed to create workspace {}: {}", workspace.display(), e))
+}
+
+fn read_artifacts(log: &run_log::RunLog) -> std::io::Result<Vec<Artifact>> {
+    log.artifacts
+        .iter()
+        .map(|artifact| {
+            let content = fs::read(&artifact.file).map_err(|e| {
+                std::io::Error::new(
+                    e.kind(),
+                    format!("Failed to read artifact {}: {}", artifact.path, e),
+                )
+            })?;
+            Ok(Artifact {
+                task: artifact.task.clone(),
+                path: artifact.path.clone(),
+                name: artifact.name.clone(),
+                sha256: artifact.sha256.clone(),
+                content,
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn queue(max_concurrent: usize) -> RunQueue {
+        let limits = Limits {
+            max_concurrent,
+            max_queued: 4,
+            ..Limits::default()
+        };
+        let audit = AuditLog::new(PathBuf::from("audit.log"));
+        RunQueue::new(limits, None, audit, None, None)
+    }
+
+    fn submit(queue: &RunQueue, source: &str) -> u64 {
+        queue.submit(source.to_string(), None, None, None, None).unwrap()
+    }
+
+    async fn wait_for(queue: &RunQueue, id: u64, status: &str) {
+        while queue.summary(id).unwrap().status != status {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+    }
+
+    async fn events(queue: &RunQueue, id: u64) -> Vec<Event> {
+        let mut followed = queue.follow(id).unwrap();
+        let mut events = Vec::new();
+        while let Some(event) = followed.recv().await {
+            events.extend(event.event);
+        }
+        events
+    }
+
+    #[tokio::test]
+    async fn replays_runs_from_the_start_and_evicts_finished_ones() {
+        let mut queue = queue(1);
+        queue.max_finished = 1;
+        let id = submit(&queue, "pipeline p {\n  a = cmd(\"echo hi\")\n  flow: a\n}");
+        wait_for(&queue, id, "succeeded").await;
+
+        // Followed once the run is over, every event is still sent
+        let events = events(&queue, id).await;
+        assert!(matches!(&events[0], Event::TaskStarted(started) if started.task == "a"));
+        assert!(events.iter().any(|e| matches!(e, Event::LogLine(log) if log.line == "hi")));
+        let last = events.last().unwrap();
+        assert!(matches!(last, Event::RunFinished(f) if f.status == "succeeded"));
+
+        let next = submit(&queue, "pipeline q {}");
+        wait_for(&queue, next, "succeeded").await;
+        submit(&queue, "pipeline r {}");
+        assert!(queue.summary(id).is_none());
+        assert!(queue.follow(id).is_none());
+    }
+
+    #[tokio::test]
+    async fn cancels_queued_and_running_runs() {
+        let queue = queue(1);
+        let running = submit(&queue, "pipeline slow {\n  a = cmd(\"sleep 30\")\n  flow: a\n}");
+        let queued = submit(&queue, "pipeline p {\n  a = cmd(\"echo hi\")\n  flow: a\n}");
+        while queue.summary(running).unwrap().current_task.is_none() {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        assert_eq!(queue.counts(), (1, 1));
+
+        // A queued run ends at once and never starts
+        assert_eq!(queue.cancel(queued).unwrap().status, "cancelled");
+        let events = events(&queue, queued).await;
+        assert!(matches!(&events[..], [Event::RunFinished(f)] if f.status == "cancelled"));
+
+        // A running one has its command killed
+        queue.cancel(running).unwrap();
+        tokio::time::timeout(Duration::from_secs(10), wait_for(&queue, running, "cancelled"))
+            .await
+            .unwrap();
+        assert_eq!(queue.counts(), (0, 0));
+        assert!(queue.summary(queued).unwrap().started_at.is_none());
+    }
+
+    #[tokio::test]
+    async fn refuses_tasks_once_slots_and_queue_are_full() {
+        let limits = Limits {
+            max_concurrent: 1,
+            max_queued: 1,
+            ..Limits::default()
+        };
+        let audit = AuditLog::new(PathBuf::from("audit.log"));
+        let queue = RunQueue::new(limits, None, audit, None, None);
+        let running = queue.task_slot().await.unwrap();
+        let waiting = tokio::spawn({
+            let queue = queue.clone();
+            async move { queue.task_slot().await.is_some() }
+        });
+        while queue.counts() != (1, 1) {
+            tokio::task::yield_now().await;
+        }
+
+        assert!(queue.task_slot().await.is_none());
+        assert!(queue.submit("pipeline p {}".to_string(), None, None, None, None).is_none());
+
+        drop(running);
+        assert!(waiting.await.unwrap());
+        assert_eq!(queue.counts(), (0, 0));
+    }
+}
@@ -0,0 +1,149 @@
+use crate::agent::agent_proto::{
+    task_event::Event, LogLine, LogStream, TaskEvent, TaskFile, TaskResult,
+};
+use crate::audit::{AuditEntry, AuditLog};
+use crate::queue::{create_workspace, TaskSlot};
+use piper_runner::policy::Policy;
+use piper_runner::task_executor::{
+    self, CommandLimits, LocalExecutor, TaskExecutor, TaskOutput, TaskRequest,
+};
+use piper_tasks::cmd;
+use std::fs;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use tokio::sync::mpsc;
+
+// Numbers the workspaces of tasks
+static NEXT_TASK: AtomicU64 = AtomicU64::new(1);
+
+/// What single tasks run under on this agent.
+#[derive(Debug, Clone)]
+pub struct TaskSettings {
+    pub limits: CommandLimits,
+    pub policy: Option<Arc<Policy>>,
+    pub audit: AuditLog,
+    /// Address of the client that sent the task
+    pub client: Option<String>,
+}
+
+/// Run a single task sent by a pipeline running elsewhere, in a workspace of
+/// its own holding its inputs. Its events are the lines its command writes
+/// followed by its result, with the files it wrote to its artifact paths.
+/// The task holds `slot` until it finishes. Dropping the receiver kills it.
+pub fn run_task(
+    request: TaskRequest,
+    slot: TaskSlot,
+    settings: TaskSettings,
+) -> mpsc::UnboundedReceiver<TaskEvent> {
+    let (sender, receiver) = mpsc::unbounded_channel();
+
+    tokio::spawn(async move {
+        let cancel = Arc::new(AtomicBool::new(false));
+        let watcher = {
+            let sender = sender.clone();
+            let cancel = cancel.clone();
+            tokio::spawn(async move {
+                sender.closed().await;
+                cancel.store(true, Ordering::SeqCst);
+            })
+        };
+
+        let events = sender.clone();
+        let workspace = std::env::temp_dir().join(format!(
+            "piper-task-{}-{}",
+            std::process::id(),
+            NEXT_TASK.fetch_add(1, Ordering::SeqCst)
+        ));
+        let result = tokio::task::spawn_blocking(move || {
+            let result = execute(&request, &workspace, &settings, events, cancel);
+            let _ = fs::remove_dir_all(&workspace);
+            result
+        })
+        .await
+        .unwrap_or_else(|e| Err(anyhow::anyhow!("The task panicked: {}", e)));
+        watcher.abort();
+        drop(slot);
+
+        let _ = sender.send(TaskEvent {
+            event: Some(Event::Result(task_result(result))),
+        });
+    });
+
+    receiver
+}
+
+fn execute(
+    request: &TaskRequest,
+    workspace: &Path,
+    settings: &TaskSettings,
+    events: mpsc::UnboundedSender<TaskEvent>,
+    cancel: Arc<AtomicBool>,
+) -> anyhow::Result<TaskOutput> {
+    // The artifacts are sent back, so they can't name files outside the
+    // workspace, whatever the policy
+    task_executor::check_artifact_paths(&request.artifacts)?;
+    if let Some(policy) = &settings.policy {
+        if let Err(violation) = policy.check_request(request, workspace) {
+            let client = settings.client.clone();
+            settings.audit.record(&AuditEntry::new(client, None, &request.task, &violation));
+            return Err(violation.into());
+        }
+    }
+    create_workspace(workspace)?;
+    if let Some(inputs) = &request.inputs {
+        inputs.unpack_files(workspace)?;
+    }
+
+    let task = request.task.clone();
+    let on_line: cmd::LineHandler = Arc::new(move |stream, line| {
+        let stream = match stream {
+            cmd::Stream::Stdout => LogStream::Stdout,
+            cmd::Stream::Stderr => LogStream::Stderr,
+        };
+        let _ = events.send(TaskEvent {
+            event: Some(Event::LogLine(LogLine {
+                task: task.clone(),
+                stream: stream as i32,
+                line: line.to_string(),
+            })),
+        });
+    });
+
+    let executor = LocalExecutor {
+        working_dir: Some(workspace.to_path_buf()),
+        limits: settings.limits,
+    };
+    let mut output = executor.run(request, on_line, Some(cancel))?;
+    output.files = task_executor::read_files(&request.artifacts, workspace)?;
+    Ok(output)
+}
+
+fn task_result(result: anyhow::Result<TaskOutput>) -> TaskResult {
+    match result {
+        Ok(output) => TaskResult {
+            output: Some(output.output),
+            files: output
+                .files
+                .into_iter()
+                .map(|(path, content)| TaskFile { path, content })
+                .collect(),
+            ..Default::default()
+        },
+        Err(e) => {
+            let (exit_code, timeout) =
+                match e.chain().find_map(|e| e.downcast_ref::<cmd::CmdError>()) {
+                    Some(cmd::CmdError::ExitCode { code, .. }) => (Some(*code), None),
+                    Some(cmd::CmdError::Timeout(timeout)) => (None, Some(*timeout)),
+                    _ => (None, None),
+                };
+            TaskResult {
+                error: Some(format!("{:#}", e)),
+                exit_code,
+                timed_out: timeout.is_some(),
+                timeout_ms: timeout.map(|timeout| timeout.as_millis() as u64),
+                ..Default::default()
+            }
+        }
+    }
+}
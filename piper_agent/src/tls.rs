@@ -0,0 +1,187 @@
+use rcgen::{
+    BasicConstraints, Certificate, CertificateParams, DnType, ExtendedKeyUsagePurpose, IsCa,
+    KeyUsagePurpose,
+};
+use serde::Deserialize;
+use std::fs;
+use std::path::{Path, PathBuf};
+use tonic::transport::{Certificate as CaCertificate, ClientTlsConfig, Identity, ServerTlsConfig};
+
+/// The `[tls]` section of a config file. All files are PEM encoded.
+///
+/// Agents use `cert` and `key` for their own identity and only accept
+/// clients whose certificate chains to `client_ca`. Clients verify the agent
+/// against `ca` and present `cert` and `key`.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct TlsConfig {
+    pub cert: Option<PathBuf>,
+    pub key: Option<PathBuf>,
+    pub client_ca: Option<PathBuf>,
+    pub ca: Option<PathBuf>,
+    /// Name to check the agent's certificate against, if not its address.
+    /// Needed for agents reached by IP address, which can't be checked.
+    pub domain: Option<String>,
+}
+
+/// DNS name every generated agent certificate is valid for, so agents can
+/// be reached by IP address with `domain` set to it.
+pub const AGENT_NAME: &str = "piper-agent";
+
+impl TlsConfig {
+    pub fn server_config(&self) -> Result<ServerTlsConfig, String> {
+        let client_ca = required(&self.client_ca, "client_ca")?;
+        Ok(ServerTlsConfig::new()
+            .identity(self.identity()?)
+            .client_ca_root(CaCertificate::from_pem(read(client_ca)?)))
+    }
+
+    pub fn client_config(&self) -> Result<ClientTlsConfig, String> {
+        let ca = required(&self.ca, "ca")?;
+        let mut config = ClientTlsConfig::new()
+            .ca_certificate(CaCertificate::from_pem(read(ca)?))
+            .identity(self.identity()?);
+        if let Some(domain) = &self.domain {
+            config = config.domain_name(domain);
+        }
+        Ok(config)
+    }
+
+    fn identity(&self) -> Result<Identity, String> {
+        let cert = required(&self.cert, "cert")?;
+        let key = required(&self.key, "key")?;
+        Ok(Identity::from_pem(read(cert)?, read(key)?))
+    }
+}
+
+fn required<'a>(path: &'a Option<PathBuf>, field: &str) -> Result<&'a Path, String> {
+    path.as_deref()
+        .ok_or_else(|| format!("Mutual TLS needs {} in the [tls] section of config.toml", field))
+}
+
+fn read(path: &Path) -> Result<Vec<u8>, String> {
+    fs::read(path).map_err(|e| format!("Failed to read {}: {}", path.display(), e))
+}
+
+/// Mint a local CA, a certificate for an agent reachable at `agent_names`
+/// (hostnames or IP addresses) and AGENT_NAME, and a client certificate, all
+/// written to `dir`. Returns the paths of the written files.
+pub fn generate_certs(dir: &Path, agent_names: &[String]) -> Result<Vec<PathBuf>, String> {
+    let ca = Certificate::from_params(ca_params()).map_err(cert_error)?;
+
+    let mut names = agent_names.to_vec();
+    names.push(AGENT_NAME.to_string());
+    let mut agent = CertificateParams::new(names);
+    agent.distinguished_name.push(DnType::CommonName, "piper agent");
+    agent.extended_key_usages = vec![ExtendedKeyUsagePurpose::ServerAuth];
+    let agent = Certificate::from_params(agent).map_err(cert_error)?;
+
+    let mut client = CertificateParams::new(Vec::new());
+    client.distinguished_name.push(DnType::CommonName, "piper client");
+    client.extended_key_usages = vec![ExtendedKeyUsagePurpose::ClientAuth];
+    let client = Certificate::from_params(client).map_err(cert_error)?;
+
+    fs::create_dir_all(dir).map_err(|e| format!("Failed to create {}: {}", dir.display(), e))?;
+    let files = [
+        ("ca.pem", ca.serialize_pem().map_err(cert_error)?, false),
+        ("ca.key", ca.serialize_private_key_pem(), true),
+        ("agent.pem", agent.serialize_pem_with_signer(&ca).map_err(cert_error)?, false),
+        ("agent.key", agent.serialize_private_key_pem(), true),
+        ("client.pem", client.serialize_pem_with_signer(&ca).map_err(cert_error)?, false),
+        ("client.key", client.serialize_private_key_pem(), true),
+    ];
+
+    let mut written = Vec::new();
+    for (name, pem, private) in files {
+        let path = dir.join(name);
+        write_pem(&path, &pem, private)
+            .map_err(|e| format!("Failed to write {}: {}", path.display(), e))?;
+        written.push(path);
+    }
+    Ok(written)
+}
+
+fn ca_params() -> CertificateParams {
+    let mut params = CertificateParams::new(Vec::new());
+    params.distinguished_name.push(DnType::CommonName, "piper local CA");
+    params.is_ca = IsCa::Ca(BasicConstraints::Constrained(0));
+    params.key_usages = vec![KeyUsagePurpose::KeyCertSign, KeyUsagePurpose::CrlSign];
+    params
+}
+
+fn cert_error(e: rcgen::Error) -> String {
+    format!("Failed to generate certificates: {}", e)
+}
+
+// Keys are only readable by their owner
+fn write_pem(path: &Path, pem: &str, private: bool) -> std::io::Result<()> {
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    if private {
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    }
+    std::io::Write::write_all(&mut options.open(path)?, pem.as_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::agent::agent_proto::piper_agent_server::PiperAgentServer;
+    use crate::agent::Agent;
+    use crate::client::agent_proto::{piper_agent_client::PiperAgentClient, HealthRequest};
+    use tokio::net::TcpListener;
+    use tokio_stream::wrappers::TcpListenerStream;
+    use tonic::transport::{Channel, Server};
+
+    fn config(dir: &Path, name: &str, ca: &str) -> TlsConfig {
+        TlsConfig {
+            cert: Some(dir.join(format!("{}.pem", name))),
+            key: Some(dir.join(format!("{}.key", name))),
+            client_ca: Some(dir.join(ca)),
+            ca: Some(dir.join(ca)),
+            domain: Some(AGENT_NAME.to_string()),
+        }
+    }
+
+    async fn health(address: &str, tls: &TlsConfig) -> Result<(), String> {
+        let endpoint = Channel::from_shared(address.to_string())
+            .unwrap()
+            .tls_config(tls.client_config()?)
+            .unwrap();
+        let channel = endpoint.connect().await.map_err(|e| e.to_string())?;
+        PiperAgentClient::new(channel)
+            .health(HealthRequest {})
+            .await
+            .map(|_| ())
+            .map_err(|e| e.to_string())
+    }
+
+    #[tokio::test]
+    async fn only_accepts_clients_of_its_own_ca() {
+        let root = std::env::temp_dir().join(format!("piper-tls-{}", std::process::id()));
+        let (ours, theirs) = (root.join("ours"), root.join("theirs"));
+        generate_certs(&ours, &["127.0.0.1".to_string()]).unwrap();
+        generate_certs(&theirs, &["127.0.0.1".to_string()]).unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = format!("https://{}", listener.local_addr().unwrap());
+        let server = Server::builder()
+            .tls_config(config(&ours, "agent", "ca.pem").server_config().unwrap())
+            .unwrap()
+            .add_service(PiperAgentServer::new(Agent::default()))
+            .serve_with_incoming(TcpListenerStream::new(listener));
+        let server = tokio::spawn(server);
+
+        // A certificate from another CA, even one trusting the agent, is refused
+        let foreign = TlsConfig {
+            ca: Some(ours.join("ca.pem")),
+            ..config(&theirs, "client", "ca.pem")
+        };
+        assert!(health(&address, &foreign).await.is_err());
+        health(&address, &config(&ours, "client", "ca.pem")).await.unwrap();
+
+        server.abort();
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,99 @@
+use crate::parser::{ParseError, Task, TaskType, Value};
+use crate::retry::parse_duration;
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+/// Whether and for how long a task's result is cached, from its `cache` and
+/// `cache_ttl` arguments.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CachePolicy {
+    // None keeps the result until the cache is cleared
+    pub ttl: Option<Duration>,
+}
+
+impl Task {
+    /// The task's cache policy, or None if its result isn't cached. Setting
+    /// `cache_ttl` turns caching on unless `cache=false` is given.
+    pub fn cache_policy(&self) -> Result<Option<CachePolicy>, ParseError> {
+        let args = &self.named_arguments;
+        let enabled = match args.get("cache") {
+            Some(Value::Boolean(enabled)) => *enabled,
+            Some(_) => return Err(invalid("cache", "must be true or false")),
+            None => args.contains_key("cache_ttl"),
+        };
+        if !enabled {
+            return Ok(None);
+        }
+
+        if !matches!(self.task_type, TaskType::Cmd | TaskType::Llm) {
+            return Err(invalid(
+                "cache",
+                &format!(
+                    "{} tasks can't be cached, only cmd and llm tasks",
+                    self.task_type.to_string()
+                ),
+            ));
+        }
+
+        let ttl = match args.get("cache_ttl") {
+            Some(Value::String(s)) => {
+                Some(parse_duration(s).map_err(|e| invalid("cache_ttl", &e))?)
+            }
+            Some(Value::Number(n)) if *n >= 0.0 => Some(Duration::from_secs_f64(*n)),
+            Some(_) => return Err(invalid("cache_ttl", "must be a duration such as \"24h\"")),
+            None => None,
+        };
+
+        Ok(Some(CachePolicy { ttl }))
+    }
+}
+
+fn invalid(field: &str, message: &str) -> ParseError {
+    ParseError::InvalidValue {
+        field: field.to_string(),
+        message: message.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::Pipeline;
+
+    #[test]
+    fn parses_cache_arguments() {
+        let pipeline = Pipeline::parse(
+            r#"
+            pipeline cached {
+                whois = cmd(command="whois example.com", cache=true)
+                dns = cmd(command="dig example.com", cache_ttl="24h")
+                scan = cmd(command="nmap example.com", cache=false, cache_ttl="1h")
+                flow: whois > dns > scan
+            }
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            pipeline.tasks["whois"].cache_policy().unwrap(),
+            Some(CachePolicy { ttl: None })
+        );
+        assert_eq!(
+            pipeline.tasks["dns"].cache_policy().unwrap(),
+            Some(CachePolicy {
+                ttl: Some(Duration::from_secs(86400))
+            })
+        );
+        assert_eq!(pipeline.tasks["scan"].cache_policy().unwrap(), None);
+
+        let result = Pipeline::parse(
+            r#"
+            pipeline cached {
+                code = lua(code="ctx.x = 1", cache=true)
+                flow: code
+            }
+            "#,
+        );
+        assert!(result.is_err());
+    }
+}
@@ -0,0 +1,305 @@
+use crate::parser::{Condition, ParseError, Pipeline, TaskType, Value};
+use std::collections::{BTreeSet, HashMap, HashSet};
+
+/// The tasks of a pipeline without an explicit flow, and the tasks each one
+/// needs the outputs of.
+#[derive(Debug, Clone)]
+pub struct TaskGraph {
+    /// Every task, after the tasks it depends on
+    pub order: Vec<String>,
+    pub dependencies: HashMap<String, Vec<String>>,
+}
+
+impl TaskGraph {
+    /// Group the tasks into stages. Every task in a stage depends only on
+    /// tasks from earlier stages.
+    pub fn stages(&self) -> Vec<Vec<String>> {
+        let mut levels: HashMap<&str, usize> = HashMap::new();
+        let mut stages: Vec<Vec<String>> = Vec::new();
+
+        for task in &self.order {
+            let level = self.dependencies[task]
+                .iter()
+                .map(|dep| levels[dep.as_str()] + 1)
+                .max()
+                .unwrap_or(0);
+            levels.insert(task, level);
+            if stages.len() <= level {
+                stages.resize(level + 1, Vec::new());
+            }
+            stages[level].push(task.clone());
+        }
+
+        stages
+    }
+}
+
+impl Pipeline {
+    /// Infer the task graph from the outputs tasks declare with `output=` and
+    /// the variables they use with `#{...}`. Sub-pipelines and loops store
+    /// their outputs under the task name, so their names count as outputs too.
+    pub fn task_graph(&self) -> Result<TaskGraph, ParseError> {
+        // Loop bodies, cleanup tasks, hook tasks and meta tasks only run
+        // through other tasks
+        let mut nested: HashSet<&str> = self.hooks.tasks().collect();
+        for task in self.tasks.values() {
+            if let Some(config) = &task.for_each_config {
+                nested.insert(config.task.as_str());
+            }
+            if let Some(cleanup) = task.on_failure() {
+                nested.insert(cleanup);
+            }
+        }
+        let nodes: BTreeSet<&str> = self
+            .tasks
+            .iter()
+            .filter(|(name, task)| {
+                !nested.contains(name.as_str())
+                    && !matches!(
+                        task.task_type,
+                        TaskType::MetaTask | TaskType::GenerateTasks | TaskType::GenerateFlow
+                    )
+            })
+            .map(|(name, _)| name.as_str())
+            .collect();
+
+        let mut producers: HashMap<&str, &str> = HashMap::new();
+        for &name in &nodes {
+            if matches!(self.tasks[name].task_type, TaskType::SubPipeline | TaskType::ForEach) {
+                producers.insert(name, name);
+            }
+        }
+        for &name in &nodes {
+            if let Some(Value::String(output)) = self.tasks[name].named_arguments.get("output") {
+                if let Some(other) = producers.insert(output, name) {
+                    if other != name {
+                        return Err(ParseError::InvalidValue {
+                            field: "output".to_string(),
+                            message: format!("'{}' is produced by both {} and {}", output, other, name),
+                        });
+                    }
+                }
+            }
+        }
+
+        let mut dependencies = HashMap::new();
+        for &name in &nodes {
+            let mut refs = self.task_references(name);
+            if let Some(config) = &self.tasks[name].for_each_config {
+                let body_refs = self.task_references(&config.task);
+                refs.extend(body_refs.into_iter().filter(|r| r != &config.binding));
+            }
+
+            let deps: BTreeSet<&str> = refs
+                .iter()
+                .filter_map(|r| producers.get(r.as_str()).copied())
+                .filter(|dep| *dep != name)
+                .collect();
+            dependencies.insert(name.to_string(), deps.into_iter().map(String::from).collect());
+        }
+
+        let order = topological_order(&nodes, &dependencies)?;
+        Ok(TaskGraph { order, dependencies })
+    }
+
+    // Variables used in a task's arguments
+    fn task_references(&self, name: &str) -> Vec<String> {
+        let task = match self.tasks.get(name) {
+            Some(task) => task,
+            None => return Vec::new(),
+        };
+
+        let mut refs = Vec::new();
+        for arg in &task.arguments {
+            if let Some(arg_name) = &arg.name {
+                if ["output", "on_failure", "task"].contains(&arg_name.as_str()) {
+                    continue;
+                }
+            }
+            collect_value_references(&arg.value, &mut refs);
+        }
+        refs
+    }
+}
+
+// Kahn's algorithm, taking ready tasks in name order so the result is stable
+fn topological_order(
+    nodes: &BTreeSet<&str>,
+    dependencies: &HashMap<String, Vec<String>>,
+) -> Result<Vec<String>, ParseError> {
+    let mut order = Vec::new();
+    let mut done: HashSet<&str> = HashSet::new();
+    let mut pending: Vec<&str> = nodes.iter().copied().collect();
+
+    while !pending.is_empty() {
+        let (ready, blocked): (Vec<&str>, Vec<&str>) = pending
+            .into_iter()
+            .partition(|name| dependencies[*name].iter().all(|d| done.contains(d.as_str())));
+
+        if ready.is_empty() {
+            return Err(ParseError::CyclicDependency(find_cycle(&blocked, dependencies)));
+        }
+
+        for name in ready {
+            done.insert(name);
+            order.push(name.to_string());
+        }
+        pending = blocked;
+    }
+
+    Ok(order)
+}
+
+// Follow dependencies between the blocked tasks until one repeats. Every
+// blocked task waits on another blocked task, so this always finds a cycle
+fn find_cycle(blocked: &[&str], dependencies: &HashMap<String, Vec<String>>) -> String {
+    let mut path: Vec<&str> = vec![blocked[0]];
+
+    loop {
+        let current = path[path.len() - 1];
+        let next = dependencies[current]
+            .iter()
+            .map(String::as_str)
+            .find(|dep| blocked.contains(dep))
+            .unwrap_or(current);
+
+        if let Some(pos) = path.iter().position(|name| *name == next) {
+            let mut cycle = path[pos..].to_vec();
+            cycle.push(next);
+            return cycle.join(" -> ");
+        }
+        path.push(next);
+    }
+}
+
+impl Value {
+    /// Names of the variables the value refers to, including `#{...}` in strings.
+    pub fn references(&self) -> Vec<String> {
+        let mut refs = Vec::new();
+        collect_value_references(self, &mut refs);
+        refs
+    }
+}
+
+fn collect_value_references(value: &Value, refs: &mut Vec<String>) {
+    match value {
+        Value::String(s) | Value::MultilineString(s) => collect_interpolations(s, refs),
+        Value::Number(_) | Value::Boolean(_) | Value::Secret(_) => {}
+        Value::Object(map) => map.values().for_each(|v| collect_value_references(v, refs)),
+        Value::Array(items) => items.iter().for_each(|v| collect_value_references(v, refs)),
+        Value::VarInterpolation(expr) => collect_identifiers(expr, refs),
+        Value::PropertyAccess { base, path } => {
+            refs.push(base.clone());
+            refs.extend(path.iter().cloned());
+        }
+        Value::FallbackExpr { primary, .. } => collect_value_references(primary, refs),
+        Value::FunctionCall { arguments, .. } => arguments
+            .iter()
+            .for_each(|arg| collect_value_references(&arg.value, refs)),
+        Value::ConditionalValue {
+            condition,
+            if_true,
+            if_false,
+        } => {
+            collect_condition_references(condition, refs);
+            collect_value_references(if_true, refs);
+            collect_value_references(if_false, refs);
+        }
+    }
+}
+
+fn collect_condition_references(condition: &Condition, refs: &mut Vec<String>) {
+    match condition {
+        Condition::Comparison { left, right, .. } => {
+            collect_value_references(left, refs);
+            collect_value_references(right, refs);
+        }
+        Condition::Boolean(_) => {}
+        Condition::VarInterpolation(text) => collect_interpolations(text, refs),
+        Condition::LogicalOperation { left, right, .. } => {
+            collect_condition_references(left, refs);
+            collect_condition_references(right, refs);
+        }
+    }
+}
+
+fn collect_interpolations(s: &str, refs: &mut Vec<String>) {
+    let mut rest = s;
+    while let Some(start) = rest.find("#{") {
+        let after = &rest[start + 2..];
+        let end = match after.find('}') {
+            Some(end) => end,
+            None => break,
+        };
+        // The fallback of `var || "default"` is a literal
+        let expr = after[..end].split("||").next().unwrap_or_default();
+        collect_identifiers(expr, refs);
+        rest = &after[end + 1..];
+    }
+}
+
+// Identifiers in an expression such as `CONFIG[depth].ports`, skipping
+// quoted keys and numeric indexes
+fn collect_identifiers(expr: &str, refs: &mut Vec<String>) {
+    let mut in_quotes = false;
+    let mut current = String::new();
+
+    for c in expr.chars().chain(std::iter::once(' ')) {
+        if c == '"' {
+            in_quotes = !in_quotes;
+        } else if !in_quotes && (c.is_ascii_alphanumeric() || c == '_') {
+            current.push(c);
+            continue;
+        }
+        if !current.is_empty() && !current.starts_with(|c: char| c.is_ascii_digit()) {
+            refs.push(current.clone());
+        }
+        current.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn infers_order_from_outputs() {
+        let pipeline = Pipeline::parse(
+            r#"
+            pipeline recon {
+                report = cmd("echo #{whois_output} #{records}")
+                whois = cmd("whois #{TARGET}", output="whois_output")
+                dns = cmd("dig #{TARGET}", output="records")
+                flow: auto
+            }
+            "#,
+        )
+        .unwrap();
+        assert!(pipeline.flow.is_none());
+
+        let graph = pipeline.task_graph().unwrap();
+        assert_eq!(graph.dependencies["report"], vec!["dns", "whois"]);
+        assert_eq!(
+            graph.stages(),
+            vec![vec!["dns".to_string(), "whois".to_string()], vec!["report".to_string()]]
+        );
+    }
+
+    #[test]
+    fn reports_dependency_cycles() {
+        let pipeline = Pipeline::parse(
+            r#"
+            pipeline cyclic {
+                a = cmd("echo #{b_out}", output="a_out")
+                b = cmd("echo #{a_out}", output="b_out")
+            }
+            "#,
+        )
+        .unwrap();
+
+        match pipeline.task_graph() {
+            Err(ParseError::CyclicDependency(cycle)) => assert_eq!(cycle, "a -> b -> a"),
+            other => panic!("expected a cycle, got {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,187 @@
+use crate::parser::{Module, ParseError, Pipeline, TaskType};
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+impl Pipeline {
+    /// Load a pipeline file, resolving its imports relative to the file's directory.
+    pub fn load(path: &Path) -> Result<Self, ParseError> {
+        let mut stack = Vec::new();
+        load_file(path, &mut stack)?
+            .into_iter()
+            .next()
+            .ok_or_else(|| ParseError::MissingField("pipeline".to_string()))
+    }
+
+    /// Resolve the imports of an already parsed pipeline relative to `base_dir`.
+    pub fn resolve_imports(&mut self, base_dir: &Path) -> Result<(), ParseError> {
+        let mut stack = Vec::new();
+        self.modules = load_modules(self, base_dir, &mut stack)?;
+        check_calls(self)
+    }
+}
+
+// Parse every pipeline in a file and resolve the file's imports.
+// `stack` holds the canonical paths of the files currently being loaded.
+fn load_file(path: &Path, stack: &mut Vec<PathBuf>) -> Result<Vec<Pipeline>, ParseError> {
+    let canonical = fs::canonicalize(path).map_err(|e| ParseError::Import {
+        path: path.display().to_string(),
+        message: e.to_string(),
+    })?;
+
+    if let Some(pos) = stack.iter().position(|p| p == &canonical) {
+        let chain: Vec<String> = stack[pos..]
+            .iter()
+            .chain(std::iter::once(&canonical))
+            .map(|p| p.display().to_string())
+            .collect();
+        return Err(ParseError::CyclicImport(chain.join(" -> ")));
+    }
+
+    let input = fs::read_to_string(&canonical).map_err(|e| ParseError::Import {
+        path: path.display().to_string(),
+        message: e.to_string(),
+    })?;
+    let mut pipelines = Pipeline::parse_all(&input)?;
+
+    stack.push(canonical.clone());
+    let base_dir = canonical.parent().unwrap_or(Path::new("."));
+    // Imports are declared per file, so every pipeline in it shares them
+    if let Some(first) = pipelines.first() {
+        let modules = load_modules(first, base_dir, stack)?;
+        for pipeline in pipelines.iter_mut() {
+            pipeline.modules = modules.clone();
+            check_calls(pipeline)?;
+        }
+    }
+    stack.pop();
+
+    Ok(pipelines)
+}
+
+fn load_modules(
+    pipeline: &Pipeline,
+    base_dir: &Path,
+    stack: &mut Vec<PathBuf>,
+) -> Result<HashMap<String, Module>, ParseError> {
+    let mut modules = HashMap::new();
+
+    for import in &pipeline.imports {
+        if modules.contains_key(&import.alias) {
+            return Err(ParseError::Import {
+                path: import.path.clone(),
+                message: format!("alias '{}' is already in use", import.alias),
+            });
+        }
+
+        let path = base_dir.join(&import.path);
+        let pipelines = load_file(&path, stack)?
+            .into_iter()
+            .map(|p| (p.name.clone(), p))
+            .collect();
+
+        modules.insert(import.alias.clone(), Module { path, pipelines });
+    }
+
+    Ok(modules)
+}
+
+// Make sure every sub-pipeline task refers to an imported pipeline
+fn check_calls(pipeline: &Pipeline) -> Result<(), ParseError> {
+    for (name, task) in &pipeline.tasks {
+        if task.task_type != TaskType::SubPipeline {
+            continue;
+        }
+        let call = task
+            .sub_pipeline_config
+            .as_ref()
+            .ok_or_else(|| ParseError::MissingField(format!("{}: pipeline call", name)))?;
+        let module = pipeline.modules.get(&call.import).ok_or_else(|| ParseError::InvalidValue {
+            field: name.clone(),
+            message: format!("unknown import '{}'", call.import),
+        })?;
+        if !module.pipelines.contains_key(&call.pipeline) {
+            return Err(ParseError::InvalidValue {
+                field: name.clone(),
+                message: format!(
+                    "no pipeline '{}' in {}",
+                    call.pipeline,
+                    module.path.display()
+                ),
+            });
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("piper_imports_{}_{}", name, std::process::id()));
+        fs::create_dir_all(dir.join("lib")).unwrap();
+        dir
+    }
+
+    #[test]
+    fn resolves_sub_pipeline_calls() {
+        let dir = temp_dir("resolve");
+        fs::write(
+            dir.join("lib/common.piper"),
+            r#"
+            pipeline port_scan(target) {
+                scan = cmd(command="nmap #{target}", output="scan_output")
+                flow: scan
+            }
+            pipeline whois(target) {
+                lookup = cmd(command="whois #{target}", output="whois_output")
+                flow: lookup
+            }
+            "#,
+        )
+        .unwrap();
+        fs::write(
+            dir.join("main.piper"),
+            r#"
+            import "lib/common.piper" as common
+            pipeline engagement(target="example.com") {
+                sub = common.port_scan(target=target)
+                flow: sub
+            }
+            "#,
+        )
+        .unwrap();
+
+        let pipeline = Pipeline::load(&dir.join("main.piper")).unwrap();
+        let task = &pipeline.tasks["sub"];
+        assert_eq!(task.task_type, TaskType::SubPipeline);
+        let call = task.sub_pipeline_config.as_ref().unwrap();
+        assert_eq!(call.import, "common");
+        assert_eq!(call.pipeline, "port_scan");
+        assert_eq!(pipeline.modules["common"].pipelines.len(), 2);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn detects_cyclic_imports() {
+        let dir = temp_dir("cycle");
+        fs::write(
+            dir.join("a.piper"),
+            "import \"lib/b.piper\" as b\npipeline a { t = b.b() flow: t }",
+        )
+        .unwrap();
+        fs::write(
+            dir.join("lib/b.piper"),
+            "import \"../a.piper\" as a\npipeline b { t = a.a() flow: t }",
+        )
+        .unwrap();
+
+        let err = Pipeline::load(&dir.join("a.piper")).unwrap_err();
+        assert!(matches!(err, ParseError::CyclicImport(_)));
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
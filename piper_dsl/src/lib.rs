@@ -1,8 +1,20 @@
 pub mod parser;
+mod imports;
+mod cache;
+mod dag;
+mod render;
+mod retry;
+mod tools;
 
 // Re-export types from the parser
 pub use parser::{
     Pipeline, Task, TaskType, Value, ParseError, Parameter, Argument,
     Flow, FlowItem, Condition, ComparisonOperator, LogicalOperator,
     PiperParser, MetaTaskConfig, GenerateTasksConfig, GenerateFlowConfig,
-};
\ No newline at end of file
+    Import, Module, SubPipelineConfig, ForEachConfig, Hooks,
+};
+pub use cache::CachePolicy;
+pub use dag::TaskGraph;
+pub use render::GraphFormat;
+pub use retry::{parse_duration, Backoff, RetryCondition, RetryPolicy, MAX_BACKOFF_DELAY};
+pub use tools::{command_programs, command_tools, command_writes};
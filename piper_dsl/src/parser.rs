@@ -24,6 +24,15 @@ pub enum ParseError {
     
     #[error("Invalid value for field {field}: {message}")]
     InvalidValue { field: String, message: String },
+    
+    #[error("Failed to import {path}: {message}")]
+    Import { path: String, message: String },
+    
+    #[error("Cyclic import detected: {0}")]
+    CyclicImport(String),
+    
+    #[error("Cyclic task dependency detected: {0}")]
+    CyclicDependency(String),
 }
 
 // Updated Pipeline structure for the new syntax
@@ -35,6 +44,27 @@ pub struct Pipeline {
     pub data_literals: HashMap<String, Value>,
     pub tasks: HashMap<String, Task>,
     pub flow: Option<Flow>,
+    pub finally: Option<Flow>,
+    // Tasks run when the pipeline starts, succeeds or fails
+    #[serde(default)]
+    pub hooks: Hooks,
+    pub imports: Vec<Import>,
+    // Resolved imports keyed by alias, populated by Pipeline::load
+    #[serde(default)]
+    pub modules: HashMap<String, Module>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Import {
+    pub path: String,
+    pub alias: String,
+}
+
+// The pipelines defined in an imported file
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Module {
+    pub path: PathBuf,
+    pub pipelines: HashMap<String, Pipeline>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -50,11 +80,14 @@ pub enum TaskType {
     Llm,
     Http,
     Notify,
+    Template,
     SetVar,
     Lua,
     MetaTask,
     GenerateTasks,
     GenerateFlow,
+    SubPipeline,
+    ForEach,
 }
 
 impl TaskType {
@@ -65,11 +98,14 @@ impl TaskType {
             "llm" => Ok(TaskType::Llm),
             "http" => Ok(TaskType::Http),
             "notify" => Ok(TaskType::Notify),
+            "template" => Ok(TaskType::Template),
             "set_var" => Ok(TaskType::SetVar),
             "lua" => Ok(TaskType::Lua),
             "meta_task" => Ok(TaskType::MetaTask),
             "generate_tasks" => Ok(TaskType::GenerateTasks),
             "generate_flow" => Ok(TaskType::GenerateFlow),
+            "pipeline" => Ok(TaskType::SubPipeline),
+            "for_each" => Ok(TaskType::ForEach),
             _ => Err(ParseError::InvalidTaskType(s.to_string())),
         }
     }
@@ -81,11 +117,14 @@ impl TaskType {
             TaskType::Llm => "llm".to_string(),
             TaskType::Http => "http".to_string(),
             TaskType::Notify => "notify".to_string(),
+            TaskType::Template => "template".to_string(),
             TaskType::SetVar => "set_var".to_string(),
             TaskType::Lua => "lua".to_string(),
             TaskType::MetaTask => "meta_task".to_string(),
             TaskType::GenerateTasks => "generate_tasks".to_string(),
             TaskType::GenerateFlow => "generate_flow".to_string(),
+            TaskType::SubPipeline => "pipeline".to_string(),
+            TaskType::ForEach => "for_each".to_string(),
         }
     }
 }
@@ -98,6 +137,8 @@ pub struct Task {
     pub meta_task_config: Option<MetaTaskConfig>,
     pub generate_tasks_config: Option<GenerateTasksConfig>,
     pub generate_flow_config: Option<GenerateFlowConfig>,
+    pub sub_pipeline_config: Option<SubPipelineConfig>,
+    pub for_each_config: Option<ForEachConfig>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -123,6 +164,89 @@ pub struct GenerateFlowConfig {
     pub visualization: Option<bool>,
 }
 
+// Target of a call to a pipeline from an imported file
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SubPipelineConfig {
+    pub import: String,
+    pub pipeline: String,
+}
+
+// Runs `task` once per item with the item bound to `binding`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ForEachConfig {
+    pub items: Value,
+    pub binding: String,
+    pub task: String,
+    pub parallel: usize,
+    pub continue_on_error: bool,
+    // Agents the items' task runs on in turn, empty to run it locally
+    pub agents: Vec<String>,
+}
+
+impl ForEachConfig {
+    // The flow node that executes the for_each task `name`
+    pub fn to_flow(&self, name: &str) -> Flow {
+        Flow::ForEach {
+            name: name.to_string(),
+            items: self.items.clone(),
+            binding: self.binding.clone(),
+            body: Box::new(FlowItem::Task(self.task.clone())),
+            parallel: self.parallel,
+            continue_on_error: self.continue_on_error,
+            agents: self.agents.clone(),
+        }
+    }
+}
+
+// Tasks run when a pipeline starts, succeeds or fails, from a `hooks { }`
+// block or `on_start`, `on_success` and `on_failure` in `meta { }`
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Hooks {
+    pub on_start: Vec<String>,
+    pub on_success: Vec<String>,
+    pub on_failure: Vec<String>,
+}
+
+impl Hooks {
+    // Each hook's name and the tasks it runs
+    pub fn events(&self) -> [(&'static str, &[String]); 3] {
+        [
+            ("on_start", &self.on_start),
+            ("on_success", &self.on_success),
+            ("on_failure", &self.on_failure),
+        ]
+    }
+    
+    // Every task run by a hook
+    pub fn tasks(&self) -> impl Iterator<Item = &str> {
+        self.on_start.iter().chain(&self.on_success).chain(&self.on_failure).map(String::as_str)
+    }
+    
+    fn tasks_mut(&mut self, event: &str) -> Option<&mut Vec<String>> {
+        match event {
+            "on_start" => Some(&mut self.on_start),
+            "on_success" => Some(&mut self.on_success),
+            "on_failure" => Some(&mut self.on_failure),
+            _ => None,
+        }
+    }
+}
+
+impl Task {
+    // The task to run when this one fails, from `on_failure=cleanup`
+    pub fn on_failure(&self) -> Option<&str> {
+        match self.named_arguments.get("on_failure")? {
+            Value::VarInterpolation(name) | Value::String(name) => Some(name),
+            _ => None,
+        }
+    }
+    
+    // Whether the flow carries on after this task fails
+    pub fn continue_on_error(&self) -> bool {
+        matches!(self.named_arguments.get("continue_on_error"), Some(Value::Boolean(true)))
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Argument {
     pub name: Option<String>,
@@ -143,6 +267,19 @@ pub enum Flow {
         if_true: Box<FlowItem>,
         if_false: Option<Box<FlowItem>>,
     },
+    ForEach {
+        name: String,
+        items: Value,
+        binding: String,
+        body: Box<FlowItem>,
+        parallel: usize,
+        continue_on_error: bool,
+        agents: Vec<String>,
+    },
+    Try {
+        body: Box<Flow>,
+        catch: Box<Flow>,
+    },
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -207,6 +344,8 @@ pub enum Value {
         function: String,
         arguments: Vec<Argument>,
     },
+    /// A secret looked up by name when the pipeline runs, as `secret("NAME")`
+    Secret(String),
     ConditionalValue {
         condition: Box<Condition>,
         if_true: Box<Value>,
@@ -266,6 +405,14 @@ impl Pipeline {
         pipeline.push_str(&format!("// Generated from meta-pipeline: {}\n", self.name));
         pipeline.push_str("// This pipeline was automatically generated from meta-tasks and constraints\n\n");
         
+        // Add imports
+        for import in &self.imports {
+            pipeline.push_str(&format!("import \"{}\" as {}\n", import.path, import.alias));
+        }
+        if !self.imports.is_empty() {
+            pipeline.push_str("\n");
+        }
+        
         // Add pipeline definition
         pipeline.push_str(&format!("pipeline {}(", self.name));
         
@@ -289,6 +436,17 @@ impl Pipeline {
         }
         pipeline.push_str("  }\n\n");
         
+        // Add hooks
+        if self.hooks.tasks().next().is_some() {
+            pipeline.push_str("  hooks {\n");
+            for (event, tasks) in self.hooks.events() {
+                if !tasks.is_empty() {
+                    pipeline.push_str(&format!("    {}: [{}]\n", event, tasks.join(", ")));
+                }
+            }
+            pipeline.push_str("  }\n\n");
+        }
+        
         // Add data literals
         for (name, value) in &self.data_literals {
             pipeline.push_str(&format!("  {} = {}\n", name, value_to_string(value)));
@@ -305,9 +463,16 @@ impl Pipeline {
                 pipeline.push_str(&format!("    command=\"echo 'Implementing: {}'\",\n", meta_config.task));
                 pipeline.push_str(&format!("    description=\"{}\"\n", meta_config.task));
                 pipeline.push_str("  )\n\n");
+            } else if let Some(call) = &task.sub_pipeline_config {
+                // Call of an imported pipeline
+                pipeline.push_str(&format!("  {} = {}.{}(\n", name, call.import, call.pipeline));
+                for (key, value) in &task.named_arguments {
+                    pipeline.push_str(&format!("    {}={},\n", key, value_to_string(value)));
+                }
+                pipeline.push_str("  )\n\n");
             } else {
                 // Regular task
-                pipeline.push_str(&format!("  {} = {:#}(\n", name, task.task_type));
+                pipeline.push_str(&format!("  {} = {}(\n", name, task.task_type.to_string()));
                 for (key, value) in &task.named_arguments {
                     pipeline.push_str(&format!("    {}={},\n", key, value_to_string(value)));
                 }
@@ -330,6 +495,11 @@ impl Pipeline {
             pipeline.push_str(&format!("    {}\n", task_names.join(" > ")));
         }
         
+        if let Some(finally) = &self.finally {
+            pipeline.push_str("  finally:\n");
+            pipeline.push_str(&format!("    {}\n", flow_to_string(finally)));
+        }
+        
         // Close the pipeline
         pipeline.push_str("}\n");
         
@@ -337,106 +507,163 @@ impl Pipeline {
     }
     
     pub fn parse(input: &str) -> Result<Self, ParseError> {
+        // The first pipeline in a file is its entry point
+        Self::parse_all(input)?
+            .into_iter()
+            .next()
+            .ok_or_else(|| ParseError::MissingField("pipeline".to_string()))
+    }
+    
+    // Parse every pipeline defined in a file, e.g. a library of sub-pipelines
+    pub fn parse_all(input: &str) -> Result<Vec<Self>, ParseError> {
         let file = PiperParser::parse(Rule::file, input)?
             .next()
             .unwrap();
         
-        let mut pipeline_name = String::new();
-        let mut parameters = Vec::new();
-        let mut metadata = HashMap::new();
-        let mut data_literals = HashMap::new();
-        let mut tasks = HashMap::new();
-        let mut flow = None;
+        let mut imports = Vec::new();
+        let mut pipelines = Vec::new();
         
         for record in file.into_inner() {
             match record.as_rule() {
+                Rule::import_statement => {
+                    imports.push(parse_import(record)?);
+                },
                 Rule::pipeline => {
-                    let mut inner_rules = record.into_inner();
-                    let name_rule = inner_rules.next().unwrap();
-                    pipeline_name = name_rule.as_str().to_string();
-                    
-                    // Parse optional parameters
-                    let next_rule = inner_rules.next().unwrap();
-                    if next_rule.as_rule() == Rule::parameters {
-                        parameters = parse_parameters(next_rule)?;
-                        
-                        // Continue with the rest of the pipeline
-                        for rule in inner_rules {
-                            match rule.as_rule() {
-                                Rule::metadata => {
-                                    metadata = parse_metadata(rule)?;
-                                },
-                                Rule::data_literal => {
-                                    let (name, value) = parse_data_literal(rule)?;
-                                    data_literals.insert(name, value);
-                                },
-                                Rule::task_definition => {
-                                    let (name, task) = parse_task_definition(rule)?;
-                                    tasks.insert(name, task);
-                                },
-                                Rule::flow_definition => {
-                                    flow = Some(parse_flow_definition(rule)?);
-                                },
-                                _ => {}
-                            }
-                        }
-                    } else {
-                        // No parameters, continue with the pipeline content
-                        match next_rule.as_rule() {
-                            Rule::metadata => {
-                                metadata = parse_metadata(next_rule)?;
-                            },
-                            Rule::data_literal => {
-                                let (name, value) = parse_data_literal(next_rule)?;
-                                data_literals.insert(name, value);
-                            },
-                            Rule::task_definition => {
-                                let (name, task) = parse_task_definition(next_rule)?;
-                                tasks.insert(name, task);
-                            },
-                            Rule::flow_definition => {
-                                flow = Some(parse_flow_definition(next_rule)?);
-                            },
-                            _ => {}
-                        }
-                        
-                        // Process remaining rules
-                        for rule in inner_rules {
-                            match rule.as_rule() {
-                                Rule::metadata => {
-                                    metadata = parse_metadata(rule)?;
-                                },
-                                Rule::data_literal => {
-                                    let (name, value) = parse_data_literal(rule)?;
-                                    data_literals.insert(name, value);
-                                },
-                                Rule::task_definition => {
-                                    let (name, task) = parse_task_definition(rule)?;
-                                    tasks.insert(name, task);
-                                },
-                                Rule::flow_definition => {
-                                    flow = Some(parse_flow_definition(rule)?);
-                                },
-                                _ => {}
-                            }
-                        }
-                    }
+                    let mut pipeline = parse_pipeline(record)?;
+                    pipeline.imports = imports.clone();
+                    pipelines.push(pipeline);
                 },
                 _ => {}
             }
         }
         
-        Ok(Pipeline {
-            name: pipeline_name,
-            parameters,
-            metadata,
-            data_literals,
-            tasks,
-            flow,
-        })
+        Ok(pipelines)
     }
 }
 
+fn parse_import(import_rule: pest::iterators::Pair<Rule>) -> Result<Import, ParseError> {
+    let mut import_inner = import_rule.into_inner();
+    let path = import_inner.next().unwrap().into_inner().next().unwrap().as_str().to_string();
+    let alias = import_inner.next().unwrap().as_str().to_string();
+    
+    Ok(Import { path, alias })
+}
+
+fn parse_pipeline(pipeline_rule: pest::iterators::Pair<Rule>) -> Result<Pipeline, ParseError> {
+    let mut inner_rules = pipeline_rule.into_inner();
+    let name = inner_rules.next().unwrap().as_str().to_string();
+    
+    let mut parameters = Vec::new();
+    let mut metadata = HashMap::new();
+    let mut data_literals = HashMap::new();
+    let mut tasks = HashMap::new();
+    let mut flow = None;
+    let mut finally = None;
+    let mut hooks = Hooks::default();
+    // Hooks are read once every task is known, as they can define their own
+    let mut hook_entries = Vec::new();
+    
+    for rule in inner_rules {
+        match rule.as_rule() {
+            Rule::parameters => {
+                parameters = parse_parameters(rule)?;
+            },
+            Rule::metadata => {
+                for (key, value) in parse_entries(rule) {
+                    if hooks.tasks_mut(&key).is_some() {
+                        hook_entries.push((key, value));
+                    } else {
+                        metadata.insert(key, parse_value(value)?);
+                    }
+                }
+            },
+            Rule::hooks => {
+                for (key, value) in parse_entries(rule) {
+                    if hooks.tasks_mut(&key).is_none() {
+                        return Err(ParseError::InvalidValue {
+                            field: "hooks".to_string(),
+                            message: format!(
+                                "Unknown hook '{}', expected on_start, on_success or on_failure",
+                                key
+                            ),
+                        });
+                    }
+                    hook_entries.push((key, value));
+                }
+            },
+            Rule::data_literal => {
+                let (name, value) = parse_data_literal(rule)?;
+                data_literals.insert(name, value);
+            },
+            Rule::task_definition => {
+                let (name, task) = parse_task_definition(rule)?;
+                // Reject invalid retry and cache arguments up front
+                task.retry_policy()?;
+                task.cache_policy()?;
+                tasks.insert(name, task);
+            },
+            Rule::flow_definition => {
+                flow = Some(parse_flow_definition(rule)?);
+            },
+            Rule::finally_definition => {
+                finally = Some(parse_flow_definition(rule)?);
+            },
+            _ => {}
+        }
+    }
+    
+    for (event, value) in hook_entries {
+        let names = parse_hook(&event, value, &mut tasks)?;
+        hooks.tasks_mut(&event).unwrap().extend(names);
+    }
+    if let Some(unknown) = hooks.tasks().find(|name| !tasks.contains_key(*name)) {
+        return Err(ParseError::InvalidValue {
+            field: "hooks".to_string(),
+            message: format!("A hook refers to unknown task '{}'", unknown),
+        });
+    }
+    
+    // for_each tasks run as loops in the flow
+    let flow = flow.map(|flow| expand_for_each(flow, &tasks));
+    let finally = finally.map(|flow| expand_for_each(flow, &tasks));
+    
+    // `flow: auto` infers the flow from data dependencies, like leaving it out
+    let flow = match flow {
+        Some(Flow::Sequential { items }) if !tasks.contains_key("auto")
+            && matches!(items.as_slice(), [FlowItem::Task(name)] if name == "auto") => None,
+        flow => flow,
+    };
+    
+    for (name, task) in &tasks {
+        if task.named_arguments.contains_key("on_failure") {
+            match task.on_failure() {
+                Some(cleanup) if tasks.contains_key(cleanup) => {},
+                Some(cleanup) => return Err(ParseError::InvalidValue {
+                    field: "on_failure".to_string(),
+                    message: format!("Task {} refers to unknown task '{}'", name, cleanup),
+                }),
+                None => return Err(ParseError::InvalidValue {
+                    field: "on_failure".to_string(),
+                    message: format!("Task {} must name a task to run on failure", name),
+                }),
+            }
+        }
+    }
+    
+    Ok(Pipeline {
+        name,
+        parameters,
+        metadata,
+        data_literals,
+        tasks,
+        flow,
+        finally,
+        hooks,
+        imports: Vec::new(),
+        modules: HashMap::new(),
+    })
+}
+
 fn parse_parameters(params_rule: pest::iterators::Pair<Rule>) -> Result<Vec<Parameter>, ParseError> {
     let mut parameters = Vec::new();
     
@@ -461,19 +688,56 @@ fn parse_parameters(params_rule: pest::iterators::Pair<Rule>) -> Result<Vec<Para
     Ok(parameters)
 }
 
-fn parse_metadata(metadata_rule: pest::iterators::Pair<Rule>) -> Result<HashMap<String, Value>, ParseError> {
-    let mut metadata = HashMap::new();
-    
-    for pair in metadata_rule.into_inner() {
-        if pair.as_rule() == Rule::pair {
-            let mut pair_inner = pair.into_inner();
-            let key = pair_inner.next().unwrap().as_str().to_string();
-            let value = parse_value(pair_inner.next().unwrap())?;
-            metadata.insert(key, value);
+// The `name: value` entries of a meta or hooks block
+fn parse_entries(block_rule: pest::iterators::Pair<Rule>) -> Vec<(String, pest::iterators::Pair<Rule>)> {
+    let mut inner = block_rule.into_inner();
+    let mut entries = Vec::new();
+    while let (Some(key), Some(value)) = (inner.next(), inner.next()) {
+        entries.push((key.as_str().to_string(), value));
+    }
+    entries
+}
+
+// The tasks a hook runs: a task's name, a list of them, or a call such as
+// `notify(...)`, which becomes a task named after the hook
+fn parse_hook(
+    event: &str,
+    value_rule: pest::iterators::Pair<Rule>,
+    tasks: &mut HashMap<String, Task>,
+) -> Result<Vec<String>, ParseError> {
+    let invalid = |message: String| ParseError::InvalidValue {
+        field: event.to_string(),
+        message,
+    };
+    let call = value_rule
+        .clone()
+        .into_inner()
+        .next()
+        .filter(|inner| inner.as_rule() == Rule::basic_value)
+        .and_then(|inner| inner.into_inner().next())
+        .filter(|inner| inner.as_rule() == Rule::function_call);
+    if let Some(call) = call {
+        if tasks.contains_key(event) {
+            return Err(invalid(format!("{} calls a task, but a task is already named {}", event, event)));
         }
+        let task = parse_function_call(call)?;
+        task.retry_policy()?;
+        task.cache_policy()?;
+        tasks.insert(event.to_string(), task);
+        return Ok(vec![event.to_string()]);
     }
     
-    Ok(metadata)
+    let name = |value: &Value| match value {
+        Value::VarInterpolation(name) | Value::String(name) => Some(name.clone()),
+        _ => None,
+    };
+    let names = match parse_value(value_rule)? {
+        Value::Array(items) => items.iter().map(name).collect(),
+        value => name(&value).map(|name| vec![name]),
+    };
+    names.ok_or_else(|| {
+        invalid(format!("{} must name tasks, as [alert, cleanup], or call one, as notify(...)", event))
+    })
 }
 
 fn parse_data_literal(data_rule: pest::iterators::Pair<Rule>) -> Result<(String, Value), ParseError> {
@@ -490,6 +754,7 @@ fn parse_task_definition(task_rule: pest::iterators::Pair<Rule>) -> Result<(Stri
     
     let task_content = task_inner.next().unwrap();
     let task = match task_content.as_rule() {
+        Rule::pipeline_call => parse_pipeline_call(task_content)?,
         Rule::function_call => parse_function_call(task_content)?,
         Rule::inline_command => parse_inline_command(task_content)?,
         _ => return Err(ParseError::InvalidValue {
@@ -501,15 +766,32 @@ fn parse_task_definition(task_rule: pest::iterators::Pair<Rule>) -> Result<(Stri
     Ok((name, task))
 }
 
-fn parse_function_call(call_rule: pest::iterators::Pair<Rule>) -> Result<Task, ParseError> {
+fn parse_pipeline_call(call_rule: pest::iterators::Pair<Rule>) -> Result<Task, ParseError> {
     let mut call_inner = call_rule.into_inner();
-    let task_type = call_inner.next().unwrap().as_str().to_string();
+    let import = call_inner.next().unwrap().as_str().to_string();
+    let pipeline = call_inner.next().unwrap().as_str().to_string();
+    
+    let (arguments, named_arguments) = parse_call_arguments(call_inner)?;
     
+    Ok(Task {
+        task_type: TaskType::SubPipeline,
+        arguments,
+        named_arguments,
+        meta_task_config: None,
+        generate_tasks_config: None,
+        generate_flow_config: None,
+        sub_pipeline_config: Some(SubPipelineConfig { import, pipeline }),
+        for_each_config: None,
+    })
+}
+
+fn parse_call_arguments(
+    arg_rules: pest::iterators::Pairs<Rule>,
+) -> Result<(Vec<Argument>, HashMap<String, Value>), ParseError> {
     let mut arguments = Vec::new();
     let mut named_arguments = HashMap::new();
     
-    // Parse arguments
-    for arg_rule in call_inner {
+    for arg_rule in arg_rules {
         if arg_rule.as_rule() == Rule::argument {
             let mut arg_inner = arg_rule.into_inner();
             let first = arg_inner.next().unwrap();
@@ -534,6 +816,15 @@ fn parse_function_call(call_rule: pest::iterators::Pair<Rule>) -> Result<Task, P
         }
     }
     
+    Ok((arguments, named_arguments))
+}
+
+fn parse_function_call(call_rule: pest::iterators::Pair<Rule>) -> Result<Task, ParseError> {
+    let mut call_inner = call_rule.into_inner();
+    let task_type = call_inner.next().unwrap().as_str().to_string();
+    
+    let (arguments, named_arguments) = parse_call_arguments(call_inner)?;
+    
     // Check for special task types
     let meta_task_config = if task_type == "meta_task" {
         // Parse meta_task arguments
@@ -666,19 +957,76 @@ fn parse_function_call(call_rule: pest::iterators::Pair<Rule>) -> Result<Task, P
         None
     };
     
+    let for_each_config = if task_type == "for_each" {
+        // Parse for_each arguments
+        let items = named_arguments.get("items")
+            .cloned()
+            .ok_or_else(|| ParseError::MissingField("for_each: items".to_string()))?;
+        
+        let task = match named_arguments.get("task") {
+            Some(Value::VarInterpolation(s)) | Some(Value::String(s)) => s.clone(),
+            _ => return Err(ParseError::MissingField("for_each: task".to_string())),
+        };
+        
+        let binding = match named_arguments.get("as") {
+            Some(Value::String(s)) | Some(Value::VarInterpolation(s)) => s.clone(),
+            _ => "item".to_string(),
+        };
+        
+        let parallel = match named_arguments.get("parallel") {
+            Some(Value::Number(n)) if *n >= 1.0 => *n as usize,
+            Some(_) => return Err(ParseError::InvalidValue {
+                field: "parallel".to_string(),
+                message: "must be a number of at least 1".to_string(),
+            }),
+            None => 1,
+        };
+        
+        let continue_on_error = matches!(named_arguments.get("continue_on_error"), Some(Value::Boolean(true)));
+        
+        let invalid_agents = || ParseError::InvalidValue {
+            field: "agents".to_string(),
+            message: "must be a list of agent names".to_string(),
+        };
+        let agents = match named_arguments.get("agents") {
+            Some(Value::Array(items)) => items
+                .iter()
+                .map(|item| match item {
+                    Value::String(agent) => Ok(agent.clone()),
+                    _ => Err(invalid_agents()),
+                })
+                .collect::<Result<Vec<_>, _>>()?,
+            Some(_) => return Err(invalid_agents()),
+            None => Vec::new(),
+        };
+        
+        Some(ForEachConfig {
+            items,
+            binding,
+            task,
+            parallel,
+            continue_on_error,
+            agents,
+        })
+    } else {
+        None
+    };
+    
     Ok(Task {
-        task_type,
+        task_type: TaskType::from_str(&task_type)?,
         arguments,
         named_arguments,
         meta_task_config,
         generate_tasks_config,
         generate_flow_config,
+        sub_pipeline_config: None,
+        for_each_config,
     })
 }
 
 fn parse_inline_command(cmd_rule: pest::iterators::Pair<Rule>) -> Result<Task, ParseError> {
     let mut cmd_inner = cmd_rule.into_inner();
-    let command = cmd_inner.next().unwrap().as_str().to_string();
+    let command = cmd_inner.next().unwrap().into_inner().next().unwrap().as_str().to_string();
     
     let mut arguments = Vec::new();
     let mut named_arguments = HashMap::new();
@@ -693,7 +1041,7 @@ fn parse_inline_command(cmd_rule: pest::iterators::Pair<Rule>) -> Result<Task, P
     
     // Check for output redirection
     if let Some(output_rule) = cmd_inner.next() {
-        let output = output_rule.as_str().to_string();
+        let output = output_rule.into_inner().next().unwrap().as_str().to_string();
         let output_value = Value::String(output);
         arguments.push(Argument {
             name: Some("output".to_string()),
@@ -703,12 +1051,14 @@ fn parse_inline_command(cmd_rule: pest::iterators::Pair<Rule>) -> Result<Task, P
     }
     
     Ok(Task {
-        task_type: "cmd".to_string(),
+        task_type: TaskType::Cmd,
         arguments,
         named_arguments,
         meta_task_config: None,
         generate_tasks_config: None,
         generate_flow_config: None,
+        sub_pipeline_config: None,
+        for_each_config: None,
     })
 }
 
@@ -751,8 +1101,50 @@ fn parse_flow_expr(expr_rule: pest::iterators::Pair<Rule>) -> Result<Flow, Parse
     Ok(Flow::Sequential { items })
 }
 
+// Replace references to for_each tasks with the loops they describe
+fn expand_for_each(flow: Flow, tasks: &HashMap<String, Task>) -> Flow {
+    let expand_item = |item: FlowItem| match item {
+        FlowItem::Task(name) => match tasks.get(&name).and_then(|t| t.for_each_config.as_ref()) {
+            Some(config) => FlowItem::Flow(config.to_flow(&name)),
+            None => FlowItem::Task(name),
+        },
+        FlowItem::Flow(flow) => FlowItem::Flow(expand_for_each(flow, tasks)),
+    };
+    
+    match flow {
+        Flow::Sequential { items } => Flow::Sequential {
+            items: items.into_iter().map(expand_item).collect(),
+        },
+        Flow::Parallel { items } => Flow::Parallel {
+            items: items.into_iter().map(expand_item).collect(),
+        },
+        Flow::Conditional { condition, if_true, if_false } => Flow::Conditional {
+            condition,
+            if_true: Box::new(expand_item(*if_true)),
+            if_false: if_false.map(|item| Box::new(expand_item(*item))),
+        },
+        Flow::ForEach { name, items, binding, body, parallel, continue_on_error, agents } => Flow::ForEach {
+            name,
+            items,
+            binding,
+            body: Box::new(expand_item(*body)),
+            parallel,
+            continue_on_error,
+            agents,
+        },
+        Flow::Try { body, catch } => Flow::Try {
+            body: Box::new(expand_for_each(*body, tasks)),
+            catch: Box::new(expand_for_each(*catch, tasks)),
+        },
+    }
+}
+
 fn parse_flow_item(item_rule: pest::iterators::Pair<Rule>) -> Result<FlowItem, ParseError> {
     match item_rule.as_rule() {
+        Rule::flow_item => {
+            let inner = item_rule.into_inner().next().unwrap();
+            parse_flow_item(inner)
+        },
         Rule::identifier => {
             let task_name = item_rule.as_str().to_string();
             Ok(FlowItem::Task(task_name))
@@ -784,6 +1176,16 @@ fn parse_flow_item(item_rule: pest::iterators::Pair<Rule>) -> Result<FlowItem, P
                 if_false,
             }))
         },
+        Rule::try_flow => {
+            let mut try_inner = item_rule.into_inner();
+            let body = parse_flow_expr(try_inner.next().unwrap())?;
+            let catch = parse_flow_expr(try_inner.next().unwrap())?;
+            
+            Ok(FlowItem::Flow(Flow::Try {
+                body: Box::new(body),
+                catch: Box::new(catch),
+            }))
+        },
         Rule::flow_expr => {
             let flow = parse_flow_expr(item_rule)?;
             Ok(FlowItem::Flow(flow))
@@ -938,6 +1340,7 @@ fn value_to_string(value: &Value) -> String {
                 .collect();
             format!("{}({})", function, args.join(", "))
         },
+        Value::Secret(name) => format!("secret(\"{}\")", name),
         Value::ConditionalValue { condition, if_true, if_false } => {
             format!("{} ? {} : {}", 
                 condition_to_string(condition), 
@@ -948,7 +1351,7 @@ fn value_to_string(value: &Value) -> String {
 }
 
 // Helper function to convert a Condition to a string representation
-fn condition_to_string(condition: &Condition) -> String {
+pub(crate) fn condition_to_string(condition: &Condition) -> String {
     match condition {
         Condition::Comparison { left, operator, right } => {
             let op_str = match operator {
@@ -962,6 +1365,8 @@ fn condition_to_string(condition: &Condition) -> String {
             format!("{} {} {}", value_to_string(left), op_str, value_to_string(right))
         },
         Condition::Boolean(b) => b.to_string(),
+        // The parser keeps the #{} around condition interpolations
+        Condition::VarInterpolation(v) if v.starts_with("#{") => v.clone(),
         Condition::VarInterpolation(v) => format!("#{{{}}}", v),
         Condition::LogicalOperation { left, operator, right } => {
             let op_str = match operator {
@@ -973,6 +1378,12 @@ fn condition_to_string(condition: &Condition) -> String {
     }
 }
 
+impl std::fmt::Display for Flow {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", flow_to_string(self))
+    }
+}
+
 // Helper function to convert a Flow to a string representation
 fn flow_to_string(flow: &Flow) -> String {
     match flow {
@@ -1000,6 +1411,11 @@ fn flow_to_string(flow: &Flow) -> String {
                     flow_item_to_string(if_true))
             }
         },
+        // The loop itself is emitted with the for_each task definition
+        Flow::ForEach { name, .. } => name.clone(),
+        Flow::Try { body, catch } => {
+            format!("try {{ {} }} catch {{ {} }}", flow_to_string(body), flow_to_string(catch))
+        },
     }
 }
 
@@ -1028,6 +1444,10 @@ fn parse_value(value_rule: pest::iterators::Pair<Rule>) -> Result<Value, ParseEr
                     let content = inner.into_inner().next().unwrap().as_str().to_string();
                     Ok(Value::MultilineString(content))
                 },
+                Rule::secret_value => {
+                    let name = inner.into_inner().next().unwrap().into_inner().next().unwrap();
+                    Ok(Value::Secret(name.as_str().to_string()))
+                },
                 Rule::number => {
                     let num_str = inner.as_str();
                     let num = num_str.parse::<f64>().map_err(|_| {
@@ -1177,3 +1597,119 @@ fn parse_value(value_rule: pest::iterators::Pair<Rule>) -> Result<Value, ParseEr
         }),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_for_each_into_flow() {
+        let pipeline = Pipeline::parse(r#"
+            pipeline loops {
+                probe = cmd(command="curl -I #{host}", output="headers")
+                probe_each = for_each(items=#{subdomains}, as="host", task=probe, parallel=8, continue_on_error=true, agents=["alpha", "beta"])
+                flow: probe_each
+            }
+        "#).unwrap();
+
+        let config = pipeline.tasks["probe_each"].for_each_config.as_ref().unwrap();
+        assert_eq!(config.binding, "host");
+        assert_eq!(config.task, "probe");
+        assert_eq!(config.parallel, 8);
+        assert!(config.continue_on_error);
+        assert_eq!(config.agents, ["alpha", "beta"]);
+
+        match pipeline.flow {
+            Some(Flow::Sequential { items }) => match &items[0] {
+                FlowItem::Flow(Flow::ForEach { name, body, .. }) => {
+                    assert_eq!(name, "probe_each");
+                    assert!(matches!(body.as_ref(), FlowItem::Task(t) if t == "probe"));
+                }
+                other => panic!("expected a for_each loop, got {:?}", other),
+            },
+            other => panic!("unexpected flow {:?}", other),
+        }
+    }
+
+    #[test]
+    fn parses_error_handling() {
+        let pipeline = Pipeline::parse(r#"
+            pipeline guarded {
+                cleanup = cmd("rm -rf /tmp/scan")
+                scan = cmd("nmap example.com", on_failure=cleanup, continue_on_error=true)
+                alert = cmd("echo failed")
+                flow: try { scan > cleanup } catch { alert }
+                finally: cleanup
+            }
+        "#).unwrap();
+
+        assert_eq!(pipeline.tasks["scan"].on_failure(), Some("cleanup"));
+        assert!(pipeline.tasks["scan"].continue_on_error());
+        assert!(matches!(pipeline.flow, Some(Flow::Try { .. })));
+        assert!(pipeline.finally.is_some());
+
+        let unknown = Pipeline::parse(r#"
+            pipeline guarded {
+                scan = cmd("nmap example.com", on_failure=missing)
+                flow: scan
+            }
+        "#);
+        assert!(unknown.is_err());
+    }
+
+    #[test]
+    fn parses_secrets_as_values() {
+        let pipeline = Pipeline::parse(r#"
+            pipeline notify(token=secret("env:SLACK_TOKEN")) {
+                API_KEY = secret("API_KEY")
+                env = { KEY: secret("file:keys/shodan.txt") }
+                secret_scan = cmd("trufflehog #{API_KEY}")
+                flow: secret_scan
+            }
+        "#).unwrap();
+
+        assert!(matches!(&pipeline.data_literals["API_KEY"], Value::Secret(name) if name == "API_KEY"));
+        assert!(matches!(
+            &pipeline.parameters[0].default_value,
+            Some(Value::Secret(name)) if name == "env:SLACK_TOKEN"
+        ));
+        assert!(pipeline.tasks.contains_key("secret_scan"));
+        assert!(!pipeline.tasks.contains_key("API_KEY"));
+    }
+
+    #[test]
+    fn parses_hooks_and_metadata() {
+        let pipeline = Pipeline::parse(r##"
+            pipeline scan {
+                meta {
+                    author: "Red Team"
+                    on_start: notify(uri="stdout", message="Starting")
+                }
+                hooks {
+                    on_failure: [page, cleanup]
+                    on_success: page
+                }
+                page = notify(uri="https://example.com/hook", message="#{run.summary}")
+                cleanup = cmd("rm -rf tmp")
+                nmap = cmd("nmap example.com")
+            }
+        "##).unwrap();
+
+        assert!(matches!(&pipeline.metadata["author"], Value::String(author) if author == "Red Team"));
+        assert!(!pipeline.metadata.contains_key("on_start"));
+        assert_eq!(pipeline.hooks.on_start, ["on_start"]);
+        assert_eq!(pipeline.tasks["on_start"].task_type, TaskType::Notify);
+        assert_eq!(pipeline.hooks.on_failure, ["page", "cleanup"]);
+        assert_eq!(pipeline.hooks.on_success, ["page"]);
+        // Hook tasks only run through their hooks
+        assert_eq!(pipeline.task_graph().unwrap().order, ["nmap"]);
+
+        for source in [
+            "pipeline p { hooks { on_finish: a } a = cmd(\"true\") }",
+            "pipeline p { hooks { on_failure: missing } }",
+            "pipeline p { hooks { on_failure: 3 } }",
+        ] {
+            assert!(Pipeline::parse(source).is_err(), "{}", source);
+        }
+    }
+}
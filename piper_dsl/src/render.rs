@@ -0,0 +1,453 @@
+use crate::parser::{condition_to_string, Flow, FlowItem, ParseError, Pipeline, TaskType, Value};
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::str::FromStr;
+
+/// Output formats for `Pipeline::render_graph`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GraphFormat {
+    Dot,
+    Mermaid,
+    Ascii,
+}
+
+impl FromStr for GraphFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "dot" => Ok(GraphFormat::Dot),
+            "mermaid" => Ok(GraphFormat::Mermaid),
+            "ascii" => Ok(GraphFormat::Ascii),
+            _ => Err(format!("unknown graph format '{}', expected dot, mermaid or ascii", s)),
+        }
+    }
+}
+
+impl Pipeline {
+    /// Render the pipeline's flow, or the order inferred from its data
+    /// dependencies when it has none. Tasks are annotated with their type and
+    /// declared outputs.
+    pub fn render_graph(&self, format: GraphFormat) -> Result<String, ParseError> {
+        if format == GraphFormat::Ascii {
+            return self.render_ascii();
+        }
+
+        let graph = self.build_graph()?;
+        Ok(match format {
+            GraphFormat::Dot => graph.to_dot(&self.name),
+            GraphFormat::Mermaid => graph.to_mermaid(),
+            GraphFormat::Ascii => unreachable!(),
+        })
+    }
+
+    // "scan", "cmd -> scan_output"
+    fn task_annotation(&self, name: &str) -> (String, Option<String>) {
+        let task = match self.tasks.get(name) {
+            Some(task) => task,
+            None => return (name.to_string(), None),
+        };
+
+        let mut annotation = match &task.sub_pipeline_config {
+            Some(call) => format!("pipeline {}.{}", call.import, call.pipeline),
+            None => task.task_type.to_string(),
+        };
+
+        let mut outputs = Vec::new();
+        if let Some(Value::String(output)) = task.named_arguments.get("output") {
+            outputs.push(output.clone());
+        }
+        // Sub-pipelines and loops store their outputs under the task name
+        if matches!(task.task_type, TaskType::SubPipeline | TaskType::ForEach) {
+            outputs.push(name.to_string());
+        }
+        if !outputs.is_empty() {
+            annotation.push_str(&format!(" -> {}", outputs.join(", ")));
+        }
+
+        (name.to_string(), Some(annotation))
+    }
+
+    fn build_graph(&self) -> Result<Graph, ParseError> {
+        let mut graph = Graph::default();
+
+        let exits = match &self.flow {
+            Some(flow) => self.add_flow(&mut graph, flow, Vec::new()),
+            None => {
+                let task_graph = self.task_graph()?;
+                let mut ids: HashMap<&str, usize> = HashMap::new();
+                for task in &task_graph.order {
+                    let id = self.add_task(&mut graph, task);
+                    for dep in &task_graph.dependencies[task] {
+                        graph.edges.push((ids[dep.as_str()], id, None));
+                    }
+                    ids.insert(task.as_str(), id);
+                }
+                // Tasks nothing else waits for are where the run ends
+                task_graph
+                    .order
+                    .iter()
+                    .filter(|task| !task_graph.dependencies.values().any(|deps| deps.contains(task)))
+                    .map(|task| (ids[task.as_str()], None))
+                    .collect()
+            }
+        };
+
+        if let Some(finally) = &self.finally {
+            let exits = exits
+                .into_iter()
+                .map(|(node, _)| (node, Some("finally".to_string())))
+                .collect();
+            self.add_flow(&mut graph, finally, exits);
+        }
+
+        Ok(graph)
+    }
+
+    fn add_task(&self, graph: &mut Graph, name: &str) -> usize {
+        let (name, annotation) = self.task_annotation(name);
+        let label = match annotation {
+            Some(annotation) => vec![name, annotation],
+            None => vec![name],
+        };
+        graph.add_node(label, Shape::Task)
+    }
+
+    // Add `flow` after the `prev` exits and return the flow's own exits
+    fn add_flow(&self, graph: &mut Graph, flow: &Flow, prev: Vec<Exit>) -> Vec<Exit> {
+        match flow {
+            Flow::Sequential { items } => items
+                .iter()
+                .fold(prev, |prev, item| self.add_item(graph, item, prev)),
+            Flow::Parallel { items } => items
+                .iter()
+                .flat_map(|item| self.add_item(graph, item, prev.clone()))
+                .collect(),
+            Flow::Conditional {
+                condition,
+                if_true,
+                if_false,
+            } => {
+                let decision = graph.add_node(vec![condition_to_string(condition)], Shape::Decision);
+                graph.connect(&prev, decision);
+
+                let mut exits =
+                    self.add_item(graph, if_true, vec![(decision, Some("true".to_string()))]);
+                let if_false_exits = vec![(decision, Some("false".to_string()))];
+                exits.extend(match if_false {
+                    Some(if_false) => self.add_item(graph, if_false, if_false_exits),
+                    None => if_false_exits,
+                });
+                exits
+            }
+            Flow::ForEach {
+                name,
+                binding,
+                body,
+                parallel,
+                ..
+            } => {
+                let (name, annotation) = self.task_annotation(name);
+                let mut label = vec![format!("for_each {}", name)];
+                label.push(format!("{} in items, {} at a time", binding, parallel));
+                if let Some(annotation) = annotation {
+                    label.push(annotation);
+                }
+                let node = graph.add_node(label, Shape::Loop);
+                graph.connect(&prev, node);
+                self.add_item(graph, body, vec![(node, Some("each".to_string()))])
+            }
+            Flow::Try { body, catch } => {
+                let node = graph.add_node(vec!["try".to_string()], Shape::Decision);
+                graph.connect(&prev, node);
+
+                let mut exits = self.add_flow(graph, body, vec![(node, None)]);
+                exits.extend(self.add_flow(graph, catch, vec![(node, Some("on error".to_string()))]));
+                exits
+            }
+        }
+    }
+
+    fn add_item(&self, graph: &mut Graph, item: &FlowItem, prev: Vec<Exit>) -> Vec<Exit> {
+        match item {
+            // The empty branch of a conditional
+            FlowItem::Task(name) if name == "null" => prev,
+            FlowItem::Task(name) => {
+                let node = self.add_task(graph, name);
+                graph.connect(&prev, node);
+                vec![(node, None)]
+            }
+            FlowItem::Flow(flow) => self.add_flow(graph, flow, prev),
+        }
+    }
+
+    fn render_ascii(&self) -> Result<String, ParseError> {
+        let mut out = String::new();
+
+        match &self.flow {
+            Some(flow) => {
+                writeln!(out, "pipeline {}", self.name).unwrap();
+                let lines = self.ascii_flow(flow);
+                push_children(&mut out, "", lines);
+            }
+            None => {
+                writeln!(out, "pipeline {} (order inferred from data dependencies)", self.name)
+                    .unwrap();
+                let graph = self.task_graph()?;
+                let stages = graph
+                    .stages()
+                    .iter()
+                    .enumerate()
+                    .map(|(number, stage)| {
+                        let tasks = stage
+                            .iter()
+                            .map(|task| {
+                                let mut line = self.ascii_task(task);
+                                let deps = &graph.dependencies[task];
+                                if !deps.is_empty() {
+                                    line.push_str(&format!(" <- {}", deps.join(", ")));
+                                }
+                                AsciiNode::leaf(line)
+                            })
+                            .collect();
+                        AsciiNode::new(format!("stage {}", number + 1), tasks)
+                    })
+                    .collect();
+                push_children(&mut out, "", stages);
+            }
+        }
+
+        if let Some(finally) = &self.finally {
+            writeln!(out, "finally").unwrap();
+            push_children(&mut out, "", self.ascii_flow(finally));
+        }
+
+        Ok(out)
+    }
+
+    // "scan (cmd -> scan_output)"
+    fn ascii_task(&self, name: &str) -> String {
+        match self.task_annotation(name) {
+            (name, Some(annotation)) => format!("{} ({})", name, annotation),
+            (name, None) => name,
+        }
+    }
+
+    // The nodes for a flow, flattening sequences into their parent
+    fn ascii_flow(&self, flow: &Flow) -> Vec<AsciiNode> {
+        match flow {
+            Flow::Sequential { items } => items.iter().flat_map(|item| self.ascii_item(item)).collect(),
+            Flow::Parallel { items } => {
+                let branches = items
+                    .iter()
+                    .map(|item| match self.ascii_item(item).as_slice() {
+                        [single] => single.clone(),
+                        nodes => AsciiNode::new("sequence".to_string(), nodes.to_vec()),
+                    })
+                    .collect();
+                vec![AsciiNode::new("parallel".to_string(), branches)]
+            }
+            Flow::Conditional {
+                condition,
+                if_true,
+                if_false,
+            } => {
+                let mut branches = vec![AsciiNode::new("then".to_string(), self.ascii_item(if_true))];
+                if let Some(if_false) = if_false {
+                    let nodes = self.ascii_item(if_false);
+                    if !nodes.is_empty() {
+                        branches.push(AsciiNode::new("else".to_string(), nodes));
+                    }
+                }
+                vec![AsciiNode::new(format!("if {}", condition_to_string(condition)), branches)]
+            }
+            Flow::ForEach {
+                name,
+                binding,
+                body,
+                parallel,
+                ..
+            } => {
+                let label = format!(
+                    "for_each {}, {} in items, {} at a time",
+                    self.ascii_task(name),
+                    binding,
+                    parallel
+                );
+                vec![AsciiNode::new(label, self.ascii_item(body))]
+            }
+            Flow::Try { body, catch } => vec![
+                AsciiNode::new("try".to_string(), self.ascii_flow(body)),
+                AsciiNode::new("catch".to_string(), self.ascii_flow(catch)),
+            ],
+        }
+    }
+
+    fn ascii_item(&self, item: &FlowItem) -> Vec<AsciiNode> {
+        match item {
+            FlowItem::Task(name) if name == "null" => Vec::new(),
+            FlowItem::Task(name) => vec![AsciiNode::leaf(self.ascii_task(name))],
+            FlowItem::Flow(flow) => self.ascii_flow(flow),
+        }
+    }
+}
+
+// A node and the label of the edge leaving it
+type Exit = (usize, Option<String>);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Shape {
+    Task,
+    Decision,
+    Loop,
+}
+
+#[derive(Debug, Default)]
+struct Graph {
+    // Label lines and shape, indexed by node id
+    nodes: Vec<(Vec<String>, Shape)>,
+    edges: Vec<(usize, usize, Option<String>)>,
+}
+
+impl Graph {
+    fn add_node(&mut self, label: Vec<String>, shape: Shape) -> usize {
+        self.nodes.push((label, shape));
+        self.nodes.len() - 1
+    }
+
+    fn connect(&mut self, prev: &[Exit], node: usize) {
+        for (from, label) in prev {
+            self.edges.push((*from, node, label.clone()));
+        }
+    }
+
+    fn to_dot(&self, name: &str) -> String {
+        let escape = |s: &str| s.replace('\\', "\\\\").replace('"', "\\\"");
+        let mut out = String::new();
+
+        writeln!(out, "digraph \"{}\" {{", escape(name)).unwrap();
+        writeln!(out, "    node [shape=box];").unwrap();
+        for (id, (label, shape)) in self.nodes.iter().enumerate() {
+            let label: Vec<String> = label.iter().map(|line| escape(line)).collect();
+            let shape = match shape {
+                Shape::Task => "",
+                Shape::Decision => ", shape=diamond",
+                Shape::Loop => ", shape=hexagon",
+            };
+            writeln!(out, "    n{} [label=\"{}\"{}];", id, label.join("\\n"), shape).unwrap();
+        }
+        for (from, to, label) in &self.edges {
+            match label {
+                Some(label) => {
+                    writeln!(out, "    n{} -> n{} [label=\"{}\"];", from, to, escape(label)).unwrap()
+                }
+                None => writeln!(out, "    n{} -> n{};", from, to).unwrap(),
+            }
+        }
+        writeln!(out, "}}").unwrap();
+
+        out
+    }
+
+    fn to_mermaid(&self) -> String {
+        let escape = |s: &str| s.replace('"', "#quot;");
+        let mut out = String::new();
+
+        writeln!(out, "flowchart TD").unwrap();
+        for (id, (label, shape)) in self.nodes.iter().enumerate() {
+            let label: Vec<String> = label.iter().map(|line| escape(line)).collect();
+            let label = label.join("<br/>");
+            match shape {
+                Shape::Task => writeln!(out, "    n{}[\"{}\"]", id, label),
+                Shape::Decision => writeln!(out, "    n{}{{\"{}\"}}", id, label),
+                Shape::Loop => writeln!(out, "    n{}{{{{\"{}\"}}}}", id, label),
+            }
+            .unwrap();
+        }
+        for (from, to, label) in &self.edges {
+            match label {
+                Some(label) => writeln!(out, "    n{} -->|\"{}\"| n{}", from, escape(label), to),
+                None => writeln!(out, "    n{} --> n{}", from, to),
+            }
+            .unwrap();
+        }
+
+        out
+    }
+}
+
+#[derive(Debug, Clone)]
+struct AsciiNode {
+    label: String,
+    children: Vec<AsciiNode>,
+}
+
+impl AsciiNode {
+    fn new(label: String, children: Vec<AsciiNode>) -> Self {
+        AsciiNode { label, children }
+    }
+
+    fn leaf(label: String) -> Self {
+        AsciiNode::new(label, Vec::new())
+    }
+}
+
+// Draw nodes as a tree in the style of `tree --charset=ascii`
+fn push_children(out: &mut String, prefix: &str, children: Vec<AsciiNode>) {
+    let count = children.len();
+    for (index, child) in children.into_iter().enumerate() {
+        let last = index + 1 == count;
+        let (branch, indent) = if last { ("`-- ", "    ") } else { ("|-- ", "|   ") };
+        writeln!(out, "{}{}{}", prefix, branch, child.label).unwrap();
+        push_children(out, &format!("{}{}", prefix, indent), child.children);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pipeline() -> Pipeline {
+        Pipeline::parse(
+            r#"
+            pipeline recon {
+                whois = cmd("whois #{TARGET}", output="whois_output")
+                ports = cmd("nmap #{TARGET}", output="ports")
+                report = llm(prompt="Summarize #{whois_output}", output="report")
+                flow: [whois, ports] > (#{deep} == "true" ? report)
+            }
+            "#,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn renders_ascii_tree() {
+        let ascii = pipeline().render_graph(GraphFormat::Ascii).unwrap();
+        assert_eq!(
+            ascii,
+            "pipeline recon\n\
+             |-- parallel\n\
+             |   |-- whois (cmd -> whois_output)\n\
+             |   `-- ports (cmd -> ports)\n\
+             `-- if #{deep} == \"true\"\n    \
+                 `-- then\n        \
+                     `-- report (llm -> report)\n"
+        );
+    }
+
+    #[test]
+    fn renders_dot_and_mermaid_edges() {
+        let dot = pipeline().render_graph(GraphFormat::Dot).unwrap();
+        assert!(dot.contains("n0 [label=\"whois\\ncmd -> whois_output\"];"));
+        assert!(dot.contains("n2 [label=\"#{deep} == \\\"true\\\"\", shape=diamond];"));
+        assert!(dot.contains("n0 -> n2;"));
+        assert!(dot.contains("n1 -> n2;"));
+        assert!(dot.contains("n2 -> n3 [label=\"true\"];"));
+
+        let mermaid = pipeline().render_graph(GraphFormat::Mermaid).unwrap();
+        assert!(mermaid.starts_with("flowchart TD\n"));
+        assert!(mermaid.contains("    n2{\"#{deep} == #quot;true#quot;\"}"));
+        assert!(mermaid.contains("    n2 -->|\"true\"| n3"));
+    }
+}
@@ -0,0 +1,216 @@
+use crate::parser::{ParseError, Task, Value};
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+/// How a failed task is retried, from its `retries`, `retry_delay`, `backoff`
+/// and `retry_on` arguments.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RetryPolicy {
+    pub retries: u32,
+    pub delay: Duration,
+    pub backoff: Backoff,
+    // Empty means retry on any failure
+    pub retry_on: Vec<RetryCondition>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum Backoff {
+    Constant,
+    Linear,
+    Exponential,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum RetryCondition {
+    Timeout,
+    ExitCode(i32),
+    Error,
+}
+
+/// The longest backoff makes a task wait before retrying, unless its
+/// `retry_delay` is already longer.
+pub const MAX_BACKOFF_DELAY: Duration = Duration::from_secs(5 * 60);
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            retries: 0,
+            delay: Duration::from_secs(1),
+            backoff: Backoff::Constant,
+            retry_on: Vec::new(),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// The delay before retry number `retry`, counting from 1, which backoff
+    /// doesn't grow past MAX_BACKOFF_DELAY.
+    pub fn delay_for(&self, retry: u32) -> Duration {
+        let delay = match self.backoff {
+            Backoff::Constant => self.delay,
+            Backoff::Linear => self.delay.saturating_mul(retry),
+            Backoff::Exponential => self
+                .delay
+                .saturating_mul(2u32.saturating_pow(retry.saturating_sub(1))),
+        };
+        delay.min(MAX_BACKOFF_DELAY.max(self.delay))
+    }
+
+    /// Whether a failure that timed out or exited with `exit_code` should be retried.
+    pub fn should_retry(&self, timed_out: bool, exit_code: Option<i32>) -> bool {
+        if self.retry_on.is_empty() {
+            return true;
+        }
+        self.retry_on.iter().any(|condition| match condition {
+            RetryCondition::Timeout => timed_out,
+            RetryCondition::ExitCode(code) => exit_code == Some(*code),
+            RetryCondition::Error => true,
+        })
+    }
+}
+
+impl Task {
+    /// The task's retry policy, or None if it has no retry arguments.
+    pub fn retry_policy(&self) -> Result<Option<RetryPolicy>, ParseError> {
+        let args = &self.named_arguments;
+        if !["retries", "retry_delay", "backoff", "retry_on"]
+            .iter()
+            .any(|name| args.contains_key(*name))
+        {
+            return Ok(None);
+        }
+
+        let mut policy = RetryPolicy::default();
+
+        if let Some(value) = args.get("retries") {
+            policy.retries = match value {
+                Value::Number(n) if *n >= 0.0 && n.fract() == 0.0 => *n as u32,
+                _ => return Err(invalid("retries", "must be a whole number")),
+            };
+        }
+
+        if let Some(value) = args.get("retry_delay") {
+            policy.delay = match value {
+                Value::String(s) => parse_duration(s).map_err(|e| invalid("retry_delay", &e))?,
+                Value::Number(n) if *n >= 0.0 => Duration::from_secs_f64(*n),
+                _ => return Err(invalid("retry_delay", "must be a duration such as \"5s\"")),
+            };
+        }
+
+        if let Some(value) = args.get("backoff") {
+            policy.backoff = match value {
+                Value::String(s) if s == "constant" => Backoff::Constant,
+                Value::String(s) if s == "linear" => Backoff::Linear,
+                Value::String(s) if s == "exponential" => Backoff::Exponential,
+                _ => {
+                    return Err(invalid(
+                        "backoff",
+                        "must be \"constant\", \"linear\" or \"exponential\"",
+                    ))
+                }
+            };
+        }
+
+        if let Some(value) = args.get("retry_on") {
+            let conditions = match value {
+                Value::Array(items) => items,
+                _ => return Err(invalid("retry_on", "must be an array")),
+            };
+            for condition in conditions {
+                policy.retry_on.push(match condition {
+                    Value::String(s) => parse_retry_condition(s)?,
+                    _ => return Err(invalid("retry_on", "conditions must be strings")),
+                });
+            }
+        }
+
+        Ok(Some(policy))
+    }
+}
+
+fn parse_retry_condition(s: &str) -> Result<RetryCondition, ParseError> {
+    match s {
+        "timeout" => Ok(RetryCondition::Timeout),
+        "error" => Ok(RetryCondition::Error),
+        _ => match s.strip_prefix("exit_code:") {
+            Some(code) => code
+                .trim()
+                .parse()
+                .map(RetryCondition::ExitCode)
+                .map_err(|_| invalid("retry_on", &format!("invalid exit code in '{}'", s))),
+            None => Err(invalid("retry_on", &format!("unknown condition '{}'", s))),
+        },
+    }
+}
+
+/// Parse a duration such as "500ms", "5s", "10m", "24h" or "7d". A bare
+/// number is a number of seconds.
+pub fn parse_duration(s: &str) -> Result<Duration, String> {
+    let s = s.trim();
+    let split = s
+        .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+        .unwrap_or(s.len());
+    let (number, unit) = s.split_at(split);
+
+    let number: f64 = number
+        .parse()
+        .map_err(|_| format!("invalid duration '{}'", s))?;
+    let seconds = match unit.trim() {
+        "" | "s" => number,
+        "ms" => number / 1000.0,
+        "m" => number * 60.0,
+        "h" => number * 60.0 * 60.0,
+        "d" => number * 60.0 * 60.0 * 24.0,
+        unit => return Err(format!("unknown duration unit '{}' in '{}'", unit, s)),
+    };
+
+    Ok(Duration::from_secs_f64(seconds))
+}
+
+fn invalid(field: &str, message: &str) -> ParseError {
+    ParseError::InvalidValue {
+        field: field.to_string(),
+        message: message.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::Pipeline;
+
+    #[test]
+    fn parses_retry_arguments() {
+        let pipeline = Pipeline::parse(
+            r#"
+            pipeline retrying {
+                scan = cmd(command="nmap example.com", retries=3, retry_delay="5s",
+                           backoff="exponential", retry_on=["timeout", "exit_code:1"])
+                flow: scan
+            }
+            "#,
+        )
+        .unwrap();
+
+        let policy = pipeline.tasks["scan"].retry_policy().unwrap().unwrap();
+        assert_eq!(policy.retries, 3);
+        assert_eq!(policy.backoff, Backoff::Exponential);
+        assert_eq!(
+            policy.retry_on,
+            vec![RetryCondition::Timeout, RetryCondition::ExitCode(1)]
+        );
+        assert_eq!(policy.delay_for(1), Duration::from_secs(5));
+        assert_eq!(policy.delay_for(3), Duration::from_secs(20));
+        assert_eq!(policy.delay_for(10), MAX_BACKOFF_DELAY);
+        assert!(policy.should_retry(false, Some(1)));
+        assert!(!policy.should_retry(false, Some(2)));
+    }
+
+    #[test]
+    fn parses_durations() {
+        assert_eq!(parse_duration("500ms").unwrap(), Duration::from_millis(500));
+        assert_eq!(parse_duration("90").unwrap(), Duration::from_secs(90));
+        assert_eq!(parse_duration("24h").unwrap(), Duration::from_secs(86400));
+        assert!(parse_duration("5 parsecs").is_err());
+    }
+}
@@ -0,0 +1,392 @@
+use crate::parser::{ParseError, Pipeline, Task, TaskType, Value};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+// Shell builtins and keywords, which aren't looked up on $PATH
+const SHELL_WORDS: &[&str] = &[
+    ".", ":", "[", "alias", "case", "cd", "do", "done", "echo", "elif", "else", "esac", "eval",
+    "exec", "exit", "export", "false", "fi", "for", "if", "local", "printf", "read", "return",
+    "set", "shift", "source", "test", "then", "trap", "true", "unset", "wait", "while",
+];
+
+// Commands that run the command after them
+const WRAPPERS: &[&str] = &[
+    "command", "env", "exec", "nice", "nohup", "sudo", "time", "timeout", "xargs",
+];
+
+// Shells, which run the command line given to them with -c
+const SHELLS: &[&str] = &["ash", "bash", "dash", "ksh", "sh", "zsh"];
+
+// Keywords a command can follow, as in `if grep -q x file`
+const KEYWORDS: &[&str] = &["do", "elif", "else", "if", "then", "until", "while"];
+
+// Builtins that run code given to them
+const EVALUATORS: &[&str] = &[".", "eval", "exec", "source"];
+
+// Tasks that need each tool, by tool
+type Tools = BTreeMap<String, BTreeSet<String>>;
+
+impl Pipeline {
+    /// The executables the cmd tasks that run where the pipeline runs need,
+    /// each with the tasks that run it. Tasks of imported pipelines are named
+    /// `alias.pipeline.task`.
+    pub fn required_tools(&self) -> Result<Tools, ParseError> {
+        Ok(self.tools_by_agent()?.remove(&None).unwrap_or_default())
+    }
+
+    /// Like `required_tools`, for each place tasks run: None where the
+    /// pipeline runs, and the agent's name for tasks given an `agent` or run
+    /// by a for_each loop with `agents`. Tasks whose agent is interpolated
+    /// are left out, as it is only known once they run.
+    pub fn tools_by_agent(&self) -> Result<BTreeMap<Option<String>, Tools>, ParseError> {
+        let mut tools = BTreeMap::new();
+        self.add_required_tools("", &mut tools)?;
+        Ok(tools)
+    }
+
+    fn add_required_tools(
+        &self,
+        prefix: &str,
+        tools: &mut BTreeMap<Option<String>, Tools>,
+    ) -> Result<(), ParseError> {
+        let loop_agents: HashMap<&str, &[String]> = self
+            .tasks
+            .values()
+            .filter_map(|task| task.for_each_config.as_ref())
+            .filter(|config| !config.agents.is_empty())
+            .map(|config| (config.task.as_str(), config.agents.as_slice()))
+            .collect();
+
+        for (name, task) in &self.tasks {
+            let places = match task.named_arguments.get("agent") {
+                Some(Value::String(agent)) if !agent.contains("#{") => vec![Some(agent.clone())],
+                Some(_) => continue,
+                None => match loop_agents.get(name.as_str()) {
+                    Some(agents) => agents.iter().cloned().map(Some).collect(),
+                    None => vec![None],
+                },
+            };
+            for tool in task.required_tools()? {
+                for place in &places {
+                    tools
+                        .entry(place.clone())
+                        .or_default()
+                        .entry(tool.clone())
+                        .or_default()
+                        .insert(format!("{}{}", prefix, name));
+                }
+            }
+        }
+        for (alias, module) in &self.modules {
+            for (name, pipeline) in &module.pipelines {
+                pipeline.add_required_tools(&format!("{}{}.{}.", prefix, alias, name), tools)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Task {
+    /// The executables a cmd task runs: its `requires` argument if it has
+    /// one, otherwise the commands in its command line that don't depend on
+    /// the run. Empty for other tasks.
+    pub fn required_tools(&self) -> Result<Vec<String>, ParseError> {
+        if self.task_type != TaskType::Cmd {
+            return Ok(Vec::new());
+        }
+
+        let invalid = || ParseError::InvalidValue {
+            field: "requires".to_string(),
+            message: "must be a list of executable names".to_string(),
+        };
+        match self.named_arguments.get("requires") {
+            Some(Value::Array(items)) => items
+                .iter()
+                .map(|item| match item {
+                    Value::String(tool) => Ok(tool.clone()),
+                    _ => Err(invalid()),
+                })
+                .collect(),
+            Some(Value::String(tool)) => Ok(vec![tool.clone()]),
+            Some(_) => Err(invalid()),
+            None => {
+                let command = ["command", "cmd"]
+                    .iter()
+                    .find_map(|name| self.named_arguments.get(*name))
+                    .or_else(|| {
+                        self.arguments
+                            .iter()
+                            .find(|arg| arg.name.is_none())
+                            .map(|arg| &arg.value)
+                    });
+                Ok(match command {
+                    Some(Value::String(command)) => command_tools(command),
+                    _ => Vec::new(),
+                })
+            }
+        }
+    }
+}
+
+/// The executables a shell command line runs, by name. Words that are
+/// interpolated, paths or shell builtins are left out, as they can't be
+/// looked up before the command runs.
+pub fn command_tools(command: &str) -> Vec<String> {
+    let mut tools = Vec::new();
+    for segment in segments(command, true) {
+        // The first word that isn't a variable assignment or a wrapper and
+        // its options, such as `timeout 10`
+        let mut words = words(segment).skip_while(|word| {
+            WRAPPERS.contains(word)
+                || word.starts_with(|c: char| c == '-' || c.is_ascii_digit())
+                || is_assignment(word)
+        });
+        let Some(word) = words.next() else {
+            continue;
+        };
+        if word.contains("#{")
+            || word.contains('$')
+            || word.contains('/')
+            || SHELL_WORDS.contains(&word)
+            || tools.iter().any(|tool| tool == word)
+        {
+            continue;
+        }
+        tools.push(word.to_string());
+    }
+    tools
+}
+
+/// Every program a shell command line starts, for checking against what an
+/// agent allows: wrappers such as `sudo` along with the command they run,
+/// the commands given to `sh -c` and `eval`, and paths and interpolated
+/// words as well as names. Quotes and backslashes are taken out, as the
+/// shell would, so `r''m` is `rm`. Builtins are left out, except those that
+/// run code given to them.
+pub fn command_programs(command: &str) -> Vec<String> {
+    let mut programs: Vec<String> = Vec::new();
+    let mut add = |program: String| {
+        if !programs.contains(&program) {
+            programs.push(program);
+        }
+    };
+    for segment in segments(command, false) {
+        let mut after_wrapper = false;
+        for raw in segment.split_whitespace() {
+            let word = unquote(raw.trim_start_matches(['(', '{', '!']));
+            if word.is_empty() || is_assignment(&word) || KEYWORDS.contains(&word.as_str()) {
+                continue;
+            }
+            // The wrapper's options, such as `timeout 10`
+            if after_wrapper && word.starts_with(|c: char| c == '-' || c.is_ascii_digit()) {
+                continue;
+            }
+            let builtin =
+                SHELL_WORDS.contains(&word.as_str()) && !EVALUATORS.contains(&word.as_str());
+            let name = word.rsplit('/').next().unwrap_or(&word).to_string();
+            if !builtin {
+                add(word);
+            }
+
+            // What follows the word in the segment
+            let rest = &segment[raw.as_ptr() as usize - segment.as_ptr() as usize + raw.len()..];
+            if SHELLS.contains(&name.as_str()) {
+                if let Some(script) = shell_script(rest) {
+                    command_programs(&script).into_iter().for_each(&mut add);
+                }
+                break;
+            }
+            if name == "eval" {
+                command_programs(&unquote(rest)).into_iter().for_each(&mut add);
+                break;
+            }
+            if !WRAPPERS.contains(&name.as_str()) {
+                break;
+            }
+            after_wrapper = true;
+        }
+    }
+    programs
+}
+
+// The command line a shell is given with `-c`, from the words after the
+// shell
+fn shell_script(rest: &str) -> Option<String> {
+    let mut rest = rest.trim_start();
+    loop {
+        let end = rest.find(char::is_whitespace).unwrap_or(rest.len());
+        let (option, after) = rest.split_at(end);
+        if !option.starts_with('-') {
+            return None;
+        }
+        rest = after.trim_start();
+        if !option.starts_with("--") && option.contains('c') {
+            break;
+        }
+    }
+    let script = match rest.chars().next()? {
+        quote @ ('"' | '\'') => rest[1..].split(quote).next().unwrap_or_default(),
+        _ => rest.split_whitespace().next().unwrap_or_default(),
+    };
+    Some(script.to_string())
+}
+
+// A word without the quotes and backslashes the shell takes out
+fn unquote(word: &str) -> String {
+    word.chars().filter(|c| !matches!(c, '"' | '\'' | '\\')).collect()
+}
+
+/// The files a shell command line writes to through redirections and
+/// `tee`, as written. Redirections to other descriptors, such as `2>&1`,
+/// are left out.
+pub fn command_writes(command: &str) -> Vec<String> {
+    let mut files = Vec::new();
+    for segment in segments(command, false) {
+        let words: Vec<&str> = words(segment).collect();
+        let tee = words
+            .iter()
+            .find(|word| !is_assignment(word) && !WRAPPERS.contains(word))
+            .is_some_and(|word| *word == "tee");
+
+        let mut words = words.into_iter();
+        while let Some(word) = words.next() {
+            // `>`, `>>`, `2>` or `&>`, with the file after it or joined to it
+            let redirection = word.find('>').filter(|&at| {
+                let descriptor = &word[..at];
+                descriptor.is_empty() || descriptor == "&" || descriptor.chars().all(|c| c.is_ascii_digit())
+            });
+            match redirection {
+                Some(at) => {
+                    let file = word[at..].trim_start_matches('>');
+                    if file.starts_with('&') {
+                        continue;
+                    }
+                    match file {
+                        "" => files.extend(words.next().map(str::to_string)),
+                        file => files.push(file.to_string()),
+                    }
+                }
+                None if tee && word != "tee" && !word.starts_with('-') => files.push(word.to_string()),
+                None => {}
+            }
+        }
+    }
+    files
+}
+
+// The words of a command, without the quotes around them or the grouping
+// characters before them
+fn words(segment: &str) -> impl Iterator<Item = &str> {
+    segment
+        .split_whitespace()
+        .map(|word| word.trim_start_matches(['(', '{', '!']).trim_matches(['"', '\'']))
+        .filter(|word| !word.is_empty())
+}
+
+// Split a command line into the commands it runs, keeping quoted strings
+// and redirections such as `2>&1` intact. `#{...}` interpolations are kept
+// whole in `templated` commands, as written in a pipeline. Once a command is
+// interpolated they are only text, which the shell splits like the rest
+fn segments(command: &str, templated: bool) -> Vec<&str> {
+    let bytes = command.as_bytes();
+    let mut segments = Vec::new();
+    let mut start = 0;
+    let mut quote = None;
+    let mut interpolations = 0;
+    for (i, c) in command.char_indices() {
+        let separator = match c {
+            _ if quote == Some(c) => {
+                quote = None;
+                false
+            }
+            _ if quote.is_some() => false,
+            '#' if templated && bytes.get(i + 1) == Some(&b'{') => {
+                interpolations += 1;
+                false
+            }
+            '}' if interpolations > 0 => {
+                interpolations -= 1;
+                false
+            }
+            _ if interpolations > 0 => false,
+            '"' | '\'' => {
+                quote = Some(c);
+                false
+            }
+            '|' | ';' | '\n' => true,
+            '&' => !(i > 0 && bytes[i - 1] == b'>') && bytes.get(i + 1) != Some(&b'>'),
+            _ => false,
+        };
+        if separator {
+            segments.push(&command[start..i]);
+            start = i + 1;
+        }
+    }
+    segments.push(&command[start..]);
+    segments
+}
+
+fn is_assignment(word: &str) -> bool {
+    word.split_once('=').is_some_and(|(name, _)| {
+        !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_the_tools_commands_run() {
+        assert_eq!(
+            command_tools("nmap -sV #{target} | tee out.txt && sudo masscan -p80 x; echo done"),
+            ["nmap", "tee", "masscan"]
+        );
+        assert_eq!(
+            command_tools("LANG=C timeout 10 whois example.com 2>&1"),
+            ["whois"]
+        );
+        assert!(command_tools("#{tool} -x; ./scan.sh; $HOME/bin/x; cd out").is_empty());
+        assert_eq!(
+            command_tools("dig #{target || \"a;b\"} | grep 'x|y'"),
+            ["dig", "grep"]
+        );
+
+        assert_eq!(
+            command_programs("sudo timeout 10 nmap x; if /bin/rm -f y; then eval $z; fi; cd out"),
+            ["sudo", "timeout", "nmap", "/bin/rm", "eval", "$z"]
+        );
+        assert_eq!(
+            command_programs(r#"xargs \rm < x; bash -lc "r''m y | curl z"; eval 'ls'"#),
+            ["xargs", "rm", "bash", "curl", "eval", "ls"]
+        );
+        assert_eq!(
+            command_writes("nmap x > out/a.txt 2>&1 | tee -a b.txt /tmp/c; echo >>d 2>/dev/null"),
+            ["out/a.txt", "b.txt", "/tmp/c", "d", "/dev/null"]
+        );
+
+        let pipeline = Pipeline::parse(
+            r#"
+            pipeline tools(target="example.com") {
+                scan = cmd(command="nmap #{target}")
+                dirs = cmd(command="./run-gobuster.sh #{target}", requires=["gobuster"])
+                note = cmd(command="custom-tool #{target}", requires=[])
+                probe = cmd(command="httpx -u #{host}")
+                probe_each = for_each(items=["a", "b"], as="host", task=probe, agents=["alpha", "beta"])
+                sweep = cmd(command="masscan #{target}", agent="beta")
+                flow: scan > dirs > note > probe_each > sweep
+            }
+            "#,
+        )
+        .unwrap();
+        let tools = pipeline.required_tools().unwrap();
+        assert_eq!(tools.keys().collect::<Vec<_>>(), ["gobuster", "nmap"]);
+        assert!(tools["nmap"].contains("scan"));
+
+        let by_agent = pipeline.tools_by_agent().unwrap();
+        assert_eq!(by_agent[&Some("alpha".to_string())].keys().collect::<Vec<_>>(), ["httpx"]);
+        assert_eq!(
+            by_agent[&Some("beta".to_string())].keys().collect::<Vec<_>>(),
+            ["httpx", "masscan"]
+        );
+    }
+}
@@ -0,0 +1,247 @@
+use crate::runner::files_in;
+use anyhow::{anyhow, bail, Context, Result};
+use piper_dsl::{Pipeline, TaskType, Value};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+
+/// A pipeline file and the local files it reads, to run it on an agent.
+/// Paths are relative to the directory the pipeline runs from, and each
+/// distinct content is stored once under its SHA-256.
+#[derive(Debug, Clone, Default)]
+pub struct Bundle {
+    /// Path of the pipeline file
+    pub entry: String,
+    /// SHA-256 of the content of every file, by path
+    pub files: BTreeMap<String, String>,
+    /// Content of the files, by SHA-256
+    pub blobs: BTreeMap<String, Vec<u8>>,
+}
+
+impl Bundle {
+    /// Bundle the pipeline file at `path` with the files it imports, the
+    /// files of its `script(file=...)` and `template(file=...)` tasks, the
+    /// templates of its notify tasks and the files and directories its
+    /// tasks declare as `inputs`. Relative paths in the pipeline are
+    /// taken from `root`, the directory it would run from locally, and every
+    /// file must be below it. Absolute paths are expected to exist on the
+    /// agent and aren't bundled.
+    pub fn collect(path: &Path, root: &Path) -> Result<Bundle> {
+        let root = fs::canonicalize(root)
+            .with_context(|| format!("Failed to resolve {}", root.display()))?;
+        let pipeline = Pipeline::load(path)
+            .with_context(|| format!("Failed to load pipeline {}", path.display()))?;
+
+        let mut bundle = Bundle::default();
+        bundle.entry = bundle.add(&root, path)?;
+        bundle.add_pipeline(&root, &pipeline)?;
+        Ok(bundle)
+    }
+
+    /// Bundle the files and directories at `paths`, such as a task's
+    /// `inputs`, relative to `root`. Absolute paths aren't bundled.
+    pub fn inputs(paths: &[String], root: &Path) -> Result<Bundle> {
+        let root = fs::canonicalize(root)
+            .with_context(|| format!("Failed to resolve {}", root.display()))?;
+        let mut bundle = Bundle::default();
+        for path in paths.iter().filter(|path| !Path::new(path).is_absolute()) {
+            bundle.add(&root, &root.join(path))?;
+        }
+        Ok(bundle)
+    }
+
+    /// Total size of the bundled content in bytes.
+    pub fn size(&self) -> u64 {
+        self.blobs.values().map(|content| content.len() as u64).sum()
+    }
+
+    /// Write the bundled files under `dir`, checking each against its hash.
+    /// Returns the path of the pipeline file.
+    pub fn unpack(&self, dir: &Path) -> Result<PathBuf> {
+        self.unpack_files(dir)?;
+        if !self.files.contains_key(&self.entry) {
+            bail!("The bundle doesn't contain its pipeline {}", self.entry);
+        }
+        Ok(dir.join(checked_path(&self.entry)?))
+    }
+
+    /// Write the bundled files under `dir` like `unpack`, for bundles
+    /// without a pipeline.
+    pub fn unpack_files(&self, dir: &Path) -> Result<()> {
+        for (sha256, content) in &self.blobs {
+            if &format!("{:x}", Sha256::digest(content)) != sha256 {
+                bail!("A bundled file doesn't match its hash {}", sha256);
+            }
+        }
+
+        for (path, sha256) in &self.files {
+            let file = dir.join(checked_path(path)?);
+            let content = self
+                .blobs
+                .get(sha256)
+                .ok_or_else(|| anyhow!("The content of bundled file {} is missing", path))?;
+            if let Some(parent) = file.parent() {
+                fs::create_dir_all(parent)
+                    .with_context(|| format!("Failed to create {}", parent.display()))?;
+            }
+            fs::write(&file, content)
+                .with_context(|| format!("Failed to write {}", file.display()))?;
+        }
+        Ok(())
+    }
+
+    fn add_pipeline(&mut self, root: &Path, pipeline: &Pipeline) -> Result<()> {
+        let mut names: Vec<&String> = pipeline.tasks.keys().collect();
+        names.sort();
+        for name in names {
+            let task = &pipeline.tasks[name];
+            let mut paths = Vec::new();
+            match task.task_type {
+                TaskType::Script | TaskType::Template => {
+                    paths.extend(task.named_arguments.get("file"))
+                }
+                TaskType::Notify => paths.extend(task.named_arguments.get("template")),
+                _ => {}
+            }
+            match task.named_arguments.get("inputs") {
+                Some(Value::Array(items)) => paths.extend(items),
+                Some(value) => paths.push(value),
+                None => {}
+            }
+
+            for value in paths {
+                match value {
+                    Value::String(path) if path.contains("#{") => println!(
+                        "[!] {} of task {} depends on the run and isn't sent to the agent",
+                        path, name
+                    ),
+                    Value::String(path) if Path::new(path).is_absolute() => {}
+                    Value::String(path) => {
+                        self.add(root, &root.join(path))
+                            .with_context(|| format!("Failed to bundle an input of task {}", name))?;
+                    }
+                    _ => bail!("The inputs of task {} must be paths", name),
+                }
+            }
+        }
+
+        for module in pipeline.modules.values() {
+            self.add(root, &module.path)?;
+            for imported in module.pipelines.values() {
+                self.add_pipeline(root, imported)?;
+            }
+        }
+        Ok(())
+    }
+
+    // Add a file, or every file in a directory, returning its bundle path
+    fn add(&mut self, root: &Path, path: &Path) -> Result<String> {
+        let canonical = fs::canonicalize(path)
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+        let relative = canonical.strip_prefix(root).map_err(|_| {
+            anyhow!(
+                "{} is outside {}, only files below it can be sent to an agent",
+                path.display(),
+                root.display()
+            )
+        })?;
+
+        if canonical.is_dir() {
+            for file in files_in(&canonical)? {
+                self.add(root, &canonical.join(file))?;
+            }
+        } else {
+            let content = fs::read(&canonical)
+                .with_context(|| format!("Failed to read {}", path.display()))?;
+            let sha256 = format!("{:x}", Sha256::digest(&content));
+            self.files.insert(bundle_path(relative), sha256.clone());
+            self.blobs.insert(sha256, content);
+        }
+        Ok(bundle_path(relative))
+    }
+}
+
+// Bundle paths always use forward slashes
+fn bundle_path(relative: &Path) -> String {
+    relative
+        .components()
+        .map(|c| c.as_os_str().to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+// Bundles come from the network, so don't let a path climb out of the
+// directory it is unpacked in
+fn checked_path(path: &str) -> Result<&Path> {
+    let path = Path::new(path);
+    if path.as_os_str().is_empty() || !path.components().all(|c| matches!(c, Component::Normal(_))) {
+        bail!("Bundled file {} has an invalid path", path.display());
+    }
+    Ok(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bundles_and_unpacks_pipeline_files() {
+        let root = std::env::temp_dir().join(format!("piper_bundle_{}", std::process::id()));
+        fs::create_dir_all(root.join("pipelines/lib")).unwrap();
+        fs::create_dir_all(root.join("wordlists")).unwrap();
+        fs::write(root.join("wordlists/common.txt"), "admin\n").unwrap();
+        fs::write(root.join("wordlists/copy.txt"), "admin\n").unwrap();
+        fs::write(root.join("parse.lua"), "ctx.parsed = true").unwrap();
+        fs::write(
+            root.join("pipelines/lib/common.piper"),
+            r#"
+            pipeline lookup(target) {
+                whois = cmd(command="whois #{target}")
+                flow: whois
+            }
+            "#,
+        )
+        .unwrap();
+        fs::write(
+            root.join("pipelines/main.piper"),
+            r#"
+            import "lib/common.piper" as common
+            pipeline main(target="example.com") {
+                dirs = cmd(command="gobuster -w wordlists/common.txt", inputs=["wordlists", "/usr/share/wordlists"])
+                parse = script(file="parse.lua")
+                sub = common.lookup(target=target)
+                flow: dirs > parse > sub
+            }
+            "#,
+        )
+        .unwrap();
+
+        let bundle = Bundle::collect(&root.join("pipelines/main.piper"), &root).unwrap();
+        assert_eq!(bundle.entry, "pipelines/main.piper");
+        let paths: Vec<&str> = bundle.files.keys().map(String::as_str).collect();
+        assert_eq!(
+            paths,
+            [
+                "parse.lua",
+                "pipelines/lib/common.piper",
+                "pipelines/main.piper",
+                "wordlists/common.txt",
+                "wordlists/copy.txt",
+            ]
+        );
+        // The two wordlists have the same content
+        assert_eq!(bundle.blobs.len(), 4);
+
+        let workspace = root.join("workspace");
+        let entry = bundle.unpack(&workspace).unwrap();
+        assert_eq!(entry, workspace.join("pipelines/main.piper"));
+        assert_eq!(fs::read_to_string(workspace.join("wordlists/copy.txt")).unwrap(), "admin\n");
+
+        let mut escaping = bundle.clone();
+        escaping.files.insert("../escaped.txt".to_string(), bundle.files["parse.lua"].clone());
+        assert!(escaping.unpack(&workspace).is_err());
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
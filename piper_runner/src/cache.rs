@@ -0,0 +1,183 @@
+use crate::history::unix_millis;
+use anyhow::{Context, Result};
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+use std::time::{Duration, SystemTime};
+
+const SCHEMA: &str = r#"
+    CREATE TABLE IF NOT EXISTS task_cache (
+        key TEXT PRIMARY KEY,
+        task TEXT NOT NULL,
+        task_type TEXT NOT NULL,
+        output TEXT NOT NULL,
+        created_at INTEGER NOT NULL,
+        expires_at INTEGER
+    );
+"#;
+
+/// A cached task result. Times are UTC.
+#[derive(Debug, Clone, Serialize)]
+pub struct CacheEntry {
+    pub key: String,
+    /// Name of the task that stored the result
+    pub task: String,
+    pub task_type: String,
+    pub output: String,
+    pub created_at: String,
+    /// None if the entry never expires
+    pub expires_at: Option<String>,
+}
+
+/// Results of deterministic tasks, kept in a local SQLite database and
+/// shared between runs.
+pub struct TaskCache {
+    conn: Connection,
+}
+
+impl TaskCache {
+    pub fn open(path: &Path) -> Result<Self> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)
+                .with_context(|| format!("Failed to create {}", dir.display()))?;
+        }
+        let conn = Connection::open(path)
+            .with_context(|| format!("Failed to open task cache {}", path.display()))?;
+        TaskCache::with_connection(conn)
+    }
+
+    pub fn open_in_memory() -> Result<Self> {
+        TaskCache::with_connection(Connection::open_in_memory()?)
+    }
+
+    fn with_connection(conn: Connection) -> Result<Self> {
+        conn.execute_batch(SCHEMA)?;
+        Ok(TaskCache { conn })
+    }
+
+    /// The cache key of a task of type `task_type` run with `args`, which
+    /// must already be interpolated.
+    pub fn key(task_type: &str, args: &BTreeMap<String, String>) -> String {
+        let mut hasher = Sha256::new();
+        hasher.update(task_type);
+        for (name, value) in args {
+            // Length prefixes keep ("a", "bc") and ("ab", "c") apart
+            hasher.update((name.len() as u64).to_le_bytes());
+            hasher.update(name);
+            hasher.update((value.len() as u64).to_le_bytes());
+            hasher.update(value);
+        }
+        format!("{:x}", hasher.finalize())
+    }
+
+    /// The unexpired result stored under `key`.
+    pub fn get(&self, key: &str) -> Result<Option<CacheEntry>> {
+        let entry = self
+            .conn
+            .query_row(
+                &format!("{} WHERE key = ?1 AND (expires_at IS NULL OR expires_at > ?2)", ENTRY_QUERY),
+                params![key, unix_millis(SystemTime::now())],
+                cache_entry,
+            )
+            .optional()?;
+        Ok(entry)
+    }
+
+    pub fn put(
+        &self,
+        key: &str,
+        task: &str,
+        task_type: &str,
+        output: &str,
+        ttl: Option<Duration>,
+    ) -> Result<()> {
+        let now = SystemTime::now();
+        self.conn.execute(
+            "INSERT OR REPLACE INTO task_cache (key, task, task_type, output, created_at, expires_at)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            params![
+                key,
+                task,
+                task_type,
+                output,
+                unix_millis(now),
+                ttl.map(|ttl| unix_millis(now + ttl)),
+            ],
+        )?;
+        Ok(())
+    }
+
+    /// Every entry, newest first, including expired ones.
+    pub fn entries(&self) -> Result<Vec<CacheEntry>> {
+        let mut stmt = self
+            .conn
+            .prepare(&format!("{} ORDER BY created_at DESC", ENTRY_QUERY))?;
+        let entries = stmt
+            .query_map([], cache_entry)?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(entries)
+    }
+
+    /// Remove every entry, or only the expired ones, returning how many were
+    /// removed.
+    pub fn clear(&self, expired_only: bool) -> Result<usize> {
+        let removed = if expired_only {
+            self.conn.execute(
+                "DELETE FROM task_cache WHERE expires_at <= ?1",
+                params![unix_millis(SystemTime::now())],
+            )?
+        } else {
+            self.conn.execute("DELETE FROM task_cache", [])?
+        };
+        Ok(removed)
+    }
+}
+
+const ENTRY_QUERY: &str = "
+    SELECT key, task, task_type, output,
+           strftime('%Y-%m-%d %H:%M:%S', created_at / 1000, 'unixepoch'),
+           strftime('%Y-%m-%d %H:%M:%S', expires_at / 1000, 'unixepoch')
+    FROM task_cache";
+
+fn cache_entry(row: &rusqlite::Row) -> rusqlite::Result<CacheEntry> {
+    Ok(CacheEntry {
+        key: row.get(0)?,
+        task: row.get(1)?,
+        task_type: row.get(2)?,
+        output: row.get(3)?,
+        created_at: row.get(4)?,
+        expires_at: row.get(5)?,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stores_and_expires_results() {
+        let cache = TaskCache::open_in_memory().unwrap();
+        let mut args = BTreeMap::new();
+        args.insert("cmd".to_string(), "whois example.com".to_string());
+        let key = TaskCache::key("cmd", &args);
+        assert!(cache.get(&key).unwrap().is_none());
+
+        cache.put(&key, "whois", "cmd", "registrar", None).unwrap();
+        assert_eq!(cache.get(&key).unwrap().unwrap().output, "registrar");
+
+        args.insert("cmd".to_string(), "whois example.org".to_string());
+        let other = TaskCache::key("cmd", &args);
+        assert_ne!(key, other);
+        cache
+            .put(&other, "whois", "cmd", "stale", Some(Duration::ZERO))
+            .unwrap();
+        assert!(cache.get(&other).unwrap().is_none());
+
+        assert_eq!(cache.entries().unwrap().len(), 2);
+        assert_eq!(cache.clear(true).unwrap(), 1);
+        assert_eq!(cache.clear(false).unwrap(), 1);
+    }
+}
@@ -0,0 +1,33 @@
+use crate::policy::Violation;
+use piper_tasks::cmd::Stream;
+use std::time::Duration;
+
+/// Progress of a run as it happens, for callers that follow a run live such
+/// as the remote agent.
+#[derive(Debug, Clone)]
+pub enum RunEvent {
+    TaskStarted {
+        task: String,
+        task_type: String,
+        attempt: u32,
+    },
+    /// A line a command wrote to stdout or stderr
+    LogLine {
+        task: String,
+        stream: Stream,
+        line: String,
+    },
+    TaskFinished {
+        task: String,
+        attempt: u32,
+        duration: Duration,
+        exit_code: Option<i32>,
+        output: Option<String>,
+        error: Option<String>,
+    },
+    /// A task was stopped for going against the run's policy
+    PolicyViolation { task: String, violation: Violation },
+}
+
+/// Receives the events of a run.
+pub type EventSender = tokio::sync::mpsc::UnboundedSender<RunEvent>;
@@ -0,0 +1,501 @@
+use crate::run_log::{Artifact, Attempt, RunLog};
+use anyhow::{Context, Result};
+use rusqlite::{params, Connection, OptionalExtension, Row};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// Applied in order. The schema version is kept in SQLite's user_version, so
+// existing migrations must never change, only be appended to
+const MIGRATIONS: &[&str] = &[r#"
+    CREATE TABLE runs (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        pipeline TEXT NOT NULL,
+        source_path TEXT,
+        source_hash TEXT NOT NULL,
+        parameters TEXT NOT NULL,
+        status TEXT NOT NULL,
+        error TEXT,
+        started_at INTEGER NOT NULL,
+        finished_at INTEGER
+    );
+
+    CREATE TABLE task_runs (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        run_id INTEGER NOT NULL REFERENCES runs(id),
+        task TEXT NOT NULL,
+        attempt INTEGER NOT NULL,
+        status TEXT NOT NULL,
+        started_at INTEGER NOT NULL,
+        duration_ms INTEGER NOT NULL,
+        exit_code INTEGER,
+        output TEXT,
+        error TEXT
+    );
+
+    CREATE INDEX task_runs_run_id ON task_runs(run_id);
+"#, r#"
+    ALTER TABLE runs ADD COLUMN resumed_from INTEGER REFERENCES runs(id);
+    -- JSON snapshot of the context after the last completed task
+    ALTER TABLE runs ADD COLUMN context TEXT;
+
+    CREATE TABLE completed_tasks (
+        run_id INTEGER NOT NULL REFERENCES runs(id),
+        task TEXT NOT NULL,
+        PRIMARY KEY (run_id, task)
+    );
+"#, r#"
+    CREATE TABLE artifacts (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        run_id INTEGER NOT NULL REFERENCES runs(id),
+        task TEXT NOT NULL,
+        path TEXT NOT NULL,
+        -- relative to the task's artifact directory
+        name TEXT NOT NULL,
+        sha256 TEXT NOT NULL,
+        size INTEGER NOT NULL
+    );
+
+    CREATE INDEX artifacts_run_id ON artifacts(run_id);
+"#, r#"
+    -- why the run went ahead with targets outside the project's scope
+    ALTER TABLE runs ADD COLUMN ignored_scope TEXT;
+"#];
+
+/// A run as stored in the history database. Times are UTC.
+#[derive(Debug, Clone, Serialize)]
+pub struct RunRecord {
+    pub id: i64,
+    pub pipeline: String,
+    pub source_path: Option<String>,
+    pub source_hash: String,
+    pub parameters: BTreeMap<String, String>,
+    /// `running` until the run finishes, then one of the `RunStatus` names
+    pub status: String,
+    pub error: Option<String>,
+    pub started_at: String,
+    pub finished_at: Option<String>,
+    pub duration_ms: Option<i64>,
+    pub resumed_from: Option<i64>,
+    /// Why the run was let target hosts outside the project's scope
+    pub ignored_scope: Option<String>,
+}
+
+/// One attempt at a task within a stored run.
+#[derive(Debug, Clone, Serialize)]
+pub struct TaskRecord {
+    pub task: String,
+    pub attempt: u32,
+    pub status: String,
+    pub started_at: String,
+    pub duration_ms: i64,
+    pub exit_code: Option<i32>,
+    pub output: Option<String>,
+    pub error: Option<String>,
+}
+
+/// The tasks a run completed and the context they left behind, from which
+/// the run can be resumed.
+#[derive(Debug, Clone, Default)]
+pub struct Checkpoint {
+    pub completed: Vec<String>,
+    pub context: Option<serde_json::Value>,
+}
+
+/// Run history kept in a local SQLite database. Run artifacts are kept next
+/// to the database, in `runs/<id>/artifacts/<task>/`.
+pub struct History {
+    conn: Connection,
+    dir: PathBuf,
+}
+
+impl History {
+    /// Open the database at `path`, creating it and applying any pending
+    /// migrations.
+    pub fn open(path: &Path) -> Result<Self> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)
+                .with_context(|| format!("Failed to create {}", dir.display()))?;
+        }
+        let conn = Connection::open(path)
+            .with_context(|| format!("Failed to open database {}", path.display()))?;
+        let dir = match path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir.to_path_buf(),
+            _ => PathBuf::from("."),
+        };
+        History::with_connection(conn, dir)
+    }
+
+    /// Open a throwaway database. Artifacts are kept under `dir`.
+    pub fn open_in_memory(dir: &Path) -> Result<Self> {
+        History::with_connection(Connection::open_in_memory()?, dir.to_path_buf())
+    }
+
+    fn with_connection(conn: Connection, dir: PathBuf) -> Result<Self> {
+        let mut history = History { conn, dir };
+        history.migrate()?;
+        Ok(history)
+    }
+
+    fn migrate(&mut self) -> Result<()> {
+        let version: usize = self
+            .conn
+            .pragma_query_value(None, "user_version", |row| row.get(0))?;
+
+        for (index, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+            let tx = self.conn.transaction()?;
+            tx.execute_batch(migration)
+                .with_context(|| format!("Failed to apply database migration {}", index + 1))?;
+            tx.pragma_update(None, "user_version", index + 1)?;
+            tx.commit()?;
+        }
+
+        Ok(())
+    }
+
+    /// Record the start of a run and return its id.
+    pub fn start_run(
+        &self,
+        pipeline: &str,
+        source_path: Option<&Path>,
+        source_hash: &str,
+        parameters: &BTreeMap<String, String>,
+        resumed_from: Option<i64>,
+    ) -> Result<i64> {
+        self.conn.execute(
+            "INSERT INTO runs
+                (pipeline, source_path, source_hash, parameters, status, started_at, resumed_from)
+             VALUES (?1, ?2, ?3, ?4, 'running', ?5, ?6)",
+            params![
+                pipeline,
+                source_path.map(|p| p.display().to_string()),
+                source_hash,
+                serde_json::to_string(parameters)?,
+                unix_millis(SystemTime::now()),
+                resumed_from,
+            ],
+        )?;
+        Ok(self.conn.last_insert_rowid())
+    }
+
+    /// Record why a run was let target hosts outside the project's scope.
+    pub fn record_ignored_scope(&self, run_id: i64, reason: &str) -> Result<()> {
+        self.conn.execute(
+            "UPDATE runs SET ignored_scope = ?1 WHERE id = ?2",
+            params![reason, run_id],
+        )?;
+        Ok(())
+    }
+
+    /// Mark `task` as completed, leaving `context` behind.
+    pub fn record_checkpoint(
+        &self,
+        run_id: i64,
+        task: &str,
+        context: &serde_json::Value,
+    ) -> Result<()> {
+        self.save_checkpoint(
+            run_id,
+            &Checkpoint {
+                completed: vec![task.to_string()],
+                context: Some(context.clone()),
+            },
+        )
+    }
+
+    /// Add the tasks of `checkpoint` to the run's completed tasks and replace
+    /// its context.
+    pub fn save_checkpoint(&self, run_id: i64, checkpoint: &Checkpoint) -> Result<()> {
+        let tx = self.conn.unchecked_transaction()?;
+        for task in &checkpoint.completed {
+            tx.execute(
+                "INSERT OR IGNORE INTO completed_tasks (run_id, task) VALUES (?1, ?2)",
+                params![run_id, task],
+            )?;
+        }
+        if let Some(context) = &checkpoint.context {
+            tx.execute(
+                "UPDATE runs SET context = ?1 WHERE id = ?2",
+                params![serde_json::to_string(context)?, run_id],
+            )?;
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    pub fn checkpoint(&self, run_id: i64) -> Result<Checkpoint> {
+        let mut stmt = self
+            .conn
+            .prepare("SELECT task FROM completed_tasks WHERE run_id = ?1 ORDER BY task")?;
+        let completed = stmt
+            .query_map(params![run_id], |row| row.get(0))?
+            .collect::<rusqlite::Result<_>>()?;
+
+        let context: Option<String> = self
+            .conn
+            .query_row("SELECT context FROM runs WHERE id = ?1", params![run_id], |row| {
+                row.get(0)
+            })
+            .optional()?
+            .flatten();
+        let context = context
+            .map(|context| serde_json::from_str(&context))
+            .transpose()
+            .context("The checkpointed context is not valid JSON")?;
+
+        Ok(Checkpoint { completed, context })
+    }
+
+    pub fn record_attempt(&self, run_id: i64, attempt: &Attempt) -> Result<()> {
+        let status = match attempt.error {
+            Some(_) => "failed",
+            None => "succeeded",
+        };
+        self.conn.execute(
+            "INSERT INTO task_runs
+                (run_id, task, attempt, status, started_at, duration_ms, exit_code, output, error)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+            params![
+                run_id,
+                attempt.task,
+                attempt.number,
+                status,
+                unix_millis(attempt.started_at),
+                attempt.duration.as_millis() as i64,
+                attempt.exit_code,
+                attempt.output,
+                attempt.error,
+            ],
+        )?;
+        Ok(())
+    }
+
+    pub fn finish_run(&self, run_id: i64, log: &RunLog) -> Result<()> {
+        self.conn.execute(
+            "UPDATE runs SET status = ?1, error = ?2, finished_at = ?3 WHERE id = ?4",
+            params![
+                log.status.as_str(),
+                log.error,
+                unix_millis(SystemTime::now()),
+                run_id
+            ],
+        )?;
+        Ok(())
+    }
+
+    /// Where the artifacts of `task` are kept for a run.
+    pub fn artifact_dir(&self, run_id: i64, task: &str) -> PathBuf {
+        self.dir
+            .join("runs")
+            .join(run_id.to_string())
+            .join("artifacts")
+            .join(task)
+    }
+
+    /// Record an artifact that was copied into the run's artifact directory.
+    pub fn record_artifact(&self, run_id: i64, artifact: &Artifact) -> Result<()> {
+        self.conn.execute(
+            "INSERT INTO artifacts (run_id, task, path, name, sha256, size)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            params![
+                run_id,
+                artifact.task,
+                artifact.path,
+                artifact.name,
+                artifact.sha256,
+                artifact.size as i64,
+            ],
+        )?;
+        Ok(())
+    }
+
+    /// The artifacts of a run, in the order they were collected.
+    pub fn artifacts(&self, run_id: i64) -> Result<Vec<Artifact>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT task, path, name, sha256, size FROM artifacts WHERE run_id = ?1 ORDER BY id",
+        )?;
+        let artifacts = stmt
+            .query_map(params![run_id], |row| {
+                let task: String = row.get(0)?;
+                let name: String = row.get(2)?;
+                Ok(Artifact {
+                    file: self.artifact_dir(run_id, &task).join(&name),
+                    task,
+                    path: row.get(1)?,
+                    name,
+                    sha256: row.get(3)?,
+                    size: row.get::<_, i64>(4)? as u64,
+                })
+            })?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(artifacts)
+    }
+
+    /// The most recent runs, newest first.
+    pub fn runs(&self, limit: usize) -> Result<Vec<RunRecord>> {
+        let mut stmt = self
+            .conn
+            .prepare(&format!("{} ORDER BY id DESC LIMIT ?1", RUN_QUERY))?;
+        let runs = stmt
+            .query_map(params![limit as i64], run_record)?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(runs)
+    }
+
+    pub fn run(&self, id: i64) -> Result<Option<RunRecord>> {
+        let run = self
+            .conn
+            .query_row(&format!("{} WHERE id = ?1", RUN_QUERY), params![id], run_record)
+            .optional()?;
+        Ok(run)
+    }
+
+    /// Every task attempt of a run, in the order they finished.
+    pub fn task_runs(&self, run_id: i64) -> Result<Vec<TaskRecord>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT task, attempt, status,
+                    strftime('%Y-%m-%d %H:%M:%S', started_at / 1000, 'unixepoch'),
+                    duration_ms, exit_code, output, error
+             FROM task_runs WHERE run_id = ?1 ORDER BY id",
+        )?;
+        let tasks = stmt
+            .query_map(params![run_id], |row| {
+                Ok(TaskRecord {
+                    task: row.get(0)?,
+                    attempt: row.get(1)?,
+                    status: row.get(2)?,
+                    started_at: row.get(3)?,
+                    duration_ms: row.get(4)?,
+                    exit_code: row.get(5)?,
+                    output: row.get(6)?,
+                    error: row.get(7)?,
+                })
+            })?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(tasks)
+    }
+}
+
+const RUN_QUERY: &str = "
+    SELECT id, pipeline, source_path, source_hash, parameters, status, error,
+           strftime('%Y-%m-%d %H:%M:%S', started_at / 1000, 'unixepoch'),
+           strftime('%Y-%m-%d %H:%M:%S', finished_at / 1000, 'unixepoch'),
+           finished_at - started_at, resumed_from, ignored_scope
+    FROM runs";
+
+fn run_record(row: &Row) -> rusqlite::Result<RunRecord> {
+    let parameters: String = row.get(4)?;
+    Ok(RunRecord {
+        id: row.get(0)?,
+        pipeline: row.get(1)?,
+        source_path: row.get(2)?,
+        source_hash: row.get(3)?,
+        parameters: serde_json::from_str(&parameters).unwrap_or_default(),
+        status: row.get(5)?,
+        error: row.get(6)?,
+        started_at: row.get(7)?,
+        finished_at: row.get(8)?,
+        duration_ms: row.get(9)?,
+        resumed_from: row.get(10)?,
+        ignored_scope: row.get(11)?,
+    })
+}
+
+pub(crate) fn unix_millis(time: SystemTime) -> i64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as i64)
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::run_log::RunStatus;
+    use std::time::Duration;
+
+    #[test]
+    fn records_runs_and_attempts() {
+        let history = History::open_in_memory(Path::new(".")).unwrap();
+        let mut parameters = BTreeMap::new();
+        parameters.insert("TARGET".to_string(), "example.com".to_string());
+
+        let run_id = history
+            .start_run("recon", Some(Path::new("recon.piper")), "abc123", &parameters, None)
+            .unwrap();
+        assert_eq!(history.run(run_id).unwrap().unwrap().status, "running");
+
+        let mut log = RunLog::new("recon");
+        log.record(Attempt {
+            task: "scan".to_string(),
+            number: 1,
+            started_at: SystemTime::now(),
+            duration: Duration::from_millis(1500),
+            exit_code: Some(0),
+            output: Some("80/tcp open".to_string()),
+            error: None,
+        });
+        history.record_attempt(run_id, &log.attempts[0]).unwrap();
+        log.status = RunStatus::PartiallyFailed;
+        history.finish_run(run_id, &log).unwrap();
+
+        let run = history.run(run_id).unwrap().unwrap();
+        assert_eq!(run.status, "partially_failed");
+        assert_eq!(run.parameters["TARGET"], "example.com");
+        assert!(run.finished_at.is_some());
+        assert_eq!(history.runs(10).unwrap().len(), 1);
+
+        let tasks = history.task_runs(run_id).unwrap();
+        assert_eq!(tasks[0].task, "scan");
+        assert_eq!(tasks[0].duration_ms, 1500);
+        assert_eq!(tasks[0].output.as_deref(), Some("80/tcp open"));
+
+        history
+            .record_artifact(
+                run_id,
+                &Artifact {
+                    task: "scan".to_string(),
+                    path: "./results/nmap.txt".to_string(),
+                    name: "nmap.txt".to_string(),
+                    file: PathBuf::from("./results/nmap.txt"),
+                    sha256: "def456".to_string(),
+                    size: 11,
+                },
+            )
+            .unwrap();
+        let artifacts = history.artifacts(run_id).unwrap();
+        assert_eq!(
+            artifacts[0].file,
+            Path::new(".").join(format!("runs/{}/artifacts/scan/nmap.txt", run_id))
+        );
+    }
+
+    #[test]
+    fn keeps_latest_checkpoint() {
+        let history = History::open_in_memory(Path::new(".")).unwrap();
+        let run_id = history
+            .start_run("recon", None, "abc123", &BTreeMap::new(), None)
+            .unwrap();
+
+        history
+            .record_checkpoint(run_id, "whois", &serde_json::json!({"whois_output": "a"}))
+            .unwrap();
+        history
+            .record_checkpoint(run_id, "dns", &serde_json::json!({"whois_output": "a", "records": "b"}))
+            .unwrap();
+
+        let checkpoint = history.checkpoint(run_id).unwrap();
+        assert_eq!(checkpoint.completed, vec!["dns", "whois"]);
+        assert_eq!(checkpoint.context.unwrap()["records"], "b");
+
+        let resumed = history
+            .start_run("recon", None, "abc123", &BTreeMap::new(), Some(run_id))
+            .unwrap();
+        history
+            .save_checkpoint(resumed, &history.checkpoint(run_id).unwrap())
+            .unwrap();
+        assert_eq!(history.checkpoint(resumed).unwrap().completed.len(), 2);
+        assert_eq!(history.run(resumed).unwrap().unwrap().resumed_from, Some(run_id));
+    }
+}
@@ -0,0 +1,171 @@
+use ipnet::IpNet;
+use std::fmt;
+use std::net::{IpAddr, ToSocketAddrs};
+use std::str::FromStr;
+
+/// Hosts given by name, by wildcard such as `*.example.com`, by IP address
+/// or by CIDR range such as `10.0.0.0/8`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HostPattern {
+    Name(String),
+    /// Any name under this suffix, which starts with a dot
+    Wildcard(String),
+    Range(IpNet),
+}
+
+impl FromStr for HostPattern {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim().to_ascii_lowercase();
+        if let Some(suffix) = s.strip_prefix('*') {
+            if !suffix.starts_with('.') || suffix.len() < 2 || suffix[1..].contains('*') {
+                return Err(format!(
+                    "invalid wildcard '{}', expected one like *.example.com",
+                    s
+                ));
+            }
+            return Ok(HostPattern::Wildcard(suffix.to_string()));
+        }
+        if let Ok(range) = s.parse::<IpNet>() {
+            return Ok(HostPattern::Range(range.trunc()));
+        }
+        if let Ok(ip) = s.parse::<IpAddr>() {
+            return Ok(HostPattern::Range(IpNet::from(ip)));
+        }
+        let valid = |c: char| c.is_ascii_alphanumeric() || c == '-' || c == '.' || c == '_';
+        if s.is_empty() || !s.chars().all(valid) {
+            return Err(format!("invalid host '{}'", s));
+        }
+        Ok(HostPattern::Name(s.trim_end_matches('.').to_string()))
+    }
+}
+
+impl fmt::Display for HostPattern {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            HostPattern::Name(name) => write!(f, "{}", name),
+            HostPattern::Wildcard(suffix) => write!(f, "*{}", suffix),
+            HostPattern::Range(range) if range.prefix_len() == range.max_prefix_len() => {
+                write!(f, "{}", range.addr())
+            }
+            HostPattern::Range(range) => write!(f, "{}", range),
+        }
+    }
+}
+
+impl HostPattern {
+    /// Whether the pattern covers `host`, a name or an IP address. Ranges
+    /// cover a name if they cover any of `addresses`, what it resolves to.
+    pub fn matches(&self, host: &str, addresses: &[IpAddr]) -> bool {
+        let host = host.trim_end_matches('.').to_ascii_lowercase();
+        match self {
+            HostPattern::Name(name) => host == *name,
+            HostPattern::Wildcard(suffix) => host.ends_with(suffix.as_str()),
+            HostPattern::Range(range) => match host.parse::<IpAddr>() {
+                Ok(ip) => range.contains(&ip),
+                Err(_) => addresses.iter().any(|ip| range.contains(ip)),
+            },
+        }
+    }
+}
+
+/// Why `check` refused a host.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Refusal {
+    Denied,
+    NotAllowed,
+}
+
+/// Check `host` against `allow`, which allows any host if None, and `deny`,
+/// which wins over it. Names are resolved when either has IP ranges: a name
+/// is denied if any of its addresses is, and allowed by ranges if all of
+/// them are.
+pub fn check(
+    host: &str,
+    allow: Option<&[HostPattern]>,
+    deny: &[HostPattern],
+) -> Result<(), Refusal> {
+    let has_ranges = |patterns: &[HostPattern]| {
+        patterns
+            .iter()
+            .any(|pattern| matches!(pattern, HostPattern::Range(_)))
+    };
+    let addresses = if has_ranges(deny) || allow.is_some_and(has_ranges) {
+        resolve(host)
+    } else {
+        Vec::new()
+    };
+
+    if deny.iter().any(|pattern| pattern.matches(host, &addresses)) {
+        return Err(Refusal::Denied);
+    }
+    let Some(allow) = allow else {
+        return Ok(());
+    };
+    let by_name = allow
+        .iter()
+        .any(|pattern| !matches!(pattern, HostPattern::Range(_)) && pattern.matches(host, &[]));
+    let by_address = !addresses.is_empty()
+        && addresses.iter().all(|address| {
+            let address = address.to_string();
+            allow.iter().any(|pattern| pattern.matches(&address, &[]))
+        });
+    if by_name || by_address {
+        Ok(())
+    } else {
+        Err(Refusal::NotAllowed)
+    }
+}
+
+/// The addresses `host` resolves to, itself if it is an IP address. Empty
+/// if it doesn't resolve.
+pub fn resolve(host: &str) -> Vec<IpAddr> {
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    if let Ok(ip) = host.parse::<IpAddr>() {
+        return vec![ip];
+    }
+    (host, 0)
+        .to_socket_addrs()
+        .map(|addresses| addresses.map(|address| address.ip()).collect())
+        .unwrap_or_default()
+}
+
+/// The host of a URL such as `https://example.com:8443/x`.
+pub fn url_host(url: &str) -> Result<String, String> {
+    let parsed = url::Url::parse(url).map_err(|e| format!("invalid URL '{}': {}", url, e))?;
+    parsed
+        .host_str()
+        .map(|host| {
+            host.trim_start_matches('[')
+                .trim_end_matches(']')
+                .to_string()
+        })
+        .ok_or_else(|| format!("URL '{}' has no host", url))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matches_hosts_by_name_wildcard_and_range() {
+        let pattern = |s: &str| s.parse::<HostPattern>().unwrap();
+        let internal = ["10.1.2.3".parse().unwrap()];
+
+        assert!(pattern("Example.com").matches("example.com.", &[]));
+        assert!(!pattern("example.com").matches("www.example.com", &[]));
+        assert!(pattern("*.example.com").matches("a.b.example.com", &[]));
+        assert!(!pattern("*.example.com").matches("example.com", &[]));
+        assert!(!pattern("*.example.com").matches("badexample.com", &[]));
+        assert!(pattern("10.0.0.0/8").matches("10.9.9.9", &[]));
+        assert!(pattern("10.0.0.0/8").matches("intranet", &internal));
+        assert!(pattern("::1").matches("::1", &[]));
+        assert!(!pattern("169.254.169.254").matches("169.254.169.253", &[]));
+
+        assert!("*".parse::<HostPattern>().is_err());
+        assert!("a b".parse::<HostPattern>().is_err());
+        assert_eq!(pattern("10.1.0.0/8").to_string(), "10.0.0.0/8");
+        assert_eq!(url_host("http://[::1]:8080/x").unwrap(), "::1");
+    }
+}
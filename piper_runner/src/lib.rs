@@ -1 +1,11 @@
 pub mod runner;
+pub mod run_log;
+pub mod events;
+pub mod history;
+pub mod cache;
+pub mod bundle;
+pub mod task_executor;
+pub mod hosts;
+pub mod policy;
+pub mod scope;
+pub mod secrets;
@@ -0,0 +1,344 @@
+use crate::hosts::{self, HostPattern, Refusal};
+use crate::task_executor::TaskRequest;
+use anyhow::{anyhow, Context, Result};
+use piper_dsl::{command_programs, command_writes, TaskType};
+use serde::Deserialize;
+use std::fmt;
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+
+// Shell syntax that runs commands command_programs can't see
+const SUBSTITUTIONS: &[&str] = &["$(", "`", "<(", ">("];
+
+// Always writable, as writing to them goes nowhere
+const STANDARD_FILES: &[&str] = &["/dev/null", "/dev/stdout", "/dev/stderr"];
+
+/// What tasks run by an agent may do, read from its policy file. Anything
+/// is allowed where a list is left out, and deny lists win over allow
+/// lists.
+#[derive(Debug, Clone, Default)]
+pub struct Policy {
+    /// Task types
+    pub tasks: Rules<TaskType>,
+    /// Programs cmd tasks run, by name or path
+    pub programs: Rules<String>,
+    /// Hosts http and notify tasks send requests to
+    pub hosts: Rules<HostPattern>,
+    /// Where commands and artifacts may write, relative to where the run
+    /// runs unless absolute
+    pub writable: Option<Vec<PathBuf>>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Rules<T> {
+    /// Everything is allowed if None
+    pub allow: Option<Vec<T>>,
+    pub deny: Vec<T>,
+}
+
+impl<T> Default for Rules<T> {
+    fn default() -> Self {
+        Rules {
+            allow: None,
+            deny: Vec::new(),
+        }
+    }
+}
+
+/// A task doing something the policy doesn't allow.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Violation {
+    /// The section of the policy file it goes against: tasks, cmd, http or
+    /// paths
+    pub rule: &'static str,
+    pub message: String,
+}
+
+impl std::error::Error for Violation {}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Policy violation: {}", self.message)
+    }
+}
+
+fn violation(rule: &'static str, message: String) -> Result<(), Violation> {
+    Err(Violation { rule, message })
+}
+
+// The policy file as written
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct PolicyFile {
+    tasks: RulesFile,
+    cmd: RulesFile,
+    http: RulesFile,
+    paths: PathsFile,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct RulesFile {
+    allow: Option<Vec<String>>,
+    deny: Vec<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct PathsFile {
+    writable: Option<Vec<PathBuf>>,
+}
+
+impl RulesFile {
+    fn parse<T>(
+        &self,
+        section: &str,
+        parse: impl Fn(&str) -> Result<T, String>,
+    ) -> Result<Rules<T>> {
+        let parse_all = |items: &[String]| {
+            items
+                .iter()
+                .map(|item| parse(item).map_err(|e| anyhow!("[{}]: {}", section, e)))
+                .collect::<Result<Vec<T>>>()
+        };
+        Ok(Rules {
+            allow: self.allow.as_deref().map(parse_all).transpose()?,
+            deny: parse_all(&self.deny)?,
+        })
+    }
+}
+
+impl Policy {
+    /// Read a policy file, such as:
+    ///
+    /// ```toml
+    /// [tasks]
+    /// deny = ["lua", "script"]
+    ///
+    /// [cmd]
+    /// allow = ["nmap", "curl", "/opt/tools/scan.sh"]
+    ///
+    /// [http]
+    /// allow = ["*.example.com", "10.0.0.0/8"]
+    /// deny = ["169.254.169.254"]
+    ///
+    /// [paths]
+    /// writable = ["out", "/tmp/scans"]
+    /// ```
+    pub fn load(path: &Path) -> Result<Policy> {
+        let source = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read policy {}", path.display()))?;
+        Policy::parse(&source).with_context(|| format!("Invalid policy {}", path.display()))
+    }
+
+    pub fn parse(source: &str) -> Result<Policy> {
+        let file: PolicyFile = toml::from_str(source)?;
+        Ok(Policy {
+            tasks: file.tasks.parse("tasks", |s| {
+                TaskType::from_str(s).map_err(|_| format!("unknown task type '{}'", s))
+            })?,
+            programs: file.cmd.parse("cmd", |s| Ok(s.to_string()))?,
+            hosts: file.http.parse("http", str::parse)?,
+            writable: file.paths.writable,
+        })
+    }
+
+    pub fn check_task_type(&self, task_type: &TaskType) -> Result<(), Violation> {
+        let allowed = self
+            .tasks
+            .allow
+            .as_ref()
+            .is_none_or(|allow| allow.contains(task_type));
+        if !allowed || self.tasks.deny.contains(task_type) {
+            return violation(
+                "tasks",
+                format!("{} tasks aren't allowed", task_type.to_string()),
+            );
+        }
+        Ok(())
+    }
+
+    /// Check the programs a shell command line starts and the files it
+    /// redirects to, resolving relative paths against `root`. Commands that
+    /// run others through substitution such as `$(...)` are refused when
+    /// programs are allowed by name, as what they run can't be checked.
+    ///
+    /// Denied programs are caught behind wrappers, quotes, `sh -c` and
+    /// `eval`, but a command can still reach one some other way, such as
+    /// through a copy or an interpreter. Allow lists are what keep commands
+    /// to known programs.
+    pub fn check_command(&self, command: &str, root: &Path) -> Result<(), Violation> {
+        if let Some(allow) = &self.programs.allow {
+            if SUBSTITUTIONS.iter().any(|syntax| command.contains(syntax)) {
+                return violation(
+                    "cmd",
+                    "commands can't run other commands through substitution".to_string(),
+                );
+            }
+            for program in command_programs(command) {
+                if program.contains('$') {
+                    return violation("cmd", format!("{} is only known once it runs", program));
+                }
+                if !allow.contains(&program) {
+                    return violation("cmd", format!("{} isn't an allowed program", program));
+                }
+            }
+        }
+        for program in command_programs(command) {
+            let name = program.rsplit('/').next().unwrap_or(&program);
+            if self
+                .programs
+                .deny
+                .iter()
+                .any(|denied| *denied == program || denied == name)
+            {
+                return violation("cmd", format!("{} is a denied program", program));
+            }
+        }
+        for file in command_writes(command) {
+            self.check_writable(&file, root)?;
+        }
+        Ok(())
+    }
+
+    /// Check that `path`, relative to `root` unless absolute, is under one
+    /// of the writable paths.
+    pub fn check_writable(&self, path: &str, root: &Path) -> Result<(), Violation> {
+        let Some(writable) = &self.writable else {
+            return Ok(());
+        };
+        if STANDARD_FILES.contains(&path) {
+            return Ok(());
+        }
+        let root = std::env::current_dir().map_or(root.to_path_buf(), |dir| dir.join(root));
+        let target = normalize(&root.join(path));
+        if !writable
+            .iter()
+            .any(|dir| target.starts_with(normalize(&root.join(dir))))
+        {
+            return violation("paths", format!("{} isn't a writable path", path));
+        }
+        Ok(())
+    }
+
+    /// Check the host of a URL, as `hosts::check` does.
+    pub fn check_url(&self, url: &str) -> Result<(), Violation> {
+        let host = hosts::url_host(url).map_err(|e| Violation {
+            rule: "http",
+            message: e,
+        })?;
+        match hosts::check(&host, self.hosts.allow.as_deref(), &self.hosts.deny) {
+            Ok(()) => Ok(()),
+            Err(Refusal::Denied) => violation("http", format!("{} is a denied host", host)),
+            Err(Refusal::NotAllowed) => {
+                violation("http", format!("{} isn't an allowed host", host))
+            }
+        }
+    }
+
+    /// Check a single task sent to run under `root`.
+    pub fn check_request(&self, request: &TaskRequest, root: &Path) -> Result<(), Violation> {
+        self.check_task_type(&request.task_type)?;
+        if request.task_type == TaskType::Cmd {
+            let command = request.args.get("cmd").map_or("", String::as_str);
+            self.check_command(command, root)?;
+        }
+        for path in &request.artifacts {
+            self.check_writable(path, root)?;
+        }
+        Ok(())
+    }
+}
+
+// Resolve `.` and `..` without touching the filesystem
+fn normalize(path: &Path) -> PathBuf {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                normalized.pop();
+            }
+            component => normalized.push(component),
+        }
+    }
+    normalized
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn checks_tasks_against_the_policy() {
+        let policy = Policy::parse(
+            r#"
+            [tasks]
+            deny = ["lua"]
+
+            [cmd]
+            allow = ["nmap", "tee", "timeout", "/opt/scan.sh"]
+            deny = ["rm"]
+
+            [http]
+            allow = ["*.example.com", "10.0.0.0/8"]
+            deny = ["10.0.0.1"]
+
+            [paths]
+            writable = ["out", "/tmp/scans"]
+            "#,
+        )
+        .unwrap();
+        let root = Path::new("/work/run");
+
+        assert!(policy.check_task_type(&TaskType::Cmd).is_ok());
+        assert_eq!(
+            policy.check_task_type(&TaskType::Lua).unwrap_err().rule,
+            "tasks"
+        );
+
+        assert!(policy
+            .check_command("timeout 60 nmap x | tee out/nmap.txt", root)
+            .is_ok());
+        assert!(policy
+            .check_command("/opt/scan.sh > /tmp/scans/a 2>&1", root)
+            .is_ok());
+        for command in [
+            "nmap x; curl y",
+            "nmap $(cat hosts)",
+            "$TOOL x",
+            "/usr/bin/nmap x",
+            "nmap x > ../../etc/cron.d/x",
+            "nmap x > out/../../x",
+            "nmap x#{; curl evil; echo }",
+            "nmap #{\ncurl evil\n}",
+        ] {
+            assert!(policy.check_command(command, root).is_err(), "{}", command);
+        }
+        let deny_only = Policy::parse("[cmd]\ndeny = [\"rm\"]").unwrap();
+        for command in [
+            "ls; /bin/rm -rf x",
+            "command rm -rf x",
+            "\\rm x",
+            "r''m x",
+            "find . | xargs rm",
+            "sh -c 'rm x'",
+            "bash -c \"ls; rm x\"",
+            "eval \"rm x\"",
+            "ls #{; rm x; }",
+        ] {
+            assert!(deny_only.check_command(command, root).is_err(), "{}", command);
+        }
+        assert!(deny_only.check_command("sh -c 'ls x'", root).is_ok());
+
+        assert!(policy.check_url("https://api.example.com/x").is_ok());
+        assert!(policy.check_url("http://10.2.3.4:8080/").is_ok());
+        assert!(policy.check_url("http://10.0.0.1/").is_err());
+        assert!(policy.check_url("http://169.254.169.254/latest").is_err());
+        assert!(policy.check_url("not a url").is_err());
+
+        assert!(Policy::parse("[tasks]\nallow = [\"shell\"]").is_err());
+        assert!(Policy::parse("[http]\nallow = [\"*\"]").is_err());
+        assert!(Policy::parse("[cmd]\nallowed = []").is_err());
+    }
+}
@@ -0,0 +1,211 @@
+use serde::Serialize;
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime};
+
+/// One attempt at running a task.
+#[derive(Debug, Clone, Serialize)]
+pub struct Attempt {
+    pub task: String,
+    pub number: u32,
+    pub started_at: SystemTime,
+    pub duration: Duration,
+    pub exit_code: Option<i32>,
+    /// What the task produced, such as a command's stdout
+    pub output: Option<String>,
+    pub error: Option<String>,
+}
+
+/// A task failure the run carried on from, through `continue_on_error`, a
+/// `try`/`catch` block or a for_each loop.
+#[derive(Debug, Clone, Serialize)]
+pub struct Failure {
+    pub task: String,
+    pub error: String,
+}
+
+/// A file a task declared with `artifacts=[...]`.
+#[derive(Debug, Clone, Serialize)]
+pub struct Artifact {
+    pub task: String,
+    /// The path the task wrote, as declared
+    pub path: String,
+    /// Path within the task's artifact directory
+    pub name: String,
+    /// Where the file is kept: a copy in the run's artifact directory if the
+    /// run is recorded, otherwise the file the task wrote
+    pub file: PathBuf,
+    pub sha256: String,
+    pub size: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize)]
+pub enum RunStatus {
+    #[default]
+    Succeeded,
+    /// Finished, but some task failures were tolerated
+    PartiallyFailed,
+    Failed,
+}
+
+impl RunStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            RunStatus::Succeeded => "succeeded",
+            RunStatus::PartiallyFailed => "partially_failed",
+            RunStatus::Failed => "failed",
+        }
+    }
+}
+
+/// Every task attempt made during a run, in the order they finished, and how
+/// the run ended.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct RunLog {
+    pub pipeline: String,
+    /// Id of the run in the history database, if it was recorded
+    pub run_id: Option<i64>,
+    pub status: RunStatus,
+    pub attempts: Vec<Attempt>,
+    pub failures: Vec<Failure>,
+    pub artifacts: Vec<Artifact>,
+    /// Why the run failed
+    pub error: Option<String>,
+}
+
+impl RunLog {
+    pub fn new(pipeline: &str) -> Self {
+        RunLog {
+            pipeline: pipeline.to_string(),
+            ..Default::default()
+        }
+    }
+
+    pub fn record(&mut self, attempt: Attempt) {
+        self.attempts.push(attempt);
+    }
+
+    pub fn record_failure(&mut self, task: &str, error: &anyhow::Error) {
+        self.failures.push(Failure {
+            task: task.to_string(),
+            error: format!("{:#}", error),
+        });
+    }
+
+    pub fn attempts_for<'a>(&'a self, task: &'a str) -> impl Iterator<Item = &'a Attempt> {
+        self.attempts.iter().filter(move |a| a.task == task)
+    }
+
+    /// The run so far, `duration` after it started, for its hooks.
+    /// `failed_task` is the task the run failed at, if it did.
+    pub fn summary(
+        &self,
+        status: &str,
+        duration: Duration,
+        failed_task: Option<&str>,
+    ) -> RunSummary {
+        let artifacts: Vec<String> = self
+            .artifacts
+            .iter()
+            .map(|artifact| artifact.file.display().to_string())
+            .collect();
+
+        let mut text = format!(
+            "Pipeline {} {} after {}",
+            self.pipeline,
+            status.replace('_', " "),
+            format_duration(duration)
+        );
+        if let Some(id) = self.run_id {
+            text.push_str(&format!(" (run {})", id));
+        }
+        // The error names the task the run failed at
+        if let Some(error) = &self.error {
+            text.push_str(&format!("\nError: {}", error));
+        }
+        for failure in &self.failures {
+            text.push_str(&format!("\nTask {} failed: {}", failure.task, failure.error));
+        }
+        if !artifacts.is_empty() {
+            text.push_str(&format!("\nArtifacts:\n  {}", artifacts.join("\n  ")));
+        }
+
+        RunSummary {
+            pipeline: self.pipeline.clone(),
+            id: self.run_id,
+            status: status.to_string(),
+            failed_task: failed_task.map(str::to_string),
+            error: self.error.clone(),
+            failures: self.failures.clone(),
+            duration_secs: duration.as_secs_f64(),
+            duration: format_duration(duration),
+            artifacts,
+            summary: text,
+        }
+    }
+}
+
+/// What a pipeline's hooks are told about its run, as `run`.
+#[derive(Debug, Clone, Serialize)]
+pub struct RunSummary {
+    pub pipeline: String,
+    /// Id of the run in the history database, if it was recorded
+    pub id: Option<i64>,
+    /// `running` for on_start hooks, then how the run ended
+    pub status: String,
+    pub failed_task: Option<String>,
+    pub error: Option<String>,
+    /// Failures the run carried on from
+    pub failures: Vec<Failure>,
+    pub duration_secs: f64,
+    pub duration: String,
+    /// Where the run's artifacts are kept
+    pub artifacts: Vec<String>,
+    /// All of the above as text, for the body of a notification
+    pub summary: String,
+}
+
+/// A duration as "850ms", "12.3s", "4m 05s" or "2h 13m".
+pub fn format_duration(duration: Duration) -> String {
+    let ms = duration.as_millis();
+    let secs = duration.as_secs();
+    match secs {
+        0 => format!("{}ms", ms),
+        1..=59 => format!("{:.1}s", ms as f64 / 1000.0),
+        60..=3599 => format!("{}m {:02}s", secs / 60, secs % 60),
+        _ => format!("{}h {:02}m", secs / 3600, secs % 3600 / 60),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn summarizes_runs_for_hooks() {
+        let mut log = RunLog::new("recon");
+        log.run_id = Some(7);
+        log.error = Some("Task nmap failed: command exited with code 1".to_string());
+        log.record_failure("whois", &anyhow::anyhow!("timed out"));
+        log.artifacts.push(Artifact {
+            task: "nmap".to_string(),
+            path: "scan.xml".to_string(),
+            name: "scan.xml".to_string(),
+            file: PathBuf::from(".piper/artifacts/7/nmap/scan.xml"),
+            sha256: String::new(),
+            size: 0,
+        });
+
+        let summary = log.summary("failed", Duration::from_secs(245), Some("nmap"));
+        assert_eq!(summary.failed_task.as_deref(), Some("nmap"));
+        assert_eq!(summary.duration, "4m 05s");
+        assert_eq!(
+            summary.summary,
+            "Pipeline recon failed after 4m 05s (run 7)\n\
+             Error: Task nmap failed: command exited with code 1\n\
+             Task whois failed: timed out\n\
+             Artifacts:\n  .piper/artifacts/7/nmap/scan.xml"
+        );
+        assert_eq!(format_duration(Duration::from_millis(850)), "850ms");
+        assert_eq!(format_duration(Duration::from_secs(8000)), "2h 13m");
+    }
+}
@@ -1,95 +1,2459 @@
-use anyhow::{Context, Result};
+use crate::bundle::Bundle;
+use crate::cache::TaskCache;
+use crate::events::{EventSender, RunEvent};
+use crate::history::{Checkpoint, History};
+use crate::policy::{Policy, Violation};
+use crate::scope::{OutOfScope, Scope};
+use crate::secrets::{self, Redactor, SecretsFile};
+use crate::run_log::{Artifact, Attempt, RunLog, RunStatus, RunSummary};
+use crate::task_executor::{self, CommandLimits, LocalExecutor, TaskExecutor, TaskRequest};
+use anyhow::{anyhow, bail, Context, Result};
+use notifications::{Route, SmtpConfig};
 use piper_dsl::{
-    Argument, Flow, FlowItem, Parameter, ParseError, Pipeline, PiperParser, Task, TaskType, Value,
+    parse_duration, CachePolicy, ComparisonOperator, Condition, Flow, FlowItem, GraphFormat, LogicalOperator,
+    Module, Pipeline, Task, TaskType, Value,
 };
 use piper_tasks::*;
-use std::collections::HashMap;
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt;
 use std::fs;
 use std::path::{Path, PathBuf};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
 use tokio::runtime::Runtime;
 
-use mlua::{Lua, LuaTable, Value as LuaValue};
+use mlua::{
+    DeserializeOptions, Function as LuaFunction, Lua, LuaSerdeExt, MultiValue as LuaMultiValue,
+    Result as LuaResult, SerializeOptions, Table as LuaTable, Value as LuaValue,
+};
 use regex::Regex;
 
-pub fn run(pipeline_string: String) -> Result<(), Box<dyn std::error::Error>> {
-    let runtime = Runtime::new().unwrap();
+/// Options for `run_from_file_with_options`.
+#[derive(Debug, Clone, Default)]
+pub struct RunOptions {
+    /// Regenerate meta-pipelines even if a generated pipeline exists
+    pub regenerate: bool,
+    /// SQLite database to record the run in
+    pub history: Option<PathBuf>,
+    /// Id of a failed or interrupted run to resume
+    pub resume: Option<i64>,
+    /// Resume even if the pipeline source changed since the run
+    pub force: bool,
+    /// Database of cached task results, None to run every task
+    pub cache: Option<PathBuf>,
+    /// Where to copy artifacts to if the run isn't recorded in `history`.
+    /// Recorded runs keep them next to the database
+    pub artifact_dir: Option<PathBuf>,
+    /// Where to send task events as the run progresses
+    pub events: Option<EventSender>,
+    /// Set to stop the run. Running commands are killed and no more tasks
+    /// are started
+    pub cancel: Option<Arc<AtomicBool>>,
+    /// Directory commands run in and relative paths are resolved against,
+    /// the current directory if None
+    pub working_dir: Option<PathBuf>,
+    /// Where tasks given `agent="name"`, or run by a for_each loop with
+    /// `agents=[...]`, are sent, by agent name
+    pub agents: BTreeMap<String, Arc<dyn TaskExecutor>>,
+    /// Limits put on the commands tasks run here
+    pub command_limits: CommandLimits,
+    /// What tasks are allowed to do, anything if None
+    pub policy: Option<Arc<Policy>>,
+    /// Hosts the run may target, any if None
+    pub scope: Option<Scope>,
+    /// Why the run goes ahead with targets outside `scope`, recorded in
+    /// `history`
+    pub ignore_scope: Option<String>,
+    /// Where `secret("NAME")` values not in the environment are looked up
+    pub secrets: Option<SecretsFile>,
+    /// The server notify tasks send email through, None if they can't
+    pub smtp: Option<SmtpConfig>,
+    /// How long each of the pipeline's hooks may run, `HOOK_TIME_LIMIT` if
+    /// None
+    pub hook_time_limit: Option<Duration>,
+}
+
+/// How long a pipeline's hook may run before its commands are killed. Hooks
+/// run even if the run was cancelled, but not for longer than this.
+pub const HOOK_TIME_LIMIT: Duration = Duration::from_secs(5 * 60);
 
+/// Runs a pipeline from its source. Imports are resolved relative to the
+/// current directory. Failures while running are reported through the
+/// returned log's status; only a pipeline that can't be loaded is an error.
+pub fn run(pipeline_string: String) -> Result<RunLog> {
+    run_with_options(pipeline_string, &RunOptions::default())
+}
+
+/// Like `run`, with options.
+pub fn run_with_options(pipeline_string: String, options: &RunOptions) -> Result<RunLog> {
     // Parse the pipeline using the DSL parser
-    let pipeline = Pipeline::parse(&pipeline_string).context("Failed to parse pipeline")?;
+    let mut pipeline = Pipeline::parse(&pipeline_string).context("Failed to parse pipeline")?;
+    pipeline
+        .resolve_imports(Path::new("."))
+        .context("Failed to resolve imports")?;
+
+    let source = Source {
+        path: None,
+        hash: source_hash(&pipeline_string, &pipeline),
+    };
+    execute(&pipeline, &source, options)
+}
+
+/// Runs a pipeline file, expanding it first if it is a meta-pipeline.
+pub fn run_from_file_with_options(path: PathBuf, options: &RunOptions) -> Result<RunLog> {
+    let mut pipeline = Pipeline::load(&path)
+        .with_context(|| format!("Failed to load pipeline {}", path.display()))?;
+    let source_text = fs::read_to_string(&path)
+        .with_context(|| format!("Failed to read pipeline {}", path.display()))?;
+    let source = Source {
+        hash: source_hash(&source_text, &pipeline),
+        path: Some(path.clone()),
+    };
+
+    if is_meta_pipeline(&pipeline) {
+        let generated = pipeline
+            .generate_pipeline(options.regenerate)
+            .context("Failed to generate pipeline")?;
+        let visualize = pipeline.tasks.values().any(|task| {
+            task.generate_flow_config
+                .as_ref()
+                .is_some_and(|config| config.visualization == Some(true))
+        });
+
+        pipeline = Pipeline::parse(&generated).context("Failed to parse generated pipeline")?;
+        pipeline
+            .resolve_imports(path.parent().unwrap_or(Path::new(".")))
+            .context("Failed to resolve imports")?;
+
+        if visualize {
+            print!("{}", pipeline.render_graph(GraphFormat::Ascii)?);
+        }
+    }
+
+    execute(&pipeline, &source, options)
+}
+
+fn is_meta_pipeline(pipeline: &Pipeline) -> bool {
+    pipeline.tasks.values().any(|task| {
+        matches!(
+            task.task_type,
+            TaskType::MetaTask | TaskType::GenerateTasks | TaskType::GenerateFlow
+        )
+    })
+}
+
+// Fail before any task runs if a cmd task needs an executable that isn't
+// on the $PATH of wherever it runs, naming every missing one
+fn check_tools(pipeline: &Pipeline, executor: &Executor) -> Result<()> {
+    let mut missing = Vec::new();
+    for (agent, tools) in pipeline.tools_by_agent()? {
+        let names: Vec<String> = tools.keys().cloned().collect();
+        let place = agent.as_ref().map(|agent| format!(" on agent {}", agent)).unwrap_or_default();
+        let not_found = executor
+            .task_executor(agent.as_deref())?
+            .missing_tools(&names)
+            .with_context(|| format!("Failed to check the tools{}", place))?;
+        for tool in not_found {
+            let tasks: Vec<&str> = tools.get(&tool).into_iter().flatten().map(String::as_str).collect();
+            missing.push(format!("{}{} (needed by {})", tool, place, tasks.join(", ")));
+        }
+    }
+    if !missing.is_empty() {
+        bail!("Missing tools: {}", missing.join(", "));
+    }
+    Ok(())
+}
+
+// Where a pipeline came from, for the run history
+struct Source {
+    path: Option<PathBuf>,
+    hash: String,
+}
+
+// Hash of the pipeline source and every file it imports, so that a run can
+// be matched to the exact source it ran
+fn source_hash(source: &str, pipeline: &Pipeline) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(source);
+    hash_modules(&mut hasher, &pipeline.modules);
+    format!("{:x}", hasher.finalize())
+}
+
+fn hash_modules(hasher: &mut Sha256, modules: &HashMap<String, Module>) {
+    let mut aliases: Vec<&String> = modules.keys().collect();
+    aliases.sort();
+    for alias in aliases {
+        let module = &modules[alias];
+        hasher.update(alias);
+        hasher.update(fs::read(&module.path).unwrap_or_default());
+        // Every pipeline in a file shares the file's imports
+        if let Some(pipeline) = module.pipelines.values().next() {
+            hash_modules(hasher, &pipeline.modules);
+        }
+    }
+}
+
+fn execute(pipeline: &Pipeline, source: &Source, options: &RunOptions) -> Result<RunLog> {
+    let runtime = Runtime::new()?;
 
     // Create a Lua state for script execution
     let lua = Lua::new();
 
     // Create a context table for variable storage
     let ctx = lua.create_table()?;
-    ctx.set("bar", 1337)?;
 
-    // Create storage for meta-tasks and generated content
-    let mut meta_tasks = HashMap::new();
+    let history = options.history.as_deref().map(History::open).transpose()?;
+    let resume = match options.resume {
+        Some(run_id) => {
+            let history = history
+                .as_ref()
+                .ok_or_else(|| anyhow!("Resuming a run needs the local database"))?;
+            Some((run_id, resume_checkpoint(history, run_id, pipeline, source, options.force)?))
+        }
+        None => None,
+    };
+
+    let mut executor = Executor {
+        lua: &lua,
+        runtime: &runtime,
+        log: Mutex::new(RunLog::new(&pipeline.name)),
+        recorder: None,
+        root: ctx.clone(),
+        finalizing: AtomicBool::new(false),
+        hook_cancel: Mutex::new(None),
+        hook_time_limit: options.hook_time_limit.unwrap_or(HOOK_TIME_LIMIT),
+        resumed_from: None,
+        completed: HashSet::new(),
+        cache: options.cache.as_deref().map(TaskCache::open).transpose()?.map(Mutex::new),
+        artifact_dir: options.artifact_dir.clone(),
+        events: options.events.clone(),
+        cancel: options.cancel.clone(),
+        policy: options.policy.clone(),
+        scope: options.scope.clone(),
+        ignore_scope: options.ignore_scope.clone(),
+        secrets: options.secrets.clone(),
+        resolved_secrets: Mutex::default(),
+        redactor: Redactor::default(),
+        smtp: options.smtp.clone(),
+        working_dir: options.working_dir.clone(),
+        local: LocalExecutor {
+            working_dir: options.working_dir.clone(),
+            limits: options.command_limits,
+        },
+        agents: options.agents.clone(),
+    };
 
-    // Execute each task in the pipeline
+    // Lua's print goes to the console, so it is redacted like command output
+    let redactor = executor.redactor.clone();
+    let tostring: LuaFunction = lua.globals().get("tostring")?;
+    let print = lua.create_function(move |_, values: LuaMultiValue| {
+        let text = values
+            .into_iter()
+            .map(|value| tostring.call::<String>(value))
+            .collect::<LuaResult<Vec<_>>>()?
+            .join("\t");
+        println!("{}", redactor.redact(&text));
+        Ok(())
+    })?;
+    lua.globals().set("print", print)?;
+    check_tools(pipeline, &executor)?;
 
-    for (task_name, task) in pipeline.tasks {
+    executor.bind_parameters(pipeline, &ctx, &HashMap::new())?;
 
-        println!("[+] Running Task: {:?}", task_name);
+    if let Some((run_id, checkpoint)) = &resume {
+        println!(
+            "[+] Resuming run {}, skipping {} completed tasks",
+            run_id,
+            checkpoint.completed.len()
+        );
+        if let Some(context) = &checkpoint.context {
+            executor.restore_context(&ctx, context)?;
+        }
+        executor.resumed_from = Some(*run_id);
+        executor.completed = checkpoint.completed.iter().cloned().collect();
+    }
 
-        if let Some(name) = task_name {
-            println!("    Name: {}", name);
+    if let (Some(history), Some(path)) = (history, &options.history) {
+        let mut parameters = BTreeMap::new();
+        for param in &pipeline.parameters {
+            let value = ctx.get::<LuaValue>(param.name.as_str())?;
+            let value = executor.lua_to_string(&value)?;
+            parameters.insert(param.name.clone(), executor.redactor.redact(&value));
         }
 
-        match task.task_type {
+        let run_id = history.start_run(
+            &pipeline.name,
+            source.path.as_deref(),
+            &source.hash,
+            &parameters,
+            executor.resumed_from,
+        )?;
+        if let Some(reason) = &options.ignore_scope {
+            history.record_ignored_scope(run_id, reason)?;
         }
+        // A resumed run starts from where the old one stopped, so it can in
+        // turn be resumed
+        if let Some((_, checkpoint)) = &resume {
+            history.save_checkpoint(run_id, checkpoint)?;
+        }
+        println!("[+] Recording run {} in {}", run_id, path.display());
+        executor.log.get_mut().unwrap().run_id = Some(run_id);
+        executor.recorder = Some(Recorder {
+            history: Mutex::new(history),
+            run_id,
+        });
     }
 
-    Ok(())
+    println!("[+] Running Pipeline: {}", pipeline.name);
+    let started = Instant::now();
+    let summary = executor.log.lock().unwrap().summary("running", started.elapsed(), None);
+    executor.run_hook(pipeline, "on_start", &pipeline.hooks.on_start, &ctx, &summary);
+    let result = executor.run_body(pipeline, &ctx);
+    let failed_task = result
+        .as_ref()
+        .err()
+        .and_then(|e| e.downcast_ref::<TaskFailed>())
+        .map(|TaskFailed(task)| task.clone());
+
+    let log = executor.log.get_mut().unwrap();
+    for failure in &mut log.failures {
+        failure.error = executor.redactor.redact(&failure.error);
+    }
+    match result {
+        Err(e) => {
+            let error = executor.redactor.redact(&format!("{:#}", e));
+            println!("[!] Pipeline {} failed: {}", pipeline.name, error);
+            log.status = RunStatus::Failed;
+            log.error = Some(error);
+        }
+        Ok(()) if !log.failures.is_empty() => {
+            let tasks: Vec<&str> = log.failures.iter().map(|f| f.task.as_str()).collect();
+            println!(
+                "[!] Pipeline {} finished with failed tasks: {}",
+                pipeline.name,
+                tasks.join(", ")
+            );
+            log.status = RunStatus::PartiallyFailed;
+        }
+        Ok(()) => println!("[+] Pipeline {} finished", pipeline.name),
+    }
+
+    let summary = log.summary(log.status.as_str(), started.elapsed(), failed_task.as_deref());
+    let (event, hook) = match log.status {
+        RunStatus::Failed => ("on_failure", &pipeline.hooks.on_failure),
+        _ => ("on_success", &pipeline.hooks.on_success),
+    };
+    executor.run_hook(pipeline, event, hook, &ctx, &summary);
+
+    let log = executor.log.into_inner().unwrap();
+    if let Some(recorder) = executor.recorder {
+        recorder
+            .history
+            .into_inner()
+            .unwrap()
+            .finish_run(recorder.run_id, &log)
+            .context("Failed to record the run")?;
+    }
+
+    Ok(log)
 }
 
-// Helper function to interpolate variables in a string
-fn interpolate_variables(
-    input: &str,
-    ctx: &LuaTable,
-) -> Result<String, Box<dyn std::error::Error>> {
-    let mut result = input.to_string();
+// Checks that `run_id` can be resumed with this pipeline source and returns
+// where it stopped
+fn resume_checkpoint(
+    history: &History,
+    run_id: i64,
+    pipeline: &Pipeline,
+    source: &Source,
+    force: bool,
+) -> Result<Checkpoint> {
+    let run = history
+        .run(run_id)?
+        .ok_or_else(|| anyhow!("No run with id {}", run_id))?;
+
+    if run.pipeline != pipeline.name {
+        bail!("Run {} ran pipeline {}, not {}", run_id, run.pipeline, pipeline.name);
+    }
+    if run.status == RunStatus::Succeeded.as_str() {
+        bail!("Run {} succeeded, there is nothing to resume", run_id);
+    }
+    if run.source_hash != source.hash {
+        if !force {
+            bail!(
+                "The source of {} changed since run {}, use --force to resume it anyway",
+                pipeline.name,
+                run_id
+            );
+        }
+        println!(
+            "[!] The source of {} changed since run {}, resuming anyway",
+            pipeline.name, run_id
+        );
+    }
+
+    history.checkpoint(run_id)
+}
+
+// Executes pipelines against a shared Lua state. Contexts are Lua tables so
+// that script tasks can read and write them directly.
+struct Executor<'a> {
+    lua: &'a Lua,
+    runtime: &'a Runtime,
+    log: Mutex<RunLog>,
+    recorder: Option<Recorder>,
+    // Context of the top-level pipeline, whose tasks are checkpointed
+    root: LuaTable,
+    // Set while the top-level finally flow runs, which always runs in full
+    finalizing: AtomicBool,
+    // What stops the commands of the pipeline's hooks while they run. They
+    // go ahead even if the run was cancelled, so a run stopped for going on
+    // too long is still reported, but only for `hook_time_limit`
+    hook_cancel: Mutex<Option<Arc<AtomicBool>>>,
+    hook_time_limit: Duration,
+    resumed_from: Option<i64>,
+    // Tasks completed by the resumed run
+    completed: HashSet<String>,
+    cache: Option<Mutex<TaskCache>>,
+    artifact_dir: Option<PathBuf>,
+    events: Option<EventSender>,
+    cancel: Option<Arc<AtomicBool>>,
+    policy: Option<Arc<Policy>>,
+    scope: Option<Scope>,
+    ignore_scope: Option<String>,
+    secrets: Option<SecretsFile>,
+    // Secrets looked up so far, by name
+    resolved_secrets: Mutex<HashMap<String, String>>,
+    // Keeps the secrets out of what the run prints and records
+    redactor: Redactor,
+    smtp: Option<SmtpConfig>,
+    working_dir: Option<PathBuf>,
+    local: LocalExecutor,
+    agents: BTreeMap<String, Arc<dyn TaskExecutor>>,
+}
+
+// Writes task attempts to the run history as they happen
+struct Recorder {
+    history: Mutex<History>,
+    run_id: i64,
+}
+
+// Context added to a task's error so that a failure caught further up the
+// flow can still be put down to the task
+#[derive(Debug)]
+struct TaskFailed(String);
+
+impl fmt::Display for TaskFailed {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Task {} failed", self.0)
+    }
+}
+
+// A for_each loop from the flow
+struct ForEachLoop<'f> {
+    name: &'f str,
+    binding: &'f str,
+    body: &'f FlowItem,
+    parallel: usize,
+    continue_on_error: bool,
+    // Agents the items are handed to in turn
+    agents: &'f [String],
+}
+
+impl Executor<'_> {
+    fn run_pipeline(
+        &self,
+        pipeline: &Pipeline,
+        ctx: &LuaTable,
+        args: &HashMap<String, LuaValue>,
+    ) -> Result<()> {
+        self.bind_parameters(pipeline, ctx, args)?;
+        self.run_body(pipeline, ctx)
+    }
+
+    // Bind parameters, falling back to their defaults
+    fn bind_parameters(
+        &self,
+        pipeline: &Pipeline,
+        ctx: &LuaTable,
+        args: &HashMap<String, LuaValue>,
+    ) -> Result<()> {
+        for param in &pipeline.parameters {
+            let value = match (args.get(&param.name), &param.default_value) {
+                (Some(value), _) => value.clone(),
+                (None, Some(default)) => self.eval(default, ctx)?,
+                (None, None) => bail!(
+                    "Missing required parameter '{}' for pipeline {}",
+                    param.name,
+                    pipeline.name
+                ),
+            };
+            self.check_scope(&param.name, &value, &format!("parameter {}", param.name))?;
+            ctx.set(param.name.as_str(), value)?;
+        }
+        Ok(())
+    }
+
+    // Run a pipeline whose parameters are bound
+    fn run_body(&self, pipeline: &Pipeline, ctx: &LuaTable) -> Result<()> {
+        self.bind_data_literals(pipeline, ctx)?;
+
+        let result = match &pipeline.flow {
+            Some(flow) => self.run_flow(pipeline, flow, ctx),
+            None => self.run_graph(pipeline, ctx),
+        };
+
+        // The finally flow runs whether or not the main flow succeeded
+        let finally = match &pipeline.finally {
+            Some(finally) => finally,
+            None => return result,
+        };
+        if let Err(e) = &result {
+            self.set_error(ctx, e)?;
+        }
+        if ctx == &self.root {
+            self.finalizing.store(true, Ordering::SeqCst);
+        }
+        println!("[+] Running finally flow of {}", pipeline.name);
+        match (result, self.run_flow(pipeline, finally, ctx)) {
+            (Err(e), Err(finally_error)) => {
+                println!(
+                    "[!] Finally flow of {} failed: {}",
+                    pipeline.name,
+                    self.describe(&finally_error)
+                );
+                Err(e)
+            }
+            (result, finally_result) => result.and(finally_result),
+        }
+    }
+
+    // Run a pipeline without a flow, starting each task as soon as the tasks
+    // whose outputs it uses have finished
+    fn run_graph(&self, pipeline: &Pipeline, ctx: &LuaTable) -> Result<()> {
+        let graph = pipeline.task_graph()?;
+        println!("[+] No flow given, running tasks in dependency order:");
+        for (number, stage) in graph.stages().iter().enumerate() {
+            println!("    {}: {}", number + 1, stage.join(", "));
+        }
+
+        let mut pending: Vec<&str> = graph.order.iter().map(String::as_str).collect();
+        let mut finished: HashSet<&str> = HashSet::new();
+        let mut running = 0;
+        let mut error = None;
+
+        std::thread::scope(|scope| {
+            let (sender, receiver) = mpsc::channel();
+            loop {
+                // Don't start anything new once a task has failed
+                if error.is_none() {
+                    let (ready, blocked): (Vec<&str>, Vec<&str>) =
+                        pending.drain(..).partition(|name| {
+                            graph.dependencies[*name]
+                                .iter()
+                                .all(|dep| finished.contains(dep.as_str()))
+                        });
+                    pending = blocked;
+
+                    for name in ready {
+                        let sender = sender.clone();
+                        running += 1;
+                        scope.spawn(move || {
+                            let item = FlowItem::Task(name.to_string());
+                            let result = panic::catch_unwind(AssertUnwindSafe(|| {
+                                self.run_flow_item(pipeline, &item, ctx)
+                            }))
+                            .unwrap_or_else(|_| Err(anyhow!("Task {} panicked", name)));
+                            let _ = sender.send((name, result));
+                        });
+                    }
+                }
+
+                if running == 0 {
+                    break;
+                }
+                let (name, result) = receiver.recv().expect("a running task hung up");
+                running -= 1;
+                match result {
+                    Ok(()) => {
+                        finished.insert(name);
+                    }
+                    Err(e) => {
+                        error.get_or_insert(e);
+                    }
+                }
+            }
+        });
+
+        match error {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+
+    // Data literals may refer to each other, so bind them in dependency order
+    fn bind_data_literals(&self, pipeline: &Pipeline, ctx: &LuaTable) -> Result<()> {
+        let mut pending: Vec<(&String, &Value)> = pipeline.data_literals.iter().collect();
+
+        while !pending.is_empty() {
+            let pending_names: HashSet<&str> = pending.iter().map(|(n, _)| n.as_str()).collect();
+            let (ready, blocked): (Vec<_>, Vec<_>) = pending.into_iter().partition(|(name, value)| {
+                value
+                    .references()
+                    .iter()
+                    .all(|r| r == *name || !pending_names.contains(r.as_str()))
+            });
+
+            if ready.is_empty() {
+                let names: Vec<&str> = blocked.iter().map(|(n, _)| n.as_str()).collect();
+                bail!("Data literals reference each other cyclically: {}", names.join(", "));
+            }
+
+            for (name, value) in ready {
+                ctx.set(name.as_str(), self.eval(value, ctx)?)?;
+            }
+            pending = blocked;
+        }
+
+        Ok(())
+    }
+
+    fn run_flow(&self, pipeline: &Pipeline, flow: &Flow, ctx: &LuaTable) -> Result<()> {
+        match flow {
+            Flow::Sequential { items } => {
+                for item in items {
+                    self.run_flow_item(pipeline, item, ctx)?;
+                }
+                Ok(())
+            }
+            Flow::Parallel { items } => std::thread::scope(|scope| {
+                let handles: Vec<_> = items
+                    .iter()
+                    .map(|item| scope.spawn(move || self.run_flow_item(pipeline, item, ctx)))
+                    .collect();
+
+                // Wait for every branch before reporting the first error
+                let results: Vec<Result<()>> = handles
+                    .into_iter()
+                    .map(|handle| {
+                        handle
+                            .join()
+                            .unwrap_or_else(|_| Err(anyhow!("Parallel task panicked")))
+                    })
+                    .collect();
+                results.into_iter().collect()
+            }),
+            Flow::Conditional {
+                condition,
+                if_true,
+                if_false,
+            } => {
+                if self.eval_condition(condition, ctx)? {
+                    self.run_flow_item(pipeline, if_true, ctx)
+                } else if let Some(if_false) = if_false {
+                    self.run_flow_item(pipeline, if_false, ctx)
+                } else {
+                    Ok(())
+                }
+            }
+            Flow::ForEach {
+                name,
+                items,
+                binding,
+                body,
+                parallel,
+                continue_on_error,
+                agents,
+            } => {
+                if self.skip_completed(name, ctx) {
+                    return Ok(());
+                }
+                let items = self.loop_items(items, ctx)?;
+                let for_each = ForEachLoop {
+                    name,
+                    binding,
+                    body,
+                    parallel: *parallel,
+                    continue_on_error: *continue_on_error,
+                    agents,
+                };
+                self.run_for_each(pipeline, &for_each, items, ctx)?;
+                self.checkpoint(name, None, ctx);
+                Ok(())
+            }
+            Flow::Try { body, catch } => match self.run_flow(pipeline, body, ctx) {
+                Ok(()) => Ok(()),
+                Err(e) => {
+                    println!("[!] {}. Running catch flow", self.describe(&e));
+                    self.set_error(ctx, &e)?;
+                    self.record_failure(&e);
+                    self.run_flow(pipeline, catch, ctx)
+                }
+            },
+        }
+    }
+
+    // Expose a failure to the flows that handle it as #{error}
+    fn set_error(&self, ctx: &LuaTable, error: &anyhow::Error) -> Result<()> {
+        ctx.set("error", format!("{:#}", error))?;
+        Ok(())
+    }
+
+    fn record_failure(&self, error: &anyhow::Error) {
+        let task = match error.downcast_ref::<TaskFailed>() {
+            Some(TaskFailed(task)) => task.as_str(),
+            None => "unknown",
+        };
+        self.log.lock().unwrap().record_failure(task, error);
+    }
+
+    // Items to loop over. Strings, like the output of a cmd task, are split
+    // into lines
+    fn loop_items(&self, items: &Value, ctx: &LuaTable) -> Result<Vec<LuaValue>> {
+        Ok(match self.eval(items, ctx)? {
+            LuaValue::Table(table) => table.sequence_values::<LuaValue>().collect::<mlua::Result<_>>()?,
+            LuaValue::String(s) => s
+                .to_str()?
+                .lines()
+                .map(str::trim)
+                .filter(|line| !line.is_empty())
+                .map(|line| Ok(LuaValue::String(self.lua.create_string(line)?)))
+                .collect::<Result<_>>()?,
+            LuaValue::Nil => Vec::new(),
+            item => vec![item],
+        })
+    }
+
+    fn run_for_each(
+        &self,
+        pipeline: &Pipeline,
+        for_each: &ForEachLoop,
+        items: Vec<LuaValue>,
+        ctx: &LuaTable,
+    ) -> Result<()> {
+        println!(
+            "[+] Running {} over {} items ({} at a time)",
+            for_each.name,
+            items.len(),
+            for_each.parallel
+        );
+        if !for_each.agents.is_empty() {
+            println!("    Handing items to agents {}", for_each.agents.join(", "));
+        }
+
+        let next = AtomicUsize::new(0);
+        let failed = AtomicBool::new(false);
+        let results: Mutex<Vec<Option<Result<LuaValue>>>> =
+            Mutex::new(items.iter().map(|_| None).collect());
+
+        std::thread::scope(|scope| {
+            for _ in 0..for_each.parallel.min(items.len()) {
+                scope.spawn(|| loop {
+                    // Stop handing out items after a failure unless told otherwise
+                    if failed.load(Ordering::SeqCst) && !for_each.continue_on_error {
+                        break;
+                    }
+                    let index = next.fetch_add(1, Ordering::SeqCst);
+                    let item = match items.get(index) {
+                        Some(item) => item.clone(),
+                        None => break,
+                    };
+
+                    let result = self.run_iteration(pipeline, for_each, index, item, ctx);
+                    if result.is_err() {
+                        failed.store(true, Ordering::SeqCst);
+                    }
+                    results.lock().unwrap()[index] = Some(result);
+                });
+            }
+        });
+
+        let mut outputs = Vec::new();
+        for (index, result) in results.into_inner().unwrap().into_iter().enumerate() {
+            match result {
+                Some(Ok(output)) => outputs.push(output),
+                Some(Err(e)) if for_each.continue_on_error => {
+                    println!(
+                        "[!] {} item {} failed: {}",
+                        for_each.name,
+                        index,
+                        self.describe(&e)
+                    );
+                    self.log
+                        .lock()
+                        .unwrap()
+                        .record_failure(&format!("{}[{}]", for_each.name, index), &e);
+                }
+                Some(Err(e)) => {
+                    return Err(e.context(format!("{} item {} failed", for_each.name, index)))
+                }
+                // Skipped after an earlier failure
+                None => {}
+            }
+        }
+
+        let outputs = LuaValue::Table(self.lua.create_sequence_from(outputs)?);
+        ctx.set(for_each.name, outputs.clone())?;
+        if let Some(task) = pipeline.tasks.get(for_each.name) {
+            self.store_output(task, ctx, outputs)?;
+        }
+
+        Ok(())
+    }
+
+    // Run the loop body for one item in its own context so that parallel
+    // iterations don't overwrite each other's outputs. Items go to the
+    // loop's agents in turn
+    fn run_iteration(
+        &self,
+        pipeline: &Pipeline,
+        for_each: &ForEachLoop,
+        index: usize,
+        item: LuaValue,
+        ctx: &LuaTable,
+    ) -> Result<LuaValue> {
+        let iteration_ctx = self.child_context(ctx)?;
+        let found_in = format!("loop variable {}", for_each.binding);
+        self.check_scope(for_each.binding, &item, &found_in)?;
+        iteration_ctx.set(for_each.binding, item)?;
+
+        let agent = match for_each.agents.len() {
+            0 => None,
+            count => Some(for_each.agents[index % count].as_str()),
+        };
+        self.run_flow_item_on(pipeline, for_each.body, &iteration_ctx, agent)?;
+
+        // Collect the body's declared output, the namespace of a sub-pipeline,
+        // or everything the iteration set
+        let output = match for_each.body {
+            FlowItem::Task(task_name) => {
+                match pipeline.tasks.get(task_name).and_then(|t| t.named_arguments.get("output")) {
+                    Some(output) => {
+                        let output_name = self.eval_string(output, &iteration_ctx)?;
+                        iteration_ctx.raw_get::<LuaValue>(output_name)?
+                    }
+                    None => iteration_ctx.raw_get::<LuaValue>(task_name.as_str())?,
+                }
+            }
+            FlowItem::Flow(_) => LuaValue::Nil,
+        };
+
+        Ok(match output {
+            LuaValue::Nil => LuaValue::Table(iteration_ctx),
+            output => output,
+        })
+    }
+
+    // A context that reads through to `parent` but keeps its own writes
+    fn child_context(&self, parent: &LuaTable) -> Result<LuaTable> {
+        let child = self.lua.create_table()?;
+        let metatable = self.lua.create_table()?;
+        metatable.set("__index", parent.clone())?;
+        child.set_metatable(Some(metatable));
+        Ok(child)
+    }
+
+    fn run_flow_item(&self, pipeline: &Pipeline, item: &FlowItem, ctx: &LuaTable) -> Result<()> {
+        self.run_flow_item_on(pipeline, item, ctx, None)
+    }
+
+    // Run a flow item, sending its task to `agent` unless it names its own
+    fn run_flow_item_on(
+        &self,
+        pipeline: &Pipeline,
+        item: &FlowItem,
+        ctx: &LuaTable,
+        agent: Option<&str>,
+    ) -> Result<()> {
+        match item {
+            // `null` is used as the empty branch of a conditional
+            FlowItem::Task(name) if name == "null" => Ok(()),
+            FlowItem::Task(name) => {
+                let task = pipeline
+                    .tasks
+                    .get(name)
+                    .ok_or_else(|| anyhow!("Unknown task in flow: {}", name))?;
+                if self.skip_completed(name, ctx) {
+                    return Ok(());
+                }
+                match self.run_task_with_retries(pipeline, name, task, ctx, agent) {
+                    Ok(()) => {
+                        self.checkpoint(name, Some(task), ctx);
+                        Ok(())
+                    }
+                    Err(e) => self.handle_failure(pipeline, name, task, ctx, e),
+                }
+            }
+            FlowItem::Flow(flow) => self.run_flow(pipeline, flow, ctx),
+        }
+    }
+
+    // Only tasks of the top-level pipeline are checkpointed, as the contexts
+    // of sub-pipelines and loop iterations are rebuilt when they run
+    fn is_checkpointed(&self, ctx: &LuaTable) -> bool {
+        ctx == &self.root && !self.finalizing.load(Ordering::SeqCst)
+    }
+
+    fn skip_completed(&self, name: &str, ctx: &LuaTable) -> bool {
+        if !self.is_checkpointed(ctx) || !self.completed.contains(name) {
+            return false;
+        }
+        println!(
+            "[+] Skipping task {}, it completed in run {}",
+            name,
+            self.resumed_from.unwrap_or_default()
+        );
+        true
+    }
+
+    // Save the context after a completed task so the run can resume from here
+    fn checkpoint(&self, name: &str, task: Option<&Task>, ctx: &LuaTable) {
+        let recorder = match &self.recorder {
+            Some(recorder) if self.is_checkpointed(ctx) => recorder,
+            _ => return,
+        };
+        // Where the task left its output
+        let mut outputs = vec![name.to_string()];
+        if let Some(output) = task.and_then(|task| task.named_arguments.get("output")) {
+            outputs.extend(self.eval_string(output, ctx).ok());
+        }
+
+        let result = self.context_json(ctx).and_then(|mut context| {
+            // Secrets aren't written down. Parameters and data literals
+            // holding them are bound again when the run resumes, but a task
+            // whose output holds one isn't completed, so it runs again
+            let mut secret_output = false;
+            if let serde_json::Value::Object(object) = &mut context {
+                object.retain(|key, value| {
+                    let mut redacted = value.clone();
+                    self.redactor.redact_json(&mut redacted);
+                    secret_output |= redacted != *value && outputs.contains(key);
+                    redacted == *value
+                });
+            }
+            if secret_output {
+                println!("[!] Not checkpointing task {}, its output holds a secret", name);
+                return Ok(());
+            }
+            let history = recorder.history.lock().unwrap();
+            history.record_checkpoint(recorder.run_id, name, &context)
+        });
+        if let Err(e) = result {
+            println!("[!] Failed to checkpoint task {}: {:#}", name, e);
+        }
+    }
+
+    fn restore_context(&self, ctx: &LuaTable, context: &serde_json::Value) -> Result<()> {
+        let object = match context {
+            serde_json::Value::Object(object) => object,
+            _ => bail!("The checkpointed context is not an object"),
+        };
+        let options = SerializeOptions::new()
+            .serialize_none_to_null(false)
+            .serialize_unit_to_null(false);
+        for (name, value) in object {
+            ctx.set(name.as_str(), self.lua.to_value_with(value, options)?)?;
+        }
+        Ok(())
+    }
+
+    // Run the failed task's on_failure task, then either carry on or pass
+    // the failure up the flow
+    fn handle_failure(
+        &self,
+        pipeline: &Pipeline,
+        name: &str,
+        task: &Task,
+        ctx: &LuaTable,
+        error: anyhow::Error,
+    ) -> Result<()> {
+        let error = error.context(TaskFailed(name.to_string()));
+
+        if let Some(cleanup) = task.on_failure() {
+            let cleanup_task = pipeline
+                .tasks
+                .get(cleanup)
+                .ok_or_else(|| anyhow!("Unknown on_failure task {} for {}", cleanup, name))?;
+            println!("[!] {}. Running {}", self.describe(&error), cleanup);
+            self.set_error(ctx, &error)?;
+            // A failing cleanup doesn't hide the original failure
+            if let Err(e) = self.run_task_with_retries(pipeline, cleanup, cleanup_task, ctx, None) {
+                let e = e.context(TaskFailed(cleanup.to_string()));
+                println!("[!] {}", self.describe(&e));
+                self.log.lock().unwrap().record_failure(cleanup, &e);
+            }
+        }
+
+        if task.continue_on_error() {
+            println!("[!] {}. Continuing", self.describe(&error));
+            self.log.lock().unwrap().record_failure(name, &error);
+            return Ok(());
+        }
+
+        Err(error)
+    }
+
+    // Run a task, retrying it according to its retry policy. Every attempt is
+    // recorded in the run log
+    fn run_task_with_retries(
+        &self,
+        pipeline: &Pipeline,
+        name: &str,
+        task: &Task,
+        ctx: &LuaTable,
+        agent: Option<&str>,
+    ) -> Result<()> {
+        let policy = task.retry_policy()?.unwrap_or_default();
+        let mut number = 1;
+
+        loop {
+            if self.is_cancelled() {
+                bail!("The run was cancelled");
+            }
+            self.emit(RunEvent::TaskStarted {
+                task: name.to_string(),
+                task_type: task.task_type.to_string(),
+                attempt: number,
+            });
+            let started_at = SystemTime::now();
+            let timer = Instant::now();
+            let result = self.run_task(pipeline, name, task, ctx, agent);
+            let (timed_out, exit_code) = match &result {
+                Ok(_) if task.task_type == TaskType::Cmd => (false, Some(0)),
+                Ok(_) => (false, None),
+                Err(e) => failure_details(e),
+            };
+
+            let attempt = Attempt {
+                task: name.to_string(),
+                number,
+                started_at,
+                duration: timer.elapsed(),
+                exit_code,
+                output: result
+                    .as_ref()
+                    .ok()
+                    .cloned()
+                    .flatten()
+                    .map(|output| self.redactor.redact(&output)),
+                error: result.as_ref().err().map(|e| self.describe(e)),
+            };
+            self.emit(RunEvent::TaskFinished {
+                task: attempt.task.clone(),
+                attempt: attempt.number,
+                duration: attempt.duration,
+                exit_code: attempt.exit_code,
+                output: attempt.output.clone(),
+                error: attempt.error.clone(),
+            });
+            self.record(attempt);
+            let result = result.map(|_| ());
+
+            match result {
+                Err(e)
+                    if number <= policy.retries
+                        && policy.should_retry(timed_out, exit_code)
+                        && e.downcast_ref::<Violation>().is_none()
+                        && e.downcast_ref::<OutOfScope>().is_none()
+                        && !self.is_cancelled() =>
+                {
+                    let delay = policy.delay_for(number);
+                    println!(
+                        "[!] Task {} failed (attempt {}/{}): {}. Retrying in {:?}",
+                        name,
+                        number,
+                        policy.retries + 1,
+                        self.describe(&e),
+                        delay
+                    );
+                    self.wait(delay)?;
+                    number += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    // Sleep for `delay` in short slices, stopping if the run is cancelled
+    fn wait(&self, delay: Duration) -> Result<()> {
+        let until = Instant::now() + delay;
+        loop {
+            if self.is_cancelled() {
+                bail!("The run was cancelled");
+            }
+            let left = until.saturating_duration_since(Instant::now());
+            if left.is_zero() {
+                return Ok(());
+            }
+            std::thread::sleep(left.min(Duration::from_millis(50)));
+        }
+    }
+
+    fn is_cancelled(&self) -> bool {
+        self.cancel_flag()
+            .is_some_and(|cancel| cancel.load(Ordering::SeqCst))
+    }
+
+    // What stops the run's commands, or the hook's while a hook runs
+    fn cancel_flag(&self) -> Option<Arc<AtomicBool>> {
+        match &*self.hook_cancel.lock().unwrap() {
+            Some(hook_cancel) => Some(hook_cancel.clone()),
+            None => self.cancel.clone(),
+        }
+    }
+
+    // Run the tasks of one of the pipeline's hooks with the run's summary
+    // bound to `run`. A failing hook doesn't change how the run ended
+    fn run_hook(
+        &self,
+        pipeline: &Pipeline,
+        event: &str,
+        tasks: &[String],
+        ctx: &LuaTable,
+        summary: &RunSummary,
+    ) {
+        if tasks.is_empty() {
+            return;
+        }
+        println!("[+] Running {} hook of {}", event, pipeline.name);
+        let hook_cancel = Arc::new(AtomicBool::new(false));
+        *self.hook_cancel.lock().unwrap() = Some(hook_cancel.clone());
+        let options = SerializeOptions::new().serialize_none_to_null(false);
+        let bound = self
+            .lua
+            .to_value_with(summary, options)
+            .and_then(|summary| ctx.set("run", summary));
+        if let Err(e) = bound {
+            println!("[!] Failed to bind the run summary for {}: {}", event, e);
+        }
+
+        // Stop the hook once it goes over its time limit, or if the run is
+        // cancelled while it runs
+        let done = AtomicBool::new(false);
+        let run_cancelled = || self.cancel.as_ref().is_some_and(|c| c.load(Ordering::SeqCst));
+        let cancelled_before = run_cancelled();
+        std::thread::scope(|scope| {
+            scope.spawn(|| {
+                let started = Instant::now();
+                while !done.load(Ordering::SeqCst) {
+                    let cancelled = run_cancelled() && !cancelled_before;
+                    if cancelled || started.elapsed() > self.hook_time_limit {
+                        hook_cancel.store(true, Ordering::SeqCst);
+                        break;
+                    }
+                    std::thread::sleep(Duration::from_millis(50));
+                }
+            });
+
+            for name in tasks {
+                let result = match pipeline.tasks.get(name) {
+                    Some(task) => self.run_task_with_retries(pipeline, name, task, ctx, None),
+                    None => Err(anyhow!("Unknown {} task {}", event, name)),
+                };
+                if let Err(e) = result {
+                    let e = e.context(TaskFailed(name.to_string()));
+                    println!("[!] {} hook: {}", event, self.describe(&e));
+                }
+            }
+            done.store(true, Ordering::SeqCst);
+        });
+        *self.hook_cancel.lock().unwrap() = None;
+    }
+
+    fn emit(&self, event: RunEvent) {
+        if let Some(events) = &self.events {
+            // Nobody is listening any more, which doesn't stop the run
+            let _ = events.send(event);
+        }
+    }
+
+    fn record(&self, attempt: Attempt) {
+        if let Some(recorder) = &self.recorder {
+            let history = recorder.history.lock().unwrap();
+            if let Err(e) = history.record_attempt(recorder.run_id, &attempt) {
+                println!("[!] Failed to record task {} in the run history: {:#}", attempt.task, e);
+            }
+        }
+        self.log.lock().unwrap().record(attempt);
+    }
+
+    // Run a task once, returning what it produced, such as a command's
+    // stdout. cmd and llm tasks run on `agent`, or the agent they name
+    fn run_task(
+        &self,
+        pipeline: &Pipeline,
+        name: &str,
+        task: &Task,
+        ctx: &LuaTable,
+        agent: Option<&str>,
+    ) -> Result<Option<String>> {
+        let agent = match task.named_arguments.get("agent") {
+            Some(value) => Some(self.eval_string(value, ctx)?),
+            None => agent.map(str::to_string),
+        };
+        match &agent {
+            Some(agent) if !matches!(task.task_type, TaskType::Cmd | TaskType::Llm) => bail!(
+                "Task {} can't run on agent {}, only cmd and llm tasks can",
+                name,
+                agent
+            ),
+            Some(agent) => println!(
+                "[+] Running Task: {} ({}) on agent {}",
+                name,
+                task.task_type.to_string(),
+                agent
+            ),
+            None => println!("[+] Running Task: {} ({})", name, task.task_type.to_string()),
+        }
+        self.enforce(name, |policy| policy.check_task_type(&task.task_type))?;
+        for path in self.declared_paths(task, "artifacts", ctx)? {
+            self.enforce(name, |policy| policy.check_writable(&path, &self.root_dir()))?;
+        }
+        let mut produced = None;
+
+        let cached = self.cache_key(task, ctx, agent.as_deref())?;
+        if let Some((key, _)) = &cached {
+            if let Some(output) = self.cached_result(name, key) {
+                if task.task_type == TaskType::Cmd {
+                    let on_line = self.line_handler(name);
+                    output.lines().for_each(|line| on_line(cmd::Stream::Stdout, line));
+                }
+                self.store_output(task, ctx, LuaValue::String(self.lua.create_string(&output)?))?;
+                self.collect_artifacts(name, task, ctx)?;
+                return Ok(Some(output));
+            }
+        }
+
+        match task.task_type {
+            TaskType::Cmd | TaskType::Llm => {
+                let output = self.run_on_executor(name, task, ctx, agent.as_deref())?;
+                self.store_output(task, ctx, LuaValue::String(self.lua.create_string(&output)?))?;
+                produced = Some(output);
+            }
+            TaskType::Script => {
+                let code = match self.optional_arg(task, &["file"], ctx)? {
+                    Some(file) => fs::read_to_string(self.resolve_path(&file))
+                        .with_context(|| format!("Failed to read script {}", file))?,
+                    None => self.required_arg(task, &["script", "code"], ctx)?,
+                };
+                self.run_lua(&code, ctx)?;
+            }
+            TaskType::Lua => {
+                let code = self.required_arg(task, &["code", "script"], ctx)?;
+                self.run_lua(&code, ctx)?;
+            }
+            TaskType::SetVar => {
+                let args = self.string_args(task, ctx)?;
+                var_ops::set_var(&args, self.lua, ctx);
+            }
+            TaskType::Http => {
+                let args = self.string_args(task, ctx)?;
+                self.enforce(name, |policy| policy.check_url(http::url(&args)))?;
+                self.check_target(http::url(&args), &format!("the URL of task {}", name))?;
+                self.runtime.block_on(http::http_get(&args))?;
+            }
+            TaskType::Notify => self.notify(name, task, ctx)?,
+            TaskType::Template => {
+                let output = self.render_template(name, task, ctx)?;
+                self.store_output(task, ctx, LuaValue::String(self.lua.create_string(&output)?))?;
+                produced = Some(output);
+            }
+            TaskType::MetaTask | TaskType::GenerateTasks | TaskType::GenerateFlow => {
+                println!("    Skipping meta task, it is expanded when the pipeline is generated");
+            }
+            TaskType::SubPipeline => self.run_sub_pipeline(pipeline, name, task, ctx)?,
+            TaskType::ForEach => {
+                let config = task
+                    .for_each_config
+                    .as_ref()
+                    .ok_or_else(|| anyhow!("Task {} is missing its for_each arguments", name))?;
+                self.run_flow(pipeline, &config.to_flow(name), ctx)?;
+            }
+        }
+
+        if let (Some((key, policy)), Some(output), Some(cache)) = (&cached, &produced, &self.cache) {
+            let cache = cache.lock().unwrap();
+            let task_type = task.task_type.to_string();
+            if let Err(e) = cache.put(key, name, &task_type, output, policy.ttl) {
+                println!("[!] Failed to cache the result of task {}: {:#}", name, e);
+            }
+        }
+
+        self.collect_artifacts(name, task, ctx)?;
+        Ok(produced)
+    }
+
+    // Run a cmd or llm task on the executor for `agent`, here if None. Files
+    // the task wrote elsewhere are written where it would have written them
+    // here, so later tasks and collect_artifacts find them
+    fn run_on_executor(
+        &self,
+        name: &str,
+        task: &Task,
+        ctx: &LuaTable,
+        agent: Option<&str>,
+    ) -> Result<String> {
+        let args = match task.task_type {
+            TaskType::Cmd => {
+                let command = self.required_arg(task, &["command", "cmd"], ctx)?;
+                HashMap::from([("cmd".to_string(), command)])
+            }
+            _ => self.string_args(task, ctx)?,
+        };
+        let timeout = match task.named_arguments.get("timeout") {
+            Some(timeout) => {
+                let timeout = self.eval_string(timeout, ctx)?;
+                Some(parse_duration(&timeout).map_err(|e| anyhow!(e))?)
+            }
+            None => None,
+        };
+        let mut request = TaskRequest {
+            task: name.to_string(),
+            task_type: task.task_type.clone(),
+            args,
+            timeout,
+            artifacts: Vec::new(),
+            inputs: None,
+        };
+        if agent.is_some() {
+            request.artifacts = self.declared_paths(task, "artifacts", ctx)?;
+            let inputs = self.declared_paths(task, "inputs", ctx)?;
+            if !inputs.is_empty() {
+                let bundle = Bundle::inputs(&inputs, &self.root_dir())
+                    .with_context(|| format!("Failed to bundle the inputs of task {}", name))?;
+                request.inputs = Some(bundle);
+            }
+        }
+
+        // Agents check tasks sent to them against their own policy
+        if agent.is_none() {
+            self.enforce(name, |policy| policy.check_request(&request, &self.root_dir()))?;
+        }
+        let result = self.task_executor(agent)?.run(
+            &request,
+            self.line_handler(name),
+            self.cancel_flag(),
+        )?;
+        task_executor::write_files(&result.files, &request.artifacts, &self.root_dir())
+            .with_context(|| format!("Failed to save the files task {} sent back", name))?;
+        Ok(result.output)
+    }
+
+    // Stop task `name` if `check` finds it goes against the run's policy,
+    // reporting the violation
+    fn enforce(&self, name: &str, check: impl FnOnce(&Policy) -> Result<(), Violation>) -> Result<()> {
+        let Some(policy) = &self.policy else {
+            return Ok(());
+        };
+        check(policy).map_err(|violation| {
+            self.emit(RunEvent::PolicyViolation {
+                task: name.to_string(),
+                violation: violation.clone(),
+            });
+            anyhow::Error::new(violation)
+        })
+    }
+
+    // Look up a secret, once per run, and keep it out of what the run
+    // prints and records from then on
+    fn secret(&self, name: &str) -> Result<String> {
+        let mut resolved = self.resolved_secrets.lock().unwrap();
+        if let Some(value) = resolved.get(name) {
+            return Ok(value.clone());
+        }
+        let value = secrets::resolve(name, self.secrets.as_ref(), &self.root_dir())?;
+        self.redactor.add(&value);
+        resolved.insert(name.to_string(), value.clone());
+        Ok(value)
+    }
 
-    // Find all variable interpolations (#{var})
-    let re = regex::Regex::new(r"#\{([a-zA-Z0-9_]+)\}")?;
+    // An error as it is printed and recorded, without secrets
+    fn describe(&self, error: &anyhow::Error) -> String {
+        self.redactor.redact(&format!("{:#}", error))
+    }
+
+    // Refuse a parameter or loop item bound to `name` if it holds targets
+    // outside the run's scope
+    fn check_scope(&self, name: &str, value: &LuaValue, found_in: &str) -> Result<()> {
+        if !self.scope.as_ref().is_some_and(|scope| scope.holds_targets(name)) {
+            return Ok(());
+        }
+        let targets = match value {
+            LuaValue::Table(table) => table
+                .clone()
+                .sequence_values::<LuaValue>()
+                .map(|item| self.lua_to_string(&item?))
+                .collect::<Result<Vec<_>>>()?
+                .join(" "),
+            value => self.lua_to_string(value)?,
+        };
+        self.check_target(&targets, found_in)
+    }
+
+    // Refuse targets outside the run's scope, unless it is ignored
+    fn check_target(&self, targets: &str, found_in: &str) -> Result<()> {
+        let Some(scope) = &self.scope else {
+            return Ok(());
+        };
+        match (scope.check(targets, found_in), &self.ignore_scope) {
+            (Ok(()), _) => Ok(()),
+            (Err(e), Some(reason)) => {
+                println!(
+                    "[!] {} is out of scope, going ahead as the scope is ignored: {}",
+                    e.target, reason
+                );
+                Ok(())
+            }
+            (Err(e), None) => Err(e.into()),
+        }
+    }
+
+    // Where tasks sent to `agent` run, here if None
+    fn task_executor(&self, agent: Option<&str>) -> Result<&dyn TaskExecutor> {
+        match agent {
+            None => Ok(&self.local),
+            Some(agent) => self
+                .agents
+                .get(agent)
+                .map(|executor| executor.as_ref())
+                .ok_or_else(|| anyhow!("No agent named {} in config.toml", agent)),
+        }
+    }
+
+    // The paths a task lists in its `argument`, such as its artifacts
+    fn declared_paths(&self, task: &Task, argument: &str, ctx: &LuaTable) -> Result<Vec<String>> {
+        match task.named_arguments.get(argument) {
+            Some(Value::Array(items)) => items.iter().map(|item| self.eval_string(item, ctx)).collect(),
+            Some(value) => Ok(vec![self.eval_string(value, ctx)?]),
+            None => Ok(Vec::new()),
+        }
+    }
+
+    // Collect the files a task declared with `artifacts=[...]`, copying them
+    // into the run's artifact directory so later tasks can't overwrite them.
+    // Declared directories are collected with everything in them
+    fn collect_artifacts(&self, name: &str, task: &Task, ctx: &LuaTable) -> Result<()> {
+        for declared_path in self.declared_paths(task, "artifacts", ctx)? {
+            let source = &self.resolve_path(&declared_path);
+            let base = source
+                .file_name()
+                .map(PathBuf::from)
+                .unwrap_or_else(|| PathBuf::from(name));
+            let files = if source.is_dir() {
+                files_in(source)?
+                    .into_iter()
+                    .map(|relative| (source.join(&relative), base.join(relative)))
+                    .collect()
+            } else if source.is_file() {
+                vec![(source.to_path_buf(), base)]
+            } else {
+                println!("[!] Artifact {} of task {} was not found", declared_path, name);
+                continue;
+            };
+
+            for (file, relative) in files {
+                let content = fs::read(&file)
+                    .with_context(|| format!("Failed to read artifact {}", file.display()))?;
+                let artifact_name = self.unique_artifact_name(name, &relative);
+                let mut artifact = Artifact {
+                    task: name.to_string(),
+                    path: file.display().to_string(),
+                    name: artifact_name,
+                    file: file.clone(),
+                    sha256: format!("{:x}", Sha256::digest(&content)),
+                    size: content.len() as u64,
+                };
+
+                let task_dir = match (&self.recorder, &self.artifact_dir) {
+                    (Some(recorder), _) => {
+                        let history = recorder.history.lock().unwrap();
+                        Some(history.artifact_dir(recorder.run_id, name))
+                    }
+                    (None, Some(dir)) => Some(dir.join(name)),
+                    (None, None) => None,
+                };
+                if let Some(task_dir) = task_dir {
+                    let stored = task_dir.join(&artifact.name);
+                    if let Some(dir) = stored.parent() {
+                        fs::create_dir_all(dir)
+                            .with_context(|| format!("Failed to create {}", dir.display()))?;
+                    }
+                    fs::write(&stored, &content)
+                        .with_context(|| format!("Failed to store artifact {}", stored.display()))?;
+                    artifact.file = stored;
+                }
+
+                if let Some(recorder) = &self.recorder {
+                    let history = recorder.history.lock().unwrap();
+                    if let Err(e) = history.record_artifact(recorder.run_id, &artifact) {
+                        println!(
+                            "[!] Failed to record artifact {} in the run history: {:#}",
+                            artifact.path, e
+                        );
+                    }
+                }
+
+                println!("    Collected artifact {} ({} bytes)", artifact.path, artifact.size);
+                self.log.lock().unwrap().artifacts.push(artifact);
+            }
+        }
+
+        Ok(())
+    }
+
+    // A name for the artifact within the task's artifact directory that no
+    // earlier artifact of the task uses, e.g. when a loop writes nmap.txt on
+    // every iteration
+    fn unique_artifact_name(&self, task: &str, relative: &Path) -> String {
+        let log = self.log.lock().unwrap();
+        let taken = |name: &str| log.artifacts.iter().any(|a| a.task == task && a.name == name);
+
+        let name = relative.to_string_lossy().replace('\\', "/");
+        if !taken(&name) {
+            return name;
+        }
+        let stem = relative.file_stem().unwrap_or_default().to_string_lossy();
+        let extension = relative
+            .extension()
+            .map(|e| format!(".{}", e.to_string_lossy()))
+            .unwrap_or_default();
+        (2..)
+            .map(|n| {
+                relative
+                    .with_file_name(format!("{}-{}{}", stem, n, extension))
+                    .to_string_lossy()
+                    .replace('\\', "/")
+            })
+            .find(|name| !taken(name))
+            .unwrap()
+    }
+
+    // Print a command's output as it is written, passing it on as events
+    fn line_handler(&self, task: &str) -> cmd::LineHandler {
+        let task = task.to_string();
+        let events = self.events.clone();
+        let redactor = self.redactor.clone();
+        Arc::new(move |stream, line| {
+            let line = &redactor.redact(line);
+            match stream {
+                cmd::Stream::Stdout => println!("{}", line),
+                cmd::Stream::Stderr => eprintln!("{}", line),
+            }
+            if let Some(events) = &events {
+                let _ = events.send(RunEvent::LogLine {
+                    task: task.clone(),
+                    stream,
+                    line: line.to_string(),
+                });
+            }
+        })
+    }
+
+    // The key a task's result is cached under, from its type, interpolated
+    // arguments and the agent it runs on. None if the task isn't cached or
+    // caching is turned off
+    fn cache_key(
+        &self,
+        task: &Task,
+        ctx: &LuaTable,
+        agent: Option<&str>,
+    ) -> Result<Option<(String, CachePolicy)>> {
+        let policy = match task.cache_policy()? {
+            Some(policy) if self.cache.is_some() => policy,
+            _ => return Ok(None),
+        };
+
+        let mut args = BTreeMap::new();
+        for (index, arg) in task.arguments.iter().enumerate() {
+            let name = match &arg.name {
+                // Arguments that control how the task runs don't change its result
+                Some(name) if CONTROL_ARGUMENTS.contains(&name.as_str()) => continue,
+                Some(name) => name.clone(),
+                None => index.to_string(),
+            };
+            args.insert(name, self.eval_string(&arg.value, ctx)?);
+        }
+        // The same command can find different things from different agents
+        if let Some(agent) = agent {
+            args.insert("agent".to_string(), agent.to_string());
+        }
+
+        let key = TaskCache::key(&task.task_type.to_string(), &args);
+        Ok(Some((key, policy)))
+    }
 
-    for cap in re.captures_iter(input) {
-        let var_name = &cap[1];
-        let var_value = ctx.get::<_, LuaValue>(var_name)?;
+    fn cached_result(&self, name: &str, key: &str) -> Option<String> {
+        let cache = self.cache.as_ref()?.lock().unwrap();
+        match cache.get(key) {
+            Ok(Some(entry)) => {
+                println!("    Using the result cached at {} UTC", entry.created_at);
+                Some(entry.output)
+            }
+            Ok(None) => None,
+            Err(e) => {
+                println!("[!] Failed to read the cached result of task {}: {:#}", name, e);
+                None
+            }
+        }
+    }
+
+    // Runs an imported pipeline in its own context. The sub-pipeline's
+    // context is stored under the task name, e.g. #{sub.scan_output}
+    fn run_sub_pipeline(
+        &self,
+        pipeline: &Pipeline,
+        name: &str,
+        task: &Task,
+        ctx: &LuaTable,
+    ) -> Result<()> {
+        let call = task
+            .sub_pipeline_config
+            .as_ref()
+            .ok_or_else(|| anyhow!("Task {} is missing its pipeline call", name))?;
+        let sub_pipeline = pipeline
+            .modules
+            .get(&call.import)
+            .and_then(|module| module.pipelines.get(&call.pipeline))
+            .ok_or_else(|| anyhow!("Unknown pipeline {}.{}", call.import, call.pipeline))?;
+
+        let mut args = HashMap::new();
+        let mut positional = sub_pipeline.parameters.iter();
+        for arg in &task.arguments {
+            let param_name = match &arg.name {
+                Some(arg_name) => {
+                    if !sub_pipeline.parameters.iter().any(|p| &p.name == arg_name) {
+                        bail!(
+                            "Pipeline {}.{} has no parameter '{}'",
+                            call.import,
+                            call.pipeline,
+                            arg_name
+                        );
+                    }
+                    arg_name.clone()
+                }
+                None => positional
+                    .next()
+                    .map(|p| p.name.clone())
+                    .ok_or_else(|| anyhow!("Too many arguments for {}.{}", call.import, call.pipeline))?,
+            };
+            args.insert(param_name, self.eval(&arg.value, ctx)?);
+        }
+
+        println!("    Calling pipeline {}.{}", call.import, call.pipeline);
+        let sub_ctx = self.lua.create_table()?;
+        self.run_pipeline(sub_pipeline, &sub_ctx, &args)?;
+        ctx.set(name, sub_ctx)?;
+
+        Ok(())
+    }
+
+    // Run a chunk with `ctx` in its own environment rather than a global, as
+    // parallel tasks share the Lua state. Other globals read through to the
+    // shared ones
+    fn run_lua(&self, code: &str, ctx: &LuaTable) -> Result<()> {
+        let env = self.lua.create_table()?;
+        env.set("ctx", ctx.clone())?;
+        let metatable = self.lua.create_table()?;
+        metatable.set("__index", self.lua.globals())?;
+        env.set_metatable(Some(metatable));
+        self.lua.load(code).set_environment(env).exec()?;
+        Ok(())
+    }
+
+    fn store_output(&self, task: &Task, ctx: &LuaTable, value: LuaValue) -> Result<()> {
+        if let Some(output) = task.named_arguments.get("output") {
+            let output_name = self.eval_string(output, ctx)?;
+            ctx.set(output_name, value)?;
+        }
+        Ok(())
+    }
+
+    // Look up the first of `names` among the named arguments, falling back to
+    // the first positional argument
+    fn optional_arg(&self, task: &Task, names: &[&str], ctx: &LuaTable) -> Result<Option<String>> {
+        let value = names
+            .iter()
+            .find_map(|name| task.named_arguments.get(*name))
+            .or_else(|| {
+                task.arguments
+                    .iter()
+                    .find(|arg| arg.name.is_none())
+                    .map(|arg| &arg.value)
+            });
+
+        value.map(|v| self.eval_string(v, ctx)).transpose()
+    }
+
+    fn required_arg(&self, task: &Task, names: &[&str], ctx: &LuaTable) -> Result<String> {
+        self.optional_arg(task, names, ctx)?
+            .ok_or_else(|| anyhow!("Missing required argument '{}'", names[0]))
+    }
+
+    // The directory relative paths are resolved against
+    fn root_dir(&self) -> PathBuf {
+        self.working_dir.clone().unwrap_or_else(|| PathBuf::from("."))
+    }
+
+    // A path from the pipeline, relative to the working directory
+    fn resolve_path(&self, path: &str) -> PathBuf {
+        match &self.working_dir {
+            Some(dir) => dir.join(path),
+            None => PathBuf::from(path),
+        }
+    }
+
+    // The context as JSON, leaving out what JSON can't hold such as functions
+    fn context_json(&self, ctx: &LuaTable) -> Result<serde_json::Value> {
+        let options = DeserializeOptions::new().deny_unsupported_types(false);
+        Ok(self.lua.from_value_with(LuaValue::Table(ctx.clone()), options)?)
+    }
+
+    // Render the template in `file`, or given inline as `source`, against the
+    // context, writing the result to `to` if given. Secrets are redacted, as
+    // reports are meant to be passed around
+    fn render_template(&self, name: &str, task: &Task, ctx: &LuaTable) -> Result<String> {
+        let rendered = match task.named_arguments.get("source") {
+            Some(source) => {
+                let source = self.eval_string(source, ctx)?;
+                template::render(&source, name, &self.context_json(ctx)?, Some(&self.root_dir()))?
+            }
+            None => {
+                let file = self.required_arg(task, &["file"], ctx)?;
+                self.render_file(&file, ctx)?
+            }
+        };
+        let rendered = self.redactor.redact(&rendered);
+
+        let to = task.named_arguments.get("to").map(|to| self.eval_string(to, ctx)).transpose()?;
+        if let Some(to) = to {
+            self.enforce(name, |policy| policy.check_writable(&to, &self.root_dir()))?;
+            let path = self.resolve_path(&to);
+            if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+                fs::create_dir_all(dir)
+                    .with_context(|| format!("Failed to create {}", dir.display()))?;
+            }
+            fs::write(&path, &rendered).with_context(|| format!("Failed to write {}", to))?;
+            println!("    Wrote {}", to);
+        }
+        Ok(rendered)
+    }
+
+    // Render a template file, which can include others next to it
+    fn render_file(&self, file: &str, ctx: &LuaTable) -> Result<String> {
+        let path = self.resolve_path(file);
+        let source = fs::read_to_string(&path)
+            .with_context(|| format!("Failed to read template {}", file))?;
+        let name = path
+            .file_name()
+            .map_or_else(|| file.to_string(), |n| n.to_string_lossy().to_string());
+        template::render(&source, &name, &self.context_json(ctx)?, path.parent())
+    }
+
+    // Send a notify task's fields, its named arguments and those of the
+    // object it is given, along the route they pick. A `template` file
+    // renders the body. Secrets are left in to route by, as webhook URLs
+    // often are one, but not in what is sent
+    fn notify(&self, name: &str, task: &Task, ctx: &LuaTable) -> Result<()> {
+        let mut fields = serde_json::Map::new();
+        for (arg, value) in &task.named_arguments {
+            if !CONTROL_ARGUMENTS.contains(&arg.as_str()) {
+                fields.insert(arg.clone(), self.lua.from_value(self.eval(value, ctx)?)?);
+            }
+        }
+        if let Some(arg) = task.arguments.iter().find(|a| a.name.is_none()) {
+            match self.lua.from_value(self.eval(&arg.value, ctx)?)? {
+                serde_json::Value::Object(object) => fields.extend(object),
+                body => {
+                    fields.insert("body".to_string(), body);
+                }
+            }
+        }
+
+        // A template file renders the body against the whole context
+        if let Some(file) = fields.remove("template") {
+            let file = match file {
+                serde_json::Value::String(file) => file,
+                _ => bail!("The template of task {} must be a path", name),
+            };
+            let body = self.render_file(&file, ctx)?;
+            fields.insert("body".to_string(), serde_json::Value::String(body));
+        }
+
+        let route = match Route::from_fields(&fields)? {
+            Route::File(path) => {
+                let path = path.to_string_lossy().to_string();
+                self.enforce(name, |policy| policy.check_writable(&path, &self.root_dir()))?;
+                Route::File(self.resolve_path(&path))
+            }
+            route => route,
+        };
+        match (&route, &self.smtp) {
+            (Route::Webhook(uri) | Route::Chat(_, uri), _) => {
+                self.enforce(name, |policy| policy.check_url(uri))?
+            }
+            (Route::Email(_), Some(smtp)) => {
+                let server = format!("smtp://{}", smtp.host);
+                self.enforce(name, |policy| policy.check_url(&server))?
+            }
+            _ => {}
+        }
+        let notifier = route.notifier(self.smtp.as_ref())?;
+
+        let mut body = serde_json::Value::Object(fields);
+        self.redactor.redact_json(&mut body);
+        let serde_json::Value::Object(fields) = body else {
+            unreachable!("redacting keeps objects")
+        };
+        let mut message = notifications::Message::from_fields(fields);
+        message.attachments = message
+            .attachments
+            .iter()
+            .map(|path| self.resolve_path(&path.to_string_lossy()))
+            .collect();
+
+        println!(
+            "    Sending notification to {}",
+            self.redactor.redact(&notifier.destination())
+        );
+        self.runtime.block_on(notifier.send(&message))?;
+        Ok(())
+    }
+
+    // Evaluate all named arguments into strings for the piper_tasks functions
+    fn string_args(&self, task: &Task, ctx: &LuaTable) -> Result<HashMap<String, String>> {
+        task.named_arguments
+            .iter()
+            .map(|(name, value)| Ok((name.clone(), self.eval_string(value, ctx)?)))
+            .collect()
+    }
+
+    fn eval_string(&self, value: &Value, ctx: &LuaTable) -> Result<String> {
+        let value = self.eval(value, ctx)?;
+        self.lua_to_string(&value)
+    }
 
-        let var_str = match var_value {
+    // Evaluate a DSL value against the context
+    fn eval(&self, value: &Value, ctx: &LuaTable) -> Result<LuaValue> {
+        match value {
+            Value::String(s) | Value::MultilineString(s) => {
+                let interpolated = self.interpolate_variables(s, ctx)?;
+                Ok(LuaValue::String(self.lua.create_string(&interpolated)?))
+            }
+            Value::Number(n) => Ok(LuaValue::Number(*n)),
+            Value::Boolean(b) => Ok(LuaValue::Boolean(*b)),
+            Value::Object(map) => {
+                let table = self.lua.create_table()?;
+                for (key, value) in map {
+                    table.set(key.as_str(), self.eval(value, ctx)?)?;
+                }
+                Ok(LuaValue::Table(table))
+            }
+            Value::Array(items) => {
+                let table = self.lua.create_table()?;
+                for item in items {
+                    table.push(self.eval(item, ctx)?)?;
+                }
+                Ok(LuaValue::Table(table))
+            }
+            Value::VarInterpolation(name) => self.resolve_expression(name, ctx),
+            Value::PropertyAccess { base, path } => {
+                let mut current = self.lookup(base, ctx)?;
+                for key in path {
+                    // The parser does not keep brackets apart from dots, so a
+                    // key that is not a field is treated as a variable holding one
+                    let key = key.trim_matches('"');
+                    let next = self.index(&current, key)?;
+                    current = match next {
+                        LuaValue::Nil => match ctx.get::<LuaValue>(key)? {
+                            LuaValue::Nil => LuaValue::Nil,
+                            var => {
+                                let key = self.lua_to_string(&var)?;
+                                self.index(&current, &key)?
+                            }
+                        },
+                        next => next,
+                    };
+                }
+                Ok(current)
+            }
+            Value::FallbackExpr { primary, fallback } => match self.eval(primary, ctx) {
+                Ok(value) if is_truthy(&value) => Ok(value),
+                _ => self.eval(fallback, ctx),
+            },
+            Value::FunctionCall { function, .. } => {
+                bail!("Function '{}' cannot be used as a value", function)
+            }
+            Value::Secret(name) => {
+                let value = self.secret(name)?;
+                Ok(LuaValue::String(self.lua.create_string(&value)?))
+            }
+            Value::ConditionalValue {
+                condition,
+                if_true,
+                if_false,
+            } => {
+                if self.eval_condition(condition, ctx)? {
+                    self.eval(if_true, ctx)
+                } else {
+                    self.eval(if_false, ctx)
+                }
+            }
+        }
+    }
+
+    fn eval_condition(&self, condition: &Condition, ctx: &LuaTable) -> Result<bool> {
+        match condition {
+            Condition::Boolean(b) => Ok(*b),
+            Condition::VarInterpolation(text) => {
+                let expr = text.trim_start_matches("#{").trim_end_matches('}');
+                Ok(is_truthy(&self.resolve_expression(expr, ctx)?))
+            }
+            Condition::LogicalOperation {
+                left,
+                operator,
+                right,
+            } => match operator {
+                LogicalOperator::And => {
+                    Ok(self.eval_condition(left, ctx)? && self.eval_condition(right, ctx)?)
+                }
+                LogicalOperator::Or => {
+                    Ok(self.eval_condition(left, ctx)? || self.eval_condition(right, ctx)?)
+                }
+            },
+            Condition::Comparison {
+                left,
+                operator,
+                right,
+            } => {
+                let left = self.eval_string(left, ctx)?;
+                let right = self.eval_string(right, ctx)?;
+
+                // Compare numerically when both sides are numbers
+                let ordering = match (left.parse::<f64>(), right.parse::<f64>()) {
+                    (Ok(l), Ok(r)) => l.partial_cmp(&r),
+                    _ => Some(left.cmp(&right)),
+                };
+                let ordering = match ordering {
+                    Some(ordering) => ordering,
+                    None => return Ok(false),
+                };
+
+                Ok(match operator {
+                    ComparisonOperator::Equal => ordering.is_eq(),
+                    ComparisonOperator::NotEqual => ordering.is_ne(),
+                    ComparisonOperator::GreaterThan => ordering.is_gt(),
+                    ComparisonOperator::LessThan => ordering.is_lt(),
+                    ComparisonOperator::GreaterThanOrEqual => ordering.is_ge(),
+                    ComparisonOperator::LessThanOrEqual => ordering.is_le(),
+                })
+            }
+        }
+    }
+
+    // Helper function to interpolate variables in a string
+    fn interpolate_variables(&self, input: &str, ctx: &LuaTable) -> Result<String> {
+        let re = interpolation_regex();
+        let mut result = String::with_capacity(input.len());
+        let mut last = 0;
+
+        // Find all variable interpolations (#{var})
+        for cap in re.captures_iter(input) {
+            let whole = cap.get(0).unwrap();
+            let value = self.resolve_expression(&cap[1], ctx)?;
+            result.push_str(&input[last..whole.start()]);
+            result.push_str(&self.lua_to_string(&value)?);
+            last = whole.end();
+        }
+        result.push_str(&input[last..]);
+
+        Ok(result)
+    }
+
+    // Resolve an interpolation expression such as `var`, `a.b`,
+    // `CONFIG[depth].ports` or `var || "default"`
+    fn resolve_expression(&self, expr: &str, ctx: &LuaTable) -> Result<LuaValue> {
+        if let Some((primary, fallback)) = expr.split_once("||") {
+            return match self.resolve_expression(primary.trim(), ctx) {
+                Ok(value) if is_truthy(&value) => Ok(value),
+                _ => {
+                    let fallback = fallback.trim().trim_matches('"');
+                    Ok(LuaValue::String(self.lua.create_string(fallback)?))
+                }
+            };
+        }
+
+        let mut segments = path_segments(expr.trim()).into_iter();
+        let base = match segments.next() {
+            Some(PathSegment::Field(base)) => base,
+            _ => bail!("Invalid interpolation expression: #{{{}}}", expr),
+        };
+
+        let mut current = self.lookup(&base, ctx)?;
+        for segment in segments {
+            let key = match segment {
+                PathSegment::Field(key) | PathSegment::Literal(key) => key,
+                PathSegment::Variable(var) => {
+                    let value = self.lookup(&var, ctx)?;
+                    self.lua_to_string(&value)?
+                }
+            };
+            current = self.index(&current, &key)?;
+        }
+
+        Ok(current)
+    }
+
+    fn lookup(&self, name: &str, ctx: &LuaTable) -> Result<LuaValue> {
+        match ctx.get::<LuaValue>(name)? {
+            LuaValue::Nil => bail!("Undefined variable: {}", name),
+            value => Ok(value),
+        }
+    }
+
+    fn index(&self, value: &LuaValue, key: &str) -> Result<LuaValue> {
+        let table = match value {
+            LuaValue::Table(table) => table,
+            _ => return Ok(LuaValue::Nil),
+        };
+        // Arrays are indexed from zero in the DSL
+        match key.parse::<i64>() {
+            Ok(i) => Ok(table.get::<LuaValue>(i + 1)?),
+            Err(_) => Ok(table.get::<LuaValue>(key)?),
+        }
+    }
+
+    fn lua_to_string(&self, value: &LuaValue) -> Result<String> {
+        Ok(match value {
+            LuaValue::Nil => String::new(),
             LuaValue::String(s) => s.to_str()?.to_string(),
             LuaValue::Integer(i) => i.to_string(),
+            LuaValue::Number(n) if n.fract() == 0.0 && n.is_finite() => (*n as i64).to_string(),
             LuaValue::Number(n) => n.to_string(),
             LuaValue::Boolean(b) => b.to_string(),
+            LuaValue::Table(_) => {
+                let json: serde_json::Value = self.lua.from_value(value.clone())?;
+                json.to_string()
+            }
             _ => "[complex value]".to_string(),
-        };
+        })
+    }
+}
+
+// Task arguments that are handled by the runner rather than the task
+const CONTROL_ARGUMENTS: &[&str] = &[
+    "output",
+    "artifacts",
+    "timeout",
+    "retries",
+    "retry_delay",
+    "backoff",
+    "retry_on",
+    "on_failure",
+    "continue_on_error",
+    "cache",
+    "cache_ttl",
+    "inputs",
+    "requires",
+    "agent",
+];
+
+// Every file under `dir`, relative to it, in name order
+pub(crate) fn files_in(dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    let mut entries: Vec<_> = fs::read_dir(dir)
+        .with_context(|| format!("Failed to read {}", dir.display()))?
+        .collect::<std::io::Result<_>>()?;
+    entries.sort_by_key(|entry| entry.file_name());
+
+    for entry in entries {
+        let path = entry.path();
+        if path.is_dir() {
+            let name = PathBuf::from(entry.file_name());
+            files.extend(files_in(&path)?.into_iter().map(|file| name.join(file)));
+        } else {
+            files.push(PathBuf::from(entry.file_name()));
+        }
+    }
 
-        result = result.replace(&format!("#{{{}}}", var_name), &var_str);
+    Ok(files)
+}
+
+// Whether a task failure was a timeout, and the exit code of a failed command
+fn failure_details(error: &anyhow::Error) -> (bool, Option<i32>) {
+    match error.chain().find_map(|e| e.downcast_ref::<cmd::CmdError>()) {
+        Some(cmd::CmdError::Timeout(_)) => (true, None),
+        Some(cmd::CmdError::ExitCode { code, .. }) => (false, Some(*code)),
+        _ => (false, None),
+    }
+}
+
+fn is_truthy(value: &LuaValue) -> bool {
+    match value {
+        LuaValue::Nil => false,
+        LuaValue::Boolean(b) => *b,
+        LuaValue::String(s) => !s.as_bytes().is_empty(),
+        _ => true,
     }
+}
 
-    Ok(result)
+fn interpolation_regex() -> Regex {
+    Regex::new(r"#\{([^}]+)\}").unwrap()
 }
 
-// Function to generate tasks from meta-task descriptions
-fn generate_tasks_from_meta_tasks(
-    meta_tasks: &HashMap<String, (String, String)>,
-    meta_task_names: &[String],
-    custom_task_names: &[String],
-    model: &str,
-    style: Option<&str>,
-) -> Result<String, Box<dyn std::error::Error>> {
-    todo!()
+enum PathSegment {
+    // `a` or `.a`
+    Field(String),
+    // `[a]`, the value of the variable a
+    Variable(String),
+    // `["a"]`
+    Literal(String),
+}
+
+fn path_segments(expr: &str) -> Vec<PathSegment> {
+    let re = Regex::new(r#"^\.?([A-Za-z0-9_]+)|^\[\s*"([^"]*)"\s*\]|^\[\s*([A-Za-z0-9_]+)\s*\]"#)
+        .unwrap();
+    let mut segments = Vec::new();
+    let mut rest = expr;
+
+    while let Some(cap) = re.captures(rest) {
+        if let Some(field) = cap.get(1) {
+            segments.push(PathSegment::Field(field.as_str().to_string()));
+        } else if let Some(literal) = cap.get(2) {
+            segments.push(PathSegment::Literal(literal.as_str().to_string()));
+        } else if let Some(var) = cap.get(3) {
+            let var = var.as_str();
+            // Numeric indexes are literal keys
+            if var.chars().all(|c| c.is_ascii_digit()) {
+                segments.push(PathSegment::Literal(var.to_string()));
+            } else {
+                segments.push(PathSegment::Variable(var.to_string()));
+            }
+        }
+        rest = &rest[cap.get(0).unwrap().end()..];
+    }
+
+    segments
 }
 
-// Function to generate flow from constraints
-fn generate_flow_from_constraints(
-    tasks: &LuaValue,
-    constraints: &LuaValue,
-    description: &LuaValue,
-    model: &str,
-    visualization: bool,
-) -> Result<String, Box<dyn std::error::Error>> {
-    todo!()
-}
\ No newline at end of file
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run_pipeline(source: &str) -> RunLog {
+        run_with_options(source.to_string(), &RunOptions::default()).unwrap()
+    }
+
+    // An empty directory for a test's run to work in
+    fn scratch(test: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("piper-{}-{}", test, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn attempts<'a>(log: &'a RunLog, task: &str) -> Vec<&'a Attempt> {
+        log.attempts.iter().filter(|a| a.task == task).collect()
+    }
+
+    fn finished(attempt: &Attempt) -> SystemTime {
+        attempt.started_at + attempt.duration
+    }
+
+    #[test]
+    fn retries_failed_tasks() {
+        let dir = scratch("retries");
+        let options = RunOptions {
+            working_dir: Some(dir.clone()),
+            ..Default::default()
+        };
+        let source = r#"pipeline flaky {
+  flaky = cmd("test -e tried && exit 0; touch tried; exit 1", retries=1, retry_delay="10ms")
+  picky = cmd("exit 1", retries=3, retry_on=["exit_code:2"])
+  flow: flaky > picky
+}"#;
+        let log = run_with_options(source.to_string(), &options).unwrap();
+        let flaky = attempts(&log, "flaky");
+        assert_eq!(flaky.iter().map(|a| a.number).collect::<Vec<_>>(), [1, 2]);
+        assert_eq!((flaky[0].exit_code, flaky[0].error.is_some()), (Some(1), true));
+        assert!(flaky[1].error.is_none());
+        assert!(finished(flaky[0]) <= flaky[1].started_at);
+
+        // Failures retry_on doesn't name aren't retried
+        assert_eq!(attempts(&log, "picky").len(), 1);
+        assert_eq!(log.status, RunStatus::Failed);
+        fs::remove_dir_all(&dir).unwrap();
+
+        // Cancelling a run stops it waiting to retry
+        let cancel = Arc::new(AtomicBool::new(false));
+        let options = RunOptions {
+            cancel: Some(cancel.clone()),
+            ..Default::default()
+        };
+        let source = r#"pipeline patient {
+  down = cmd("exit 1", retries=10, retry_delay="5s", backoff="exponential")
+  flow: down
+}"#;
+        let started = Instant::now();
+        let canceller = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(300));
+            cancel.store(true, Ordering::SeqCst);
+        });
+        let log = run_with_options(source.to_string(), &options).unwrap();
+        canceller.join().unwrap();
+        assert_eq!(log.status, RunStatus::Failed);
+        assert_eq!(attempts(&log, "down").len(), 1);
+        assert!(started.elapsed() < Duration::from_secs(4));
+    }
+
+    #[test]
+    fn carries_on_past_caught_and_tolerated_failures() {
+        let log = run_pipeline(
+            r#"pipeline guarded {
+  broken = cmd("exit 1", continue_on_error=true)
+  scan = cmd("exit 2")
+  parse = cmd("echo never")
+  alert = cmd("echo caught")
+  report = cmd("echo done")
+  flow: broken > try { scan > parse } catch { alert } > report
+}"#,
+        );
+        assert_eq!(log.status, RunStatus::PartiallyFailed, "{:?}", log.error);
+        let failed: Vec<_> = log.failures.iter().map(|f| f.task.as_str()).collect();
+        assert_eq!(failed, ["broken", "scan"]);
+        assert!(attempts(&log, "parse").is_empty());
+        assert_eq!(attempts(&log, "alert").len(), 1);
+        assert_eq!(attempts(&log, "report")[0].output.as_deref(), Some("done"));
+
+        // Without them, the first failure ends the run
+        let log = run_pipeline(
+            r#"pipeline unguarded {
+  scan = cmd("exit 2")
+  report = cmd("echo done")
+  flow: scan > report
+}"#,
+        );
+        assert_eq!(log.status, RunStatus::Failed);
+        assert!(log.failures.is_empty() && attempts(&log, "report").is_empty());
+    }
+
+    #[test]
+    fn runs_independent_tasks_in_parallel_and_dependent_ones_after() {
+        let log = run_pipeline(
+            r#"pipeline recon {
+  merge = cmd("echo #{a_out} #{b_out}")
+  a = cmd("sleep 0.5; echo a", output="a_out")
+  b = cmd("sleep 0.5; echo b", output="b_out")
+}"#,
+        );
+        assert_eq!(log.status, RunStatus::Succeeded, "{:?}", log.error);
+        let (a, b) = (attempts(&log, "a")[0], attempts(&log, "b")[0]);
+        let merge = attempts(&log, "merge")[0];
+        assert!(a.started_at < finished(b) && b.started_at < finished(a));
+        assert!(merge.started_at >= finished(a) && merge.started_at >= finished(b));
+        assert_eq!(merge.output.as_deref().map(str::trim), Some("a b"));
+    }
+
+    #[test]
+    fn resumes_failed_runs_after_their_completed_tasks() {
+        let dir = scratch("resume");
+        let mut options = RunOptions {
+            history: Some(dir.join("history.db")),
+            working_dir: Some(dir.clone()),
+            ..Default::default()
+        };
+        let source = r#"pipeline resumable {
+  first = cmd("echo ran >> first.log; echo found", output="found")
+  second = cmd("test -e ready && echo #{found}")
+  flow: first > second
+}"#;
+        let log = run_with_options(source.to_string(), &options).unwrap();
+        assert_eq!(log.status, RunStatus::Failed);
+
+        fs::write(dir.join("ready"), "").unwrap();
+        options.resume = log.run_id;
+        let resumed = run_with_options(source.to_string(), &options).unwrap();
+        assert_eq!(resumed.status, RunStatus::Succeeded, "{:?}", resumed.error);
+        assert!(attempts(&resumed, "first").is_empty());
+        // The skipped task's output comes from the checkpoint
+        let second = attempts(&resumed, "second")[0];
+        assert_eq!(second.output.as_deref(), Some("found"));
+        assert_eq!(fs::read_to_string(dir.join("first.log")).unwrap(), "ran\n");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn reruns_tasks_whose_output_holds_a_secret_when_resuming() {
+        let dir = scratch("resume-secret");
+        std::env::set_var("PIPER_TEST_RESUME_KEY", "s3cret");
+        let mut options = RunOptions {
+            history: Some(dir.join("history.db")),
+            working_dir: Some(dir.clone()),
+            ..Default::default()
+        };
+        let source = r#"pipeline login {
+  API_KEY = secret("env:PIPER_TEST_RESUME_KEY")
+  login = cmd("echo ran >> login.log; echo token-#{API_KEY}", output="token")
+  whoami = cmd("echo admin", output="user")
+  use = cmd("test -e ready && echo #{user} #{token} > used.txt")
+  flow: login > whoami > use
+}"#;
+        let log = run_with_options(source.to_string(), &options).unwrap();
+        assert_eq!(log.status, RunStatus::Failed);
+
+        fs::write(dir.join("ready"), "").unwrap();
+        options.resume = log.run_id;
+        let resumed = run_with_options(source.to_string(), &options).unwrap();
+        assert_eq!(resumed.status, RunStatus::Succeeded, "{:?}", resumed.error);
+        assert_eq!(attempts(&resumed, "login").len(), 1);
+        assert!(attempts(&resumed, "whoami").is_empty());
+        let used = fs::read_to_string(dir.join("used.txt")).unwrap();
+        assert_eq!(used, "admin token-s3cret\n");
+        assert_eq!(fs::read_to_string(dir.join("login.log")).unwrap(), "ran\nran\n");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn reuses_cached_results() {
+        let dir = scratch("cache");
+        let mut options = RunOptions {
+            cache: Some(dir.join("cache.db")),
+            working_dir: Some(dir.clone()),
+            ..Default::default()
+        };
+        let source = r#"pipeline lookups {
+  whois = cmd("echo ran >> whois.log; echo registrar", cache=true, output="whois")
+  report = cmd("echo #{whois}")
+  flow: whois > report
+}"#;
+        for _ in 0..2 {
+            let log = run_with_options(source.to_string(), &options).unwrap();
+            assert_eq!(log.status, RunStatus::Succeeded, "{:?}", log.error);
+            let report = attempts(&log, "report")[0];
+            assert_eq!(report.output.as_deref(), Some("registrar"));
+        }
+        assert_eq!(fs::read_to_string(dir.join("whois.log")).unwrap(), "ran\n");
+
+        // Runs without a cache run everything
+        options.cache = None;
+        run_with_options(source.to_string(), &options).unwrap();
+        assert_eq!(fs::read_to_string(dir.join("whois.log")).unwrap(), "ran\nran\n");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn caches_results_per_agent() {
+        let dir = scratch("cache-agents");
+        let mut agents: BTreeMap<String, Arc<dyn TaskExecutor>> = BTreeMap::new();
+        for region in ["local", "alpha", "beta"] {
+            fs::create_dir_all(dir.join(region)).unwrap();
+            fs::write(dir.join(region).join("region.txt"), region).unwrap();
+            let executor = LocalExecutor {
+                working_dir: Some(dir.join(region)),
+                limits: CommandLimits::default(),
+            };
+            agents.insert(region.to_string(), Arc::new(executor));
+        }
+        let options = RunOptions {
+            cache: Some(dir.join("cache.db")),
+            working_dir: Some(dir.join("local")),
+            agents,
+            ..Default::default()
+        };
+        let source = r#"pipeline regions {
+  hosts = ["a", "b"]
+  here = cmd("cat region.txt", cache=true)
+  alpha = cmd("cat region.txt", cache=true, agent="alpha")
+  beta = cmd("cat region.txt", cache=true, agent="beta")
+  probe = cmd("cat region.txt", cache=true)
+  each = for_each(items=#{hosts}, task=probe, agents=["alpha", "beta"])
+  flow: here > alpha > beta > each
+}"#;
+        for _ in 0..2 {
+            let log = run_with_options(source.to_string(), &options).unwrap();
+            assert_eq!(log.status, RunStatus::Succeeded, "{:?}", log.error);
+            let outputs: Vec<_> = log.attempts.iter().map(|a| a.output.as_deref()).collect();
+            let expected = ["local", "alpha", "beta", "alpha", "beta"];
+            assert_eq!(outputs, expected.map(Some), "{:?}", log.attempts);
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn keeps_declared_artifacts() {
+        let dir = scratch("artifacts");
+        let options = RunOptions {
+            artifact_dir: Some(dir.join("artifacts")),
+            working_dir: Some(dir.clone()),
+            ..Default::default()
+        };
+        let source = r#"pipeline scan {
+  scan = cmd("mkdir -p shots; echo open > ports.txt; echo png > shots/a.png",
+             artifacts=["ports.txt", "shots", "missing.txt"])
+  flow: scan
+}"#;
+        let log = run_with_options(source.to_string(), &options).unwrap();
+        assert_eq!(log.status, RunStatus::Succeeded, "{:?}", log.error);
+        let names: Vec<_> = log.artifacts.iter().map(|a| a.name.as_str()).collect();
+        assert_eq!(names, ["ports.txt", "shots/a.png"]);
+
+        let ports = &log.artifacts[0];
+        assert_eq!(ports.file, dir.join("artifacts/scan/ports.txt"));
+        assert_eq!(fs::read_to_string(&ports.file).unwrap(), "open\n");
+        assert_eq!(ports.size, 5);
+        assert_eq!(ports.sha256, format!("{:x}", Sha256::digest(b"open\n")));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn parallel_scripts_see_their_own_context() {
+        let hosts: Vec<String> = (0..400).map(|i| format!("\"h{}\"", i)).collect();
+        let log = run_pipeline(&format!(
+            r#"pipeline probes {{
+  hosts = [{}]
+  probe = lua(code="ctx.probe = ctx.host")
+  each = for_each(items=#{{hosts}}, as="host", task=probe, parallel=8)
+  check = lua(code="for _, it in ipairs(ctx.each) do assert(it.probe == it.host, it.host) end")
+  flow: each > check
+}}"#,
+            hosts.join(", ")
+        ));
+        assert_eq!(log.status, RunStatus::Succeeded, "{:?}", log.error);
+    }
+
+    #[test]
+    fn runs_failure_hooks_within_their_time_limit() {
+        let log = run_pipeline(
+            r#"pipeline nightly {
+  hooks {
+    on_success: cmd("echo never")
+    on_failure: cmd("echo failed #{run.failed_task}")
+  }
+  crash = cmd("exit 3")
+  flow: crash
+}"#,
+        );
+        assert_eq!(log.status, RunStatus::Failed);
+        let hook = log.attempts.iter().find(|a| a.task == "on_failure").unwrap();
+        assert_eq!(hook.output.as_deref().map(str::trim), Some("failed crash"));
+        assert!(log.attempts.iter().all(|a| a.task != "on_success"));
+
+        // A cancelled run still runs its hooks, but they are stopped in turn
+        let options = RunOptions {
+            cancel: Some(Arc::new(AtomicBool::new(true))),
+            hook_time_limit: Some(Duration::from_millis(500)),
+            ..Default::default()
+        };
+        let started = Instant::now();
+        let source = r#"pipeline slow {
+  hooks { on_failure: cmd("sleep 30") }
+  scan = cmd("true")
+  flow: scan
+}"#;
+        let log = run_with_options(source.to_string(), &options).unwrap();
+        assert_eq!(log.status, RunStatus::Failed);
+        assert!(log.attempts.iter().any(|a| a.task == "on_failure" && a.error.is_some()));
+        assert!(started.elapsed() < Duration::from_secs(10));
+    }
+}
@@ -0,0 +1,201 @@
+use crate::hosts::{self, HostPattern};
+use ipnet::IpNet;
+use serde::Deserialize;
+use std::fmt;
+use std::net::IpAddr;
+
+// The last word of a parameter name that holds targets, as in
+// `target_domain`
+const TARGET_WORDS: &[&str] = &[
+    "target", "targets", "domain", "domains", "host", "hosts", "ip", "ips", "cidr", "url", "urls",
+];
+
+/// The `[scope]` section of a project's config file.
+///
+/// ```toml
+/// [scope]
+/// include = ["example.com", "*.example.com", "10.0.0.0/24"]
+/// exclude = ["vpn.example.com", "10.0.0.1"]
+/// ```
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ScopeConfig {
+    /// Hosts runs may target, any that aren't excluded if empty
+    pub include: Vec<String>,
+    /// Hosts runs may never target
+    pub exclude: Vec<String>,
+    /// Parameters and loop variables that hold targets, if not the ones
+    /// named like `target`, `host` or `url`
+    pub params: Vec<String>,
+}
+
+/// The hosts a project's runs may target.
+#[derive(Debug, Clone, Default)]
+pub struct Scope {
+    pub include: Vec<HostPattern>,
+    pub exclude: Vec<HostPattern>,
+    pub params: Vec<String>,
+}
+
+/// A run targeting a host outside the project's scope.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OutOfScope {
+    pub target: String,
+    /// Where the target came from, such as `parameter target`
+    pub found_in: String,
+}
+
+impl std::error::Error for OutOfScope {}
+
+impl fmt::Display for OutOfScope {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} in {} is out of scope, give --ignore-scope with a reason to go ahead",
+            self.target, self.found_in
+        )
+    }
+}
+
+impl ScopeConfig {
+    pub fn scope(&self) -> Result<Scope, String> {
+        let parse = |patterns: &[String], field: &str| {
+            patterns
+                .iter()
+                .map(|pattern| {
+                    pattern
+                        .parse()
+                        .map_err(|e| format!("{} in [scope]: {}", field, e))
+                })
+                .collect::<Result<Vec<HostPattern>, String>>()
+        };
+        Ok(Scope {
+            include: parse(&self.include, "include")?,
+            exclude: parse(&self.exclude, "exclude")?,
+            params: self.params.clone(),
+        })
+    }
+}
+
+impl Scope {
+    /// Whether a parameter or loop variable called `name` holds targets.
+    pub fn holds_targets(&self, name: &str) -> bool {
+        let name = name.to_ascii_lowercase();
+        if !self.params.is_empty() {
+            return self
+                .params
+                .iter()
+                .any(|param| param.eq_ignore_ascii_case(&name));
+        }
+        let last = name.rsplit(['_', '-']).next().unwrap_or(&name);
+        TARGET_WORDS.contains(&last)
+    }
+
+    /// Check every target in `value`: URLs, hosts with or without a port,
+    /// IP addresses and CIDR ranges, separated by whitespace or commas.
+    /// Ranges are in scope when an included range holds all of them and no
+    /// excluded one overlaps them.
+    pub fn check(&self, value: &str, found_in: &str) -> Result<(), OutOfScope> {
+        let allow = (!self.include.is_empty()).then_some(self.include.as_slice());
+        for target in value.split(|c: char| c.is_whitespace() || c == ',') {
+            let target = target.trim_matches(['"', '\'']);
+            if target.is_empty() {
+                continue;
+            }
+            let in_scope = match target.parse::<IpNet>() {
+                Ok(range) if !target.contains("://") => self.covers(range),
+                _ => host(target)
+                    .is_some_and(|host| hosts::check(&host, allow, &self.exclude).is_ok()),
+            };
+            if !in_scope {
+                return Err(OutOfScope {
+                    target: target.to_string(),
+                    found_in: found_in.to_string(),
+                });
+            }
+        }
+        Ok(())
+    }
+
+    fn covers(&self, range: IpNet) -> bool {
+        let ranges = |patterns: &[HostPattern]| -> Vec<IpNet> {
+            patterns
+                .iter()
+                .filter_map(|pattern| match pattern {
+                    HostPattern::Range(range) => Some(*range),
+                    _ => None,
+                })
+                .collect()
+        };
+        let included = self.include.is_empty()
+            || ranges(&self.include)
+                .iter()
+                .any(|included| included.contains(&range));
+        let excluded = ranges(&self.exclude).iter().any(|excluded| {
+            excluded.contains(&range.network()) || range.contains(&excluded.network())
+        });
+        included && !excluded
+    }
+}
+
+// The host in a target such as `https://example.com/x`, `example.com:8443`
+// or `[::1]:80`
+fn host(target: &str) -> Option<String> {
+    if target.contains("://") {
+        return hosts::url_host(target).ok();
+    }
+    if target.parse::<IpAddr>().is_ok() {
+        return Some(target.to_string());
+    }
+    let authority = target.split('/').next().unwrap_or(target);
+    let host = match authority.strip_prefix('[') {
+        Some(rest) => rest.split(']').next().unwrap_or(rest),
+        None => authority.split(':').next().unwrap_or(authority),
+    };
+    (!host.is_empty()).then(|| host.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn checks_targets_against_the_scope() {
+        let scope = ScopeConfig {
+            include: vec![
+                "example.com".into(),
+                "*.example.com".into(),
+                "10.0.0.0/16".into(),
+            ],
+            exclude: vec!["vpn.example.com".into(), "10.0.5.0/24".into()],
+            params: Vec::new(),
+        }
+        .scope()
+        .unwrap();
+
+        for value in [
+            "example.com",
+            "https://api.example.com:8443/v1",
+            "www.example.com:80, 10.0.1.7",
+            "10.0.1.0/24",
+            "[10.0.2.2]:22",
+        ] {
+            assert!(scope.check(value, "parameter target").is_ok(), "{}", value);
+        }
+        for value in [
+            "exmaple.com",
+            "example.com.evil.net",
+            "vpn.example.com",
+            "api.example.com 10.0.5.9",
+            "10.0.0.0/8",
+            "10.0.4.0/23",
+        ] {
+            assert!(scope.check(value, "parameter target").is_err(), "{}", value);
+        }
+
+        assert!(scope.holds_targets("target"));
+        assert!(scope.holds_targets("TARGET_DOMAIN"));
+        assert!(!scope.holds_targets("hosts_file"));
+        assert!(!scope.holds_targets("wordlist"));
+    }
+}
@@ -0,0 +1,332 @@
+use anyhow::{anyhow, bail, Context, Result};
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, CHACHA20_POLY1305, NONCE_LEN};
+use ring::pbkdf2;
+use ring::rand::{SecureRandom, SystemRandom};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::num::NonZeroU32;
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Arc, RwLock};
+
+/// Where a project keeps its secrets unless its config says otherwise.
+pub const DEFAULT_SECRETS_FILE: &str = ".piper/secrets.enc";
+
+/// The environment variable the secrets file's passphrase is read from
+/// unless the config says otherwise.
+pub const DEFAULT_KEY_VAR: &str = "PIPER_SECRETS_KEY";
+
+/// What secrets are replaced with in logs, history and notifications.
+pub const REDACTED: &str = "***";
+
+// Starts every secrets file, and is authenticated along with its content
+const MAGIC: &[u8] = b"PIPERSECRETS1";
+const SALT_LEN: usize = 16;
+const KEY_ITERATIONS: u32 = 100_000;
+
+/// Secrets kept encrypted with a passphrase, as a JSON object of names to
+/// values sealed with ChaCha20-Poly1305 under a key derived with PBKDF2.
+#[derive(Clone)]
+pub struct SecretsFile {
+    pub path: PathBuf,
+    /// None if it wasn't given, which leaves the file unreadable
+    passphrase: Option<String>,
+}
+
+// Leaves the passphrase out
+impl fmt::Debug for SecretsFile {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("SecretsFile")
+            .field("path", &self.path)
+            .finish_non_exhaustive()
+    }
+}
+
+impl SecretsFile {
+    pub fn new(path: PathBuf, passphrase: Option<String>) -> Self {
+        SecretsFile { path, passphrase }
+    }
+
+    /// Every secret in the file, none if there is no file yet.
+    pub fn load(&self) -> Result<BTreeMap<String, String>> {
+        let sealed = match fs::read(&self.path) {
+            Ok(sealed) => sealed,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(BTreeMap::new()),
+            Err(e) => {
+                return Err(e).with_context(|| format!("Failed to read {}", self.path.display()))
+            }
+        };
+        let invalid = || anyhow!("{} is not a secrets file", self.path.display());
+        let rest = sealed.strip_prefix(MAGIC).ok_or_else(invalid)?;
+        if rest.len() < SALT_LEN + NONCE_LEN {
+            return Err(invalid());
+        }
+        let (salt, rest) = rest.split_at(SALT_LEN);
+        let (nonce, ciphertext) = rest.split_at(NONCE_LEN);
+        let nonce = Nonce::try_assume_unique_for_key(nonce).map_err(|_| invalid())?;
+
+        let mut content = ciphertext.to_vec();
+        let content = self
+            .key(salt)?
+            .open_in_place(nonce, Aad::from(MAGIC), &mut content)
+            .map_err(|_| {
+                anyhow!(
+                    "Failed to decrypt {}, the passphrase is wrong or the file was changed",
+                    self.path.display()
+                )
+            })?;
+        serde_json::from_slice(content)
+            .with_context(|| format!("{} holds no secrets", self.path.display()))
+    }
+
+    /// Replace the file with `secrets`, readable only by its owner.
+    pub fn save(&self, secrets: &BTreeMap<String, String>) -> Result<()> {
+        let random = SystemRandom::new();
+        let mut salt = [0u8; SALT_LEN];
+        let mut nonce = [0u8; NONCE_LEN];
+        random
+            .fill(&mut salt)
+            .and_then(|_| random.fill(&mut nonce))
+            .map_err(|_| anyhow!("Failed to generate a nonce"))?;
+
+        let mut content = serde_json::to_vec(secrets)?;
+        self.key(&salt)?
+            .seal_in_place_append_tag(
+                Nonce::assume_unique_for_key(nonce),
+                Aad::from(MAGIC),
+                &mut content,
+            )
+            .map_err(|_| anyhow!("Failed to encrypt the secrets"))?;
+        let sealed = [MAGIC, &salt, &nonce, &content].concat();
+
+        if let Some(dir) = self.path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            fs::create_dir_all(dir)
+                .with_context(|| format!("Failed to create {}", dir.display()))?;
+        }
+        write_private(&self.path, &sealed)
+            .with_context(|| format!("Failed to write {}", self.path.display()))
+    }
+
+    pub fn get(&self, name: &str) -> Result<Option<String>> {
+        Ok(self.load()?.remove(name))
+    }
+
+    pub fn set(&self, name: &str, value: &str) -> Result<()> {
+        let mut secrets = self.load()?;
+        secrets.insert(name.to_string(), value.to_string());
+        self.save(&secrets)
+    }
+
+    /// Remove a secret, returning whether there was one.
+    pub fn remove(&self, name: &str) -> Result<bool> {
+        let mut secrets = self.load()?;
+        let removed = secrets.remove(name).is_some();
+        if removed {
+            self.save(&secrets)?;
+        }
+        Ok(removed)
+    }
+
+    fn key(&self, salt: &[u8]) -> Result<LessSafeKey> {
+        let passphrase = self.passphrase.as_deref().ok_or_else(|| {
+            anyhow!(
+                "No passphrase for the secrets file {}, set {} or `key` under [secrets] in config.toml",
+                self.path.display(),
+                DEFAULT_KEY_VAR
+            )
+        })?;
+        let mut key = [0u8; 32];
+        pbkdf2::derive(
+            pbkdf2::PBKDF2_HMAC_SHA256,
+            NonZeroU32::new(KEY_ITERATIONS).unwrap(),
+            salt,
+            passphrase.as_bytes(),
+            &mut key,
+        );
+        let key = UnboundKey::new(&CHACHA20_POLY1305, &key)
+            .map_err(|_| anyhow!("Failed to derive the secrets key"))?;
+        Ok(LessSafeKey::new(key))
+    }
+}
+
+// Secret files are only readable by their owner
+fn write_private(path: &Path, content: &[u8]) -> std::io::Result<()> {
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    std::io::Write::write_all(&mut options.open(path)?, content)
+}
+
+/// Resolve a `secret("NAME")` value: `env:NAME` reads only the environment
+/// variable, `file:PATH` reads the file at PATH, which must be under `root`,
+/// and a bare NAME is looked up in the environment, then in `file`. The
+/// secrets file's passphrase is never given out, as pipelines sent to an
+/// agent could read it otherwise.
+pub fn resolve(name: &str, file: Option<&SecretsFile>, root: &Path) -> Result<String> {
+    let passphrase = file.and_then(|file| file.passphrase.as_deref());
+    let check = |value: String| {
+        if Some(value.as_str()) == passphrase {
+            bail!("Secret {} is the secrets file's passphrase, which can't be used", name);
+        }
+        Ok(value)
+    };
+    let var = name.strip_prefix("env:").unwrap_or(name);
+    if var == DEFAULT_KEY_VAR {
+        bail!("Secret {} is the secrets file's passphrase, which can't be used", name);
+    }
+
+    if name.starts_with("env:") {
+        return std::env::var(var)
+            .map_err(|_| {
+                anyhow!(
+                    "Secret {}: the environment variable {} is not set",
+                    name,
+                    var
+                )
+            })
+            .and_then(check);
+    }
+    if let Some(path) = name.strip_prefix("file:") {
+        let value = fs::read_to_string(confine(path, root)?)
+            .with_context(|| format!("Failed to read secret {}", path))?;
+        return check(value.trim_end_matches(['\r', '\n']).to_string());
+    }
+    if let Ok(value) = std::env::var(name) {
+        return check(value);
+    }
+    match file {
+        Some(file) => file.get(name)?.ok_or_else(|| {
+            anyhow!(
+                "Secret {} is not set in the environment or {}",
+                name,
+                file.path.display()
+            )
+        }),
+        None => bail!("Secret {} is not set in the environment", name),
+    }
+}
+
+// The path of a `file:` secret, which must be under `root` once links are
+// followed
+fn confine(path: &str, root: &Path) -> Result<PathBuf> {
+    let outside = || anyhow!("Secret file:{} is outside the run's directory", path);
+    let relative = Path::new(path)
+        .components()
+        .all(|component| matches!(component, Component::Normal(_) | Component::CurDir));
+    if !relative {
+        return Err(outside());
+    }
+    let root = fs::canonicalize(root)
+        .with_context(|| format!("Failed to read secret {}", path))?;
+    let file = fs::canonicalize(root.join(path))
+        .with_context(|| format!("Failed to read secret {}", path))?;
+    if !file.starts_with(&root) {
+        return Err(outside());
+    }
+    Ok(file)
+}
+
+/// The secret values a run resolved, to replace wherever its output goes.
+#[derive(Debug, Clone, Default)]
+pub struct Redactor {
+    values: Arc<RwLock<Vec<String>>>,
+}
+
+impl Redactor {
+    pub fn add(&self, value: &str) {
+        let value = value.trim();
+        let mut values = self.values.write().unwrap();
+        if value.is_empty() || values.iter().any(|known| known == value) {
+            return;
+        }
+        values.push(value.to_string());
+        // Longer secrets first, so one holding another is replaced whole
+        values.sort_by_key(|known| std::cmp::Reverse(known.len()));
+    }
+
+    pub fn redact(&self, text: &str) -> String {
+        let values = self.values.read().unwrap();
+        values.iter().fold(text.to_string(), |text, value| {
+            text.replace(value.as_str(), REDACTED)
+        })
+    }
+
+    /// Redact every string in a JSON value, keys included.
+    pub fn redact_json(&self, value: &mut serde_json::Value) {
+        match value {
+            serde_json::Value::String(s) => *s = self.redact(s),
+            serde_json::Value::Array(items) => {
+                items.iter_mut().for_each(|item| self.redact_json(item))
+            }
+            serde_json::Value::Object(object) => {
+                *object = std::mem::take(object)
+                    .into_iter()
+                    .map(|(key, mut value)| {
+                        self.redact_json(&mut value);
+                        (self.redact(&key), value)
+                    })
+                    .collect();
+            }
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_secrets_encrypted_and_redacts_them() {
+        let dir = std::env::temp_dir().join(format!("piper-secrets-{}", std::process::id()));
+        let path = dir.join("secrets.enc");
+        let file = SecretsFile::new(path.clone(), Some("passphrase".to_string()));
+        assert!(file.load().unwrap().is_empty());
+
+        file.set("API_KEY", "sk-12345").unwrap();
+        file.set("TOKEN", "t0k3n").unwrap();
+        assert!(file.remove("TOKEN").unwrap());
+        assert_eq!(file.get("API_KEY").unwrap().as_deref(), Some("sk-12345"));
+        assert!(!fs::read(&path)
+            .unwrap()
+            .windows(8)
+            .any(|w| w == b"sk-12345"));
+        assert!(SecretsFile::new(path.clone(), Some("wrong".to_string()))
+            .load()
+            .is_err());
+        assert!(SecretsFile::new(path, None).load().is_err());
+
+        let value = resolve("API_KEY", Some(&file), &dir).unwrap();
+        assert_eq!(value, "sk-12345");
+        assert!(resolve("MISSING_PIPER_SECRET", Some(&file), &dir).is_err());
+
+        fs::create_dir_all(dir.join("keys")).unwrap();
+        fs::write(dir.join("keys/shodan.txt"), "sh0dan\n").unwrap();
+        fs::write(dir.join("keys/phrase.txt"), "passphrase").unwrap();
+        assert_eq!(resolve("file:keys/shodan.txt", Some(&file), &dir).unwrap(), "sh0dan");
+        let keys = dir.join("keys");
+        for name in [
+            "file:/etc/hostname",
+            "file:../secrets.enc",
+            "file:../keys/../../etc/hostname",
+            "file:phrase.txt",
+            "env:PIPER_SECRETS_KEY",
+            "PIPER_SECRETS_KEY",
+        ] {
+            assert!(resolve(name, Some(&file), &keys).is_err(), "{}", name);
+        }
+        fs::remove_dir_all(&dir).unwrap();
+
+        let redactor = Redactor::default();
+        redactor.add(&value);
+        redactor.add("sk-12345-long");
+        assert_eq!(
+            redactor.redact("curl -H 'Authorization: sk-12345' -d sk-12345-long"),
+            "curl -H 'Authorization: ***' -d ***"
+        );
+        let mut body = serde_json::json!({"text": "key sk-12345", "n": 1});
+        redactor.redact_json(&mut body);
+        assert_eq!(body, serde_json::json!({"text": "key ***", "n": 1}));
+    }
+}
@@ -0,0 +1,229 @@
+use crate::bundle::Bundle;
+use crate::runner::files_in;
+use anyhow::{bail, Context, Result};
+use piper_dsl::TaskType;
+use piper_tasks::{cmd, llm};
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+use std::sync::atomic::AtomicBool;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// A task with its arguments interpolated, ready to run in this process or
+/// on an agent. The context it was interpolated from stays where the
+/// pipeline runs, so an agent only gets the values the task uses.
+#[derive(Debug, Clone)]
+pub struct TaskRequest {
+    pub task: String,
+    pub task_type: TaskType,
+    /// Arguments as the functions in piper_tasks take them
+    pub args: HashMap<String, String>,
+    pub timeout: Option<Duration>,
+    /// Paths the task declared as artifacts, which agents send back
+    pub artifacts: Vec<String>,
+    /// Local files the task declared as inputs, which are sent to agents
+    pub inputs: Option<Bundle>,
+}
+
+/// What a task produced.
+#[derive(Debug, Clone, Default)]
+pub struct TaskOutput {
+    /// Such as a command's stdout, without its trailing newlines
+    pub output: String,
+    /// The files the task wrote to its declared artifact paths, by path,
+    /// when it ran somewhere the pipeline can't read them
+    pub files: Vec<(String, Vec<u8>)>,
+}
+
+/// Runs single cmd and llm tasks, in this process or elsewhere.
+pub trait TaskExecutor: Send + Sync + fmt::Debug {
+    /// Run a task, passing each line its command writes to `on_line`. A
+    /// failed command fails with a `cmd::CmdError`, which retry policies
+    /// look at.
+    fn run(
+        &self,
+        request: &TaskRequest,
+        on_line: cmd::LineHandler,
+        cancel: Option<Arc<AtomicBool>>,
+    ) -> Result<TaskOutput>;
+
+    /// Which of `tools` aren't on the $PATH tasks run with.
+    fn missing_tools(&self, tools: &[String]) -> Result<Vec<String>>;
+}
+
+/// Limits put on each command a task starts, applied to the command's
+/// process and inherited by whatever it starts.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CommandLimits {
+    /// Most memory the command can map, in bytes
+    pub memory: Option<u64>,
+    /// Most CPU time the command can use before it is killed
+    pub cpu_time: Option<Duration>,
+}
+
+impl CommandLimits {
+    // As the arguments cmd::run_streaming takes them
+    fn add_args(&self, args: &mut HashMap<String, String>) {
+        if let Some(memory) = self.memory {
+            args.insert("memory_limit".to_string(), memory.to_string());
+        }
+        if let Some(cpu_time) = self.cpu_time {
+            args.insert("cpu_limit".to_string(), cpu_time.as_secs().max(1).to_string());
+        }
+    }
+}
+
+/// Runs tasks in this process, from `working_dir` if set.
+#[derive(Debug, Clone, Default)]
+pub struct LocalExecutor {
+    pub working_dir: Option<PathBuf>,
+    pub limits: CommandLimits,
+}
+
+impl TaskExecutor for LocalExecutor {
+    fn run(
+        &self,
+        request: &TaskRequest,
+        on_line: cmd::LineHandler,
+        cancel: Option<Arc<AtomicBool>>,
+    ) -> Result<TaskOutput> {
+        let output = match request.task_type {
+            TaskType::Cmd => {
+                let mut args = request.args.clone();
+                if let Some(dir) = &self.working_dir {
+                    args.insert("cwd".to_string(), dir.display().to_string());
+                }
+                self.limits.add_args(&mut args);
+                let output = cmd::run_streaming(&args, request.timeout, on_line, cancel)?;
+                output.stdout.trim_end_matches('\n').to_string()
+            }
+            TaskType::Llm => llm::run(&request.args)?,
+            ref task_type => bail!("{} tasks can't run on their own", task_type.to_string()),
+        };
+        Ok(TaskOutput {
+            output,
+            files: Vec::new(),
+        })
+    }
+
+    fn missing_tools(&self, tools: &[String]) -> Result<Vec<String>> {
+        Ok(tools
+            .iter()
+            .filter(|tool| cmd::find_executable(tool).is_none())
+            .cloned()
+            .collect())
+    }
+}
+
+/// Check that artifact `paths` are relative paths that stay under the
+/// directory the task runs in, as agents send the files back to whoever sent
+/// the task.
+pub fn check_artifact_paths(paths: &[String]) -> Result<()> {
+    for path in paths {
+        let path = Path::new(path);
+        let relative = path.components().all(|c| matches!(c, Component::Normal(_)));
+        if path.as_os_str().is_empty() || !relative {
+            bail!("Artifact {} isn't a path within the task's directory", path.display());
+        }
+    }
+    Ok(())
+}
+
+/// Read the files at `paths` relative to `root`, with every file in the
+/// directories among them, for sending back what a task wrote. Paths that
+/// don't exist are left out, as a task may not write all it declares, and
+/// paths or links leading out of `root` are an error.
+pub fn read_files(paths: &[String], root: &Path) -> Result<Vec<(String, Vec<u8>)>> {
+    check_artifact_paths(paths)?;
+    let root =
+        fs::canonicalize(root).with_context(|| format!("Failed to read {}", root.display()))?;
+    let mut files = Vec::new();
+    for path in paths {
+        let full = root.join(path);
+        let found = if full.is_dir() {
+            files_in(&full)?
+                .into_iter()
+                .map(|relative| {
+                    let relative = relative.to_string_lossy().replace('\\', "/");
+                    (format!("{}/{}", path.trim_end_matches('/'), relative), full.join(relative))
+                })
+                .collect()
+        } else if full.is_file() {
+            vec![(path.clone(), full)]
+        } else {
+            Vec::new()
+        };
+        for (path, file) in found {
+            if !fs::canonicalize(&file).is_ok_and(|file| file.starts_with(&root)) {
+                bail!("Artifact {} leads out of the task's directory", path);
+            }
+            let content =
+                fs::read(&file).with_context(|| format!("Failed to read {}", file.display()))?;
+            files.push((path, content));
+        }
+    }
+    Ok(files)
+}
+
+/// Write the files a task sent back relative to `root`, where it would have
+/// written them had it run there. Only the `declared` paths and files in
+/// them are written.
+pub fn write_files(files: &[(String, Vec<u8>)], declared: &[String], root: &Path) -> Result<()> {
+    for (path, content) in files {
+        let is_declared = declared.iter().any(|declared| {
+            let declared = declared.trim_end_matches('/');
+            path == declared || path.starts_with(&format!("{}/", declared))
+        });
+        if !is_declared || Path::new(path).components().any(|c| c == Component::ParentDir) {
+            bail!("{} isn't one of the task's artifacts", path);
+        }
+
+        let file = root.join(path);
+        if let Some(parent) = file.parent() {
+            fs::create_dir_all(parent)
+                .with_context(|| format!("Failed to create {}", parent.display()))?;
+        }
+        fs::write(&file, content).with_context(|| format!("Failed to write {}", file.display()))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sends_back_only_declared_files() {
+        let root = std::env::temp_dir().join(format!("piper_task_files_{}", std::process::id()));
+        fs::create_dir_all(root.join("agent/out/shots")).unwrap();
+        fs::write(root.join("agent/out/nmap.txt"), "22/tcp open").unwrap();
+        fs::write(root.join("agent/out/shots/a.png"), "png").unwrap();
+
+        let declared = vec!["out/nmap.txt".to_string(), "out/shots".to_string(), "gone.txt".to_string()];
+        let files = read_files(&declared, &root.join("agent")).unwrap();
+        let paths: Vec<&str> = files.iter().map(|(path, _)| path.as_str()).collect();
+        assert_eq!(paths, ["out/nmap.txt", "out/shots/a.png"]);
+        for outside in ["/etc/hostname", "../local", "out/../../x", ""] {
+            let outside = [outside.to_string()];
+            assert!(read_files(&outside, &root.join("agent")).is_err(), "{:?}", outside);
+        }
+        #[cfg(unix)]
+        {
+            std::os::unix::fs::symlink("/etc/hostname", root.join("agent/out/shots/link")).unwrap();
+            assert!(read_files(&declared, &root.join("agent")).is_err());
+            fs::remove_file(root.join("agent/out/shots/link")).unwrap();
+        }
+
+        write_files(&files, &declared, &root.join("local")).unwrap();
+        assert_eq!(fs::read_to_string(root.join("local/out/shots/a.png")).unwrap(), "png");
+
+        let escaping = vec![("out/shots/../../../x".to_string(), Vec::new())];
+        assert!(write_files(&escaping, &declared, &root.join("local")).is_err());
+        let undeclared = vec![("out/other.txt".to_string(), Vec::new())];
+        assert!(write_files(&undeclared, &declared, &root.join("local")).is_err());
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
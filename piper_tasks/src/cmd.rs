@@ -1,7 +1,12 @@
-use std::process::Command;
+use std::process::{Child, Command, Stdio};
 use std::str;
 use std::env;
 use std::collections::HashMap;
+use std::io::{BufRead, BufReader, Read};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
 
 /// Runs a given command using the default system shell.
 pub fn run(args: &HashMap<String, String>) -> (String, String) {
@@ -17,3 +22,229 @@ pub fn run(args: &HashMap<String, String>) -> (String, String) {
     
     (stdout, stderr)
 }
+
+/// Where `name` would be run from, searching $PATH unless it is a path.
+pub fn find_executable(name: &str) -> Option<std::path::PathBuf> {
+    if name.contains('/') {
+        let path = std::path::PathBuf::from(name);
+        return is_executable(&path).then_some(path);
+    }
+    env::split_paths(&env::var_os("PATH")?)
+        .map(|dir| dir.join(name))
+        .find(|path| is_executable(path))
+}
+
+fn is_executable(path: &std::path::Path) -> bool {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        path.metadata()
+            .is_ok_and(|m| m.is_file() && m.permissions().mode() & 0o111 != 0)
+    }
+    #[cfg(not(unix))]
+    path.is_file()
+}
+
+/// Output of a command that ran to completion.
+#[derive(Debug, Clone)]
+pub struct CmdOutput {
+    pub stdout: String,
+    pub stderr: String,
+    pub exit_code: i32,
+}
+
+#[derive(Debug)]
+pub enum CmdError {
+    Spawn(std::io::Error),
+    Timeout(Duration),
+    /// Killed because the run was cancelled
+    Cancelled,
+    ExitCode { code: i32, stdout: String, stderr: String },
+}
+
+impl std::error::Error for CmdError {}
+
+impl std::fmt::Display for CmdError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            CmdError::Spawn(e) => write!(f, "failed to execute process: {}", e),
+            CmdError::Timeout(timeout) => write!(f, "command timed out after {:?}", timeout),
+            CmdError::Cancelled => write!(f, "command was cancelled"),
+            CmdError::ExitCode { code, stderr, .. } => {
+                write!(f, "command exited with code {}", code)?;
+                if !stderr.trim().is_empty() {
+                    write!(f, ": {}", stderr.trim())?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Which of a command's output streams a line came from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stream {
+    Stdout,
+    Stderr,
+}
+
+/// Called with each line a command writes, without its line ending.
+pub type LineHandler = Arc<dyn Fn(Stream, &str) + Send + Sync>;
+
+/// Runs a command like `run`, but fails on a non-zero exit code and kills the
+/// command if it runs longer than `timeout`.
+pub fn run_checked(
+    args: &HashMap<String, String>,
+    timeout: Option<Duration>,
+) -> Result<CmdOutput, CmdError> {
+    run_command(args, timeout, None, None)
+}
+
+/// Runs a command like `run_checked`, passing each line of output to
+/// `on_line` as soon as the command writes it. The command is killed once
+/// `cancel` is set.
+pub fn run_streaming(
+    args: &HashMap<String, String>,
+    timeout: Option<Duration>,
+    on_line: LineHandler,
+    cancel: Option<Arc<AtomicBool>>,
+) -> Result<CmdOutput, CmdError> {
+    run_command(args, timeout, Some(on_line), cancel)
+}
+
+fn run_command(
+    args: &HashMap<String, String>,
+    timeout: Option<Duration>,
+    on_line: Option<LineHandler>,
+    cancel: Option<Arc<AtomicBool>>,
+) -> Result<CmdOutput, CmdError> {
+    let cmd = args.get("cmd").cloned().unwrap_or_default();
+
+    let mut command = Command::new("sh");
+    command
+        .arg("-c")
+        .arg(cmd)
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped());
+    if let Some(dir) = args.get("cwd") {
+        command.current_dir(dir);
+    }
+    // Its own process group, so killing it also kills whatever the shell started
+    #[cfg(unix)]
+    std::os::unix::process::CommandExt::process_group(&mut command, 0);
+    #[cfg(unix)]
+    limit(&mut command, args)?;
+    let mut child = command.spawn().map_err(CmdError::Spawn)?;
+
+    // Drain the pipes while waiting so a chatty command can't block on a full pipe
+    let stdout = read_pipe(child.stdout.take(), Stream::Stdout, on_line.clone());
+    let stderr = read_pipe(child.stderr.take(), Stream::Stderr, on_line);
+
+    let started = Instant::now();
+    let status = loop {
+        if let Some(status) = child.try_wait().map_err(CmdError::Spawn)? {
+            break status;
+        }
+        if let Some(timeout) = timeout {
+            if started.elapsed() >= timeout {
+                kill(&mut child);
+                return Err(CmdError::Timeout(timeout));
+            }
+        }
+        if cancel.as_ref().is_some_and(|cancel| cancel.load(Ordering::SeqCst)) {
+            kill(&mut child);
+            return Err(CmdError::Cancelled);
+        }
+        thread::sleep(Duration::from_millis(20));
+    };
+
+    let stdout = stdout.join().unwrap_or_default();
+    let stderr = stderr.join().unwrap_or_default();
+    // Killed by a signal
+    let exit_code = status.code().unwrap_or(-1);
+
+    if exit_code != 0 {
+        return Err(CmdError::ExitCode { code: exit_code, stdout, stderr });
+    }
+
+    Ok(CmdOutput { stdout, stderr, exit_code })
+}
+
+// Apply the memory_limit (bytes) and cpu_limit (seconds) arguments as
+// rlimits of the shell, which whatever it starts inherits
+#[cfg(unix)]
+fn limit(command: &mut Command, args: &HashMap<String, String>) -> Result<(), CmdError> {
+    let parse = |key: &str| {
+        args.get(key)
+            .map(|value| {
+                value.parse::<u64>().map_err(|_| {
+                    let message = format!("{} must be a whole number, got {}", key, value);
+                    CmdError::Spawn(std::io::Error::new(std::io::ErrorKind::InvalidInput, message))
+                })
+            })
+            .transpose()
+    };
+    let memory = parse("memory_limit")?;
+    let cpu = parse("cpu_limit")?;
+    if memory.is_none() && cpu.is_none() {
+        return Ok(());
+    }
+
+    let rlimit = |value: u64| libc::rlimit {
+        rlim_cur: value as libc::rlim_t,
+        rlim_max: value as libc::rlim_t,
+    };
+    // SAFETY: the closure runs in the forked child before exec and only
+    // calls setrlimit, which is async-signal-safe
+    unsafe {
+        std::os::unix::process::CommandExt::pre_exec(command, move || {
+            if let Some(memory) = memory {
+                if libc::setrlimit(libc::RLIMIT_AS, &rlimit(memory)) != 0 {
+                    return Err(std::io::Error::last_os_error());
+                }
+            }
+            if let Some(cpu) = cpu {
+                if libc::setrlimit(libc::RLIMIT_CPU, &rlimit(cpu)) != 0 {
+                    return Err(std::io::Error::last_os_error());
+                }
+            }
+            Ok(())
+        });
+    }
+    Ok(())
+}
+
+fn kill(child: &mut Child) {
+    #[cfg(unix)]
+    unsafe {
+        // The group id is the shell's pid
+        libc::kill(-(child.id() as libc::pid_t), libc::SIGKILL);
+    }
+    let _ = child.kill();
+    let _ = child.wait();
+}
+
+fn read_pipe<R: Read + Send + 'static>(
+    pipe: Option<R>,
+    stream: Stream,
+    on_line: Option<LineHandler>,
+) -> thread::JoinHandle<String> {
+    thread::spawn(move || {
+        let mut buf = Vec::new();
+        if let Some(pipe) = pipe {
+            let mut reader = BufReader::new(pipe);
+            let mut line = Vec::new();
+            while let Ok(read) = reader.read_until(b'\n', &mut line) {
+                if read == 0 {
+                    break;
+                }
+                if let Some(on_line) = &on_line {
+                    let text = String::from_utf8_lossy(&line);
+                    on_line(stream, text.trim_end_matches(['\n', '\r']));
+                }
+                buf.append(&mut line);
+            }
+        }
+        String::from_utf8_lossy(&buf).into_owned()
+    })
+}
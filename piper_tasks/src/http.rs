@@ -2,11 +2,26 @@ use std::collections::HashMap;
 
 use anyhow::Ok;
 
-// Issues a simple HTTP GET request.
+// Requested by http tasks that don't give a url
+const DEFAULT_URL: &str = "http://httpbin.org/ip";
+
+/// The URL an http task requests, its `url` argument.
+pub fn url(args: &HashMap<String, String>) -> &str {
+    args.get("url").map_or(DEFAULT_URL, String::as_str)
+}
+
+// Issues a simple HTTP GET request. Redirects aren't followed, as the URL
+// is what the runner checks against the agent's policy and the run's scope
 pub async fn http_get(args: &HashMap<String, String>) -> Result<(), anyhow::Error> {
-    let res = reqwest::get("http://httpbin.org/ip").await?;
+    let client = reqwest::Client::builder()
+        .redirect(reqwest::redirect::Policy::none())
+        .build()?;
+    let res = client.get(url(args)).send().await?;
 
     println!("Status: {}", res.status());
+    if let Some(location) = res.headers().get(reqwest::header::LOCATION) {
+        println!("Not following the redirect to {}", location.to_str().unwrap_or("?"));
+    }
     println!("body = {res:?}");
     Ok(())
 }
@@ -26,6 +41,7 @@ pub async fn http_post(args: &HashMap<String, String>) -> Result<(), anyhow::Err
 ///
 /// Example task args in yaml
 ///
+/// ```yaml
 /// - name: POST to /foo/bar
 ///   task: raw_http_req
 ///   args:
@@ -37,6 +53,7 @@ pub async fn http_post(args: &HashMap<String, String>) -> Result<(), anyhow::Err
 ///         Content-Type: application/json
 ///
 ///         {"foo":"bar"}
+/// ```
 pub async fn raw_http_req(args: &HashMap<String, String>) -> Result<(), anyhow::Error> {
     Ok(())
 }
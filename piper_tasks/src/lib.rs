@@ -1,5 +1,5 @@
 pub mod cmd;
 pub mod http;
 pub mod llm;
-pub mod notify;
+pub mod template;
 pub mod var_ops;
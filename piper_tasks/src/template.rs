@@ -0,0 +1,147 @@
+use anyhow::{anyhow, Result};
+use minijinja::{path_loader, Environment, Error, ErrorKind, UndefinedBehavior, Value};
+use std::path::Path;
+
+// How long `truncate` cuts text to if not told
+const DEFAULT_TRUNCATE: usize = 255;
+
+/// Renders `source`, a Jinja-style template, against `context`. `name` is
+/// used in errors and picks HTML escaping for `.html` templates, and
+/// `{% include %}` and `{% extends %}` load templates from `dir`.
+///
+/// Undefined variables are errors, as with `#{var}`, so check for them with
+/// `is defined` or give them a `default`. Besides the usual Jinja filters
+/// there are:
+///
+/// * `truncate(length=255, end="...")` cuts text to `length` characters
+/// * `json(indent)` writes a value as JSON, pretty printed if given an indent
+/// * `markdown_escape` escapes text so markdown shows it as is
+/// * `from_json` reads a JSON document or JSON lines, such as a tool's
+///   output, into values to loop over
+pub fn render(
+    source: &str,
+    name: &str,
+    context: &serde_json::Value,
+    dir: Option<&Path>,
+) -> Result<String> {
+    let mut env = Environment::new();
+    env.set_undefined_behavior(UndefinedBehavior::Strict);
+    env.set_trim_blocks(true);
+    env.set_lstrip_blocks(true);
+    env.set_keep_trailing_newline(true);
+    if let Some(dir) = dir {
+        env.set_loader(path_loader(dir));
+    }
+    env.add_filter("truncate", truncate);
+    env.add_filter("json", json);
+    env.add_filter("markdown_escape", markdown_escape);
+    env.add_filter("from_json", from_json);
+
+    env.render_named_str(name, source, context)
+        .map_err(|e| anyhow!("Failed to render template {}: {}", name, describe(&e)))
+}
+
+// The error with the errors that caused it, which hold the details of
+// failures in included templates
+fn describe(error: &Error) -> String {
+    let mut description = error.to_string();
+    let mut source = std::error::Error::source(error);
+    while let Some(cause) = source {
+        description.push_str(&format!(": {}", cause));
+        source = cause.source();
+    }
+    description
+}
+
+fn truncate(text: String, length: Option<usize>, end: Option<String>) -> String {
+    let length = length.unwrap_or(DEFAULT_TRUNCATE);
+    if text.chars().count() <= length {
+        return text;
+    }
+    let end = end.unwrap_or_else(|| "...".to_string());
+    let mut cut: String = text
+        .chars()
+        .take(length.saturating_sub(end.chars().count()))
+        .collect();
+    cut.push_str(&end);
+    cut
+}
+
+fn json(value: Value, indent: Option<usize>) -> Result<String, Error> {
+    let result = match indent {
+        Some(indent) => {
+            let indent = " ".repeat(indent);
+            let formatter = serde_json::ser::PrettyFormatter::with_indent(indent.as_bytes());
+            let mut out = Vec::new();
+            let mut serializer = serde_json::Serializer::with_formatter(&mut out, formatter);
+            serde::Serialize::serialize(&value, &mut serializer)
+                .map(|_| String::from_utf8_lossy(&out).to_string())
+        }
+        None => serde_json::to_string(&value),
+    };
+    result.map_err(|e| Error::new(ErrorKind::BadSerialization, e.to_string()))
+}
+
+// Backslash escapes the punctuation that starts markdown formatting. Dots
+// and dashes are left alone, so hosts and addresses stay readable
+fn markdown_escape(text: String) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        if "\\`*_{}[]()<>#+!|~".contains(c) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+fn from_json(text: String) -> Result<Value, Error> {
+    let invalid =
+        |e: serde_json::Error| Error::new(ErrorKind::InvalidOperation, format!("not JSON: {}", e));
+    match serde_json::from_str::<serde_json::Value>(&text) {
+        Ok(value) => Ok(Value::from_serialize(value)),
+        // Tools such as nuclei and httpx write a JSON object per line, and
+        // nothing at all when they find nothing
+        Err(e) => text
+            .lines()
+            .filter(|line| !line.trim().is_empty())
+            .map(serde_json::from_str::<serde_json::Value>)
+            .collect::<Result<Vec<_>, _>>()
+            .map(Value::from_serialize)
+            .map_err(|_| invalid(e)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn renders_reports_with_filters() {
+        let context = json!({
+            "target": "acme.com",
+            "scan": "{\"host\":\"a.acme.com\",\"severity\":\"high\"}\n{\"host\":\"b.acme.com\",\"severity\":\"low\"}\n",
+            "notes": "*urgent* [see](x)",
+        });
+        let source = "# Findings for {{ target }}\n\
+            {% for finding in scan | from_json %}\n\
+            - {{ finding.host }}: {{ finding.severity }}\n\
+            {% endfor %}\n\
+            {{ notes | markdown_escape }}\n\
+            {{ target | truncate(6) }} {{ {\"n\": 1} | json }}\n";
+        assert_eq!(
+            render(source, "report.md.tmpl", &context, None).unwrap(),
+            "# Findings for acme.com\n\
+             - a.acme.com: high\n\
+             - b.acme.com: low\n\
+             \\*urgent\\* \\[see\\]\\(x\\)\n\
+             acm... {\"n\":1}\n"
+        );
+
+        let empty = render("{{ '' | from_json | length }}", "count", &context, None);
+        assert_eq!(empty.unwrap(), "0");
+        let error = render("{{ missing }}", "report.md.tmpl", &context, None).unwrap_err();
+        assert!(error.to_string().contains("report.md.tmpl"), "{}", error);
+    }
+}